@@ -0,0 +1,172 @@
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BitfieldError {
+    #[error("bitfield is {actual} bytes, expected {expected} for {num_pieces} pieces")]
+    Length {
+        actual: usize,
+        expected: usize,
+        num_pieces: usize,
+    },
+    #[error("bitfield has bits set past the last piece")]
+    SpareBits,
+}
+
+// Which pieces a peer has, one bit per piece. Bit 0 is the high bit of byte 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    // All pieces missing
+    pub fn new(num_pieces: usize) -> Self {
+        Bitfield(vec![0; num_pieces.div_ceil(8)])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.0.len()
+    }
+
+    // Bits past the end are simply missing pieces
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.0
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    // Grows the bitfield if needed
+    pub fn set_piece(&mut self, index: usize) {
+        if index / 8 >= self.0.len() {
+            self.0.resize(index / 8 + 1, 0);
+        }
+        self.0[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self, num_pieces: usize) -> bool {
+        (0..num_pieces).all(|index| self.has_piece(index))
+    }
+
+    // A bitfield from the wire must be exactly long enough, with the spare bits clear
+    pub fn validate(&self, num_pieces: usize) -> Result<(), BitfieldError> {
+        let expected = num_pieces.div_ceil(8);
+        if self.0.len() != expected {
+            return Err(BitfieldError::Length {
+                actual: self.0.len(),
+                expected,
+                num_pieces,
+            });
+        }
+        if self.count_ones() != self.pieces(num_pieces).count() {
+            return Err(BitfieldError::SpareBits);
+        }
+        Ok(())
+    }
+
+    // Indices of the pieces we have, ignoring spare bits past `num_pieces`
+    pub fn pieces(&self, num_pieces: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..num_pieces).filter(|&index| self.has_piece(index))
+    }
+}
+
+// How many of the bitfields have each piece
+pub fn availability<'a>(
+    bitfields: impl IntoIterator<Item = &'a Bitfield>,
+    num_pieces: usize,
+) -> Vec<usize> {
+    let mut counts = vec![0; num_pieces];
+    for bitfield in bitfields {
+        for index in bitfield.pieces(num_pieces) {
+            counts[index] += 1;
+        }
+    }
+    counts
+}
+
+impl From<Vec<u8>> for Bitfield {
+    fn from(value: Vec<u8>) -> Self {
+        Bitfield(value)
+    }
+}
+
+impl From<&[u8]> for Bitfield {
+    fn from(value: &[u8]) -> Self {
+        Bitfield(value.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_order_is_msb_first() {
+        let bitfield = Bitfield::from(vec![0b1000_0001, 0b0100_0000]);
+        assert!(bitfield.has_piece(0));
+        assert!(!bitfield.has_piece(1));
+        assert!(bitfield.has_piece(7));
+        assert!(!bitfield.has_piece(8));
+        assert!(bitfield.has_piece(9));
+        assert_eq!(bitfield.count_ones(), 3);
+    }
+
+    #[test]
+    fn test_set_piece() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set_piece(0);
+        bitfield.set_piece(9);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+
+        // Past the end grows rather than panics
+        bitfield.set_piece(17);
+        assert_eq!(bitfield.len_bytes(), 3);
+        assert!(bitfield.has_piece(17));
+    }
+
+    #[test]
+    fn test_longer_than_piece_count() {
+        // 3 pieces, but the peer sent two bytes with spare bits set
+        let bitfield = Bitfield::from(vec![0b1011_1111, 0xff]);
+        assert!(!bitfield.has_piece(100));
+        assert_eq!(bitfield.pieces(3).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(bitfield.count_ones(), 15);
+    }
+
+    #[test]
+    fn test_validate() {
+        // 10 pieces: two bytes, the last six bits spare
+        assert_eq!(Bitfield::from(vec![0xff, 0b1100_0000]).validate(10), Ok(()));
+        assert!(Bitfield::from(vec![0xff, 0b1100_0000]).is_complete(10));
+        assert!(!Bitfield::from(vec![0xff, 0b1000_0000]).is_complete(10));
+        assert_eq!(
+            Bitfield::from(vec![0xff, 0b1110_0000]).validate(10),
+            Err(BitfieldError::SpareBits)
+        );
+        assert_eq!(
+            Bitfield::from(vec![0xff]).validate(10),
+            Err(BitfieldError::Length {
+                actual: 1,
+                expected: 2,
+                num_pieces: 10
+            })
+        );
+        assert!(Bitfield::from(vec![0xff, 0, 0]).validate(10).is_err());
+        assert_eq!(Bitfield::new(0).validate(0), Ok(()));
+    }
+
+    #[test]
+    fn test_availability() {
+        let bitfields = [
+            Bitfield::from(vec![0b1100_0000]),
+            Bitfield::from(vec![0b0110_0001]),
+        ];
+        // The spare bit past piece 3 isn't counted
+        assert_eq!(availability(&bitfields, 4), vec![1, 2, 1, 0]);
+        assert_eq!(availability(&[], 2), vec![0, 0]);
+    }
+}
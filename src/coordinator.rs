@@ -0,0 +1,916 @@
+use anyhow::{anyhow, Error, Result};
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+
+use crate::bitfield::Bitfield;
+use crate::extension::{PexMessage, PexUpdates, MAX_PEX_PEERS};
+use crate::file::Info;
+use crate::network::{AsyncPeerStream, PeerMessage, DEFAULT_CONNECT_TIMEOUT};
+use crate::peer_set::{PeerSet, PeerSource};
+use crate::piece_selector::{PieceSelector, Strategy};
+use crate::ratelimit::RateLimits;
+
+// How many peers are downloaded from at once unless told otherwise
+pub const DEFAULT_MAX_PEERS: usize = 5;
+// How many times a piece is tried, from any peers, before it's given up on
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+// Every peer tried for a piece, & what went wrong with each
+#[derive(Debug, thiserror::Error)]
+#[error("could not download piece {piece_index}: {}", Failures(.failures))]
+pub struct PieceFailed {
+    pub piece_index: usize,
+    pub failures: Vec<(SocketAddr, String)>,
+}
+
+struct Failures<'a>(&'a [(SocketAddr, String)]);
+
+impl fmt::Display for Failures<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no peers to try");
+        }
+        for (i, (addr, reason)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", addr, reason)?;
+        }
+        Ok(())
+    }
+}
+
+// Pieces still to fetch, shared by every peer's task. A piece is queued, with
+// exactly one peer, done, or given up on after too many failed attempts.
+struct WorkQueue {
+    queued: BTreeSet<usize>,
+    in_flight: BTreeSet<usize>,
+    failed_attempts: HashMap<usize, usize>,
+    given_up: BTreeSet<usize>,
+    max_attempts: usize,
+    // Told what each peer has as workers find out
+    selector: Box<dyn PieceSelector>,
+}
+
+impl WorkQueue {
+    fn new(queued: BTreeSet<usize>, max_attempts: usize, selector: Box<dyn PieceSelector>) -> Self {
+        WorkQueue {
+            queued,
+            in_flight: BTreeSet::new(),
+            failed_attempts: HashMap::new(),
+            given_up: BTreeSet::new(),
+            max_attempts,
+            selector,
+        }
+    }
+
+    // Whichever queued piece the peer has that the selector picks
+    fn take(&mut self, peer_has: impl Fn(usize) -> bool) -> Option<usize> {
+        let mut up_for_grabs = Bitfield::default();
+        self.queued
+            .iter()
+            .filter(|&&index| peer_has(index))
+            .for_each(|&index| up_for_grabs.set_piece(index));
+        let piece_index = self.selector.next_piece(&up_for_grabs)?;
+        self.queued.remove(&piece_index);
+        self.in_flight.insert(piece_index);
+        Some(piece_index)
+    }
+
+    // Back in line for another peer, unless it's had all its attempts
+    fn requeue(&mut self, piece_index: usize) {
+        if !self.in_flight.remove(&piece_index) {
+            return;
+        }
+        let failed = self.failed_attempts.entry(piece_index).or_default();
+        *failed += 1;
+        if *failed >= self.max_attempts {
+            warn!(
+                "Giving up on piece {} after {} attempts",
+                piece_index, failed
+            );
+            self.given_up.insert(piece_index);
+        } else {
+            self.queued.insert(piece_index);
+        }
+    }
+
+    // False if the piece wasn't out with a peer, e.g. it's already done
+    fn finish(&mut self, piece_index: usize) -> bool {
+        self.in_flight.remove(&piece_index)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.queued.is_empty() && self.in_flight.is_empty()
+    }
+}
+
+// What every worker hears of the others' progress
+#[derive(Debug, Clone, Copy)]
+enum QueueChange {
+    // Verified & saved, so peers without it should hear we have it
+    Done(usize),
+    // Put back after a failure, maybe for a peer that's waiting
+    Requeued,
+}
+
+// Downloads from several peers at once, a tokio task per peer. Each task takes
+// whichever queued piece its peer has, verifies it & sends it back; a peer that
+// fails or drops puts its piece back for the others. Peers are sent a Have for each
+// piece saved, & ones left waiting on the last pieces are kept alive.
+pub struct Coordinator {
+    info: Arc<Info>,
+    max_peers: usize,
+    max_attempts: usize,
+    connect_timeout: Duration,
+    rate_limits: RateLimits,
+    trace: bool,
+    strategy: Strategy,
+    block_sizes: BlockSizes,
+}
+
+impl Coordinator {
+    pub fn new(info: Info) -> Self {
+        Coordinator {
+            info: Arc::new(info),
+            max_peers: DEFAULT_MAX_PEERS,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            rate_limits: RateLimits::default(),
+            trace: false,
+            strategy: Strategy::default(),
+            block_sizes: BlockSizes::default(),
+        }
+    }
+
+    // At least one
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers.max(1);
+        self
+    }
+
+    // Also at least one
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // Shared by every connection, see RateLimits
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    // How download picks the next piece for a peer; Sequential unless told otherwise
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    // Hands each of `piece_indices` to `save` exactly once, verified, in whatever
+    // order they come in. Peers that fail are marked so in `peers`; ones from
+    // `new_peers` join as slots free up. Returns the pieces no peer could supply,
+    // including any that failed `max_attempts` times.
+    pub async fn download(
+        &self,
+        peers: &mut PeerSet,
+        new_peers: &mut UnboundedReceiver<SocketAddr>,
+        piece_indices: &BTreeSet<usize>,
+        mut save: impl FnMut(usize, Vec<u8>) -> Result<()>,
+    ) -> Result<BTreeSet<usize>> {
+        let queue = Arc::new(Mutex::new(WorkQueue::new(
+            piece_indices.clone(),
+            self.max_attempts,
+            self.strategy.selector(self.info.iter_pieces().count()),
+        )));
+        let (pieces_tx, mut pieces) = mpsc::unbounded_channel();
+        let (changes, _) = broadcast::channel(piece_indices.len().max(1));
+        // Dropped on return, which stops any peer still at work
+        let mut workers = JoinSet::new();
+        let mut active = HashSet::new();
+        // Peers that ran out of pieces they have; worth another go if a piece is put back
+        let mut idle = HashSet::new();
+        let mut new_peers_open = true;
+        let (pex_found, mut pex_messages) = mpsc::unbounded_channel();
+        let pex = Pex {
+            connected: Arc::new(Mutex::new(BTreeSet::new())),
+            found: pex_found,
+        };
+
+        loop {
+            if queue.lock().unwrap().is_finished() {
+                return Ok(queue.lock().unwrap().given_up.clone());
+            }
+            let candidates: Vec<SocketAddr> = peers
+                .candidates()
+                .filter(|addr| !active.contains(addr) && !idle.contains(addr))
+                .take(self.max_peers.saturating_sub(active.len()))
+                .collect();
+            for addr in candidates {
+                active.insert(addr);
+                workers.spawn(self.work(
+                    addr,
+                    queue.clone(),
+                    pieces_tx.clone(),
+                    changes.clone(),
+                    pex.clone(),
+                ));
+            }
+            if workers.is_empty() {
+                // Every piece a finished peer sent is already in the channel
+                while let Ok((piece_index, piece)) = pieces.try_recv() {
+                    self.save_piece(&queue, &changes, &mut save, piece_index, piece)?;
+                }
+                let queue = queue.lock().unwrap();
+                let left = queue.queued.iter().chain(&queue.in_flight);
+                return Ok(left.chain(&queue.given_up).copied().collect());
+            }
+
+            tokio::select! {
+                Some((piece_index, piece)) = pieces.recv() => {
+                    self.save_piece(&queue, &changes, &mut save, piece_index, piece)?;
+                }
+                Some(joined) = workers.join_next() => {
+                    let (addr, result): (SocketAddr, Result<()>) = joined?;
+                    active.remove(&addr);
+                    match result {
+                        Ok(()) => {
+                            idle.insert(addr);
+                        }
+                        Err(e) => {
+                            warn!("Peer {} failed: {:#}", addr, e);
+                            peers.mark_failed(addr);
+                            idle.clear();
+                        }
+                    }
+                }
+                addr = new_peers.recv(), if new_peers_open => match addr {
+                    Some(addr) => {
+                        peers.insert(addr, PeerSource::Tracker);
+                    }
+                    None => new_peers_open = false,
+                },
+                Some(message) = pex_messages.recv() => {
+                    let added = peers.extend(
+                        message.added.into_iter().take(MAX_PEX_PEERS),
+                        PeerSource::Pex,
+                    );
+                    // Only peers we know of from PEX alone, & aren't talking to, are forgotten
+                    let dropped = message
+                        .dropped
+                        .iter()
+                        .filter(|addr| {
+                            let from_pex = peers
+                                .get(addr)
+                                .is_some_and(|info| info.source == PeerSource::Pex);
+                            from_pex && !active.contains(*addr) && peers.remove(addr)
+                        })
+                        .count();
+                    debug!("PEX: {} new peers, {} dropped", added, dropped);
+                }
+            }
+        }
+    }
+
+    fn save_piece(
+        &self,
+        queue: &Mutex<WorkQueue>,
+        changes: &broadcast::Sender<QueueChange>,
+        save: &mut impl FnMut(usize, Vec<u8>) -> Result<()>,
+        piece_index: usize,
+        piece: Vec<u8>,
+    ) -> Result<()> {
+        if queue.lock().unwrap().finish(piece_index) {
+            save(piece_index, piece)?;
+            // No workers left to hear it is fine
+            let _ = changes.send(QueueChange::Done(piece_index));
+        }
+        Ok(())
+    }
+
+    // One peer's task: connect, then fetch pieces until it has none left that are
+    // wanted. Any piece it was working on goes back in the queue on failure. While
+    // the only pieces left are out with other peers it stays connected, in case one
+    // is put back.
+    fn work(
+        &self,
+        addr: SocketAddr,
+        queue: Arc<Mutex<WorkQueue>>,
+        pieces: UnboundedSender<(usize, Vec<u8>)>,
+        changes: broadcast::Sender<QueueChange>,
+        pex: Pex,
+    ) -> impl std::future::Future<Output = (SocketAddr, Result<()>)> + Send + 'static {
+        // Subscribed now so nothing saved after the spawn is missed
+        let mut heard = changes.subscribe();
+        let info = self.info.clone();
+        let connect_timeout = self.connect_timeout;
+        let rate_limits = self.rate_limits.clone();
+        let trace = self.trace;
+        let block_sizes = self.block_sizes.clone();
+        async move {
+            let num_pieces = info.iter_pieces().count();
+            // What the selector's been told the peer has
+            let mut seen: Option<Bitfield> = None;
+            let result = async {
+                let mut peer_stream = ready_peer(
+                    addr,
+                    &info,
+                    connect_timeout,
+                    rate_limits,
+                    trace,
+                    &block_sizes,
+                )
+                .await?;
+                let seen = seen.insert(peer_stream.peer_bitfield().clone());
+                queue.lock().unwrap().selector.peer_added(seen);
+                pex.connected.lock().unwrap().insert(addr);
+                let mut pex_updates = PexUpdates::new();
+                loop {
+                    pex.exchange(addr, &mut peer_stream, &mut pex_updates)
+                        .await?;
+                    send_haves(&mut peer_stream, &mut heard).await?;
+                    let (taken, all_out) = {
+                        let mut queue = queue.lock().unwrap();
+                        // Its Haves since
+                        for index in peer_stream.peer_bitfield().pieces(num_pieces) {
+                            if !seen.has_piece(index) {
+                                seen.set_piece(index);
+                                queue.selector.peer_has(index);
+                            }
+                        }
+                        let taken = queue.take(|index| peer_stream.peer_has_piece(index as u32));
+                        (taken, queue.queued.is_empty())
+                    };
+                    let piece_index = match taken {
+                        Some(piece_index) => piece_index,
+                        None if all_out => {
+                            linger(&mut peer_stream, &mut heard).await?;
+                            continue;
+                        }
+                        None => {
+                            debug!("Nothing left that {} has", addr);
+                            return Ok(());
+                        }
+                    };
+                    let piece = peer_stream
+                        .download_piece(piece_index as u32, &info.piece_len(piece_index))
+                        .await;
+                    block_sizes.note(&peer_stream);
+                    match piece.and_then(|piece| verified(&info, piece_index, piece)) {
+                        Ok(piece) => {
+                            if pieces.send((piece_index, piece)).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => {
+                            queue.lock().unwrap().requeue(piece_index);
+                            let _ = changes.send(QueueChange::Requeued);
+                            return Err::<(), Error>(e);
+                        }
+                    }
+                }
+            }
+            .await;
+            pex.connected.lock().unwrap().remove(&addr);
+            if let Some(seen) = &seen {
+                queue.lock().unwrap().selector.peer_removed(seen);
+            }
+            (addr, result)
+        }
+    }
+
+    // One piece from whichever peer comes through first, trying each in turn for up
+    // to `max_attempts` attempts in all. Peers that fail are marked so in `peers`.
+    pub async fn download_piece(
+        &self,
+        peers: &mut PeerSet,
+        piece_index: usize,
+    ) -> Result<Vec<u8>, PieceFailed> {
+        let mut failures = Vec::new();
+        while failures.len() < self.max_attempts {
+            let Some(addr) = peers.next_candidate() else {
+                break;
+            };
+            let piece = async {
+                let mut peer_stream = ready_peer(
+                    addr,
+                    &self.info,
+                    self.connect_timeout,
+                    self.rate_limits.clone(),
+                    self.trace,
+                    &self.block_sizes,
+                )
+                .await?;
+                let piece = peer_stream
+                    .download_piece(piece_index as u32, &self.info.piece_len(piece_index))
+                    .await;
+                self.block_sizes.note(&peer_stream);
+                verified(&self.info, piece_index, piece?)
+            }
+            .await;
+            match piece {
+                Ok(piece) => return Ok(piece),
+                Err(e) => {
+                    warn!("Peer {} failed: {:#}", addr, e);
+                    peers.mark_failed(addr);
+                    failures.push((addr, format!("{:#}", e)));
+                }
+            }
+        }
+        Err(PieceFailed {
+            piece_index,
+            failures,
+        })
+    }
+}
+
+// What the workers share for PEX (BEP 11): who we're connected to, to tell peers
+// about, & a way to pass on the peers they tell us about
+#[derive(Clone)]
+struct Pex {
+    connected: Arc<Mutex<BTreeSet<SocketAddr>>>,
+    found: UnboundedSender<PexMessage>,
+}
+
+impl Pex {
+    // Between pieces: hand on what the peer sent, & send it what's changed if a
+    // minute has passed since the last update
+    async fn exchange(
+        &self,
+        addr: SocketAddr,
+        peer_stream: &mut AsyncPeerStream,
+        updates: &mut PexUpdates,
+    ) -> Result<()> {
+        for message in peer_stream.take_pex() {
+            let _ = self.found.send(message);
+        }
+        if !peer_stream.supports_pex() {
+            return Ok(());
+        }
+        let mut connected = self.connected.lock().unwrap().clone();
+        connected.remove(&addr);
+        if let Some(update) = updates.next_at(&connected, Instant::now()) {
+            peer_stream.write_pex(&update).await?;
+        }
+        Ok(())
+    }
+}
+
+// A Have for each piece saved since last time, to a peer that doesn't have it
+async fn send_haves(
+    peer_stream: &mut AsyncPeerStream,
+    heard: &mut broadcast::Receiver<QueueChange>,
+) -> Result<()> {
+    loop {
+        match heard.try_recv() {
+            Ok(QueueChange::Done(piece_index)) => send_have(peer_stream, piece_index).await?,
+            Ok(QueueChange::Requeued) | Err(TryRecvError::Lagged(_)) => {}
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+async fn send_have(peer_stream: &mut AsyncPeerStream, piece_index: usize) -> Result<()> {
+    let index = piece_index as u32;
+    if !peer_stream.peer_has_piece(index) {
+        peer_stream.write(&PeerMessage::Have(index)).await?;
+    }
+    Ok(())
+}
+
+// Waits for a piece to be put back, passing on Haves & keeping the connection
+// alive meanwhile. Whatever the peer sends is read, e.g. its Haves, & let be.
+async fn linger(
+    peer_stream: &mut AsyncPeerStream,
+    heard: &mut broadcast::Receiver<QueueChange>,
+) -> Result<()> {
+    debug!("Waiting on other peers with {}", peer_stream.peer_addr());
+    loop {
+        let wait = peer_stream.keep_alive().await?;
+        tokio::select! {
+            change = heard.recv() => match change {
+                Ok(QueueChange::Done(piece_index)) => send_have(peer_stream, piece_index).await?,
+                // Missed changes may have been a piece put back
+                Ok(QueueChange::Requeued) | Err(_) => return Ok(()),
+            },
+            message = peer_stream.read() => {
+                message?;
+            }
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+// Block size each peer settled on, e.g. after turning down bigger ones, carried
+// over to its next connection
+#[derive(Clone, Default)]
+struct BlockSizes(Arc<Mutex<HashMap<SocketAddr, u32>>>);
+
+impl BlockSizes {
+    fn apply(&self, peer_stream: &mut AsyncPeerStream) {
+        if let Some(&block_size) = self.0.lock().unwrap().get(&peer_stream.peer_addr()) {
+            peer_stream.set_block_size(block_size);
+        }
+    }
+
+    fn note(&self, peer_stream: &AsyncPeerStream) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(peer_stream.peer_addr(), peer_stream.block_size());
+    }
+}
+
+// Connected, handshaken & unchoked, ready to download from
+async fn ready_peer(
+    addr: SocketAddr,
+    info: &Info,
+    connect_timeout: Duration,
+    rate_limits: RateLimits,
+    trace: bool,
+    block_sizes: &BlockSizes,
+) -> Result<AsyncPeerStream> {
+    let mut peer_stream = AsyncPeerStream::connect(addr, connect_timeout).await?;
+    peer_stream.set_num_pieces(info.iter_pieces().count());
+    peer_stream.set_rate_limits(rate_limits);
+    block_sizes.apply(&mut peer_stream);
+    // Private torrents keep to the tracker's peers
+    peer_stream.set_pex(!info.is_private());
+    if trace {
+        peer_stream.set_trace(addr.to_string());
+    }
+    peer_stream.prep_download(&info.info_hash()).await?;
+    Ok(peer_stream)
+}
+
+fn verified(info: &Info, piece_index: usize, piece: Vec<u8>) -> Result<Vec<u8>> {
+    if !info.verify_piece(piece_index, &piece) {
+        return Err(anyhow!("Piece {} failed verification", piece_index));
+    }
+    Ok(piece)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piece_selector::Sequential;
+    use crate::testsupport::{
+        block_request, echo_handshake, info_for, read_message, serve_blocks, write_block,
+        write_message,
+    };
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    const PIECE_LENGTH: usize = 16;
+
+    fn content() -> Vec<u8> {
+        (0..100u8).collect()
+    }
+
+    fn info() -> Info {
+        info_for("sample.bin", &content(), PIECE_LENGTH)
+    }
+
+    // A peer with the pieces in `has`, which answers `serves` Requests before it hangs
+    // up, after keeping us waiting `delay` for its handshake
+    fn spawn_seeder(has: &[usize], serves: usize, delay: Duration) -> SocketAddr {
+        spawn_seeder_with(has, serves, delay, Duration::ZERO).0
+    }
+
+    // As spawn_seeder, taking `block_delay` over each block, & passing on whatever
+    // else we send after the first Interested
+    fn spawn_seeder_with(
+        has: &[usize],
+        serves: usize,
+        delay: Duration,
+        block_delay: Duration,
+    ) -> (SocketAddr, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let (heard_tx, heard) = std::sync::mpsc::channel();
+        let content = content();
+        let mut bitfield = Bitfield::new(content.len().div_ceil(PIECE_LENGTH));
+        has.iter().for_each(|&index| bitfield.set_piece(index));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            std::thread::sleep(delay);
+            echo_handshake(&mut stream).unwrap();
+            write_message(&mut stream, 5, bitfield.as_bytes());
+            write_message(&mut stream, 1, &[]);
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).unwrap();
+            serve_blocks(
+                &mut stream,
+                &content,
+                PIECE_LENGTH,
+                serves,
+                block_delay,
+                |message| {
+                    let _ = heard_tx.send(message);
+                },
+            );
+        });
+        (addr, heard)
+    }
+
+    async fn download(
+        coordinator: &Coordinator,
+        peers: &mut PeerSet,
+        piece_indices: &BTreeSet<usize>,
+    ) -> (BTreeSet<usize>, BTreeMap<usize, Vec<Vec<u8>>>) {
+        let (_, mut new_peers) = mpsc::unbounded_channel();
+        let mut saved: BTreeMap<usize, Vec<Vec<u8>>> = BTreeMap::new();
+        let left = coordinator
+            .download(
+                peers,
+                &mut new_peers,
+                piece_indices,
+                |piece_index, piece| {
+                    saved.entry(piece_index).or_default().push(piece);
+                    Ok(())
+                },
+            )
+            .await
+            .unwrap();
+        (left, saved)
+    }
+
+    #[tokio::test]
+    async fn test_download_survives_a_peer_dying() {
+        let all: Vec<usize> = (0..7).collect();
+        // Two pieces, then gone; the others are slow to start so it's sure to get a third
+        let dying = spawn_seeder(&all, 2, Duration::ZERO);
+        let mut peers = PeerSet::new();
+        peers.insert(dying, PeerSource::Manual);
+        for _ in 0..2 {
+            let addr = spawn_seeder(&all, usize::MAX, Duration::from_millis(200));
+            peers.insert(addr, PeerSource::Manual);
+        }
+
+        let coordinator = Coordinator::new(info()).with_max_peers(3);
+        let piece_indices = all.iter().copied().collect();
+        let (left, saved) = download(&coordinator, &mut peers, &piece_indices).await;
+
+        assert!(left.is_empty(), "{:?}", left);
+        let content = content();
+        for (&piece_index, copies) in &saved {
+            // Every piece saved exactly once
+            assert_eq!(copies.len(), 1, "piece {}", piece_index);
+            let start = piece_index * PIECE_LENGTH;
+            let end = (start + PIECE_LENGTH).min(content.len());
+            assert_eq!(copies[0], content[start..end]);
+        }
+        assert_eq!(saved.len(), 7);
+        assert_eq!(peers.get(&dying).unwrap().fail_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_keeps_one_connection_per_peer() {
+        let content = content();
+        let (accepted_tx, accepted) = std::sync::mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            // Serves every connection in full, so only the count tells a reconnect apart
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                accepted_tx.send(()).unwrap();
+                let content = content.clone();
+                std::thread::spawn(move || {
+                    echo_handshake(&mut stream).unwrap();
+                    write_message(&mut stream, 5, &[0xfe]);
+                    write_message(&mut stream, 1, &[]);
+                    serve_blocks(
+                        &mut stream,
+                        &content,
+                        PIECE_LENGTH,
+                        usize::MAX,
+                        Duration::ZERO,
+                        |_| {},
+                    );
+                });
+            }
+        });
+        let mut peers = PeerSet::new();
+        peers.insert(addr, PeerSource::Manual);
+
+        let coordinator = Coordinator::new(info());
+        let piece_indices = (0..7).collect();
+        let (left, saved) = download(&coordinator, &mut peers, &piece_indices).await;
+
+        assert!(left.is_empty(), "{:?}", left);
+        assert_eq!(saved.len(), 7);
+        // Every piece over the one handshake
+        assert_eq!(accepted.try_iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_goes_by_bitfields() {
+        // Nobody has piece 3
+        let mut peers = PeerSet::new();
+        peers.insert(
+            spawn_seeder(&[0, 1], usize::MAX, Duration::ZERO),
+            PeerSource::Manual,
+        );
+        peers.insert(
+            spawn_seeder(&[2, 4, 5, 6], usize::MAX, Duration::ZERO),
+            PeerSource::Manual,
+        );
+
+        let coordinator = Coordinator::new(info());
+        let piece_indices = (0..7).collect();
+        let (left, saved) = download(&coordinator, &mut peers, &piece_indices).await;
+
+        assert_eq!(left, BTreeSet::from([3]));
+        assert_eq!(
+            saved.keys().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 4, 5, 6]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_sends_haves() {
+        // Each slow peer has one piece, so they're both out while the fast peer works
+        let spawn = |has: &[usize], block_delay| {
+            spawn_seeder_with(has, usize::MAX, Duration::ZERO, block_delay)
+        };
+        let (fast, fast_heard) = spawn(&[0, 1, 2, 3, 4], Duration::ZERO);
+        let (slow, slow_heard) = spawn(&[5], Duration::from_millis(200));
+        let (slower, _) = spawn(&[6], Duration::from_millis(400));
+        let mut peers = PeerSet::new();
+        peers.extend([fast, slow, slower], PeerSource::Manual);
+
+        let coordinator = Coordinator::new(info()).with_max_peers(3);
+        let piece_indices = (0..7).collect();
+        let (left, saved) = download(&coordinator, &mut peers, &piece_indices).await;
+        assert!(left.is_empty(), "{:?}", left);
+        assert_eq!(saved.len(), 7);
+
+        // Read off the runtime, which has yet to drop the connections. The last
+        // piece's Have may not have gone out before they were.
+        let heard = |heard: std::sync::mpsc::Receiver<Vec<u8>>| async move {
+            tokio::task::spawn_blocking(move || heard.iter().collect::<Vec<_>>())
+                .await
+                .unwrap()
+        };
+        let have = |index: u8| vec![4, 0, 0, 0, index];
+        // Waiting on the others, it hears of the piece it lacks that's done meanwhile
+        let fast_heard = heard(fast_heard).await;
+        assert_eq!(fast_heard[0], have(5), "{:?}", fast_heard);
+        assert!(fast_heard[1..].iter().all(|message| *message == have(6)));
+        // Between pieces, it hears of those it lacks but never its own
+        let slow_heard = heard(slow_heard).await;
+        assert_eq!(slow_heard[..5], (0..5).map(have).collect::<Vec<_>>());
+        assert!(slow_heard[5..].iter().all(|message| *message == have(6)));
+    }
+
+    // Nothing listening there, so connecting is refused
+    fn refusing_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_fails_over_to_next_peer() {
+        let refusing = refusing_addr();
+        let mut peers = PeerSet::new();
+        peers.insert(refusing, PeerSource::Tracker);
+        peers.insert(
+            spawn_seeder(&[0, 1, 2], usize::MAX, Duration::ZERO),
+            PeerSource::Tracker,
+        );
+
+        let piece = Coordinator::new(info())
+            .download_piece(&mut peers, 1)
+            .await
+            .unwrap();
+        assert_eq!(piece, content()[16..32]);
+        assert_eq!(peers.get(&refusing).unwrap().fail_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_explains_every_failure() {
+        // One refuses, one lacks the piece, & there's no third attempt
+        let (refusing, lacking) = (
+            refusing_addr(),
+            spawn_seeder(&[0], usize::MAX, Duration::ZERO),
+        );
+        let mut peers = PeerSet::new();
+        peers.insert(refusing, PeerSource::Tracker);
+        peers.insert(lacking, PeerSource::Tracker);
+        peers.insert(
+            spawn_seeder(&[1], usize::MAX, Duration::ZERO),
+            PeerSource::Tracker,
+        );
+
+        let err = Coordinator::new(info())
+            .with_max_attempts(2)
+            .download_piece(&mut peers, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.piece_index, 1);
+        let tried: Vec<SocketAddr> = err.failures.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(tried, vec![refusing, lacking]);
+        let message = err.to_string();
+        assert!(
+            message.starts_with("could not download piece 1: "),
+            "{}",
+            message
+        );
+        assert!(message.contains("doesn't have piece 1"), "{}", message);
+
+        let err = Coordinator::new(info())
+            .download_piece(&mut PeerSet::new(), 0)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not download piece 0: no peers to try"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_size_carries_over_to_next_connection() {
+        let content: Vec<u8> = (0..32768u32).map(|i| (i % 251) as u8).collect();
+        let info = info_for("big.bin", &content, content.len());
+        let (lengths_tx, lengths) = std::sync::mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            // Hangs up on the first Request, then serves the next connection
+            for connection in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                echo_handshake(&mut stream).unwrap();
+                write_message(&mut stream, 5, &[0x80]);
+                write_message(&mut stream, 1, &[]);
+                while let Ok(message) = read_message(&mut stream) {
+                    let Some((_, begin, length)) = block_request(&message) else {
+                        continue;
+                    };
+                    lengths_tx.send(length).unwrap();
+                    if connection == 0 {
+                        break;
+                    }
+                    let block = &content[begin as usize..(begin + length) as usize];
+                    write_block(&mut stream, 0, begin, block);
+                }
+            }
+        });
+
+        let mut peers = PeerSet::new().with_cooldown(Duration::ZERO);
+        peers.insert(addr, PeerSource::Tracker);
+        Coordinator::new(info)
+            .download_piece(&mut peers, 0)
+            .await
+            .unwrap();
+        // A failed connection halves the block size, & the next one starts from there
+        assert_eq!(
+            lengths.try_iter().collect::<Vec<_>>()[..3],
+            [16384, 8192, 8192]
+        );
+    }
+
+    #[test]
+    fn test_work_queue() {
+        let mut queue = WorkQueue::new(BTreeSet::from([0, 1, 2]), 2, Box::new(Sequential));
+        assert_eq!(queue.take(|index| index > 0), Some(1));
+        assert_eq!(queue.take(|index| index == 1), None);
+        queue.requeue(1);
+        assert_eq!(queue.take(|index| index == 1), Some(1));
+        assert!(queue.finish(1));
+        // Done is done: no second copy, & no putting it back
+        assert!(!queue.finish(1));
+        queue.requeue(1);
+
+        // The second failure is the last
+        assert_eq!(queue.take(|_| true), Some(0));
+        queue.requeue(0);
+        assert_eq!(queue.take(|_| true), Some(0));
+        queue.requeue(0);
+        assert_eq!(queue.take(|_| true), Some(2));
+        assert!(!queue.is_finished());
+        queue.finish(2);
+        assert!(queue.is_finished());
+        assert_eq!(queue.given_up, BTreeSet::from([0]));
+    }
+}
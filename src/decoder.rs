@@ -1,6 +1,5 @@
 use std::{collections::BTreeMap, fmt};
 
-use anyhow::Context;
 use serde_json::{self};
 
 #[derive(Debug, PartialEq)]
@@ -11,6 +10,9 @@ pub enum BencodedValue {
     Dict(BTreeMap<BencodedString, BencodedValue>),
 }
 
+// Longer strings are cut short by the `{:#}` format
+const MAX_PRETTY_STRING: usize = 80;
+
 #[derive(Debug, PartialEq, Hash, Eq, PartialOrd, Ord, Clone)]
 pub struct BencodedString(pub Vec<u8>);
 
@@ -19,6 +21,10 @@ impl BencodedString {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 // Convert from a byte array to a BencodedString
@@ -46,7 +52,7 @@ impl From<String> for BencodedString {
 // Convert from a BencodedString to a String
 impl From<&BencodedString> for String {
     fn from(value: &BencodedString) -> Self {
-        return String::from_utf8_lossy(&value.0).to_string();
+        String::from_utf8_lossy(&value.0).to_string()
     }
 }
 
@@ -77,14 +83,14 @@ impl fmt::Display for BencodedString {
 // Convert from a BenodedString to a byte array
 impl From<&BencodedString> for Vec<u8> {
     fn from(value: &BencodedString) -> Self {
-        return value.0.clone();
+        value.0.clone()
     }
 }
 
-// Convert from a byte array to a BencodedValue
+// Convert from a byte array to a BencodedValue, panicking on malformed input
 impl From<&[u8]> for BencodedValue {
     fn from(value: &[u8]) -> Self {
-        let (_, out) = decode_bencoded_value(value);
+        let (_, out) = decode_bencoded_value(value).expect("Invalid bencoded value");
         out
     }
 }
@@ -112,8 +118,48 @@ impl From<BencodedValue> for serde_json::Value {
     }
 }
 
+impl BencodedValue {
+    // A key or item per line with nested values indented. Binary strings are shown
+    // as their length, long ones cut short.
+    fn fmt_pretty(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let pad = "  ".repeat(depth + 1);
+        match self {
+            BencodedValue::List(items) if !items.is_empty() => {
+                writeln!(f, "[")?;
+                for item in items {
+                    write!(f, "{}", pad)?;
+                    item.fmt_pretty(f, depth + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{}]", "  ".repeat(depth))
+            }
+            BencodedValue::Dict(dict) if !dict.is_empty() => {
+                writeln!(f, "{{")?;
+                for (key, value) in dict {
+                    write!(f, "{}{}: ", pad, key)?;
+                    value.fmt_pretty(f, depth + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{}}}", "  ".repeat(depth))
+            }
+            BencodedValue::String(s) if !s.0.is_ascii() => write!(f, "<{} bytes>", s.len()),
+            BencodedValue::String(s) if s.len() > MAX_PRETTY_STRING => write!(
+                f,
+                "{}... <{} bytes>",
+                String::from_utf8_lossy(&s.0[..MAX_PRETTY_STRING]),
+                s.len()
+            ),
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+// `{}` is all on one line, `{:#}` spread over several
 impl fmt::Display for BencodedValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return self.fmt_pretty(f, 0);
+        }
         match self {
             BencodedValue::String(s) => {
                 write!(f, "{}", s)
@@ -175,83 +221,85 @@ impl Bencodeable for BencodedValue {
                 out.push(b'e');
             }
         }
-        return out;
+        out
     }
 }
 
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("invalid string length {0:?}")]
+    InvalidLength(String),
+    #[error("invalid integer {0:?}")]
+    InvalidInteger(String),
+    #[error("unexpected byte {0:?}")]
+    UnexpectedByte(char),
+    #[error("duplicate dict key {0:?}")]
+    DuplicateKey(String),
+    #[error("dict key {0:?} out of order")]
+    UnsortedKey(String),
+}
+
 // Should take in either a string or a byte array
 // Example: "5:hello" -> "hello"
-pub fn decode_bencoded_string<T: AsRef<[u8]>>(encoded_value: T) -> (usize, BencodedValue) {
+pub fn decode_bencoded_string<T: AsRef<[u8]>>(
+    encoded_value: T,
+) -> Result<(usize, BencodedValue), DecodeError> {
     let encoded_value = encoded_value.as_ref();
     let colon_index = encoded_value
         .iter()
         .position(|&c| c == b':')
-        // return if found, panic with message if not
-        .with_context(|| {
-            format!(
-                "Could not find ':' in {:?}, in string {:?}",
-                encoded_value,
-                String::from_utf8_lossy(encoded_value)
-            )
-        })
-        .unwrap();
-    let length_part = &encoded_value[..colon_index];
-    let length = String::from_utf8_lossy(length_part)
+        .ok_or(DecodeError::UnexpectedEof)?;
+    let length_part = String::from_utf8_lossy(&encoded_value[..colon_index]);
+    let length = length_part
         .parse::<usize>()
-        .with_context(|| {
-            format!(
-                "Could not parse length: {:?} (str {}) -- input: {:?}",
-                length_part,
-                String::from_utf8_lossy(length_part),
-                encoded_value
-            )
-        })
-        .unwrap();
-    let text_part = &encoded_value[colon_index + 1..colon_index + 1 + length as usize];
+        .map_err(|_| DecodeError::InvalidLength(length_part.to_string()))?;
+    let ending_index = colon_index + 1 + length;
+    let text_part = encoded_value
+        .get(colon_index + 1..ending_index)
+        .ok_or(DecodeError::UnexpectedEof)?;
     let bencode_text = BencodedString(text_part.to_vec());
-    let ending_index = colon_index + 1 + length as usize;
-    return (ending_index, BencodedValue::String(bencode_text));
+    Ok((ending_index, BencodedValue::String(bencode_text)))
 }
 
 // Example: "i3e" -> 3
 // Example 2: "i-3e" -> -3
-pub fn decode_bencoded_integer<T: AsRef<[u8]>>(encoded_value: T) -> (usize, BencodedValue) {
+pub fn decode_bencoded_integer<T: AsRef<[u8]>>(
+    encoded_value: T,
+) -> Result<(usize, BencodedValue), DecodeError> {
     // Get number string from start until 'e'
     let encoded_value = encoded_value.as_ref();
-    let mut ending_index = 2;
-    let mut number = 0;
-    let mut mult = 1;
-    for (_, &c) in encoded_value[1..].iter().enumerate() {
-        match c {
-            b'e' => break,
-            b'-' => {
-                ending_index += 1;
-                mult = -1;
-            }
-            b'0'..=b'9' => {
-                number = number * 10 + (c - b'0') as i64;
-                ending_index += 1;
-            }
-            _ => panic!("Invalid bencoded integer: {:?}", encoded_value),
-        }
-    }
-    return (ending_index, BencodedValue::Integer(number * mult as i64));
+    let end_index = encoded_value
+        .iter()
+        .position(|&c| c == b'e')
+        .ok_or(DecodeError::UnexpectedEof)?;
+    let number_part = String::from_utf8_lossy(&encoded_value[1..end_index]);
+    let number = number_part
+        .parse::<i64>()
+        .map_err(|_| DecodeError::InvalidInteger(number_part.to_string()))?;
+    Ok((end_index + 1, BencodedValue::Integer(number)))
 }
 
 // Example: "l5:helloi3ee" -> ["hello", 3]
 // Example 2: "l4:spam4:eggse" -> ["spam", "eggs"]
 // Example 3: "l4:spaml1:a1:bee" -> ["spam", ["a", "b"]]
-pub fn decode_bencoded_list<T: AsRef<[u8]>>(encoded_value: T) -> (usize, BencodedValue) {
+pub fn decode_bencoded_list<T: AsRef<[u8]>>(
+    encoded_value: T,
+) -> Result<(usize, BencodedValue), DecodeError> {
+    decode_list(encoded_value.as_ref(), false)
+}
+
+fn decode_list(encoded_value: &[u8], strict: bool) -> Result<(usize, BencodedValue), DecodeError> {
     // Get string from start until 'e'
-    let encoded_value = encoded_value.as_ref();
     let mut encoded_value = &encoded_value[1..];
     let mut list = Vec::new();
     let mut ending_index = 1;
     loop {
-        match encoded_value.iter().next().unwrap() {
+        match encoded_value.first().ok_or(DecodeError::UnexpectedEof)? {
             b'e' => break,
             _ => {
-                let (child_index, decoded_value) = decode_bencoded_value(encoded_value);
+                let (child_index, decoded_value) = decode_value(encoded_value, strict)?;
                 list.push(decoded_value);
                 encoded_value = &encoded_value[child_index..];
                 ending_index += child_index;
@@ -259,7 +307,7 @@ pub fn decode_bencoded_list<T: AsRef<[u8]>>(encoded_value: T) -> (usize, Bencode
         }
     }
     ending_index += 1;
-    return (ending_index, BencodedValue::List(list));
+    Ok((ending_index, BencodedValue::List(list)))
 }
 
 // Example: "d3:cow3:moo4:spam4:eggse" -> {"cow": "moo", "spam": "eggs"}
@@ -267,45 +315,75 @@ pub fn decode_bencoded_list<T: AsRef<[u8]>>(encoded_value: T) -> (usize, Bencode
 // Example 3: "d4:foodd1:a3:baree" -> {"food": {"a": "bar"}}
 // Example 4: "d4:foodd1:a3:bare5:drinkd1:b3:bazee" -> {"food": {"a": "bar"}, "drink": {"b": "baz"}}
 // -> {"publisher": "bob", "publisher-webpage": "www.example.com", "publisher.location": "home"}
-pub fn decode_bencoded_dict<T: AsRef<[u8]>>(encoded_value: T) -> (usize, BencodedValue) {
+pub fn decode_bencoded_dict<T: AsRef<[u8]>>(
+    encoded_value: T,
+) -> Result<(usize, BencodedValue), DecodeError> {
+    decode_dict(encoded_value.as_ref(), false)
+}
+
+// Leniently, a repeated key keeps its last value & order doesn't matter. Strictly,
+// keys must be unique & sorted as the spec says.
+fn decode_dict(encoded_value: &[u8], strict: bool) -> Result<(usize, BencodedValue), DecodeError> {
     // Get string from start until 'e'
-    let encoded_value = encoded_value.as_ref();
     let mut encoded_value = &encoded_value[1..];
     let mut ending_index = 1;
     let mut dict: BTreeMap<BencodedString, BencodedValue> = BTreeMap::new();
     loop {
-        match encoded_value.iter().next().unwrap() {
+        match encoded_value.first().ok_or(DecodeError::UnexpectedEof)? {
             b'e' => break,
+            c if !c.is_ascii_digit() => return Err(DecodeError::UnexpectedByte(*c as char)),
             _ => {
-                let (key_index, key) = decode_bencoded_string(encoded_value);
+                let (key_index, key) = decode_bencoded_string(encoded_value)?;
                 encoded_value = &encoded_value[key_index..];
                 ending_index += key_index;
-                let (value_index, value) = decode_bencoded_value(encoded_value);
+                let (value_index, value) = decode_value(encoded_value, strict)?;
                 encoded_value = &encoded_value[value_index..];
                 ending_index += value_index;
                 let key = match key {
                     BencodedValue::String(s) => s,
-                    _ => panic!("Invalid key: {:?}", key),
+                    _ => unreachable!("decode_bencoded_string always returns a string"),
                 };
+                if strict {
+                    match dict.last_key_value() {
+                        Some((last, _)) if *last == key => {
+                            return Err(DecodeError::DuplicateKey(key.to_string()))
+                        }
+                        Some((last, _)) if *last > key => {
+                            return Err(DecodeError::UnsortedKey(key.to_string()))
+                        }
+                        _ => {}
+                    }
+                }
                 dict.insert(key, value);
             }
         }
     }
     ending_index += 1;
-    return (ending_index, BencodedValue::Dict(dict));
+    Ok((ending_index, BencodedValue::Dict(dict)))
 }
 
-pub fn decode_bencoded_value<T: AsRef<[u8]> + std::fmt::Debug>(
+pub fn decode_bencoded_value<T: AsRef<[u8]>>(
     encoded_value: T,
-) -> (usize, BencodedValue) {
+) -> Result<(usize, BencodedValue), DecodeError> {
+    decode_value(encoded_value.as_ref(), false)
+}
+
+// Like decode_bencoded_value, but rejects dicts with duplicate or unsorted keys
+pub fn decode_bencoded_value_strict<T: AsRef<[u8]>>(
+    encoded_value: T,
+) -> Result<(usize, BencodedValue), DecodeError> {
+    decode_value(encoded_value.as_ref(), true)
+}
+
+fn decode_value(encoded_value: &[u8], strict: bool) -> Result<(usize, BencodedValue), DecodeError> {
     // If encoded_value starts with a digit, it's a number
-    let first_char = encoded_value.as_ref()[0] as char;
+    let first_char = *encoded_value.first().ok_or(DecodeError::UnexpectedEof)? as char;
     match first_char {
-        '0'..='9' => return decode_bencoded_string(encoded_value),
-        'i' => return decode_bencoded_integer(encoded_value),
-        'l' => return decode_bencoded_list(encoded_value),
-        'd' => return decode_bencoded_dict(encoded_value),
-        _ => panic!("Unhandled bencoded value: {:?}", encoded_value),
+        '0'..='9' => decode_bencoded_string(encoded_value),
+        'i' => decode_bencoded_integer(encoded_value),
+        'l' => decode_list(encoded_value, strict),
+        'd' => decode_dict(encoded_value, strict),
+        _ => Err(DecodeError::UnexpectedByte(first_char)),
     }
 }
 
@@ -317,7 +395,7 @@ mod tests {
 
     #[test]
     fn test_decode_bencoded_string() {
-        let (index, value) = decode_bencoded_string("5:hello".as_bytes());
+        let (index, value) = decode_bencoded_string("5:hello".as_bytes()).unwrap();
         assert_eq!(index, 7);
         assert_eq!(value, BencodedValue::String(b"hello".to_vec().into()));
     }
@@ -325,7 +403,7 @@ mod tests {
     #[test]
     fn test_decode_bencoded_nonutf8_string() {
         // First
-        let (index, value) = decode_bencoded_string(b"4:\x80\x81\x82\x83");
+        let (index, value) = decode_bencoded_string(b"4:\x80\x81\x82\x83").unwrap();
         assert_eq!(index, 6);
         assert_eq!(
             value,
@@ -341,7 +419,7 @@ mod tests {
         input.extend_from_slice(b"14:");
         input.extend_from_slice(byte_vec);
 
-        let (index, value) = decode_bencoded_string(input);
+        let (index, value) = decode_bencoded_string(input).unwrap();
         assert_eq!(index, 17);
         assert_eq!(
             value,
@@ -358,7 +436,7 @@ mod tests {
         input.extend_from_slice(b"18:");
         input.extend_from_slice(&byte_vec);
 
-        let (index, value) = decode_bencoded_string(input);
+        let (index, value) = decode_bencoded_string(input).unwrap();
         assert_eq!(index, 21);
         assert_eq!(
             value,
@@ -368,18 +446,18 @@ mod tests {
 
     #[test]
     fn test_decode_bencoded_integer() {
-        let (index, value) = decode_bencoded_integer("i3e".as_bytes());
+        let (index, value) = decode_bencoded_integer("i3e".as_bytes()).unwrap();
         assert_eq!(index, 3);
         assert_eq!(value, BencodedValue::Integer(3));
 
-        let (index, value) = decode_bencoded_integer("i-3e".as_bytes());
+        let (index, value) = decode_bencoded_integer("i-3e".as_bytes()).unwrap();
         assert_eq!(index, 4);
         assert_eq!(value, BencodedValue::Integer(-3));
     }
 
     #[test]
     fn test_decode_bencoded_list() {
-        let (index, value) = decode_bencoded_list("l5:helloi3ee".as_bytes());
+        let (index, value) = decode_bencoded_list("l5:helloi3ee".as_bytes()).unwrap();
         assert_eq!(index, 12);
         assert_eq!(
             value,
@@ -389,7 +467,7 @@ mod tests {
             ])
         );
 
-        let (index, value) = decode_bencoded_list("l4:spam4:eggse".as_bytes());
+        let (index, value) = decode_bencoded_list("l4:spam4:eggse".as_bytes()).unwrap();
         assert_eq!(index, 14);
         assert_eq!(
             value,
@@ -399,7 +477,7 @@ mod tests {
             ])
         );
 
-        let (index, value) = decode_bencoded_list("l4:spaml1:a1:bee".as_bytes());
+        let (index, value) = decode_bencoded_list("l4:spaml1:a1:bee".as_bytes()).unwrap();
         assert_eq!(index, 16);
         assert_eq!(
             value,
@@ -415,7 +493,7 @@ mod tests {
 
     #[test]
     fn test_decode_bencoded_dict() {
-        let (index, value) = decode_bencoded_dict("d3:cow3:moo4:spam4:eggse".as_bytes());
+        let (index, value) = decode_bencoded_dict("d3:cow3:moo4:spam4:eggse".as_bytes()).unwrap();
         assert_eq!(index, 24);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -428,7 +506,7 @@ mod tests {
         );
         assert_eq!(value, BencodedValue::Dict(expected));
 
-        let (index, value) = decode_bencoded_dict("d4:spaml1:a1:bee".as_bytes());
+        let (index, value) = decode_bencoded_dict("d4:spaml1:a1:bee".as_bytes()).unwrap();
         assert_eq!(index, 16);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -440,7 +518,7 @@ mod tests {
         );
         assert_eq!(value, BencodedValue::Dict(expected), "d4:spaml1:a1:bee");
 
-        let (index, value) = decode_bencoded_dict("d4:foodd1:a3:baree".as_bytes());
+        let (index, value) = decode_bencoded_dict("d4:foodd1:a3:baree".as_bytes()).unwrap();
         assert_eq!(index, 18);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -452,7 +530,8 @@ mod tests {
         );
         assert_eq!(value, BencodedValue::Dict(expected), "d4:foodd1:a3:baree");
 
-        let (index, value) = decode_bencoded_dict("d4:foodd1:a3:bare5:drinkd1:b3:bazee".as_bytes());
+        let (index, value) =
+            decode_bencoded_dict("d4:foodd1:a3:bare5:drinkd1:b3:bazee".as_bytes()).unwrap();
         assert_eq!(index, 35);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -480,7 +559,7 @@ mod tests {
     fn test_decode_bencoded_dict_with_bytes() {
         // Some non-utf8 bytes
         let input = b"d4:foodd1:a4:\x80\x81\x82\x83ee";
-        let (index, value) = decode_bencoded_dict(input);
+        let (index, value) = decode_bencoded_dict(input).unwrap();
         assert_eq!(index, 19);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -503,7 +582,7 @@ mod tests {
 
         // Another
         let input = b"d12:min intervali60e5:peers18:\xa5\xe8!M\xc8\xe5\xb2>RY\xc9\x01\xb2>U\x14\xc9%8:completei3e10:incompletei1e8:intervali60ee";
-        let (index, value) = decode_bencoded_dict(input);
+        let (index, value) = decode_bencoded_dict(input).unwrap();
         assert_eq!(index, 92);
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -537,7 +616,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_decode_bencoded_malformed() {
+        assert_eq!(decode_bencoded_value(b""), Err(DecodeError::UnexpectedEof));
+        assert_eq!(
+            decode_bencoded_value(b"5:hel"),
+            Err(DecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_bencoded_value(b"i3"),
+            Err(DecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_bencoded_value(b"i3x4e"),
+            Err(DecodeError::InvalidInteger("3x4".to_string()))
+        );
+        assert_eq!(
+            decode_bencoded_value(b"l5:hello"),
+            Err(DecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_bencoded_value(b"di3e5:helloe"),
+            Err(DecodeError::UnexpectedByte('i'))
+        );
+        assert_eq!(
+            decode_bencoded_value(b"x"),
+            Err(DecodeError::UnexpectedByte('x'))
+        );
+    }
+
     // Test encoding
+    #[test]
+    fn test_decode_strict_dict_keys() {
+        assert_eq!(
+            decode_bencoded_value_strict("d1:a1:x1:a1:ye"),
+            Err(DecodeError::DuplicateKey("a".to_string()))
+        );
+        assert_eq!(
+            decode_bencoded_value_strict("d1:b1:x1:a1:ye"),
+            Err(DecodeError::UnsortedKey("a".to_string()))
+        );
+        // Nested dicts are held to it too
+        assert_eq!(
+            decode_bencoded_value_strict("ld1:b1:x1:a1:yee"),
+            Err(DecodeError::UnsortedKey("a".to_string()))
+        );
+        assert!(decode_bencoded_value_strict("d1:a1:x1:b1:ye").is_ok());
+
+        // The lenient path keeps the last value
+        let (_, value) = decode_bencoded_value("d1:a1:x1:a1:ye").unwrap();
+        assert_eq!(value.to_string(), "{a: y}");
+    }
+
     #[test]
     fn test_encode_bencoded_vec() {
         let value = BencodedValue::String(b"hello".to_vec().into());
@@ -683,4 +813,35 @@ mod tests {
         let bencoded_value = BencodedValue::Dict(dict);
         assert_eq!(format!("{}", bencoded_value), "{cow: moo, spam: eggs}");
     }
+
+    #[test]
+    fn test_bencoded_value_pretty() {
+        let (_, bencoded_value) = decode_bencoded_value(
+            &b"d8:announce9:http://t/4:infod6:lengthi5e6:pieces3:\xff\x00\x01e5:nodesll1:hi1eeee"[..],
+        )
+        .unwrap();
+        let pretty = format!("{:#}", bencoded_value);
+        assert!(pretty.contains("\n    length: 5\n"), "{}", pretty);
+        assert_eq!(
+            pretty,
+            "{\n  announce: http://t/\n  info: {\n    length: 5\n    pieces: <3 bytes>\n  }\n  nodes: [\n    [\n      h\n      1\n    ]\n  ]\n}"
+        );
+        // The compact form is as it was
+        assert_eq!(
+            format!("{}", bencoded_value),
+            "{announce: http://t/, info: {length: 5, pieces: \u{fffd}\0\u{1}}, nodes: [[h, 1]]}"
+        );
+
+        // Empty containers & small values stay on one line, long strings are cut
+        let long = "a".repeat(100);
+        let (_, bencoded_value) =
+            decode_bencoded_value(format!("l0:dele{}:{}e", long.len(), long).as_bytes()).unwrap();
+        assert_eq!(
+            format!("{:#}", bencoded_value),
+            format!(
+                "[\n  \n  {{}}\n  []\n  {}... <100 bytes>\n]",
+                "a".repeat(80)
+            )
+        );
+    }
 }
@@ -0,0 +1,536 @@
+// A minimal Mainline DHT client (BEP 5): enough to find peers for an info hash
+// without a tracker
+use crate::decoder::{decode_bencoded_value, Bencodeable, BencodedString, BencodedValue};
+use crate::network::{compact_peer, parse_compact_peer};
+use anyhow::{anyhow, Error};
+use log::debug;
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::net::{lookup_host, UdpSocket};
+
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// Nodes asked in parallel per round, & the cap on queries for one lookup
+const ALPHA: usize = 3;
+const MAX_QUERIES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    Ping { id: [u8; 20] },
+    FindNode { id: [u8; 20], target: [u8; 20] },
+    GetPeers { id: [u8; 20], info_hash: [u8; 20] },
+}
+
+// Replies carry whichever of these the query asked for
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    pub id: [u8; 20],
+    pub nodes: Vec<Node>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KrpcMessage {
+    Query {
+        transaction_id: Vec<u8>,
+        query: Query,
+    },
+    Response {
+        transaction_id: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+fn key(key: &str) -> BencodedString {
+    BencodedString(key.as_bytes().to_vec())
+}
+
+fn string(bytes: &[u8]) -> BencodedValue {
+    BencodedValue::String(bytes.to_vec().into())
+}
+
+impl From<&KrpcMessage> for BencodedValue {
+    fn from(value: &KrpcMessage) -> Self {
+        let mut dict = BTreeMap::new();
+        match value {
+            KrpcMessage::Query {
+                transaction_id,
+                query,
+            } => {
+                let (name, args) = match query {
+                    Query::Ping { id } => ("ping", vec![("id", string(id))]),
+                    Query::FindNode { id, target } => (
+                        "find_node",
+                        vec![("id", string(id)), ("target", string(target))],
+                    ),
+                    Query::GetPeers { id, info_hash } => (
+                        "get_peers",
+                        vec![("id", string(id)), ("info_hash", string(info_hash))],
+                    ),
+                };
+                let args = args
+                    .into_iter()
+                    .map(|(name, value)| (key(name), value))
+                    .collect();
+                dict.insert(key("t"), string(transaction_id));
+                dict.insert(key("y"), string(b"q"));
+                dict.insert(key("q"), string(name.as_bytes()));
+                dict.insert(key("a"), BencodedValue::Dict(args));
+            }
+            KrpcMessage::Response {
+                transaction_id,
+                response,
+            } => {
+                let mut r = BTreeMap::from([(key("id"), string(&response.id))]);
+                if !response.nodes.is_empty() {
+                    let nodes: Vec<u8> = response
+                        .nodes
+                        .iter()
+                        .flat_map(|node| [node.id.to_vec(), compact_peer(&node.addr)].concat())
+                        .collect();
+                    r.insert(key("nodes"), string(&nodes));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|peer| string(&compact_peer(peer)))
+                        .collect();
+                    r.insert(key("values"), BencodedValue::List(values));
+                }
+                if let Some(token) = &response.token {
+                    r.insert(key("token"), string(token));
+                }
+                dict.insert(key("t"), string(transaction_id));
+                dict.insert(key("y"), string(b"r"));
+                dict.insert(key("r"), BencodedValue::Dict(r));
+            }
+            KrpcMessage::Error {
+                transaction_id,
+                code,
+                message,
+            } => {
+                dict.insert(key("t"), string(transaction_id));
+                dict.insert(key("y"), string(b"e"));
+                dict.insert(
+                    key("e"),
+                    BencodedValue::List(vec![
+                        BencodedValue::Integer(*code),
+                        string(message.as_bytes()),
+                    ]),
+                );
+            }
+        }
+        BencodedValue::Dict(dict)
+    }
+}
+
+impl TryFrom<&BencodedValue> for KrpcMessage {
+    type Error = Error;
+
+    // Spelled out, as `Self::Error` would clash with the Error variant
+    fn try_from(value: &BencodedValue) -> Result<Self, Error> {
+        let dict = match value {
+            BencodedValue::Dict(dict) => dict,
+            _ => return Err(anyhow!("KRPC message is not a dict")),
+        };
+        let bytes = |dict: &BTreeMap<BencodedString, BencodedValue>, name: &str| match dict
+            .get(&key(name))
+        {
+            Some(BencodedValue::String(s)) => Ok(s.0.clone()),
+            _ => Err(anyhow!("KRPC message has no {}", name)),
+        };
+        let id = |dict: &BTreeMap<BencodedString, BencodedValue>, name: &str| {
+            <[u8; 20]>::try_from(bytes(dict, name)?.as_slice())
+                .map_err(|_| anyhow!("KRPC {} is not 20 bytes", name))
+        };
+        let transaction_id = bytes(dict, "t")?;
+
+        match bytes(dict, "y")?.as_slice() {
+            b"q" => {
+                let args = match dict.get(&key("a")) {
+                    Some(BencodedValue::Dict(args)) => args,
+                    _ => return Err(anyhow!("KRPC query has no arguments")),
+                };
+                let query = match bytes(dict, "q")?.as_slice() {
+                    b"ping" => Query::Ping {
+                        id: id(args, "id")?,
+                    },
+                    b"find_node" => Query::FindNode {
+                        id: id(args, "id")?,
+                        target: id(args, "target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        id: id(args, "id")?,
+                        info_hash: id(args, "info_hash")?,
+                    },
+                    other => {
+                        return Err(anyhow!(
+                            "Unsupported KRPC query {}",
+                            String::from_utf8_lossy(other)
+                        ))
+                    }
+                };
+                Ok(KrpcMessage::Query {
+                    transaction_id,
+                    query,
+                })
+            }
+            b"r" => {
+                let r = match dict.get(&key("r")) {
+                    Some(BencodedValue::Dict(r)) => r,
+                    _ => return Err(anyhow!("KRPC response has no body")),
+                };
+                let nodes = match bytes(r, "nodes") {
+                    Ok(nodes) if nodes.len().is_multiple_of(26) => nodes
+                        .chunks(26)
+                        .filter_map(|chunk| {
+                            Some(Node {
+                                id: chunk[..20].try_into().ok()?,
+                                addr: parse_compact_peer(&chunk[20..])?,
+                            })
+                        })
+                        .collect(),
+                    Ok(_) => return Err(anyhow!("KRPC nodes is not a multiple of 26 bytes")),
+                    Err(_) => vec![],
+                };
+                let values = match r.get(&key("values")) {
+                    Some(BencodedValue::List(values)) => values
+                        .iter()
+                        .filter_map(|value| match value {
+                            BencodedValue::String(s) => parse_compact_peer(&s.0),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                Ok(KrpcMessage::Response {
+                    transaction_id,
+                    response: Response {
+                        id: id(r, "id")?,
+                        nodes,
+                        values,
+                        token: bytes(r, "token").ok(),
+                    },
+                })
+            }
+            b"e" => match dict.get(&key("e")) {
+                Some(BencodedValue::List(e)) => match e.as_slice() {
+                    [BencodedValue::Integer(code), BencodedValue::String(message)] => {
+                        Ok(KrpcMessage::Error {
+                            transaction_id,
+                            code: *code,
+                            message: String::from(message),
+                        })
+                    }
+                    _ => Err(anyhow!("Malformed KRPC error")),
+                },
+                _ => Err(anyhow!("KRPC error has no body")),
+            },
+            other => Err(anyhow!(
+                "Unknown KRPC message type {}",
+                String::from_utf8_lossy(other)
+            )),
+        }
+    }
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut d = [0; 20];
+    d.iter_mut()
+        .zip(a.iter().zip(b))
+        .for_each(|(d, (a, b))| *d = a ^ b);
+    d
+}
+
+pub struct Dht {
+    socket: UdpSocket,
+    id: [u8; 20],
+    timeout: Duration,
+    next_transaction: u16,
+}
+
+impl Dht {
+    pub async fn bind(addr: &str) -> Result<Self, Error> {
+        Ok(Dht {
+            socket: UdpSocket::bind(addr).await?,
+            id: rand::random(),
+            timeout: Duration::from_secs(2),
+            next_transaction: 0,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn id(&self) -> [u8; 20] {
+        self.id
+    }
+
+    // Send one query & wait for the matching reply
+    pub async fn query(&mut self, addr: SocketAddr, query: Query) -> Result<Response, Error> {
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let transaction_id = self.next_transaction.to_be_bytes().to_vec();
+        let message = KrpcMessage::Query {
+            transaction_id: transaction_id.clone(),
+            query,
+        };
+        self.socket
+            .send_to(&BencodedValue::from(&message).bencode(), addr)
+            .await?;
+
+        let mut buf = [0; 1500];
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                let (n, from) = self.socket.recv_from(&mut buf).await?;
+                // Late replies to earlier queries are just dropped
+                let Ok((_, value)) = decode_bencoded_value(&buf[..n]) else {
+                    continue;
+                };
+                match KrpcMessage::try_from(&value) {
+                    Ok(KrpcMessage::Response {
+                        transaction_id: t,
+                        response,
+                    }) if t == transaction_id && from == addr => return Ok(response),
+                    Ok(KrpcMessage::Error {
+                        transaction_id: t,
+                        code,
+                        message,
+                    }) if t == transaction_id && from == addr => {
+                        return Err(anyhow!("DHT node {} error {}: {}", addr, code, message))
+                    }
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("DHT node {} timed out", addr))?
+    }
+
+    // Ask the bootstrap nodes for nodes close to our own id
+    pub async fn bootstrap(&mut self, bootstrap: &[&str]) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for host in bootstrap {
+            let Ok(addrs) = lookup_host(host).await else {
+                debug!("Could not resolve DHT bootstrap node {}", host);
+                continue;
+            };
+            for addr in addrs {
+                let query = Query::FindNode {
+                    id: self.id,
+                    target: self.id,
+                };
+                match self.query(addr, query).await {
+                    Ok(response) => {
+                        nodes.push(Node {
+                            id: response.id,
+                            addr,
+                        });
+                        nodes.extend(response.nodes);
+                    }
+                    Err(e) => debug!("DHT bootstrap {}: {}", addr, e),
+                }
+            }
+        }
+        nodes
+    }
+
+    // Iterative get_peers: keep asking the closest nodes we've heard of until
+    // we run out of nodes or query budget
+    pub async fn get_peers(
+        &mut self,
+        info_hash: [u8; 20],
+        bootstrap: &[&str],
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let mut candidates = self.bootstrap(bootstrap).await;
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut peers: Vec<SocketAddr> = Vec::new();
+
+        while queried.len() < MAX_QUERIES {
+            candidates.retain(|node| !queried.contains(&node.addr));
+            candidates.sort_by_key(|node| distance(&node.id, &info_hash));
+            candidates.dedup_by_key(|node| node.addr);
+            let round: Vec<Node> = candidates.drain(..candidates.len().min(ALPHA)).collect();
+            if round.is_empty() {
+                break;
+            }
+            for node in round {
+                queried.insert(node.addr);
+                let query = Query::GetPeers {
+                    id: self.id,
+                    info_hash,
+                };
+                match self.query(node.addr, query).await {
+                    Ok(response) => {
+                        debug!(
+                            "DHT node {}: {} peers, {} nodes",
+                            node.addr,
+                            response.values.len(),
+                            response.nodes.len()
+                        );
+                        for peer in response.values {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                        candidates.extend(response.nodes);
+                    }
+                    Err(e) => debug!("{}", e),
+                }
+            }
+        }
+
+        if peers.is_empty() {
+            return Err(anyhow!(
+                "No peers found in the DHT after {} queries",
+                queried.len()
+            ));
+        }
+        Ok(peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the BEP 5 examples
+    const GET_PEERS_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+    const GET_PEERS_VALUES: &[u8] =
+        b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+    #[test]
+    fn test_get_peers_query_round_trip() {
+        let message = KrpcMessage::Query {
+            transaction_id: b"aa".to_vec(),
+            query: Query::GetPeers {
+                id: *b"abcdefghij0123456789",
+                info_hash: *b"mnopqrstuvwxyz123456",
+            },
+        };
+        assert_eq!(BencodedValue::from(&message).bencode(), GET_PEERS_QUERY);
+
+        let (_, value) = decode_bencoded_value(GET_PEERS_QUERY).unwrap();
+        assert_eq!(KrpcMessage::try_from(&value).unwrap(), message);
+    }
+
+    #[test]
+    fn test_get_peers_response_round_trip() {
+        let (_, value) = decode_bencoded_value(GET_PEERS_VALUES).unwrap();
+        let message = KrpcMessage::try_from(&value).unwrap();
+        let expected = KrpcMessage::Response {
+            transaction_id: b"aa".to_vec(),
+            response: Response {
+                id: *b"abcdefghij0123456789",
+                nodes: vec![],
+                values: vec![
+                    "97.120.106.101:11893".parse().unwrap(),
+                    "105.100.104.116:28269".parse().unwrap(),
+                ],
+                token: Some(b"aoeusnth".to_vec()),
+            },
+        };
+        assert_eq!(message, expected);
+        assert_eq!(BencodedValue::from(&message).bencode(), GET_PEERS_VALUES);
+    }
+
+    #[test]
+    fn test_nodes_response() {
+        let mut nodes = b"d1:rd2:id20:0123456789abcdefghij5:nodes26:".to_vec();
+        nodes.extend(b"mnopqrstuvwxyz123456");
+        nodes.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        nodes.extend(b"e1:t2:aa1:y1:re");
+        let (_, value) = decode_bencoded_value(&nodes).unwrap();
+        match KrpcMessage::try_from(&value).unwrap() {
+            KrpcMessage::Response { response, .. } => assert_eq!(
+                response.nodes,
+                vec![Node {
+                    id: *b"mnopqrstuvwxyz123456",
+                    addr: "127.0.0.1:6881".parse().unwrap(),
+                }]
+            ),
+            other => panic!("Expected a response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_message() {
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let (_, value) = decode_bencoded_value(bytes.as_slice()).unwrap();
+        let message = KrpcMessage::try_from(&value).unwrap();
+        assert_eq!(
+            message,
+            KrpcMessage::Error {
+                transaction_id: b"aa".to_vec(),
+                code: 201,
+                message: "A Generic Error Ocurred".to_string(),
+            }
+        );
+        assert_eq!(BencodedValue::from(&message).bencode(), bytes);
+    }
+
+    // A single DHT node that knows one peer for every info hash
+    async fn spawn_node(peer: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let (_, value) = decode_bencoded_value(&buf[..n]).unwrap();
+                let KrpcMessage::Query {
+                    transaction_id,
+                    query,
+                } = KrpcMessage::try_from(&value).unwrap()
+                else {
+                    continue;
+                };
+                let values = match query {
+                    Query::GetPeers { .. } => vec![peer],
+                    _ => vec![],
+                };
+                let reply = KrpcMessage::Response {
+                    transaction_id,
+                    response: Response {
+                        id: [0xaa; 20],
+                        values,
+                        ..Default::default()
+                    },
+                };
+                let bytes = BencodedValue::from(&reply).bencode();
+                socket.send_to(&bytes, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_get_peers_from_node() {
+        let peer: SocketAddr = "10.0.0.7:51413".parse().unwrap();
+        let node = spawn_node(peer).await.to_string();
+        let mut dht = Dht::bind("127.0.0.1:0").await.unwrap();
+        let peers = dht.get_peers([1; 20], &[node.as_str()]).await.unwrap();
+        assert_eq!(peers, vec![peer]);
+    }
+}
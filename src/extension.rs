@@ -0,0 +1,613 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::decoder::{
+    decode_bencoded_value, Bencodeable, BencodedString, BencodedValue, DecodeError,
+};
+use crate::hasher::{PieceHasher, Sha1Hasher};
+use crate::network::{compact_peers, parse_compact_peers};
+
+// BEP 10: every extension message is message id 20, the first payload byte
+// naming the extension. 0 is the extended handshake; the rest are whatever ids
+// the receiving side gave out in its handshake's `m`.
+pub const EXTENDED_MESSAGE_ID: u8 = 20;
+pub const HANDSHAKE_ID: u8 = 0;
+// Our client & version, for the handshake's `v`
+pub const CLIENT_VERSION: &str = concat!("your_bittorrent/", env!("CARGO_PKG_VERSION"));
+// How many requests from a peer we'll queue, for the handshake's `reqq`
+pub const DEFAULT_REQQ: i64 = 250;
+// BEP 9: the info dict, passed around in 16 KiB pieces, for magnet links
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_METADATA_ID: u8 = 1;
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Well past any real info dict, but a peer can't have us allocate gigabytes
+pub const MAX_METADATA_SIZE: i64 = 16 * 1024 * 1024;
+// BEP 11: every so often, the peers the sender connected to & lost since last time
+pub const UT_PEX: &str = "ut_pex";
+pub const UT_PEX_ID: u8 = 2;
+// No more than a message a minute to each peer, naming at most 50 peers each way
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_PEX_PEERS: usize = 50;
+// The extensions we speak, with the ids peers should send them to us under
+pub const OUR_EXTENSIONS: &[(&str, u8)] = &[(UT_METADATA, UT_METADATA_ID), (UT_PEX, UT_PEX_ID)];
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ExtensionError {
+    #[error("extension message is not bencoded: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("extension message is not a dict")]
+    NotADict,
+    #[error("extension message has no {0}")]
+    MissingKey(&'static str),
+    #[error("unknown ut_metadata msg_type {0}")]
+    UnknownMessageType(i64),
+    #[error("{0} is not a list of compact peers")]
+    BadPeers(&'static str),
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MetadataError {
+    #[error("peer doesn't support ut_metadata")]
+    NotSupported,
+    #[error("peer gave a metadata size of {0:?}")]
+    BadSize(Option<i64>),
+    #[error("peer rejected the request for metadata piece {0}")]
+    Rejected(usize),
+    #[error("metadata piece {piece} is {length} bytes, expected {expected}")]
+    BadPiece {
+        piece: usize,
+        length: usize,
+        expected: usize,
+    },
+    #[error("metadata doesn't match the info hash")]
+    HashMismatch,
+}
+
+// What a peer said about itself in its extended handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerExtensions {
+    // The id to send each extension's messages to the peer under, e.g. ut_metadata
+    pub ids: BTreeMap<String, u8>,
+    pub client: Option<String>,
+    pub reqq: Option<i64>,
+    // Size of the info dict in bytes, from peers that can send it over ut_metadata
+    pub metadata_size: Option<i64>,
+}
+
+impl PeerExtensions {
+    // None if the peer doesn't speak the extension, or turned it off with id 0
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).copied().filter(|&id| id != 0)
+    }
+}
+
+impl TryFrom<&[u8]> for PeerExtensions {
+    type Error = ExtensionError;
+
+    // Anything unexpected in the dict is skipped rather than fatal, as peers send all sorts
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let (_, value) = decode_bencoded_value(payload)?;
+        let BencodedValue::Dict(dict) = value else {
+            return Err(ExtensionError::NotADict);
+        };
+        let get = |key: &str| dict.get(&BencodedString::from(key.as_bytes()));
+        let ids = match get("m") {
+            Some(BencodedValue::Dict(m)) => m
+                .iter()
+                .filter_map(|(name, id)| match id {
+                    BencodedValue::Integer(id) => {
+                        Some((String::from(name), u8::try_from(*id).ok()?))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        let client = match get("v") {
+            Some(BencodedValue::String(v)) => Some(String::from(v)),
+            _ => None,
+        };
+        let integer = |key: &str| match get(key) {
+            Some(BencodedValue::Integer(value)) => Some(*value),
+            _ => None,
+        };
+        Ok(PeerExtensions {
+            ids,
+            client,
+            reqq: integer("reqq"),
+            metadata_size: integer("metadata_size"),
+        })
+    }
+}
+
+// A ut_metadata message: a bencoded dict, followed by the piece itself for Data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: i64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl TryFrom<&[u8]> for MetadataMessage {
+    type Error = ExtensionError;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let (dict_len, value) = decode_bencoded_value(payload)?;
+        let BencodedValue::Dict(dict) = value else {
+            return Err(ExtensionError::NotADict);
+        };
+        let integer = |key: &'static str| match dict.get(&BencodedString::from(key.as_bytes())) {
+            Some(BencodedValue::Integer(value)) => Ok(*value),
+            _ => Err(ExtensionError::MissingKey(key)),
+        };
+        let piece =
+            usize::try_from(integer("piece")?).map_err(|_| ExtensionError::MissingKey("piece"))?;
+        match integer("msg_type")? {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: integer("total_size")?,
+                data: payload[dict_len..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(ExtensionError::UnknownMessageType(msg_type)),
+        }
+    }
+}
+
+impl From<&MetadataMessage> for Vec<u8> {
+    fn from(message: &MetadataMessage) -> Self {
+        let (msg_type, piece) = match message {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        let mut dict = BTreeMap::from([
+            (
+                BencodedString::from(&b"msg_type"[..]),
+                BencodedValue::Integer(msg_type),
+            ),
+            (
+                BencodedString::from(&b"piece"[..]),
+                BencodedValue::Integer(*piece as i64),
+            ),
+        ]);
+        if let MetadataMessage::Data { total_size, .. } = message {
+            dict.insert(
+                BencodedString::from(&b"total_size"[..]),
+                BencodedValue::Integer(*total_size),
+            );
+        }
+        let mut bytes = BencodedValue::Dict(dict).bencode();
+        if let MetadataMessage::Data { data, .. } = message {
+            bytes.extend(data);
+        }
+        bytes
+    }
+}
+
+// A ut_pex message. IPv4 & IPv6 peers come in separate keys but are kept together.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    // A byte of flags per added peer, e.g. 0x02 for a seed
+    pub added_flags: Vec<u8>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+}
+
+impl TryFrom<&[u8]> for PexMessage {
+    type Error = ExtensionError;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let (_, value) = decode_bencoded_value(payload)?;
+        let BencodedValue::Dict(dict) = value else {
+            return Err(ExtensionError::NotADict);
+        };
+        let bytes = |key: &str| match dict.get(&BencodedString::from(key.as_bytes())) {
+            Some(BencodedValue::String(bytes)) => &bytes.0[..],
+            _ => &[],
+        };
+        let peers = |key: &'static str, ip_len: usize| {
+            parse_compact_peers(bytes(key), ip_len).map_err(|_| ExtensionError::BadPeers(key))
+        };
+        // Flags for each added peer, 0 where the sender left them out
+        let flags = |key: &str, n_peers: usize| {
+            let mut flags = bytes(key).to_vec();
+            flags.resize(n_peers, 0);
+            flags
+        };
+
+        let added = peers("added", 4)?;
+        let added6 = peers("added6", 16)?;
+        let mut added_flags = flags("added.f", added.len());
+        added_flags.extend(flags("added6.f", added6.len()));
+        let mut dropped = peers("dropped", 4)?;
+        dropped.extend(peers("dropped6", 16)?);
+        Ok(PexMessage {
+            added: added.into_iter().chain(added6).collect(),
+            added_flags,
+            dropped,
+        })
+    }
+}
+
+impl From<&PexMessage> for Vec<u8> {
+    fn from(message: &PexMessage) -> Self {
+        let mut dict = BTreeMap::new();
+        let mut insert = |key: &str, bytes: Vec<u8>| {
+            dict.insert(
+                BencodedString::from(key.as_bytes()),
+                BencodedValue::String(BencodedString(bytes)),
+            );
+        };
+        let (added, added6): (Vec<_>, Vec<_>) = message
+            .added
+            .iter()
+            .zip(message.added_flags.iter().chain(std::iter::repeat(&0)))
+            .partition(|(peer, _)| peer.is_ipv4());
+        insert("added", compact_peers(added.iter().map(|(peer, _)| *peer)));
+        insert("added.f", added.iter().map(|(_, &flags)| flags).collect());
+        insert(
+            "added6",
+            compact_peers(added6.iter().map(|(peer, _)| *peer)),
+        );
+        insert("added6.f", added6.iter().map(|(_, &flags)| flags).collect());
+        let (dropped, dropped6): (Vec<_>, Vec<_>) =
+            message.dropped.iter().partition(|peer| peer.is_ipv4());
+        insert("dropped", compact_peers(dropped));
+        insert("dropped6", compact_peers(dropped6));
+        BencodedValue::Dict(dict).bencode()
+    }
+}
+
+// What one peer has heard from us over ut_pex, so each update only has what changed
+// since, & comes no sooner than PEX_INTERVAL after the last
+#[derive(Debug, Default)]
+pub struct PexUpdates {
+    sent: BTreeSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexUpdates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The message to send given who we're connected to now, if it's time & there's
+    // news. Changes past MAX_PEX_PEERS wait for a later message.
+    pub fn next_at(
+        &mut self,
+        connected: &BTreeSet<SocketAddr>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<SocketAddr> = connected
+            .difference(&self.sent)
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(connected)
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.sent.extend(&added);
+        for peer in &dropped {
+            self.sent.remove(peer);
+        }
+        self.last_sent = Some(now);
+        Some(PexMessage {
+            added_flags: vec![0; added.len()],
+            added,
+            dropped,
+        })
+    }
+}
+
+// The info dict as it comes in, piece by piece & in any order
+#[derive(Debug)]
+pub struct MetadataBuffer {
+    data: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataBuffer {
+    pub fn new(size: Option<i64>) -> Result<Self, MetadataError> {
+        let len = match size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size as usize,
+            _ => return Err(MetadataError::BadSize(size)),
+        };
+        Ok(MetadataBuffer {
+            data: vec![0; len],
+            received: vec![false; len.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.received.len()
+    }
+
+    // Every piece is 16 KiB but the last; none past it
+    pub fn piece_len(&self, piece: usize) -> usize {
+        match self.piece_start(piece) {
+            Some(start) => (self.data.len() - start).min(METADATA_PIECE_SIZE),
+            None => 0,
+        }
+    }
+
+    // Where a piece starts in the info dict. The index is the peer's to pick, so
+    // it's checked before any arithmetic.
+    fn piece_start(&self, piece: usize) -> Option<usize> {
+        if piece >= self.num_pieces() {
+            return None;
+        }
+        piece.checked_mul(METADATA_PIECE_SIZE)
+    }
+
+    pub fn add(&mut self, piece: usize, data: &[u8]) -> Result<(), MetadataError> {
+        let expected = self.piece_len(piece);
+        let start = match self.piece_start(piece) {
+            Some(start) if data.len() == expected => start,
+            _ => {
+                return Err(MetadataError::BadPiece {
+                    piece,
+                    length: data.len(),
+                    expected,
+                })
+            }
+        };
+        self.data[start..start + expected].copy_from_slice(data);
+        self.received[piece] = true;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|&received| received)
+    }
+
+    // The raw info dict, once it hashes to what the magnet link promised
+    pub fn finish(self, info_hash: &[u8; 20]) -> Result<Vec<u8>, MetadataError> {
+        if Sha1Hasher.digest(&self.data) != info_hash {
+            return Err(MetadataError::HashMismatch);
+        }
+        Ok(self.data)
+    }
+}
+
+// Our extended handshake: the extensions we speak with the ids to send them to us
+// under, our client & how many requests we'll queue
+pub fn handshake_payload(extensions: &[(&str, u8)]) -> Vec<u8> {
+    let m = extensions
+        .iter()
+        .map(|&(name, id)| {
+            (
+                BencodedString::from(name.as_bytes()),
+                BencodedValue::Integer(id as i64),
+            )
+        })
+        .collect();
+    BencodedValue::Dict(BTreeMap::from([
+        (BencodedString::from(&b"m"[..]), BencodedValue::Dict(m)),
+        (
+            BencodedString::from(&b"reqq"[..]),
+            BencodedValue::Integer(DEFAULT_REQQ),
+        ),
+        (
+            BencodedString::from(&b"v"[..]),
+            BencodedValue::String(BencodedString::from(CLIENT_VERSION.as_bytes())),
+        ),
+    ]))
+    .bencode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn test_parse_peer_extensions() {
+        // From a qBittorrent 4.3 peer, trimmed of its yourip
+        let payload = b"d12:complete_agoi-1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei24312e4:reqqi500e1:v17:qBittorrent/4.3.9e";
+        let extensions = PeerExtensions::try_from(&payload[..]).unwrap();
+        assert_eq!(extensions.id("ut_metadata"), Some(2));
+        assert_eq!(extensions.id("ut_pex"), Some(1));
+        assert_eq!(extensions.id("lt_donthave"), Some(7));
+        assert_eq!(extensions.id("ut_comment"), None);
+        assert_eq!(extensions.client.as_deref(), Some("qBittorrent/4.3.9"));
+        assert_eq!(extensions.reqq, Some(500));
+        assert_eq!(extensions.metadata_size, Some(24312));
+
+        // Turned off, & ids out of range, are as good as missing
+        let payload = b"d1:md6:ut_pexi0e11:ut_metadatai300eee";
+        let extensions = PeerExtensions::try_from(&payload[..]).unwrap();
+        assert_eq!(extensions.id("ut_pex"), None);
+        assert_eq!(extensions.id("ut_metadata"), None);
+        assert_eq!(extensions.client, None);
+
+        assert_eq!(
+            PeerExtensions::try_from(&b"li1ee"[..]),
+            Err(ExtensionError::NotADict)
+        );
+        assert!(PeerExtensions::try_from(&b"d1:m"[..]).is_err());
+    }
+
+    #[test]
+    fn test_handshake_payload() {
+        let payload = handshake_payload(&[("ut_metadata", 1)]);
+        let extensions = PeerExtensions::try_from(&payload[..]).unwrap();
+        assert_eq!(extensions.id("ut_metadata"), Some(1));
+        assert_eq!(extensions.client.as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(extensions.reqq, Some(DEFAULT_REQQ));
+        assert!(payload.starts_with(b"d1:md11:ut_metadatai1ee4:reqqi250e1:v"));
+    }
+
+    #[test]
+    fn test_metadata_messages() {
+        let request = MetadataMessage::Request { piece: 2 };
+        let bytes = Vec::<u8>::from(&request);
+        assert_eq!(bytes, b"d8:msg_typei0e5:piecei2ee");
+        assert_eq!(MetadataMessage::try_from(&bytes[..]), Ok(request));
+
+        // The piece follows the dict, outside the bencoding
+        let payload = b"d8:msg_typei1e5:piecei0e10:total_sizei5ee\x01\x02\x03\x04\x05";
+        let data = MetadataMessage::try_from(&payload[..]).unwrap();
+        assert_eq!(
+            data,
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 5,
+                data: vec![1, 2, 3, 4, 5]
+            }
+        );
+        assert_eq!(Vec::<u8>::from(&data), payload);
+
+        assert_eq!(
+            MetadataMessage::try_from(&b"d8:msg_typei2e5:piecei1ee"[..]),
+            Ok(MetadataMessage::Reject { piece: 1 })
+        );
+        assert_eq!(
+            MetadataMessage::try_from(&b"d8:msg_typei7e5:piecei1ee"[..]),
+            Err(ExtensionError::UnknownMessageType(7))
+        );
+        assert_eq!(
+            MetadataMessage::try_from(&b"d8:msg_typei1e5:piecei0ee"[..]),
+            Err(ExtensionError::MissingKey("total_size"))
+        );
+        assert_eq!(
+            MetadataMessage::try_from(&b"d8:msg_typei0ee"[..]),
+            Err(ExtensionError::MissingKey("piece"))
+        );
+    }
+
+    #[test]
+    fn test_metadata_buffer() {
+        let info = vec![7; METADATA_PIECE_SIZE + 100];
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let mut buffer = MetadataBuffer::new(Some(info.len() as i64)).unwrap();
+        assert_eq!(buffer.num_pieces(), 2);
+        assert_eq!(buffer.piece_len(1), 100);
+
+        buffer.add(1, &info[METADATA_PIECE_SIZE..]).unwrap();
+        assert!(!buffer.is_complete());
+        assert_eq!(
+            buffer.add(0, &info[..100]),
+            Err(MetadataError::BadPiece {
+                piece: 0,
+                length: 100,
+                expected: METADATA_PIECE_SIZE
+            })
+        );
+        assert!(buffer.add(2, &[]).is_err());
+
+        // A hostile piece index is turned down, not overflowed
+        let message = MetadataMessage::Data {
+            piece: i64::MAX as usize,
+            total_size: info.len() as i64,
+            data: vec![7; 100],
+        };
+        let Ok(MetadataMessage::Data { piece, data, .. }) =
+            MetadataMessage::try_from(&Vec::<u8>::from(&message)[..])
+        else {
+            panic!("Data message didn't round-trip");
+        };
+        assert_eq!(
+            buffer.add(piece, &data),
+            Err(MetadataError::BadPiece {
+                piece: i64::MAX as usize,
+                length: 100,
+                expected: 0
+            })
+        );
+        buffer.add(0, &info[..METADATA_PIECE_SIZE]).unwrap();
+        assert!(buffer.is_complete());
+        assert_eq!(
+            MetadataBuffer::new(Some(info.len() as i64)).map(|buffer| buffer.finish(&info_hash)),
+            Ok(Err(MetadataError::HashMismatch))
+        );
+        assert_eq!(buffer.finish(&info_hash), Ok(info));
+
+        assert!(MetadataBuffer::new(None).is_err());
+        assert!(MetadataBuffer::new(Some(0)).is_err());
+        assert!(MetadataBuffer::new(Some(MAX_METADATA_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_pex() {
+        // From a libtorrent peer: two IPv4 peers (the second a seed), one IPv6 & one dropped
+        let payload = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\xc8\xd57:added.f2:\x00\x026:added618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe18:added6.f0:7:dropped6:\x0a\x00\x00\x09\x1a\xe18:dropped60:e";
+        let pex = PexMessage::try_from(&payload[..]).unwrap();
+        let addrs = |addrs: &[&str]| -> Vec<SocketAddr> {
+            addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+        };
+        assert_eq!(
+            pex.added,
+            addrs(&["10.0.0.1:6881", "192.168.1.2:51413", "[2001:db8::1]:6881"])
+        );
+        // The IPv6 peer's flags were left out
+        assert_eq!(pex.added_flags, vec![0, 2, 0]);
+        assert_eq!(pex.dropped, addrs(&["10.0.0.9:6881"]));
+
+        // Back to the wire & again
+        let bytes = Vec::<u8>::from(&pex);
+        assert_eq!(PexMessage::try_from(&bytes[..]).unwrap(), pex);
+
+        assert_eq!(
+            PexMessage::try_from(&b"d5:added5:\x0a\x00\x00\x01\x1ae"[..]),
+            Err(ExtensionError::BadPeers("added"))
+        );
+        // Keys left out are as good as empty
+        assert!(PexMessage::try_from(&b"de"[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pex_updates_are_rate_limited() {
+        let peer = |n: usize| SocketAddr::from(([10, 0, (n / 256) as u8, (n % 256) as u8], 6881));
+        let mut updates = PexUpdates::new();
+        let now = Instant::now();
+        assert_eq!(updates.next_at(&BTreeSet::new(), now), None);
+
+        let mut connected: BTreeSet<SocketAddr> = (0..60).map(peer).collect();
+        let first = updates.next_at(&connected, now).unwrap();
+        assert_eq!(first.added.len(), MAX_PEX_PEERS);
+        assert!(first.dropped.is_empty());
+
+        // Nothing more within the minute, however much changes
+        connected.remove(&peer(0));
+        assert_eq!(updates.next_at(&connected, now + PEX_INTERVAL / 2), None);
+
+        // Then the rest of the new peers, & the one we lost
+        let second = updates.next_at(&connected, now + PEX_INTERVAL).unwrap();
+        assert_eq!(second.added, (50..60).map(peer).collect::<Vec<_>>());
+        assert_eq!(second.dropped, vec![peer(0)]);
+        assert_eq!(
+            updates.next_at(&connected, now + PEX_INTERVAL * 3),
+            None,
+            "no news, no message"
+        );
+    }
+}
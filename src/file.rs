@@ -1,34 +1,228 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+use anyhow::{bail, Context};
 use hex::ToHex;
-use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::decoder::{Bencodeable, BencodedString, BencodedValue};
+use crate::decoder::{
+    decode_bencoded_value, Bencodeable, BencodedString, BencodedValue, DecodeError,
+};
+use crate::hasher::{PieceHasher, Sha1Hasher};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetainfoError {
+    #[error("could not read {name}")]
+    Io {
+        name: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not decode bencode: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("invalid metainfo: {0}")]
+    Deserialize(#[from] serde_json::Error),
+    #[error("invalid torrent: {0}")]
+    Validation(String),
+    // BEP 52. Hybrid torrents are caught too: the v1 info hash would come out wrong,
+    // as the v2 keys aren't kept.
+    #[error("BitTorrent v{0} not supported")]
+    UnsupportedVersion(i64),
+}
 
 #[derive(Debug, Deserialize)]
 pub struct MetainfoFile {
-    pub announce: String,
+    // Trackerless torrents leave it out & list DHT nodes instead
+    #[serde(default)]
+    pub announce: Option<String>,
+    // Tiers of backup trackers (BEP 12)
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // Web seeds (BEP 19): HTTP servers holding the content, see WebSeed
+    #[serde(
+        default,
+        rename = "url-list",
+        deserialize_with = "deserialize_url_list"
+    )]
+    pub url_list: Option<Vec<String>>,
+    // DHT nodes (BEP 5) as [host, port] pairs, to bootstrap from, see dht_bootstrap
+    #[serde(default)]
+    pub nodes: Option<Vec<(String, u16)>>,
     pub info: Info,
+    // Optional descriptive fields, none of them part of the info hash
+    // Seconds since the Unix epoch, see format_timestamp
+    #[serde(default, rename = "creation date")]
+    pub creation_date: Option<i64>,
+    #[serde(default, rename = "created by")]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    // The character set of the strings in the torrent, e.g. UTF-8
+    #[serde(default)]
+    pub encoding: Option<String>,
+}
+
+// A lone web seed is often given as a string rather than a list of one
+fn deserialize_url_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<UrlList>::deserialize(deserializer)? {
+        Some(UrlList::One(url)) if url.is_empty() => None,
+        Some(UrlList::One(url)) => Some(vec![url]),
+        Some(UrlList::Many(urls)) => Some(urls),
+        None => None,
+    })
+}
+
+// Bencoded strings reach serde as JSON: an array of byte values, or a string when
+// every byte happens to be ASCII. A value that isn't a byte is an error, never
+// quietly wrapped around.
+fn deserialize_pieces<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Pieces {
+        Ascii(String),
+        Bytes(Vec<i64>),
+    }
+    match Pieces::deserialize(deserializer)? {
+        Pieces::Ascii(pieces) => Ok(pieces.into_bytes()),
+        Pieces::Bytes(pieces) => pieces
+            .into_iter()
+            .map(|byte| {
+                u8::try_from(byte).map_err(|_| {
+                    serde::de::Error::custom(format!("pieces byte {} is out of range", byte))
+                })
+            })
+            .collect(),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Info {
-    pub length: i64,
-    pub name: String,
+    // Only present for single-file torrents, see total_length()
+    #[serde(default)]
+    length: i64,
+    name: String,
     #[serde(rename = "piece length")]
-    pub piece_length: i64,
-    pub pieces: Vec<u8>,
+    piece_length: i64,
+    #[serde(deserialize_with = "deserialize_pieces")]
+    pieces: Vec<u8>,
+    // Only present for multi-file torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileEntry>>,
+    // BEP 27: 1 keeps peers to the tracker's, so no DHT or PEX
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+    // Filled in when parsed, or by the first info_hash() call. The fields are private
+    // so that nothing can change under it.
+    #[serde(skip)]
+    info_hash: OnceLock<[u8; 20]>,
 }
 
-impl From<Info> for BencodedValue {
-    fn from(value: Info) -> Self {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: i64,
+    pub path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    // BEP-47 flags: p = padding, x = executable, h = hidden, l = symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+// The part of one piece that lands in one file, see Info::file_layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    pub piece_index: usize,
+    // Relative to the output directory, as in FileEntry::relative_path
+    pub path: PathBuf,
+    pub offset_in_file: u64,
+    pub torrent_offset: u64,
+    pub length: u64,
+}
+
+impl FileEntry {
+    // Padding files only exist to align the next file to a piece boundary
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+
+    pub fn relative_path(&self) -> PathBuf {
+        self.path.iter().collect()
+    }
+}
+
+// Every component a plain file or directory name: not empty, `.` or `..`, & with
+// no separator or root that would make it more than one
+fn check_path(path: &[String]) -> Result<(), MetainfoError> {
+    let plain = |part: &String| {
+        !matches!(part.as_str(), "" | "." | "..")
+            && !part.contains(['/', '\\'])
+            && Path::new(part).is_relative()
+    };
+    if path.is_empty() || !path.iter().all(plain) {
+        return Err(MetainfoError::Validation(format!("unsafe path {:?}", path)));
+    }
+    Ok(())
+}
+
+impl From<&FileEntry> for BencodedValue {
+    fn from(value: &FileEntry) -> Self {
         let mut out = BTreeMap::new();
-        let name_bytes: Vec<u8> = value.name.into_bytes();
         out.insert(
             BencodedString(b"length".to_vec()),
             BencodedValue::Integer(value.length),
         );
+        out.insert(
+            BencodedString(b"path".to_vec()),
+            BencodedValue::List(
+                value
+                    .path
+                    .iter()
+                    .map(|part| BencodedValue::String(part.clone().into()))
+                    .collect(),
+            ),
+        );
+        if let Some(md5sum) = &value.md5sum {
+            out.insert(
+                BencodedString(b"md5sum".to_vec()),
+                BencodedValue::String(md5sum.clone().into()),
+            );
+        }
+        if let Some(attr) = &value.attr {
+            out.insert(
+                BencodedString(b"attr".to_vec()),
+                BencodedValue::String(attr.clone().into()),
+            );
+        }
+        BencodedValue::Dict(out)
+    }
+}
+
+impl From<&Info> for BencodedValue {
+    fn from(value: &Info) -> Self {
+        let mut out = BTreeMap::new();
+        let name_bytes: Vec<u8> = value.name.clone().into_bytes();
+        match &value.files {
+            Some(files) => out.insert(
+                BencodedString(b"files".to_vec()),
+                BencodedValue::List(files.iter().map(BencodedValue::from).collect()),
+            ),
+            None => out.insert(
+                BencodedString(b"length".to_vec()),
+                BencodedValue::Integer(value.length),
+            ),
+        };
         out.insert(
             BencodedString(b"name".to_vec()),
             BencodedValue::String(name_bytes.into()),
@@ -39,91 +233,1767 @@ impl From<Info> for BencodedValue {
         );
         out.insert(
             BencodedString(b"pieces".to_vec()),
-            BencodedValue::String(value.pieces.into()),
+            BencodedValue::String(value.pieces.clone().into()),
         );
+        if let Some(private) = value.private {
+            out.insert(
+                BencodedString(b"private".to_vec()),
+                BencodedValue::Integer(private),
+            );
+        }
         BencodedValue::Dict(out)
     }
 }
 
+impl From<Info> for BencodedValue {
+    fn from(value: Info) -> Self {
+        BencodedValue::from(&value)
+    }
+}
+
 impl Info {
+    // Build the info dict for a single file, hashing it one piece at a time
+    pub fn from_file<T: AsRef<Path>>(path: T, piece_length: i64) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if piece_length <= 0 {
+            bail!("Piece length must be positive, got {}", piece_length);
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("Invalid file name: {}", path.display()))?
+            .to_string();
+        let mut file = File::open(path)
+            .with_context(|| format!("I/O error: could not open {}", path.display()))?;
+
+        let mut length = 0;
+        let mut pieces = Vec::new();
+        loop {
+            let mut piece = Vec::with_capacity(piece_length as usize);
+            let read = (&mut file)
+                .take(piece_length as u64)
+                .read_to_end(&mut piece)
+                .with_context(|| format!("I/O error: could not read {}", path.display()))?;
+            if read == 0 {
+                break;
+            }
+            length += read as i64;
+            pieces.extend(Sha1Hasher.digest(&piece));
+        }
+
+        Ok(Info::single_file(name, length, piece_length, pieces))
+    }
+
+    pub fn single_file(name: String, length: i64, piece_length: i64, pieces: Vec<u8>) -> Self {
+        Info {
+            length,
+            name,
+            piece_length,
+            pieces,
+            ..Default::default()
+        }
+    }
+
+    pub fn multi_file(
+        name: String,
+        files: Vec<FileEntry>,
+        piece_length: i64,
+        pieces: Vec<u8>,
+    ) -> Self {
+        Info {
+            name,
+            piece_length,
+            pieces,
+            files: Some(files),
+            ..Default::default()
+        }
+    }
+
+    // The file's name, or for multi-file torrents the directory holding them
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn piece_length(&self) -> i64 {
+        self.piece_length
+    }
+
+    // Only present for multi-file torrents; file_entries() covers both
+    pub fn files(&self) -> Option<&[FileEntry]> {
+        self.files.as_deref()
+    }
+
+    // A bare bencoded info dict, e.g. fetched over ut_metadata. The info hash is that
+    // of the bytes as given, so keys we don't keep still count towards it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        Self::from_bytes_with(bytes, &Sha1Hasher)
+    }
+
+    pub fn from_bytes_with(bytes: &[u8], hasher: &dyn PieceHasher) -> Result<Self, MetainfoError> {
+        if !looks_like_bencode(bytes) {
+            return Err(MetainfoError::Validation(
+                "info is not a bencoded dictionary".to_string(),
+            ));
+        }
+        let (_, decoded_value) = decode_bencoded_value(bytes)?;
+        if let Some(version) = info_meta_version(&decoded_value) {
+            if version != 1 {
+                return Err(MetainfoError::UnsupportedVersion(version));
+            }
+        }
+        let info: Self = serde_json::from_value(serde_json::Value::from(decoded_value))?;
+        info.validate()?;
+        let _ = info.info_hash.set(short_hash(&hasher.digest(bytes)));
+        Ok(info)
+    }
+
     pub fn info_hash(&self) -> [u8; 20] {
-        let name_bytes = self.name.clone().into_bytes();
-        let hashmap = BTreeMap::from([
-            (
-                BencodedString(b"length".to_vec()),
-                BencodedValue::Integer(self.length),
-            ),
-            (
-                BencodedString(b"name".to_vec()),
-                BencodedValue::String(name_bytes.into()),
-            ),
-            (
-                BencodedString(b"piece length".to_vec()),
-                BencodedValue::Integer(self.piece_length),
-            ),
-            (
-                BencodedString(b"pieces".to_vec()),
-                BencodedValue::String(self.pieces.clone().into()),
-            ),
-        ]);
-        let bencode = BencodedValue::Dict(hashmap.into());
-        // println!("Bencode: {:?}", bencode);
+        *self.info_hash.get_or_init(|| {
+            #[cfg(test)]
+            tests::INFO_HASHES_COMPUTED.with(|n| n.set(n.get() + 1));
+            short_hash(&self.info_hash_with(&Sha1Hasher))
+        })
+    }
+
+    // The whole digest of the bencoded info dict, never cached
+    pub fn info_hash_with(&self, hasher: &dyn PieceHasher) -> Vec<u8> {
+        hasher.digest(&BencodedValue::from(self).bencode())
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(bencode.bencode());
-        hasher.finalize().into()
+    // Each piece's index, 20-byte hash & length, the last one usually short
+    pub fn iter_pieces(&self) -> impl Iterator<Item = (usize, &[u8], i64)> + '_ {
+        self.pieces
+            .chunks_exact(20)
+            .enumerate()
+            .map(|(index, hash)| (index, hash, self.piece_len(index)))
     }
 
     pub fn pieces(&self) -> Vec<[u8; 20]> {
-        return self
-            .pieces
-            .chunks(20)
-            .map(|chunk| {
-                let mut array = [0; 20];
-                array.copy_from_slice(chunk);
-                array
-            })
-            .collect();
+        self.iter_pieces()
+            .map(|(_, hash, _)| hash.try_into().unwrap())
+            .collect()
     }
 
     pub fn piece_hash(&self) -> Vec<String> {
-        // Pieces is a byte string, so we need to split it into 20 byte chunks
-        let piece_chunks = self.pieces.chunks(20);
-
-        // Return
-        piece_chunks
-            .map(|chunk| chunk.encode_hex::<String>())
-            .collect::<Vec<String>>()
+        self.iter_pieces()
+            .map(|(_, hash, _)| hash.encode_hex::<String>())
+            .collect()
     }
 
+    // False for pieces past the end, too
     pub fn verify_piece(&self, piece_index: usize, piece: &[u8]) -> bool {
-        let piece_hashes = self.piece_hash();
-        let selected_piece_hash = &piece_hashes[piece_index];
-        let mut hasher = Sha1::new();
-        hasher.update(piece);
-        let downloaded_hash: String = hasher.finalize().encode_hex::<String>();
-        if &downloaded_hash == selected_piece_hash {
-            true
+        self.verify_piece_with(piece_index, piece, &Sha1Hasher)
+    }
+
+    pub fn verify_piece_with(
+        &self,
+        piece_index: usize,
+        piece: &[u8],
+        hasher: &dyn PieceHasher,
+    ) -> bool {
+        self.iter_pieces()
+            .nth(piece_index)
+            .is_some_and(|(_, hash, _)| hasher.digest(piece) == hash)
+    }
+
+    // Every torrent as a list of files; single-file torrents have one entry named after the torrent
+    pub fn file_entries(&self) -> Vec<FileEntry> {
+        match &self.files {
+            Some(files) => files.clone(),
+            None => vec![FileEntry {
+                length: self.length,
+                path: vec![self.name.clone()],
+                md5sum: None,
+                attr: None,
+            }],
+        }
+    }
+
+    // The pieces overlapping each file. Neighbouring files can share a boundary piece.
+    pub fn file_piece_ranges(&self) -> Vec<(FileEntry, Range<usize>)> {
+        let piece_length = self.piece_length as usize;
+        let mut offset = 0;
+        self.file_entries()
+            .into_iter()
+            .map(|file| {
+                let start = offset / piece_length;
+                let end = (offset + file.length as usize).div_ceil(piece_length);
+                offset += file.length as usize;
+                (file, start..end.max(start))
+            })
+            .collect()
+    }
+
+    // Where every piece's bytes go, in torrent order: one span per piece per file it
+    // overlaps. Padding files get none, as nothing is written for them.
+    pub fn file_layout(&self) -> Vec<FileSpan> {
+        let piece_length = self.piece_length as u64;
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for file in self.file_entries() {
+            let file_end = file_start + file.length as u64;
+            let mut offset = file_start;
+            while offset < file_end && !file.is_padding() {
+                let piece_index = offset / piece_length;
+                let end = file_end.min((piece_index + 1) * piece_length);
+                spans.push(FileSpan {
+                    piece_index: piece_index as usize,
+                    path: file.relative_path(),
+                    offset_in_file: offset - file_start,
+                    torrent_offset: offset,
+                    length: end - offset,
+                });
+                offset = end;
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    // Every piece needed to reconstruct the selected files
+    pub fn pieces_for_files(&self, indices: &[usize]) -> BTreeSet<usize> {
+        let ranges = self.file_piece_ranges();
+        indices
+            .iter()
+            .filter_map(|&file_index| ranges.get(file_index))
+            .flat_map(|(_, range)| range.clone())
+            .collect()
+    }
+
+    // Catch corrupt torrents: every piece needs exactly one 20-byte hash
+    pub fn validate(&self) -> Result<(), MetainfoError> {
+        if self.piece_length <= 0 {
+            return Err(MetainfoError::Validation(format!(
+                "piece length must be positive, got {}",
+                self.piece_length
+            )));
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(MetainfoError::Validation(format!(
+                "pieces is {} bytes, not a multiple of 20",
+                self.pieces.len()
+            )));
+        }
+        let lengths: Vec<i64> = self.file_entries().iter().map(|file| file.length).collect();
+        if let Some(length) = lengths.iter().find(|&&length| length < 0) {
+            return Err(MetainfoError::Validation(format!(
+                "file length must not be negative, got {}",
+                length
+            )));
+        }
+        let total_length = lengths
+            .iter()
+            .try_fold(0i64, |total, &length| total.checked_add(length));
+        let Some((total_length, expected)) = total_length.and_then(|total| {
+            let expected = total.checked_add(self.piece_length - 1)? / self.piece_length;
+            Some((total, expected))
+        }) else {
+            return Err(MetainfoError::Validation(
+                "total length is too large".to_string(),
+            ));
+        };
+        let actual = self.pieces.len() / 20;
+        if expected != actual as i64 {
+            return Err(MetainfoError::Validation(format!(
+                "{} bytes at piece length {} needs {} piece hashes, found {}",
+                total_length, self.piece_length, expected, actual
+            )));
+        }
+        // Paths are joined onto the output directory, so none may lead out of it
+        check_path(std::slice::from_ref(&self.name))?;
+        for file in self.files.iter().flatten() {
+            check_path(&file.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    // Sum of all file lengths, padding included
+    pub fn total_length(&self) -> i64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length,
+        }
+    }
+
+    // The last piece is usually shorter than piece_length
+    pub fn piece_len(&self, piece_index: usize) -> i64 {
+        let n_pieces = self.pieces.len() / 20;
+        if piece_index + 1 == n_pieces {
+            self.total_length() - (piece_index as i64 * self.piece_length)
         } else {
-            false
+            self.piece_length
+        }
+    }
+
+    // Check the torrent's content on disk against the piece hashes, one result per piece.
+    // For multi-file torrents `path` is the directory holding the files.
+    pub fn verify_file<T: AsRef<Path>>(&self, path: T) -> std::io::Result<Vec<bool>> {
+        let mut content = self.content_reader(path.as_ref())?;
+        self.iter_pieces()
+            .map(|(_, hash, length)| {
+                let mut piece = Vec::new();
+                (&mut content).take(length as u64).read_to_end(&mut piece)?;
+                Ok(Sha1Hasher.digest(&piece) == hash)
+            })
+            .collect()
+    }
+
+    // Read the torrent's content back as one contiguous stream.
+    // Padding files are never written to disk, so they read back as zeros.
+    fn content_reader(&self, path: &Path) -> std::io::Result<Box<dyn Read>> {
+        let files = match &self.files {
+            Some(files) => files,
+            None => return Ok(Box::new(File::open(path)?)),
+        };
+        let mut content: Box<dyn Read> = Box::new(std::io::empty());
+        for file in files {
+            let length = file.length as u64;
+            let part: Box<dyn Read> = if file.is_padding() {
+                Box::new(std::io::repeat(0).take(length))
+            } else {
+                Box::new(File::open(path.join(file.relative_path()))?.take(length))
+            };
+            content = Box::new(content.chain(part));
+        }
+        Ok(content)
+    }
+
+    // Write the full torrent content to disk. Single-file torrents are written to
+    // `path` directly, multi-file torrents as a tree under the `path` directory.
+    pub fn write_files<T: AsRef<Path>>(&self, path: T, content: &[u8]) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let files = match &self.files {
+            Some(files) => files,
+            None => return std::fs::write(path, content),
+        };
+        let mut offset = 0;
+        for file in files {
+            let end = offset + file.length as usize;
+            if !file.is_padding() {
+                let file_path = path.join(file.relative_path());
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                File::create(file_path)?.write_all(&content[offset..end])?;
+            }
+            offset = end;
+        }
+        Ok(())
+    }
+
+    // Write only the selected files, cutting their bytes out of the downloaded pieces.
+    // Boundary pieces are downloaded whole, but only the selected file's range is written.
+    pub fn write_selected_files<T: AsRef<Path>>(
+        &self,
+        path: T,
+        indices: &[usize],
+        pieces: &BTreeMap<usize, Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let piece_length = self.piece_length as usize;
+        let mut offset = 0;
+        for (file_index, file) in self.file_entries().iter().enumerate() {
+            let (start, end) = (offset, offset + file.length as usize);
+            offset = end;
+            if !indices.contains(&file_index) || file.is_padding() {
+                continue;
+            }
+
+            let mut content = Vec::with_capacity(file.length as usize);
+            for piece_index in start / piece_length..end.div_ceil(piece_length) {
+                let piece = pieces.get(&piece_index).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("piece {} was not downloaded", piece_index),
+                    )
+                })?;
+                let piece_start = piece_index * piece_length;
+                let from = start.max(piece_start) - piece_start;
+                let to = end.min(piece_start + piece.len()) - piece_start;
+                content.extend_from_slice(&piece[from..to]);
+            }
+
+            let file_path = match &self.files {
+                Some(_) => path.join(file.relative_path()),
+                None => path.to_path_buf(),
+            };
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(file_path, content)?;
+        }
+        Ok(())
+    }
+
+    // Where a download goes: `output` itself, unless it's left out or an existing
+    // directory, in which case the torrent's name inside it. Only the last component
+    // of the name is used, so a torrent can't write outside the directory.
+    pub fn output_path(&self, output: Option<&Path>) -> PathBuf {
+        let name = Path::new(&self.name)
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("download"));
+        match output {
+            Some(dir) if dir.is_dir() => dir.join(name),
+            Some(path) => path.to_path_buf(),
+            None => name,
+        }
+    }
+
+    // Create the output files up front so pieces can be written as they arrive, see
+    // PieceWriter. `indices` limits it to the selected files, like write_selected_files.
+    pub fn piece_writer<T: AsRef<Path>>(
+        &self,
+        path: T,
+        indices: Option<&[usize]>,
+    ) -> std::io::Result<PieceWriter> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for (file_index, file) in self.file_entries().iter().enumerate() {
+            let range = offset..offset + file.length as u64;
+            offset = range.end;
+            let selected = indices.is_none_or(|indices| indices.contains(&file_index));
+            if !selected || file.is_padding() {
+                continue;
+            }
+
+            let file_path = match &self.files {
+                Some(_) => path.join(file.relative_path()),
+                None => path.to_path_buf(),
+            };
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Sparse where the filesystem allows, so this doesn't write the whole file.
+            // A .part file from an interrupted run is kept for its finished pieces.
+            let out = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(part_path(&file_path))?;
+            out.set_len(file.length as u64)?;
+            files.push((range, out, file_path));
+        }
+        Ok(PieceWriter {
+            piece_length: self.piece_length as u64,
+            total_length: self.total_length() as u64,
+            files,
+        })
+    }
+
+    // Open a finished download to read pieces back out of, e.g. to seed it. `path`
+    // is as for verify_file.
+    pub fn piece_reader<T: AsRef<Path>>(&self, path: T) -> std::io::Result<PieceReader> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for file in self.file_entries() {
+            let range = offset..offset + file.length as u64;
+            offset = range.end;
+            if file.is_padding() {
+                continue;
+            }
+            let file_path = match &self.files {
+                Some(_) => path.join(file.relative_path()),
+                None => path.to_path_buf(),
+            };
+            files.push((range, File::open(file_path)?));
+        }
+        Ok(PieceReader {
+            piece_length: self.piece_length as u64,
+            total_length: self.total_length() as u64,
+            files,
+        })
+    }
+}
+
+// Writes each piece straight to its offset in the output files, so a download only
+// holds the pieces in flight rather than the whole torrent. The files are written
+// as `<name>.part` until finish(), so an interrupted download never looks complete.
+pub struct PieceWriter {
+    piece_length: u64,
+    total_length: u64,
+    // Each output file with its byte range in the torrent's content & final path
+    files: Vec<(Range<u64>, File, PathBuf)>,
+}
+
+// Reads pieces, or any part of one, straight from the files of a finished download
+pub struct PieceReader {
+    piece_length: u64,
+    total_length: u64,
+    files: Vec<(Range<u64>, File)>,
+}
+
+impl PieceReader {
+    // `length` bytes from `begin` in the piece. A range past the piece's end is an error.
+    pub fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: u64,
+        length: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let piece_start = piece_index as u64 * self.piece_length;
+        let piece_end = (piece_start + self.piece_length).min(self.total_length);
+        let start = piece_start + begin;
+        if start + length as u64 > piece_end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at {} is past the end of piece {}",
+                    length, begin, piece_index
+                ),
+            ));
+        }
+        let mut block = vec![0; length];
+        let files = self.files.iter_mut().map(|(range, file)| (&*range, file));
+        read_at(files, start, &mut block)?;
+        Ok(block)
+    }
+
+    pub fn read_piece(&mut self, piece_index: usize) -> std::io::Result<Vec<u8>> {
+        let start = piece_index as u64 * self.piece_length;
+        let end = (start + self.piece_length).min(self.total_length);
+        self.read_block(piece_index, 0, end.saturating_sub(start) as usize)
+    }
+}
+
+// Fill `buf` with the torrent's content from `start`, from whichever of the files
+// (each with its byte range in the content) hold it. Bytes in none stay as they are.
+fn read_at<'a>(
+    files: impl Iterator<Item = (&'a Range<u64>, &'a mut File)>,
+    start: u64,
+    buf: &mut [u8],
+) -> std::io::Result<()> {
+    let end = start + buf.len() as u64;
+    for (range, file) in files {
+        let (from, to) = (start.max(range.start), end.min(range.end));
+        if from >= to {
+            continue;
+        }
+        file.seek(SeekFrom::Start(from - range.start))?;
+        file.read_exact(&mut buf[(from - start) as usize..(to - start) as usize])?;
+    }
+    Ok(())
+}
+
+// Where a file is kept while it's being downloaded
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+impl PieceWriter {
+    // Pieces can come in any order. Bytes belonging to skipped files are dropped.
+    pub fn write_piece(&mut self, piece_index: usize, piece: &[u8]) -> std::io::Result<()> {
+        let start = piece_index as u64 * self.piece_length;
+        let end = start + piece.len() as u64;
+        for (range, file, _) in self.files.iter_mut() {
+            let (from, to) = (start.max(range.start), end.min(range.end));
+            if from >= to {
+                continue;
+            }
+            file.seek(SeekFrom::Start(from - range.start))?;
+            file.write_all(&piece[(from - start) as usize..(to - start) as usize])?;
+        }
+        Ok(())
+    }
+
+    // What's on disk for a piece, e.g. left by an earlier run, to check before
+    // downloading it again. Bytes of skipped & padding files read as zeros.
+    pub fn read_piece(&mut self, piece_index: usize) -> std::io::Result<Vec<u8>> {
+        let start = piece_index as u64 * self.piece_length;
+        let end = (start + self.piece_length).min(self.total_length);
+        let mut piece = vec![0; end.saturating_sub(start) as usize];
+        let files = self
+            .files
+            .iter_mut()
+            .map(|(range, file, _)| (&*range, file));
+        read_at(files, start, &mut piece)?;
+        Ok(piece)
+    }
+
+    // Every piece is in: move the .part files to their real names
+    pub fn finish(self) -> std::io::Result<()> {
+        for (_, file, path) in self.files {
+            file.sync_all()?;
+            std::fs::rename(part_path(&path), path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Bencodeable for MetainfoFile {
+    fn bencode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::from([(BencodedString(b"info".to_vec()), (&self.info).into())]);
+        if let Some(announce) = &self.announce {
+            dict.insert(
+                BencodedString(b"announce".to_vec()),
+                BencodedValue::String(announce.clone().into()),
+            );
+        }
+        if let Some(nodes) = &self.nodes {
+            let nodes = nodes
+                .iter()
+                .map(|(host, port)| {
+                    BencodedValue::List(vec![
+                        BencodedValue::String(host.clone().into()),
+                        BencodedValue::Integer(i64::from(*port)),
+                    ])
+                })
+                .collect();
+            dict.insert(
+                BencodedString(b"nodes".to_vec()),
+                BencodedValue::List(nodes),
+            );
         }
+        if let Some(announce_list) = &self.announce_list {
+            let tiers = announce_list
+                .iter()
+                .map(|tier| {
+                    BencodedValue::List(
+                        tier.iter()
+                            .map(|url| BencodedValue::String(url.clone().into()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            dict.insert(
+                BencodedString(b"announce-list".to_vec()),
+                BencodedValue::List(tiers),
+            );
+        }
+        if let Some(url_list) = &self.url_list {
+            dict.insert(
+                BencodedString(b"url-list".to_vec()),
+                BencodedValue::List(
+                    url_list
+                        .iter()
+                        .map(|url| BencodedValue::String(url.clone().into()))
+                        .collect(),
+                ),
+            );
+        }
+        if let Some(creation_date) = self.creation_date {
+            dict.insert(
+                BencodedString(b"creation date".to_vec()),
+                BencodedValue::Integer(creation_date),
+            );
+        }
+        let strings = [
+            (b"created by".as_slice(), &self.created_by),
+            (b"comment".as_slice(), &self.comment),
+            (b"encoding".as_slice(), &self.encoding),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                dict.insert(
+                    BencodedString(key.to_vec()),
+                    BencodedValue::String(value.clone().into()),
+                );
+            }
+        }
+        BencodedValue::Dict(dict).bencode()
     }
 }
 
 impl MetainfoFile {
+    pub fn new(announce: String, info: Info) -> Self {
+        MetainfoFile {
+            announce: Some(announce),
+            announce_list: None,
+            url_list: None,
+            nodes: None,
+            info,
+            creation_date: None,
+            created_by: None,
+            comment: None,
+            encoding: None,
+        }
+    }
+
+    pub fn write_to_file<T: AsRef<Path>>(&self, filename: T) -> Result<(), MetainfoError> {
+        let path = filename.as_ref();
+        std::fs::write(path, self.bencode()).map_err(|source| MetainfoError::Io {
+            name: path.display().to_string(),
+            source,
+        })
+    }
+
     // Can take either PathBuf or &str
-    pub fn read_from_file<T: AsRef<std::path::Path>>(filename: T) -> std::io::Result<Self> {
-        // Open the file & read it into a string
-        let contents_u8: &[u8] = &std::fs::read(filename).unwrap();
-        // println!("U8: {:?}", contents_u8);
-        // println!("String: {}", contents);
+    pub fn read_from_file<T: AsRef<Path>>(filename: T) -> Result<Self, MetainfoError> {
+        Self::read_from_file_with(filename, &Sha1Hasher)
+    }
+
+    pub fn read_from_file_with<T: AsRef<Path>>(
+        filename: T,
+        hasher: &dyn PieceHasher,
+    ) -> Result<Self, MetainfoError> {
+        // Open the file & read it into a byte array
+        let path = filename.as_ref();
+        let contents_u8 = std::fs::read(path).map_err(|source| MetainfoError::Io {
+            name: path.display().to_string(),
+            source,
+        })?;
+        Self::from_bytes_with(&contents_u8, hasher)
+    }
+
+    // Read a whole torrent from any reader, e.g. stdin
+    pub fn read_from_reader<R: Read>(mut reader: R) -> Result<Self, MetainfoError> {
+        let mut contents_u8 = Vec::new();
+        reader
+            .read_to_end(&mut contents_u8)
+            .map_err(|source| MetainfoError::Io {
+                name: "<reader>".to_string(),
+                source,
+            })?;
+        Self::from_bytes(&contents_u8)
+    }
+
+    // Parse a bencoded metainfo dict that is already in memory
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        Self::from_bytes_with(bytes, &Sha1Hasher)
+    }
+
+    // The info hash goes through `hasher`, fed the info dict exactly as encoded
+    pub fn from_bytes_with(bytes: &[u8], hasher: &dyn PieceHasher) -> Result<Self, MetainfoError> {
+        if !looks_like_bencode(bytes) {
+            return Err(MetainfoError::Validation(
+                "input is not a bencoded dictionary".to_string(),
+            ));
+        }
 
         // Decode the bencoded dict
-        let decoded_value = BencodedValue::from(contents_u8);
+        let (_, decoded_value) = decode_bencoded_value(bytes)?;
+        if let Some(version) = meta_version(&decoded_value) {
+            if version != 1 {
+                return Err(MetainfoError::UnsupportedVersion(version));
+            }
+        }
         let json_value = serde_json::Value::from(decoded_value);
-        match serde_json::from_value(json_value) {
-            Ok(metainfo) => Ok(metainfo),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+        let metainfo: Self = serde_json::from_value(json_value)?;
+        metainfo.info.validate()?;
+        // Hashed as it came in, so info keys we don't keep still count
+        if let Some(info_bytes) = raw_info(bytes) {
+            let _ = metainfo
+                .info
+                .info_hash
+                .set(short_hash(&hasher.digest(info_bytes)));
+        }
+        Ok(metainfo)
+    }
+
+    // Where to join the DHT: the torrent's own nodes first, then the well-known routers
+    pub fn dht_bootstrap(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .flatten()
+            .map(|(host, port)| match host.contains(':') {
+                // IPv6 literals need brackets to take a port
+                true => format!("[{}]:{}", host, port),
+                false => format!("{}:{}", host, port),
+            })
+            .chain(
+                crate::dht::BOOTSTRAP_NODES
+                    .iter()
+                    .map(|node| node.to_string()),
+            )
+            .collect()
+    }
+
+    // Download a .torrent over http(s) and parse it
+    pub async fn fetch(url: &str) -> anyhow::Result<Self> {
+        let response = reqwest::get(url)
+            .await
+            .with_context(|| format!("HTTP error: could not fetch {}", url))?
+            .error_for_status()
+            .with_context(|| format!("HTTP error: bad response from {}", url))?;
+        let bytes = response
+            .bytes()
+            .await
+            .with_context(|| format!("HTTP error: could not read body from {}", url))?;
+        // Trackers & web servers like to answer with HTML error pages
+        Self::from_bytes(&bytes)
+            .with_context(|| format!("Parse error: content at {} is not a torrent", url))
+    }
+}
+
+// Unix seconds as a UTC date, e.g. 2023-01-17 09:30:00 UTC
+pub fn format_timestamp(secs: i64) -> String {
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's days_from_civil
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// RFC 4648 base32, as used for info hashes in magnet `btih` links
+pub fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    for chunk in bytes.chunks(5) {
+        // Pack up to 5 bytes into the top of a 40-bit buffer, then emit 5 bits at a time
+        let mut buffer = [0u8; 8];
+        buffer[3..3 + chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes(buffer);
+        let n_chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..8 {
+            if i < n_chars {
+                let index = (bits >> (35 - i * 5)) & 0x1f;
+                out.push(ALPHABET[index as usize] as char);
+            } else {
+                out.push('=');
+            }
         }
     }
+    out
+}
+
+// Case-insensitive, padding optional. None on any other character.
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut n_bits) = (0u64, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u64;
+        n_bits += 5;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            out.push((bits >> n_bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// A metainfo file is a single bencoded dict: d...e
+fn looks_like_bencode(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0] == b'd' && bytes[bytes.len() - 1] == b'e'
+}
+
+// The info dict's bytes within a metainfo dict, exactly as they were encoded
+fn raw_info(metainfo: &[u8]) -> Option<&[u8]> {
+    let mut rest = metainfo.get(1..)?;
+    while !rest.is_empty() && rest[0] != b'e' {
+        let (key_len, key) = decode_bencoded_value(rest).ok()?;
+        rest = &rest[key_len..];
+        let (value_len, _) = decode_bencoded_value(rest).ok()?;
+        if key == BencodedValue::String(BencodedString(b"info".to_vec())) {
+            return Some(&rest[..value_len]);
+        }
+        rest = &rest[value_len..];
+    }
+    None
+}
+
+// Info hashes are 20 bytes on the wire; BEP 52 cuts v2's SHA-256 ones down to fit
+fn short_hash(digest: &[u8]) -> [u8; 20] {
+    digest[..20].try_into().unwrap()
+}
+
+// `meta version` from the info dict: 2 for BEP 52 torrents, usually absent for v1
+fn meta_version(metainfo: &BencodedValue) -> Option<i64> {
+    let BencodedValue::Dict(metainfo) = metainfo else {
+        return None;
+    };
+    info_meta_version(metainfo.get(&BencodedString(b"info".to_vec()))?)
+}
+
+fn info_meta_version(info: &BencodedValue) -> Option<i64> {
+    let BencodedValue::Dict(info) = info else {
+        return None;
+    };
+    match info.get(&BencodedString(b"meta version".to_vec())) {
+        Some(BencodedValue::Integer(version)) => Some(*version),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testsupport::{info_for, serve_once};
+    use sha1::{Digest, Sha1};
+    use std::cell::Cell;
+
+    thread_local! {
+        // Bumped every time info_hash() actually hashes, see test_info_hash_is_cached
+        pub static INFO_HASHES_COMPUTED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn sample_info() -> Info {
+        Info {
+            length: 92063,
+            name: "sample.txt".to_string(),
+            piece_length: 32768,
+            pieces: (0x80..0xbc).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn sample_torrent_bytes() -> Vec<u8> {
+        MetainfoFile::new("http://tracker.example/announce".to_string(), sample_info()).bencode()
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let metainfo = MetainfoFile::from_bytes(&sample_torrent_bytes()).unwrap();
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example/announce")
+        );
+        assert_eq!(metainfo.info.length, 92063);
+        assert_eq!(metainfo.info.name, "sample.txt");
+        assert_eq!(metainfo.info.piece_length, 32768);
+        assert_eq!(metainfo.info.pieces, sample_info().pieces);
+        assert_eq!(metainfo.info.info_hash(), sample_info().info_hash());
+    }
+
+    #[test]
+    fn test_optional_metadata() {
+        let mut metainfo =
+            MetainfoFile::new("http://tracker.example/announce".to_string(), sample_info());
+        metainfo.creation_date = Some(1_673_947_800);
+        metainfo.created_by = Some("mktorrent 1.1".to_string());
+        metainfo.comment = Some("just a sample".to_string());
+        metainfo.encoding = Some("UTF-8".to_string());
+
+        let parsed = MetainfoFile::from_bytes(&metainfo.bencode()).unwrap();
+        assert_eq!(parsed.creation_date, Some(1_673_947_800));
+        assert_eq!(parsed.created_by.as_deref(), Some("mktorrent 1.1"));
+        assert_eq!(parsed.comment.as_deref(), Some("just a sample"));
+        assert_eq!(parsed.encoding.as_deref(), Some("UTF-8"));
+        // None of it is part of the info dict
+        assert_eq!(parsed.info.info_hash(), sample_info().info_hash());
+
+        let bare = MetainfoFile::from_bytes(&sample_torrent_bytes()).unwrap();
+        assert!(bare.creation_date.is_none() && bare.created_by.is_none());
+        assert!(bare.comment.is_none() && bare.encoding.is_none());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_673_947_800), "2023-01-17 09:30:00 UTC");
+        // 2000 was a leap year
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn test_announce_list_round_trip() {
+        let mut metainfo =
+            MetainfoFile::new("http://tracker.example/announce".to_string(), sample_info());
+        metainfo.announce_list = Some(vec![
+            vec!["http://a.example/announce".to_string()],
+            vec![
+                "http://b.example/announce".to_string(),
+                "udp://c.example:80".to_string(),
+            ],
+        ]);
+        let parsed = MetainfoFile::from_bytes(&metainfo.bencode()).unwrap();
+        assert_eq!(parsed.announce_list, metainfo.announce_list);
+
+        let parsed = MetainfoFile::from_bytes(&sample_torrent_bytes()).unwrap();
+        assert_eq!(parsed.announce_list, None);
+    }
+
+    #[test]
+    fn test_trackerless_with_nodes() {
+        let mut metainfo = MetainfoFile::new(String::new(), sample_info());
+        metainfo.announce = None;
+        metainfo.nodes = Some(vec![
+            ("127.0.0.1".to_string(), 6881),
+            ("dht.example.com".to_string(), 6882),
+            ("::1".to_string(), 6883),
+        ]);
+        let bytes = metainfo.bencode();
+        assert!(!bytes.windows(8).any(|window| window == b"announce"));
+
+        let parsed = MetainfoFile::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.announce, None);
+        assert_eq!(parsed.nodes, metainfo.nodes);
+        let bootstrap = parsed.dht_bootstrap();
+        assert_eq!(
+            &bootstrap[..3],
+            &["127.0.0.1:6881", "dht.example.com:6882", "[::1]:6883"]
+        );
+        assert_eq!(bootstrap.len(), 3 + crate::dht::BOOTSTRAP_NODES.len());
+    }
+
+    #[test]
+    fn test_url_list() {
+        let mut metainfo =
+            MetainfoFile::new("http://tracker.example/announce".to_string(), sample_info());
+        metainfo.url_list = Some(vec![
+            "http://a.example/files/".to_string(),
+            "http://b.example/sample.txt".to_string(),
+        ]);
+        let parsed = MetainfoFile::from_bytes(&metainfo.bencode()).unwrap();
+        assert_eq!(parsed.url_list, metainfo.url_list);
+
+        // A single web seed may be a bare string, & an empty one means none
+        let mut bytes = sample_torrent_bytes();
+        bytes.pop();
+        let mut single = bytes.clone();
+        single.extend(b"8:url-list18:http://a.example/fe");
+        let parsed = MetainfoFile::from_bytes(&single).unwrap();
+        assert_eq!(
+            parsed.url_list,
+            Some(vec!["http://a.example/f".to_string()])
+        );
+        bytes.extend(b"8:url-list0:e");
+        assert_eq!(MetainfoFile::from_bytes(&bytes).unwrap().url_list, None);
+    }
+
+    #[test]
+    fn test_v2_torrent_is_refused() {
+        let v2 =
+            b"d8:announce9:http://t/4:infod9:file treed5:a.txtd0:d6:lengthi5e11:pieces root32:\
+aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaeee12:meta versioni2e4:name5:a.txt12:piece lengthi16384eee";
+        let err = MetainfoFile::from_bytes(v2).unwrap_err();
+        assert!(
+            matches!(err, MetainfoError::UnsupportedVersion(2)),
+            "{}",
+            err
+        );
+        assert_eq!(err.to_string(), "BitTorrent v2 not supported");
+
+        // An explicit v1 is fine
+        let mut v1 = MetainfoFile::new("http://t/".to_string(), sample_info()).bencode();
+        let info_at = v1.windows(6).position(|w| w == b"4:name").unwrap();
+        v1.splice(info_at..info_at, b"12:meta versioni1e".iter().copied());
+        assert!(MetainfoFile::from_bytes(&v1).is_ok());
+    }
+
+    #[test]
+    fn test_from_bytes_not_bencode() {
+        let err = MetainfoFile::from_bytes(b"<html>nope</html>").unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+
+        let err = MetainfoFile::from_bytes(b"").unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+
+        let err = MetainfoFile::from_bytes(b"d8:announcei3").unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+
+        let err = MetainfoFile::from_bytes(b"d8:announce99:shorte").unwrap_err();
+        assert!(matches!(err, MetainfoError::Decode(_)), "{}", err);
+
+        let err = MetainfoFile::from_bytes(b"d8:announcei3ee").unwrap_err();
+        assert!(matches!(err, MetainfoError::Deserialize(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_piece_count_mismatch() {
+        // 92063 bytes at 32768 per piece needs 3 hashes, not 2
+        let mut info = sample_info();
+        info.pieces.truncate(40);
+        let metainfo = MetainfoFile::new("http://tracker.example/announce".to_string(), info);
+        let err = MetainfoFile::from_bytes(&metainfo.bencode()).unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+        assert!(
+            err.to_string().contains("needs 3 piece hashes, found 2"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_paths_out_of_the_output_dir() {
+        let (mut info, _) = three_file_info();
+        info.files.as_mut().unwrap()[1].path = vec!["..".into(), "..".into(), "x".into()];
+        let err = Info::from_bytes(&BencodedValue::from(&info).bencode()).unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+        assert!(err.to_string().contains("unsafe path"), "{}", err);
+
+        for path in [vec![], vec!["/etc".to_string()], vec!["a/b".to_string()]] {
+            info.files.as_mut().unwrap()[1].path = path;
+            assert!(info.validate().is_err());
+        }
+        let single = info_for("../escape", b"abc", 16);
+        assert!(single.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_lengths() {
+        let (mut info, _) = three_file_info();
+        // -100 + 200 still sums to what the pieces cover
+        let files = info.files.as_mut().unwrap();
+        files[1].length = -100;
+        files[2].length = 400;
+        let err = Info::from_bytes(&BencodedValue::from(&info).bencode()).unwrap_err();
+        assert!(err.to_string().contains("must not be negative"), "{}", err);
+
+        let files = info.files.as_mut().unwrap();
+        files[1].length = i64::MAX;
+        files[2].length = i64::MAX;
+        assert!(info
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+
+        for piece_length in [0, -1] {
+            let info = Info {
+                piece_length,
+                ..sample_info()
+            };
+            let err = info.validate().unwrap_err();
+            assert!(err.to_string().contains("must be positive"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_validate_pieces_not_multiple_of_20() {
+        let mut info = sample_info();
+        info.pieces.truncate(59);
+        assert!(matches!(info.validate(), Err(MetainfoError::Validation(_))));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.torrent");
+        MetainfoFile::new("http://tracker.example/announce".to_string(), info)
+            .write_to_file(&path)
+            .unwrap();
+        let err = MetainfoFile::read_from_file(&path).unwrap_err();
+        assert!(err.to_string().contains("not a multiple of 20"), "{}", err);
+    }
+
+    #[test]
+    fn test_read_from_reader() {
+        let reader = Cursor::new(sample_torrent_bytes());
+        let metainfo = MetainfoFile::read_from_reader(reader).unwrap();
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example/announce")
+        );
+        assert_eq!(metainfo.info.length, 92063);
+    }
+
+    #[test]
+    fn test_create_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&data_path, &data).unwrap();
+
+        let info = Info::from_file(&data_path, 4096).unwrap();
+        assert_eq!(info.name, "data.bin");
+        assert_eq!(info.length, 10_000);
+        assert_eq!(info.pieces().len(), 3);
+        assert!(info.verify_piece(2, &data[8192..]));
+        assert!(!info.verify_piece(3, &data[8192..]));
+        // The last piece is whatever's left
+        let pieces: Vec<_> = info.iter_pieces().collect();
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(
+            pieces
+                .iter()
+                .map(|&(index, _, length)| (index, length))
+                .collect::<Vec<_>>(),
+            vec![(0, 4096), (1, 4096), (2, 10_000 - 8192)]
+        );
+        assert_eq!(pieces[1].1, &info.pieces[20..40]);
+
+        let torrent_path = dir.path().join("data.bin.torrent");
+        let expected_hash = info.info_hash();
+        MetainfoFile::new("http://tracker.example/announce".to_string(), info)
+            .write_to_file(&torrent_path)
+            .unwrap();
+        let metainfo = MetainfoFile::read_from_file(&torrent_path).unwrap();
+        assert_eq!(metainfo.info.info_hash(), expected_hash);
+        assert_eq!(metainfo.info.pieces().len(), 3);
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("data.bin");
+        let mut data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&data_path, &data).unwrap();
+        let info = Info::from_file(&data_path, 4096).unwrap();
+        assert_eq!(info.piece_len(2), 10_000 - 8192);
+        assert_eq!(
+            info.verify_file(&data_path).unwrap(),
+            vec![true, true, true]
+        );
+
+        // Flip a single byte inside the second piece
+        data[5000] ^= 0xff;
+        std::fs::write(&data_path, &data).unwrap();
+        assert_eq!(
+            info.verify_file(&data_path).unwrap(),
+            vec![true, false, true]
+        );
+
+        // A truncated file fails the pieces it no longer covers
+        std::fs::write(&data_path, &data[..4096]).unwrap();
+        assert_eq!(
+            info.verify_file(&data_path).unwrap(),
+            vec![true, false, false]
+        );
+    }
+
+    // a.txt (3000) + .pad/1096 + b.txt (5000), so b.txt starts on the second piece
+    fn padded_info() -> (Info, Vec<u8>) {
+        let a: Vec<u8> = (0..3000u32).map(|i| (i % 7) as u8 + 1).collect();
+        let b: Vec<u8> = (0..5000u32).map(|i| (i % 13) as u8 + 1).collect();
+        let mut content = a.clone();
+        content.extend(vec![0; 1096]);
+        content.extend(&b);
+        let files = vec![
+            FileEntry {
+                length: 3000,
+                path: vec!["a.txt".to_string()],
+                md5sum: Some("0123456789abcdef0123456789abcdef".to_string()),
+                attr: None,
+            },
+            FileEntry {
+                length: 1096,
+                path: vec![".pad".to_string(), "1096".to_string()],
+                md5sum: None,
+                attr: Some("p".to_string()),
+            },
+            FileEntry {
+                length: 5000,
+                path: vec!["docs".to_string(), "b.txt".to_string()],
+                md5sum: None,
+                attr: Some("x".to_string()),
+            },
+        ];
+        let pieces = info_for("padded", &content, 4096).pieces;
+        let info = Info::multi_file("padded".to_string(), files, 4096, pieces);
+        (info, content)
+    }
+
+    #[test]
+    fn test_padded_files_round_trip() {
+        let (info, _) = padded_info();
+        assert_eq!(info.total_length(), 9096);
+        assert!(info.files.as_ref().unwrap()[1].is_padding());
+        assert!(!info.files.as_ref().unwrap()[2].is_padding());
+
+        let expected_hash = info.info_hash();
+        let bytes =
+            MetainfoFile::new("http://tracker.example/announce".to_string(), info).bencode();
+        let metainfo = MetainfoFile::from_bytes(&bytes).unwrap();
+        let files = metainfo.info.files.as_ref().unwrap();
+        assert_eq!(
+            files[0].md5sum.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(files[1].attr.as_deref(), Some("p"));
+        assert_eq!(metainfo.info.info_hash(), expected_hash);
+    }
+
+    #[test]
+    fn test_padded_files_write_and_verify() {
+        let (info, content) = padded_info();
+        let dir = tempfile::tempdir().unwrap();
+        info.write_files(dir.path(), &content).unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.path().join("a.txt")).unwrap(),
+            &content[..3000]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("docs").join("b.txt")).unwrap(),
+            &content[4096..]
+        );
+        assert!(!dir.path().join(".pad").exists());
+        assert_eq!(
+            info.verify_file(dir.path()).unwrap(),
+            vec![true, true, true]
+        );
+    }
+
+    #[test]
+    fn test_info_hash_is_cached() {
+        let info = sample_info();
+        let before = INFO_HASHES_COMPUTED.with(Cell::get);
+        assert_eq!(info.info_hash(), info.info_hash());
+        assert_eq!(INFO_HASHES_COMPUTED.with(Cell::get), before + 1);
+
+        // A clone carries the hash along
+        assert_eq!(info.clone().info_hash(), info.info_hash());
+        assert_eq!(INFO_HASHES_COMPUTED.with(Cell::get), before + 1);
+
+        // & it never ends up in the info dict itself
+        let json = serde_json::to_value(&info).unwrap();
+        assert!(json.get("info_hash").is_none());
+    }
+
+    // Keeps everything it's asked to hash
+    #[derive(Default)]
+    struct RecordingHasher {
+        fed: std::cell::RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl PieceHasher for RecordingHasher {
+        fn digest(&self, data: &[u8]) -> Vec<u8> {
+            self.fed.borrow_mut().push(data.to_vec());
+            vec![0xab; 20]
+        }
+    }
+
+    #[test]
+    fn test_hashing_goes_through_piece_hasher() {
+        let info = sample_info();
+        let hasher = RecordingHasher::default();
+        assert_eq!(info.info_hash_with(&hasher), vec![0xab; 20]);
+        assert_eq!(*hasher.fed.borrow(), [BencodedValue::from(&info).bencode()]);
+        assert_eq!(info.info_hash_with(&Sha1Hasher), info.info_hash().to_vec());
+
+        // Parsing hashes the info dict as it was encoded, unknown keys & all
+        let mut info_bytes = BencodedValue::from(&info).bencode();
+        info_bytes.pop();
+        info_bytes.extend(b"6:source3:abce");
+        let mut torrent = b"d8:announce5:http:4:info".to_vec();
+        torrent.extend(&info_bytes);
+        torrent.push(b'e');
+        let hasher = RecordingHasher::default();
+        let metainfo = MetainfoFile::from_bytes_with(&torrent, &hasher).unwrap();
+        assert_eq!(*hasher.fed.borrow(), [info_bytes.clone()]);
+        assert_eq!(metainfo.info.info_hash(), [0xab; 20]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("extra.torrent");
+        std::fs::write(&path, &torrent).unwrap();
+        let hasher = RecordingHasher::default();
+        MetainfoFile::read_from_file_with(&path, &hasher).unwrap();
+        assert_eq!(*hasher.fed.borrow(), [info_bytes.clone()]);
+
+        let hasher = RecordingHasher::default();
+        let parsed = Info::from_bytes_with(&info_bytes, &hasher).unwrap();
+        assert_eq!(*hasher.fed.borrow(), [info_bytes]);
+        assert_eq!(parsed.info_hash(), [0xab; 20]);
+
+        let hasher = RecordingHasher::default();
+        assert!(!info.verify_piece_with(1, b"some piece", &hasher));
+        assert_eq!(*hasher.fed.borrow(), [b"some piece".to_vec()]);
+        let mut info = sample_info();
+        info.pieces[20..40].fill(0xab);
+        assert!(info.verify_piece_with(1, b"some piece", &hasher));
+    }
+
+    #[test]
+    fn test_pieces_round_trip_every_byte() {
+        // Every byte value, high ones included, 13 hashes' worth
+        let info = Info {
+            length: 13 * 32768,
+            pieces: (0..260).map(|i| (i % 256) as u8).collect(),
+            ..sample_info()
+        };
+        let bytes = MetainfoFile::new("http://t/".to_string(), info.clone()).bencode();
+        let metainfo = MetainfoFile::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.info.pieces, info.pieces);
+        assert_eq!(metainfo.info.info_hash(), info.info_hash());
+
+        // All-ASCII hashes come through JSON as a string
+        let ascii = Info {
+            length: 1000,
+            pieces: b"abcdefghijklmnopqrst".to_vec(),
+            ..sample_info()
+        };
+        let bytes = MetainfoFile::new("http://t/".to_string(), ascii.clone()).bencode();
+        let metainfo = MetainfoFile::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.info.pieces, ascii.pieces);
+
+        let mut json = serde_json::to_value(&info).unwrap();
+        json["pieces"][3] = 256.into();
+        let err = serde_json::from_value::<Info>(json).unwrap_err();
+        assert!(err.to_string().contains("pieces byte 256"), "{}", err);
+    }
+
+    #[test]
+    fn test_piece_len_not_a_multiple_of_block_size() {
+        let info = Info {
+            length: 50_000,
+            name: "odd".to_string(),
+            piece_length: 20_000,
+            pieces: vec![0x80; 60],
+            ..Default::default()
+        };
+        assert!(info.validate().is_ok());
+        let lengths: Vec<i64> = (0..3)
+            .map(|piece_index| info.piece_len(piece_index))
+            .collect();
+        assert_eq!(lengths, vec![20_000, 20_000, 10_000]);
+    }
+
+    #[test]
+    fn test_output_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = sample_info();
+        assert_eq!(info.output_path(None), PathBuf::from("sample.txt"));
+        assert_eq!(
+            info.output_path(Some(dir.path())),
+            dir.path().join("sample.txt")
+        );
+        let file = dir.path().join("renamed.txt");
+        assert_eq!(info.output_path(Some(&file)), file);
+
+        // A name can't climb out of the output directory
+        let sneaky = Info {
+            name: "../../etc/passwd".to_string(),
+            ..sample_info()
+        };
+        assert_eq!(
+            sneaky.output_path(Some(dir.path())),
+            dir.path().join("passwd")
+        );
+    }
+
+    #[test]
+    fn test_output_path_writes_under_name() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let content: Vec<u8> = (0..100u8).collect();
+        let single = info_for("single.bin", &content, 64);
+        let mut writer = single
+            .piece_writer(single.output_path(Some(dir.path())), None)
+            .unwrap();
+        writer.write_piece(0, &content[..64]).unwrap();
+        writer.write_piece(1, &content[64..]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("single.bin")).unwrap(),
+            content
+        );
+
+        let (multi, content) = padded_info();
+        let root = multi.output_path(Some(dir.path()));
+        assert_eq!(root, dir.path().join("padded"));
+        let mut writer = multi.piece_writer(&root, None).unwrap();
+        for (piece_index, piece) in content.chunks(4096).enumerate() {
+            writer.write_piece(piece_index, piece).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(
+            std::fs::read(root.join("docs").join("b.txt")).unwrap(),
+            &content[4096..]
+        );
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), &content[..3000]);
+    }
+
+    #[test]
+    fn test_piece_writer_out_of_order() {
+        let (info, content) = padded_info();
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = info.piece_writer(dir.path(), None).unwrap();
+        for piece_index in [2, 0, 1] {
+            let start = piece_index * 4096;
+            let end = start + info.piece_len(piece_index) as usize;
+            writer
+                .write_piece(piece_index, &content[start..end])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert!(!dir.path().join(".pad").exists());
+        assert_eq!(
+            info.verify_file(dir.path()).unwrap(),
+            vec![true, true, true]
+        );
+
+        // Read back for seeding, padding as zeros
+        let mut reader = info.piece_reader(dir.path()).unwrap();
+        for (piece_index, _, length) in info.iter_pieces() {
+            let start = piece_index * 4096;
+            assert_eq!(
+                reader.read_piece(piece_index).unwrap(),
+                content[start..start + length as usize]
+            );
+        }
+        assert_eq!(reader.read_block(1, 100, 16).unwrap(), content[4196..4212]);
+        let last = info.piece_len(2) as u64;
+        assert!(reader.read_block(2, last - 10, 11).is_err());
+    }
+
+    #[test]
+    fn test_piece_writer_keeps_part_file_until_finished() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let info = info_for("single.bin", &content, 64);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("single.bin");
+
+        // Interrupted after the first piece
+        let mut writer = info.piece_writer(&output, None).unwrap();
+        writer.write_piece(0, &content[..64]).unwrap();
+        drop(writer);
+        assert!(!output.exists());
+        assert!(dir.path().join("single.bin.part").exists());
+
+        // The next run picks up the finished piece
+        let mut writer = info.piece_writer(&output, None).unwrap();
+        assert!(info.verify_piece(0, &writer.read_piece(0).unwrap()));
+        assert!(!info.verify_piece(1, &writer.read_piece(1).unwrap()));
+        writer.write_piece(1, &content[64..]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+        assert!(!dir.path().join("single.bin.part").exists());
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        let info_hash = [
+            0xd6, 0x9f, 0x91, 0xe6, 0xb2, 0xae, 0x4c, 0x54, 0x24, 0x68, 0xd1, 0x07, 0x3a, 0x71,
+            0xd4, 0xea, 0x13, 0x87, 0x9a, 0x7f,
+        ];
+        assert_eq!(
+            base32_encode(&info_hash),
+            "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+        );
+
+        // RFC 4648 test vectors, including padding
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY======");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI======");
+    }
+
+    #[test]
+    fn test_base32_decode() {
+        let info_hash = sample_info().info_hash();
+        let encoded = base32_encode(&info_hash);
+        assert_eq!(base32_decode(&encoded), Some(info_hash.to_vec()));
+        assert_eq!(
+            base32_decode(&encoded.to_lowercase()),
+            Some(info_hash.to_vec())
+        );
+        assert_eq!(base32_decode("MZXW6YTBOI======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW6YTB01"), None);
+    }
+
+    #[test]
+    fn test_info_from_bytes() {
+        let info = sample_info();
+        let bytes = BencodedValue::from(&info).bencode();
+        let parsed = Info::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.name, info.name);
+        assert_eq!(parsed.info_hash(), info.info_hash());
+
+        // Keys we drop still count towards the hash
+        let mut extra = bytes[..bytes.len() - 1].to_vec();
+        extra.extend(b"6:source3:abce");
+        let parsed = Info::from_bytes(&extra).unwrap();
+        assert_eq!(parsed.info_hash(), <[u8; 20]>::from(Sha1::digest(&extra)));
+        assert_ne!(parsed.info_hash(), info.info_hash());
+
+        // The private flag is kept, & hashed the same way it came in
+        let mut private = bytes[..bytes.len() - 1].to_vec();
+        private.extend(b"7:privatei1ee");
+        let parsed = Info::from_bytes(&private).unwrap();
+        assert!(parsed.is_private() && !info.is_private());
+        assert_eq!(BencodedValue::from(&parsed).bencode(), private);
+
+        assert!(Info::from_bytes(b"li1ee").is_err());
+        assert!(matches!(
+            Info::from_bytes(b"d12:meta versioni2ee"),
+            Err(MetainfoError::UnsupportedVersion(2))
+        ));
+    }
+
+    // big.bin (5000) + tiny1 (100) + tiny2 (200) with 4096-byte pieces:
+    // big.bin spans pieces 0-1, and both tiny files sit entirely inside piece 1
+    fn three_file_info() -> (Info, Vec<u8>) {
+        let content: Vec<u8> = (0..5300u32).map(|i| (i % 253) as u8).collect();
+        let entry = |length, name: &str| FileEntry {
+            length,
+            path: vec!["dir".to_string(), name.to_string()],
+            md5sum: None,
+            attr: None,
+        };
+        let files = vec![
+            entry(5000, "big.bin"),
+            entry(100, "tiny1"),
+            entry(200, "tiny2"),
+        ];
+        let pieces = info_for("three", &content, 4096).pieces;
+        let info = Info::multi_file("three".to_string(), files, 4096, pieces);
+        (info, content)
+    }
+
+    #[test]
+    fn test_file_piece_ranges() {
+        let (info, _) = three_file_info();
+        let ranges: Vec<Range<usize>> = info
+            .file_piece_ranges()
+            .into_iter()
+            .map(|(_, range)| range)
+            .collect();
+        assert_eq!(ranges, vec![0..2, 1..2, 1..2]);
+
+        assert_eq!(info.pieces_for_files(&[1]), BTreeSet::from([1]));
+        assert_eq!(info.pieces_for_files(&[1, 2]), BTreeSet::from([1]));
+        assert_eq!(info.pieces_for_files(&[0, 2]), BTreeSet::from([0, 1]));
+
+        // A single-file torrent is one file covering every piece
+        let ranges = sample_info().file_piece_ranges();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].0.path, vec!["sample.txt".to_string()]);
+        assert_eq!(ranges[0].1, 0..3);
+    }
+
+    #[test]
+    fn test_file_layout() {
+        let info = Info {
+            name: "dir".to_string(),
+            piece_length: 8000,
+            pieces: vec![0; 40],
+            files: Some(vec![
+                FileEntry {
+                    length: 10000,
+                    path: vec!["a.bin".to_string()],
+                    md5sum: None,
+                    attr: None,
+                },
+                FileEntry {
+                    length: 5000,
+                    path: vec!["sub".to_string(), "b.bin".to_string()],
+                    md5sum: None,
+                    attr: None,
+                },
+            ]),
+            ..Default::default()
+        };
+        let span = |piece_index, path: &str, offset_in_file, torrent_offset, length| FileSpan {
+            piece_index,
+            path: PathBuf::from(path),
+            offset_in_file,
+            torrent_offset,
+            length,
+        };
+        let layout = info.file_layout();
+        assert_eq!(
+            layout,
+            vec![
+                span(0, "a.bin", 0, 0, 8000),
+                // Piece 1 straddles the boundary between the two files
+                span(1, "a.bin", 8000, 8000, 2000),
+                span(1, "sub/b.bin", 0, 10000, 5000),
+            ]
+        );
+        // The spans tile the torrent with no gaps or overlaps
+        let mut end = 0;
+        for span in &layout {
+            assert_eq!(span.torrent_offset, end);
+            end += span.length;
+        }
+        assert_eq!(end as i64, info.total_length());
+    }
+
+    #[test]
+    fn test_write_selected_files() {
+        let (info, content) = three_file_info();
+        // Only piece 1 is needed for the tiny files
+        let pieces = BTreeMap::from([(1, content[4096..].to_vec())]);
+        let dir = tempfile::tempdir().unwrap();
+        info.write_selected_files(dir.path(), &[2], &pieces)
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.path().join("dir").join("tiny2")).unwrap(),
+            &content[5100..5300]
+        );
+        assert!(!dir.path().join("dir").join("tiny1").exists());
+        assert!(!dir.path().join("dir").join("big.bin").exists());
+
+        // big.bin needs piece 0 as well
+        let err = info
+            .write_selected_files(dir.path(), &[0], &pieces)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_read_from_file_missing() {
+        let err = MetainfoFile::read_from_file("/definitely/not/here.torrent").unwrap_err();
+        assert!(matches!(err, MetainfoError::Io { .. }), "{}", err);
+        assert!(err.to_string().contains("/definitely/not/here.torrent"));
+    }
+
+    #[test]
+    fn test_read_from_file_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = MetainfoFile::read_from_file(dir.path()).unwrap_err();
+        assert!(matches!(err, MetainfoError::Io { .. }), "{}", err);
+        assert!(err.to_string().contains(&dir.path().display().to_string()));
+    }
+
+    #[test]
+    fn test_read_from_file_not_bencode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "just some text").unwrap();
+        let err = MetainfoFile::read_from_file(&path).unwrap_err();
+        assert!(matches!(err, MetainfoError::Validation(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_metainfo_info_hash_keeps_unknown_keys() {
+        let mut info = BencodedValue::from(&sample_info()).bencode();
+        info.pop();
+        info.extend(b"6:source3:abce");
+        let mut bytes = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        bytes.extend(&info);
+        bytes.push(b'e');
+        let metainfo = MetainfoFile::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            metainfo.info.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+        assert_ne!(metainfo.info.info_hash(), sample_info().info_hash());
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let url = serve_once("200 OK", sample_torrent_bytes()) + "/sample.torrent";
+        let metainfo = MetainfoFile::fetch(&url).await.unwrap();
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example/announce")
+        );
+        assert_eq!(metainfo.info.info_hash(), sample_info().info_hash());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_http_error() {
+        let url = serve_once("404 Not Found", b"missing".to_vec()) + "/sample.torrent";
+        let err = MetainfoFile::fetch(&url).await.unwrap_err();
+        assert!(err.to_string().starts_with("HTTP error"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_not_bencode() {
+        let url = serve_once("200 OK", b"<html>login required</html>".to_vec()) + "/sample.torrent";
+        let err = MetainfoFile::fetch(&url).await.unwrap_err();
+        assert!(err.to_string().starts_with("Parse error"), "{}", err);
+    }
 }
@@ -0,0 +1,30 @@
+use sha1::{Digest, Sha1};
+
+// Hashes info dicts & pieces. v1 torrents use SHA-1 throughout; BEP 52's SHA-256
+// would be another implementation.
+pub trait PieceHasher {
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+}
+
+// What every torrent we support hashes with
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha1Hasher;
+
+impl PieceHasher for Sha1Hasher {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Sha1::digest(data).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1_hasher() {
+        assert_eq!(
+            hex::encode(Sha1Hasher.digest(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+}
@@ -1,3 +1,21 @@
+pub mod bitfield;
+pub mod coordinator;
 pub mod decoder;
+pub mod dht;
+pub mod extension;
 pub mod file;
+pub mod hasher;
+pub mod magnet;
 pub mod network;
+pub mod peer_id;
+pub mod peer_set;
+pub mod piece_selector;
+pub mod ratelimit;
+pub mod seed;
+pub mod webseed;
+
+#[cfg(test)]
+mod testsupport;
+// So testsupport's paths work both here & in the binary's tests
+#[cfg(test)]
+extern crate self as bittorrent_starter_rust;
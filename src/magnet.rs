@@ -0,0 +1,139 @@
+use anyhow::{anyhow, bail, Context, Error};
+use log::debug;
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::file::{base32_decode, Info, MetainfoFile};
+
+// A magnet link (BEP 9): the info hash, & maybe a name, trackers & peers to ask.
+// The info dict itself has to come from a peer, see PeerStream::fetch_metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    // `dn`, only for show until the info dict arrives
+    pub name: Option<String>,
+    // `tr`, each its own tier
+    pub trackers: Vec<String>,
+    // `x.pe`
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    // The torrent the link stands for, once the info dict is in hand
+    pub fn metainfo(&self, info: Info) -> MetainfoFile {
+        let mut metainfo = MetainfoFile::new(String::new(), info);
+        metainfo.announce = self.trackers.first().cloned();
+        if self.trackers.len() > 1 {
+            metainfo.announce_list = Some(
+                self.trackers
+                    .iter()
+                    .map(|tracker| vec![tracker.clone()])
+                    .collect(),
+            );
+        }
+        metainfo
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).with_context(|| format!("Invalid magnet link: {}", s))?;
+        if url.scheme() != "magnet" {
+            bail!("Not a magnet link: {}", s);
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    // Other kinds of xt, e.g. btmh for v2, are no use to us
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => debug!("Skipping magnet peer {}", value),
+                },
+                _ => {}
+            }
+        }
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or_else(|| anyhow!("Magnet link has no btih: {}", s))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+// 40 hex digits, or 32 base32 characters in older links
+fn parse_btih(hash: &str) -> Result<[u8; 20], Error> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32_decode(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid info hash in magnet link: {}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_magnet_link() {
+        let link: MagnetLink = "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce&tr=udp%3A%2F%2Ftracker.example%3A6969&x.pe=127.0.0.1:6881&x.pe=peer.example:6881"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hex::encode(link.info_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert_eq!(link.name.as_deref(), Some("sample.txt"));
+        assert_eq!(
+            link.trackers,
+            vec![
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://tracker.example:6969"
+            ]
+        );
+        // Hostnames would need resolving, so only literal addresses are kept
+        assert_eq!(link.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        let metainfo = link.metainfo(Info::default());
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some(link.trackers[0].as_str())
+        );
+        assert_eq!(metainfo.announce_list.unwrap().len(), 2);
+
+        // The same hash in base32
+        let link: MagnetLink = "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hex::encode(link.info_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert!(link.trackers.is_empty() && link.name.is_none());
+
+        assert!("magnet:?dn=nohash".parse::<MagnetLink>().is_err());
+        assert!("magnet:?xt=urn:btih:d69f91".parse::<MagnetLink>().is_err());
+        assert!(
+            "http://example.com/?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+                .parse::<MagnetLink>()
+                .is_err()
+        );
+    }
+}
@@ -1,9 +1,30 @@
-use bittorrent_starter_rust::decoder::decode_bencoded_value;
-use bittorrent_starter_rust::file::{Info, MetainfoFile};
-use bittorrent_starter_rust::network::{ping_tracker, PeerMessage, PeerStream};
-use clap::{Parser, Subcommand};
+use bittorrent_starter_rust::bitfield::{self, Bitfield};
+use bittorrent_starter_rust::coordinator::{Coordinator, DEFAULT_MAX_ATTEMPTS, DEFAULT_MAX_PEERS};
+use bittorrent_starter_rust::decoder::{decode_bencoded_value, BencodedValue};
+use bittorrent_starter_rust::dht::Dht;
+use bittorrent_starter_rust::file::{
+    base32_encode, format_timestamp, Info, MetainfoFile, PieceWriter,
+};
+use bittorrent_starter_rust::magnet::MagnetLink;
+use bittorrent_starter_rust::network::{
+    AsyncPeerStream, Event, ListenPort, PeerStream, PeerTimeouts, Progress, RetryPolicy,
+    TrackerCache, TrackerConfig, TrackerList, TrackerResponse, TrackerSession,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_PORT, TRACE_TARGET,
+};
+use bittorrent_starter_rust::peer_id::{client_name, PeerId};
+use bittorrent_starter_rust::peer_set::{PeerSet, PeerSource};
+use bittorrent_starter_rust::piece_selector::Strategy;
+use bittorrent_starter_rust::ratelimit::RateLimits;
+use bittorrent_starter_rust::seed::{Listener, SeededTorrent, DEFAULT_MAX_UPLOADS};
+use bittorrent_starter_rust::webseed::WebSeed;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::collections::BTreeSet;
 use std::io::Write;
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
 
 #[derive(Debug, Parser)]
 #[clap(
@@ -15,6 +36,69 @@ use std::{net::SocketAddrV4, path::PathBuf};
 struct Opts {
     #[clap(subcommand)]
     subcmd: SubCommand,
+    #[command(flatten)]
+    tracker: TrackerArgs,
+    // Cap on bytes read from peers, in KiB/s
+    #[arg(long, global = true)]
+    max_download_rate: Option<u64>,
+    // Cap on bytes written to peers, in KiB/s
+    #[arg(long, global = true)]
+    max_upload_rate: Option<u64>,
+    // Log to stderr: -v for progress, -vv for tracker & peer chatter, -vvv for everything.
+    // Only -vv and up show tracker passkeys.
+    #[arg(short = 'v', long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    // Announce & handshake with this 20-byte ID instead of a random one
+    #[arg(long, global = true, env = "BITTORRENT_PEER_ID")]
+    peer_id: Option<PeerId>,
+}
+
+// How to reach trackers, shared by every subcommand that announces
+#[derive(Debug, Args)]
+struct TrackerArgs {
+    // Always announce, even if the cached tracker response is still fresh
+    #[arg(long, global = true)]
+    no_cache: bool,
+    // Trust this PEM certificate for https trackers
+    #[arg(long, global = true)]
+    tracker_ca: Option<PathBuf>,
+    // Send tracker requests through this HTTP proxy
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+    // Don't verify https tracker certificates at all
+    #[arg(long, global = true, visible_alias = "insecure-tls")]
+    insecure_tracker_tls: bool,
+    // Give up on a tracker request after this many seconds
+    #[arg(long, global = true, default_value = "15")]
+    tracker_timeout: u64,
+    // Tries per tracker request, counting the first, for timeouts & 5xx errors
+    #[arg(long, global = true, default_value = "3")]
+    tracker_attempts: u32,
+    // Wait before the first retry in milliseconds, doubled for each one after
+    #[arg(long, global = true, default_value = "250")]
+    tracker_retry_delay: u64,
+    // Announces, counting the first, before giving up on a swarm with no peers
+    #[arg(long, global = true, default_value = "3")]
+    announce_attempts: u32,
+    // Wait before announcing to an empty swarm again in seconds, doubled for each one after
+    #[arg(long, global = true, default_value = "5")]
+    announce_retry_delay: u64,
+    // Port to accept peers on & advertise; 0 picks a free one
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    // Allow --port below 1024
+    #[arg(long, global = true)]
+    allow_privileged_port: bool,
+    // Ask trackers for the old list-of-dicts peer format, for trackers that
+    // get compact=1 wrong
+    #[arg(long, global = true)]
+    no_compact: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum HashFormat {
+    Hex,
+    Base32,
 }
 
 #[derive(Debug, Subcommand)]
@@ -22,19 +106,35 @@ enum SubCommand {
     Decode {
         #[clap(name = "ENCODED_VALUE")]
         encoded_value: String,
+        // Also print how many bytes the top-level value took up
+        #[arg(long)]
+        show_consumed: bool,
     },
     Info {
         #[clap(name = "TORRENT_FILE")]
         torrent_file: PathBuf,
+        #[arg(long, value_enum, default_value_t = HashFormat::Hex)]
+        format: HashFormat,
+        // Ask the swarm how many peers have each piece
+        #[arg(long)]
+        availability: bool,
     },
     Peers {
         #[clap(name = "TORRENT_FILE")]
         torrent_file: PathBuf,
+        // Ask the tracker for this many peers instead of its default
+        #[arg(long)]
+        numwant: Option<u32>,
+    },
+    Scrape {
+        #[clap(name = "TORRENT_FILE")]
+        torrent_file: PathBuf,
     },
     Handshake {
         #[clap(name = "TORRENT_FILE")]
         torrent_file: PathBuf,
-        peer_ip: SocketAddrV4,
+        // e.g. 127.0.0.1:6881 or [::1]:6881
+        peer_ip: SocketAddr,
     },
     #[clap(name = "download_piece")]
     DownloadPiece {
@@ -43,92 +143,632 @@ enum SubCommand {
         torrent_file: PathBuf,
         #[arg(default_value = "0")]
         piece_index: usize,
+        // Announce & print the plan, but don't connect to any peer
+        #[arg(long)]
+        dry_run: bool,
+        // Log every message sent to & received from the peer
+        #[arg(long)]
+        trace: bool,
+        // Skip the tracker & download from this peer, e.g. 127.0.0.1:6881
+        #[arg(long)]
+        peer: Option<SocketAddr>,
+        // Peers to try, one after another, before giving up on the piece
+        #[arg(long, default_value_t = DEFAULT_MAX_ATTEMPTS)]
+        max_attempts: usize,
     },
     Download {
-        #[arg(short = 'o', default_value = "/tmp/test-piece-0")]
-        output: PathBuf,
+        // A file or directory path; the torrent's name is used inside a directory
+        // or, when left out, in the current one
+        #[arg(short = 'o')]
+        output: Option<PathBuf>,
+        #[arg(required_unless_present = "magnet")]
+        torrent_file: Option<PathBuf>,
+        // Download from a magnet link instead, fetching the info dict from peers first
+        #[arg(long, conflicts_with = "torrent_file")]
+        magnet: Option<MagnetLink>,
+        // Only download these file indices, e.g. --files 0,2,5
+        #[arg(long, value_delimiter = ',')]
+        files: Option<Vec<usize>>,
+        // Announce & print the plan, but don't connect to any peer
+        #[arg(long)]
+        dry_run: bool,
+        // Ask the tracker for this many peers instead of its default
+        #[arg(long)]
+        numwant: Option<u32>,
+        // Log every message sent to & received from each peer
+        #[arg(long)]
+        trace: bool,
+        // Skip the tracker & download from this peer only, e.g. 127.0.0.1:6881
+        #[arg(long)]
+        peer: Option<SocketAddr>,
+        // How many peers to download from at once
+        #[arg(long, default_value_t = DEFAULT_MAX_PEERS)]
+        max_peers: usize,
+        // Times a piece is tried before it's left to the web seeds or given up on
+        #[arg(long, default_value_t = DEFAULT_MAX_ATTEMPTS)]
+        max_attempts: usize,
+        // Which piece to ask a peer for next: sequential or rarest-first
+        #[arg(long, default_value = "rarest-first")]
+        strategy: Strategy,
+    },
+    // Print every key of a torrent file, including ones we don't otherwise use
+    Inspect {
+        #[clap(name = "TORRENT_FILE")]
+        torrent_file: PathBuf,
+    },
+    Check {
+        #[clap(name = "TORRENT_FILE")]
+        torrent_file: PathBuf,
+        #[clap(name = "DATA_FILE")]
+        data_file: PathBuf,
+    },
+    // Serve a finished download to peers on --port until Ctrl-C
+    Seed {
+        #[clap(name = "TORRENT_FILE")]
         torrent_file: PathBuf,
+        // The file, or for multi-file torrents the directory holding them
+        #[clap(name = "FILE_PATH")]
+        file_path: PathBuf,
+        // How many peers are unchoked at once
+        #[arg(long, default_value_t = DEFAULT_MAX_UPLOADS)]
+        max_uploads: usize,
     },
+    Create {
+        #[clap(name = "INPUT_FILE")]
+        input_file: PathBuf,
+        #[arg(long, default_value = "262144")]
+        piece_length: i64,
+        #[arg(long)]
+        tracker: String,
+        // Defaults to <INPUT_FILE>.torrent
+        #[arg(short = 'o')]
+        output: Option<PathBuf>,
+    },
+}
+
+// Print a one-line error and exit non-zero instead of panicking
+fn fail(err: anyhow::Error) -> ! {
+    eprintln!("Error: {:#}", err);
+    std::process::exit(1);
+}
+
+// Torrents can be given as a local path, an http(s) URL, or - for stdin
+async fn load_metainfo(torrent_file: &Path) -> anyhow::Result<MetainfoFile> {
+    match torrent_file.to_str() {
+        Some("-") => Ok(MetainfoFile::read_from_reader(std::io::stdin().lock())?),
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            MetainfoFile::fetch(url).await
+        }
+        _ => Ok(MetainfoFile::read_from_file(torrent_file)?),
+    }
+}
+
+// Magnet links carry no info dict, so it's fetched from the first peer that has it:
+// the link's own peers & --peer, else whoever its trackers know of
+async fn load_magnet(
+    magnet: &MagnetLink,
+    args: &TrackerArgs,
+    peer: Option<SocketAddr>,
+) -> anyhow::Result<MetainfoFile> {
+    let mut peers: Vec<SocketAddr> = peer.into_iter().chain(magnet.peers.clone()).collect();
+    if peer.is_none() && !magnet.trackers.is_empty() {
+        let mut trackers = tracker_list(&magnet.metainfo(Info::default()), args);
+        // The size is unknown until the info dict is in, but left must say we're leeching
+        match trackers.announce(magnet.info_hash, 1).await {
+            Ok((response, _)) => peers.extend(response.peers),
+            Err(e) => println!("Peers: Error: {}", e),
+        }
+    }
+
+    for addr in peers {
+        let info_hash = magnet.info_hash;
+        let fetched = tokio::task::spawn_blocking(move || {
+            PeerStream::connect(addr, PeerTimeouts::default())?.fetch_metadata(&info_hash)
+        })
+        .await?;
+        match fetched {
+            Ok(info) => {
+                println!("Metadata: {} from {}", info.name(), addr);
+                return Ok(magnet.metainfo(info));
+            }
+            Err(e) => println!("Metadata from {}: Error: {}", addr, e),
+        }
+    }
+    anyhow::bail!(
+        "No peer sent the metadata for {}",
+        hex::encode(magnet.info_hash)
+    )
+}
+
+fn tracker_list(metainfo: &MetainfoFile, args: &TrackerArgs) -> TrackerList {
+    let config = TrackerConfig {
+        proxy: args.proxy.clone(),
+        ca_cert: args.tracker_ca.clone(),
+        accept_invalid_certs: args.insecure_tracker_tls,
+        timeout: Duration::from_secs(args.tracker_timeout),
+        retry: RetryPolicy {
+            attempts: args.tracker_attempts,
+            base_delay: Duration::from_millis(args.tracker_retry_delay),
+        },
+    };
+    let trackers = TrackerList::from_metainfo(metainfo)
+        .with_config(&config)
+        .unwrap_or_else(|e| fail(e))
+        .with_port(args.port)
+        .with_compact(!args.no_compact)
+        .with_empty_retry(RetryPolicy {
+            attempts: args.announce_attempts,
+            base_delay: Duration::from_secs(args.announce_retry_delay),
+        });
+    if args.no_cache {
+        trackers
+    } else {
+        trackers.with_cache(TrackerCache::new(TrackerCache::default_dir()))
+    }
+}
+
+// How many of the peers have each piece. Peers we can't reach count for nothing.
+fn piece_availability(info: &Info, peers: &[SocketAddr]) -> Vec<usize> {
+    let bitfields: Vec<Bitfield> = peers
+        .iter()
+        .filter_map(
+            |peer| match PeerStream::fetch_bitfield(*peer, &info.info_hash()) {
+                Ok(bitfield) => Some(bitfield),
+                Err(e) => {
+                    log::warn!("No bitfield from {}: {}", peer, e);
+                    None
+                }
+            },
+        )
+        .collect();
+    eprintln!(
+        "Bitfields from {} of {} peers",
+        bitfields.len(),
+        peers.len()
+    );
+    bitfield::availability(&bitfields, info.iter_pieces().count())
+}
+
+fn print_availability(out: &mut impl Write, counts: &[usize]) -> std::io::Result<()> {
+    for (piece_index, count) in counts.iter().enumerate() {
+        writeln!(out, "piece {}: {} peers", piece_index, count)?;
+    }
+    Ok(())
+}
+
+// Only the first value is decoded; anything after it is left for the caller,
+// which --show-consumed helps find in concatenated bencode
+fn print_decoded(
+    out: &mut impl Write,
+    encoded_value: &str,
+    show_consumed: bool,
+) -> anyhow::Result<()> {
+    let (consumed, decoded_value) = decode_bencoded_value(encoded_value)?;
+    writeln!(out, "{}", serde_json::Value::from(decoded_value))?;
+    if show_consumed {
+        writeln!(out, "Consumed: {} bytes", consumed)?;
+    }
+    Ok(())
+}
+
+// The whole bencoded tree as {:#} prints it, with `pieces` shown as a count of hashes
+fn print_inspected(out: &mut impl Write, value: BencodedValue) -> std::io::Result<()> {
+    writeln!(out, "{:#}", summarize_pieces(value))
+}
+
+fn summarize_pieces(value: BencodedValue) -> BencodedValue {
+    match value {
+        BencodedValue::List(items) => {
+            BencodedValue::List(items.into_iter().map(summarize_pieces).collect())
+        }
+        BencodedValue::Dict(dict) => BencodedValue::Dict(
+            dict.into_iter()
+                .map(|(key, value)| match value {
+                    BencodedValue::String(pieces) if key.0 == b"pieces" => {
+                        let summary = format!("<{} piece hashes>", pieces.len() / 20);
+                        (key, BencodedValue::String(summary.into()))
+                    }
+                    value => (key, summarize_pieces(value)),
+                })
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+// The check command: a line per piece, then the exit code, 1 if any piece failed
+async fn check(out: &mut impl Write, torrent_file: &Path, data_file: &Path) -> anyhow::Result<i32> {
+    let metainfo = load_metainfo(torrent_file).await?;
+    let results = metainfo.info.verify_file(data_file)?;
+    for (piece_index, ok) in results.iter().enumerate() {
+        writeln!(
+            out,
+            "Piece {}: {}",
+            piece_index,
+            if *ok { "OK" } else { "FAIL" }
+        )?;
+    }
+    let failed = results.iter().filter(|ok| !**ok).count();
+    if failed > 0 {
+        writeln!(out, "{} of {} pieces failed.", failed, results.len())?;
+        return Ok(1);
+    }
+    writeln!(out, "All {} pieces OK.", results.len())?;
+    Ok(0)
+}
+
+// The `peers` output on stdout; anything else goes to stderr
+fn print_peers(out: &mut impl Write, tracker_response: &TrackerResponse) -> std::io::Result<()> {
+    if let Some(complete) = tracker_response.complete {
+        writeln!(out, "Seeders: {}", complete)?;
+    }
+    if let Some(incomplete) = tracker_response.incomplete {
+        writeln!(out, "Leechers: {}", incomplete)?;
+    }
+    writeln!(out, "Peers:")?;
+    for peer in &tracker_response.peers {
+        writeln!(out, "{}", peer)?;
+    }
+    Ok(())
+}
+
+// What a download is about to do: who answered, who to ask, & for what
+struct DownloadPlan {
+    tracker: String,
+    tracker_response: TrackerResponse,
+    // Tracker, or Manual for --peer
+    source: PeerSource,
+    // (piece index, piece length)
+    pieces: Vec<(usize, i64)>,
+}
+
+impl DownloadPlan {
+    fn print(&self) {
+        println!("Tracker: {}", self.tracker);
+        println!("Peers:");
+        self.tracker_response
+            .peers
+            .iter()
+            .for_each(|peer| println!("{}", peer));
+        let total: i64 = self.pieces.iter().map(|(_, length)| length).sum();
+        println!("Pieces: {} ({} bytes)", self.pieces.len(), total);
+        self.pieces.iter().for_each(|(piece_index, length)| {
+            println!("Piece {}: {} bytes", piece_index, length);
+        });
+    }
+}
+
+// Announce only; no peer is contacted until the plan is carried out. Given a
+// `peer`, the trackers aren't asked at all & that peer is the only one.
+async fn plan_download(
+    trackers: &mut TrackerList,
+    info: &Info,
+    piece_indices: &BTreeSet<usize>,
+    event: Event,
+    peer: Option<SocketAddr>,
+) -> anyhow::Result<DownloadPlan> {
+    let (tracker_response, tracker, source) = match peer {
+        Some(peer) => (
+            TrackerResponse {
+                peers: vec![peer],
+                ..Default::default()
+            },
+            "none (--peer)".to_string(),
+            PeerSource::Manual,
+        ),
+        None => {
+            let (tracker_response, tracker) = trackers
+                .announce_for_peers(
+                    info.info_hash(),
+                    Progress::starting(info.total_length()),
+                    event,
+                )
+                .await?;
+            (tracker_response, tracker, PeerSource::Tracker)
+        }
+    };
+    Ok(DownloadPlan {
+        tracker,
+        tracker_response,
+        source,
+        pieces: info
+            .iter_pieces()
+            .filter(|(piece_index, _, _)| piece_indices.contains(piece_index))
+            .map(|(piece_index, _, length)| (piece_index, length))
+            .collect(),
+    })
+}
+
+// Fetch, verify & write out the given pieces from several peers at once. Peers the
+// tracker session finds later join in, & the web seeds are tried for whatever no
+// peer could supply.
+#[allow(clippy::too_many_arguments)]
+async fn download_pieces(
+    info: &Info,
+    mut peers: PeerSet,
+    mut new_peers: UnboundedReceiver<SocketAddr>,
+    url_list: Vec<String>,
+    progress: Arc<Mutex<Progress>>,
+    piece_indices: &BTreeSet<usize>,
+    coordinator: Coordinator,
+    mut writer: PieceWriter,
+) -> anyhow::Result<()> {
+    let n_pieces = info.iter_pieces().count();
+    let left = coordinator
+        .download(
+            &mut peers,
+            &mut new_peers,
+            piece_indices,
+            |piece_index, piece| {
+                println!(
+                    "Downloaded piece {}/{} (length {})",
+                    piece_index + 1,
+                    n_pieces,
+                    piece.len(),
+                );
+                writer.write_piece(piece_index, &piece)?;
+                record_download(&progress, piece.len());
+                Ok(())
+            },
+        )
+        .await?;
+
+    let info = info.clone();
+    tokio::task::spawn_blocking(move || {
+        // Built here, as a blocking client can't live on the async runtime
+        let web_seeds: Vec<WebSeed> = url_list
+            .iter()
+            .filter_map(|url| {
+                WebSeed::new(url)
+                    .inspect_err(|e| log::warn!("Skipping web seed: {}", e))
+                    .ok()
+            })
+            .collect();
+        save_pieces(
+            &info,
+            &left,
+            &progress,
+            |piece_index| fetch_from_web_seeds(&info, &web_seeds, piece_index),
+            |piece_index, piece| writer.write_piece(piece_index, piece),
+        )?;
+        Ok(writer.finish()?)
+    })
+    .await?
+}
+
+// The first web seed to come up with a verified piece
+fn fetch_from_web_seeds(
+    info: &Info,
+    web_seeds: &[WebSeed],
+    piece_index: usize,
+) -> anyhow::Result<Vec<u8>> {
+    for seed in web_seeds {
+        match seed.fetch_piece(info, piece_index) {
+            Ok(piece) => return Ok(piece),
+            Err(e) => log::warn!("Web seed {} failed: {:#}", seed.url(), e),
+        }
+    }
+    anyhow::bail!("No reachable peers left")
+}
+
+// Fetch, verify & write out one piece at a time, so memory is bounded by a single
+// piece no matter how large the torrent is
+fn save_pieces(
+    info: &Info,
+    piece_indices: &BTreeSet<usize>,
+    progress: &Mutex<Progress>,
+    mut fetch: impl FnMut(usize) -> anyhow::Result<Vec<u8>>,
+    mut write: impl FnMut(usize, &[u8]) -> std::io::Result<()>,
+) -> anyhow::Result<()> {
+    let n_pieces = info.iter_pieces().count();
+    for &piece_index in piece_indices {
+        println!(
+            "Downloading piece {}/{} (length {})",
+            piece_index + 1,
+            n_pieces,
+            info.piece_len(piece_index),
+        );
+        let payload = fetch(piece_index)?;
+        if !info.verify_piece(piece_index, &payload) {
+            anyhow::bail!("Downloaded piece {} failed verification.", piece_index);
+        }
+        write(piece_index, &payload)?;
+        record_download(progress, payload.len());
+    }
+    Ok(())
+}
+
+fn record_download(progress: &Mutex<Progress>, bytes: usize) {
+    let mut progress = progress.lock().unwrap();
+    progress.downloaded += bytes as u64;
+    progress.left = progress.left.saturating_sub(bytes as u64);
 }
 
 #[tokio::main]
 async fn main() {
     let opts: Opts = Opts::parse();
+    // Warnings only by default; RUST_LOG still applies on top
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(match opts.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+    if let SubCommand::DownloadPiece { trace: true, .. }
+    | SubCommand::Download { trace: true, .. } = opts.subcmd
+    {
+        logger
+            .filter_module(TRACE_TARGET, log::LevelFilter::Info)
+            .format_timestamp_millis();
+    }
+    logger.parse_default_env().init();
+    if let Some(peer_id) = opts.peer_id {
+        PeerId::set_local(peer_id).unwrap_or_else(|e| fail(e));
+    }
     let command = opts.subcmd;
+    let mut tracker_args = opts.tracker;
+    // Held for the whole run, so an ephemeral port stays ours
+    let listen_port = ListenPort::new(tracker_args.port, tracker_args.allow_privileged_port)
+        .unwrap_or_else(|e| fail(e));
+    tracker_args.port = listen_port.port();
+    let rate_limits = RateLimits::from_kib(opts.max_download_rate, opts.max_upload_rate);
     // You can use print statements as follows for debugging, they'll be visible when running tests.
     // println!("Logs from your program will appear here!");
 
     match command {
         // Usage: your_bittorrent.sh decode "<encoded_value>"
-        SubCommand::Decode { encoded_value } => {
-            let (_, decoded_value) = decode_bencoded_value(encoded_value);
-            let json_value = serde_json::Value::from(decoded_value);
-            println!("{}", json_value);
+        SubCommand::Decode {
+            encoded_value,
+            show_consumed,
+        } => {
+            print_decoded(&mut std::io::stdout(), &encoded_value, show_consumed)
+                .unwrap_or_else(|e| fail(e));
         }
         // Usage: your_bittorrent.sh info "<torrent_file>"
-        SubCommand::Info { torrent_file } => {
-            let metainfo = MetainfoFile::read_from_file(torrent_file).unwrap();
+        SubCommand::Info {
+            torrent_file,
+            format,
+            availability,
+        } => {
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+            // Built up front, as the info dict is moved out below
+            let trackers = availability.then(|| tracker_list(&metainfo, &tracker_args));
 
             // Print out the info dict
             let info: Info = metainfo.info;
-            println!("Tracker URL: {}", metainfo.announce);
-            println!("Length: {}", info.length);
+            match &metainfo.announce {
+                Some(announce) => println!("Tracker URL: {}", announce),
+                None => println!("Tracker URL: none (trackerless)"),
+            }
+            println!("Length: {}", info.total_length());
 
             // Hash the info dict
-            println!("Info Hash: {}", hex::encode(info.info_hash()));
-            println!("Piece Length: {}", info.piece_length);
+            let info_hash = match format {
+                HashFormat::Hex => hex::encode(info.info_hash()),
+                HashFormat::Base32 => base32_encode(&info.info_hash()),
+            };
+            println!("Info Hash: {}", info_hash);
+            println!("Piece Length: {}", info.piece_length());
+            if let Some(creation_date) = metainfo.creation_date {
+                println!("Creation Date: {}", format_timestamp(creation_date));
+            }
+            if let Some(created_by) = &metainfo.created_by {
+                println!("Created By: {}", created_by);
+            }
+            if let Some(comment) = &metainfo.comment {
+                println!("Comment: {}", comment);
+            }
+            if let Some(encoding) = &metainfo.encoding {
+                println!("Encoding: {}", encoding);
+            }
             let piece_hashes: Vec<String> = info.piece_hash();
             // Print piece hashes on new line
             println!("Pieces Hashes:\n{}", piece_hashes.join("\n"));
+
+            if let Some(mut trackers) = trackers {
+                let (tracker_response, _) = trackers
+                    .announce(info.info_hash(), info.total_length())
+                    .await
+                    .unwrap_or_else(|e| fail(e));
+                let counts = tokio::task::spawn_blocking(move || {
+                    piece_availability(&info, &tracker_response.peers)
+                })
+                .await
+                .unwrap_or_else(|e| fail(e.into()));
+                print_availability(&mut std::io::stdout(), &counts)
+                    .unwrap_or_else(|e| fail(e.into()));
+            }
         }
         // Usage: your_bittorrent.sh peers "<torrent_file>"
-        SubCommand::Peers { torrent_file } => {
-            let metainfo = MetainfoFile::read_from_file(torrent_file).unwrap();
+        SubCommand::Peers {
+            torrent_file,
+            numwant,
+        } => {
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+            let mut trackers = tracker_list(&metainfo, &tracker_args).with_numwant(numwant);
 
-            match ping_tracker(
-                metainfo.announce.as_str(),
-                metainfo.info.info_hash(),
-                metainfo.info.length,
-            )
-            .await
+            // Trackerless torrents find their peers over the DHT
+            if trackers.tiers().is_empty() {
+                let bootstrap = metainfo.dht_bootstrap();
+                let bootstrap: Vec<&str> = bootstrap.iter().map(String::as_str).collect();
+                let mut dht = Dht::bind("0.0.0.0:0").await.unwrap_or_else(|e| fail(e));
+                let peers = dht
+                    .get_peers(metainfo.info.info_hash(), &bootstrap)
+                    .await
+                    .unwrap_or_else(|e| fail(e));
+                println!("Peers:");
+                peers.iter().for_each(|peer| println!("{}", peer));
+                return;
+            }
+
+            match trackers
+                .announce(metainfo.info.info_hash(), metainfo.info.total_length())
+                .await
             {
-                Ok(tracker_response) => {
-                    println!("Peers:");
-                    tracker_response.peers.iter().for_each(|peer| {
-                        println!("{}", peer);
-                    });
+                Ok((tracker_response, _)) => {
+                    if let Some(warning) = &tracker_response.warning {
+                        eprintln!("Tracker warning: {}", warning);
+                    }
+                    print_peers(&mut std::io::stdout(), &tracker_response)
+                        .unwrap_or_else(|e| fail(e.into()));
                 }
                 Err(e) => {
                     println!("Peers: Error: {}", e);
                 }
             }
         }
+        // Usage: your_bittorrent.sh scrape "<torrent_file>"
+        SubCommand::Scrape { torrent_file } => {
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+
+            let (scrape, tracker) = tracker_list(&metainfo, &tracker_args)
+                .scrape(metainfo.info.info_hash())
+                .await
+                .unwrap_or_else(|e| fail(e));
+            println!("Tracker: {}", tracker);
+            println!("Seeders: {}", scrape.complete);
+            println!("Leechers: {}", scrape.incomplete);
+            println!("Completed: {}", scrape.downloaded);
+        }
         // Usage: your_bittorrent.sh handshake "<torrent_file>"
         SubCommand::Handshake {
             torrent_file,
             peer_ip,
         } => {
-            let metainfo = MetainfoFile::read_from_file(torrent_file).unwrap();
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
 
-            let peers = match ping_tracker(
-                metainfo.announce.as_str(),
-                metainfo.info.info_hash(),
-                metainfo.info.length,
-            )
-            .await
+            let peers = match tracker_list(&metainfo, &tracker_args)
+                .announce_for_peers(
+                    metainfo.info.info_hash(),
+                    Progress::starting(metainfo.info.total_length()),
+                    Event::None,
+                )
+                .await
             {
-                Ok(tracker_response) => tracker_response.peers,
+                Ok((tracker_response, _)) => tracker_response.peers,
                 Err(e) => {
                     println!("Peers: Error: {}", e);
                     return;
                 }
             };
-            // Check that peer_ip is in peers
-            assert!(peers.contains(&peer_ip), "Peer IP not in peers.");
+            if !peers.contains(&peer_ip) {
+                fail(anyhow::anyhow!("Peer IP not in peers: {}", peer_ip));
+            }
 
-            let mut peer_stream = PeerStream::new(peer_ip);
+            let mut peer_stream = AsyncPeerStream::connect(peer_ip, DEFAULT_CONNECT_TIMEOUT)
+                .await
+                .unwrap_or_else(|e| fail(e));
 
-            match peer_stream.handshake(&metainfo.info.info_hash()) {
+            match peer_stream.handshake(&metainfo.info.info_hash()).await {
                 Ok(handshake) => {
                     println!("Handshake: {:?}", handshake);
                     let hex_peer_id = handshake
@@ -137,10 +777,13 @@ async fn main() {
                         .map(|b| format!("{:02x}", b))
                         .collect::<String>();
                     println!("Peer ID: {}", hex_peer_id);
+                    // On its own line, so the Peer ID line stays as it was
+                    if let Some(client) = client_name(&handshake.peer_id) {
+                        println!("Client: {}", client);
+                    }
                 }
-                Err(e) => {
-                    println!("Handshake: Error: {}", e);
-                }
+                // A peer on the wrong torrent or protocol is a failed handshake
+                Err(e) => fail(e.context(format!("Handshake with {} failed", peer_ip))),
             }
         }
         // Usage: your_bittorrent.sh download_piece -o /tmp/test-piece-0 "<torrent_file>" <piece_index>
@@ -148,181 +791,801 @@ async fn main() {
             output,
             torrent_file,
             piece_index,
+            dry_run,
+            trace,
+            peer,
+            max_attempts,
         } => {
-            // Prepare the peer stream
-            let metainfo = MetainfoFile::read_from_file(torrent_file).unwrap();
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+            let mut trackers = tracker_list(&metainfo, &tracker_args);
             let info: Info = metainfo.info;
 
-            let peers =
-                match ping_tracker(metainfo.announce.as_str(), info.info_hash(), info.length).await
-                {
-                    Ok(tracker_response) => tracker_response.peers,
-                    Err(e) => {
-                        println!("Peers: Error: {}", e);
-                        return;
-                    }
-                };
-            let peer = peers.first().unwrap();
-            let mut peer_stream = PeerStream::new(*peer);
-
-            match peer_stream.prep_download(&info.info_hash()) {
-                Ok(prepped) => {
-                    println!("Prepped: {:?}", prepped);
-                }
+            let plan = match plan_download(
+                &mut trackers,
+                &info,
+                &BTreeSet::from([piece_index]),
+                Event::None,
+                peer,
+            )
+            .await
+            {
+                Ok(plan) => plan,
                 Err(e) => {
-                    println!("Prepped: Error: {}", e);
+                    println!("Peers: Error: {}", e);
+                    return;
                 }
+            };
+            if dry_run {
+                plan.print();
+                return;
             }
+            let mut peers = PeerSet::new();
+            peers.extend(plan.tracker_response.peers, plan.source);
 
-            // Chunk pieces into 16 * 1024 byte chunks with index
-            // then download each chunk
-            let piece_hashes = info.piece_hash();
-            let piece_length = if piece_index == piece_hashes.len() - 1 {
-                info.length - (piece_index as i64 * info.piece_length)
-            } else {
-                info.piece_length
-            };
             println!(
                 "Downloading piece {}/{} (length {})",
                 piece_index + 1,
-                piece_hashes.len(),
-                piece_length,
-            );
-            let downloads = peer_stream
-                .download_piece(piece_index as u32, &piece_length)
-                .unwrap();
-            // Zip the downloads with the piece hashes & map to download::save_piece into /tmp/test-piece-{idx}
-            let downloaded_payload: Vec<u8> =
-                downloads
-                    .iter()
-                    .enumerate()
-                    .fold(vec![], |mut acc, (_index, download)| {
-                        match download {
-                            PeerMessage::Piece {
-                                index: _,
-                                begin: _,
-                                block,
-                            } => {
-                                acc.extend_from_slice(block);
-                            }
-                            _ => {
-                                panic!("Expected Piece message, got {:?}", download);
-                            }
-                        }
-                        acc
-                    });
-            assert_eq!(
-                downloaded_payload.len(),
-                piece_length as usize,
-                "Downloaded payload length {} does not match expected length {}.",
-                downloaded_payload.len(),
-                piece_length
+                info.iter_pieces().count(),
+                info.piece_len(piece_index),
             );
-            let verified = info.verify_piece(piece_index, &downloaded_payload);
-            if verified {
-                // Save the piece to /tmp/test-piece-{idx}
-                std::fs::write(&output, downloaded_payload).unwrap();
-                let output_str = output.to_str().unwrap();
-                println!("Piece {} downloaded to {}.", piece_index, output_str);
-            } else {
-                panic!("Downloaded piece failed verification.");
-            }
+            // A peer that's down or sends a bad piece just means trying the next one
+            let piece = Coordinator::new(info)
+                .with_max_attempts(max_attempts)
+                .with_rate_limits(rate_limits)
+                .with_trace(trace)
+                .download_piece(&mut peers, piece_index)
+                .await
+                .unwrap_or_else(|e| fail(e.into()));
+            // Save the piece to /tmp/test-piece-{idx}
+            std::fs::write(&output, piece).unwrap_or_else(|e| fail(e.into()));
+            println!("Piece {} downloaded to {}.", piece_index, output.display());
         }
         SubCommand::Download {
             output,
             torrent_file,
+            magnet,
+            files,
+            dry_run,
+            numwant,
+            trace,
+            peer,
+            max_peers,
+            max_attempts,
+            strategy,
         } => {
-            let metainfo = MetainfoFile::read_from_file(torrent_file).unwrap();
+            let metainfo = match (magnet, torrent_file) {
+                (Some(magnet), _) => load_magnet(&magnet, &tracker_args, peer).await,
+                (None, Some(torrent_file)) => load_metainfo(&torrent_file).await,
+                (None, None) => unreachable!("clap requires a torrent file or --magnet"),
+            }
+            .unwrap_or_else(|e| fail(e));
+            let mut trackers = tracker_list(&metainfo, &tracker_args).with_numwant(numwant);
+            let url_list = metainfo.url_list.unwrap_or_default();
             let info: Info = metainfo.info;
 
-            let peers =
-                match ping_tracker(metainfo.announce.as_str(), info.info_hash(), info.length).await
-                {
-                    Ok(tracker_response) => tracker_response.peers,
-                    Err(e) => {
-                        println!("Peers: Error: {}", e);
-                        return;
+            // Only fetch the pieces that overlap the requested files
+            let piece_indices: BTreeSet<usize> = match &files {
+                Some(files) => {
+                    let n_files = info.file_entries().len();
+                    if let Some(bad) = files.iter().find(|&&file_index| file_index >= n_files) {
+                        fail(anyhow::anyhow!(
+                            "File index {} out of range, torrent has {} files",
+                            bad,
+                            n_files
+                        ));
                     }
-                };
-            let peer = peers.first().unwrap();
-            let mut peer_stream = PeerStream::new(*peer);
-
-            match peer_stream.prep_download(&info.info_hash()) {
-                Ok(prepped) => {
-                    println!("Prepped: {:?}", prepped);
+                    info.pieces_for_files(files)
                 }
+                None => info.iter_pieces().map(|(index, _, _)| index).collect(),
+            };
+
+            let event = if dry_run { Event::None } else { Event::Started };
+            let plan = match plan_download(&mut trackers, &info, &piece_indices, event, peer).await
+            {
+                Ok(plan) => plan,
                 Err(e) => {
-                    println!("Prepped: Error: {}", e);
+                    println!("Peers: Error: {}", e);
+                    return;
                 }
+            };
+            if dry_run {
+                plan.print();
+                return;
             }
 
-            // Download all the pieces
-            let all_downloads: Vec<Vec<PeerMessage>> = (0..info.piece_hash().len())
-                .map(|piece_index| {
-                    let piece_hashes = info.piece_hash();
-                    let piece_length = if piece_index == piece_hashes.len() - 1 {
-                        info.length - (piece_index as i64 * info.piece_length)
-                    } else {
-                        info.piece_length
-                    };
-                    println!(
-                        "Downloading piece {}/{} (length {})",
-                        piece_index + 1,
-                        piece_hashes.len(),
-                        piece_length,
-                    );
-                    let downloads = peer_stream
-                        .download_piece(piece_index as u32, &piece_length)
-                        .unwrap();
-                    downloads
+            // Pieces go to disk as soon as they're verified
+            let output = info.output_path(output.as_deref());
+            let mut writer = info
+                .piece_writer(&output, files.as_deref())
+                .unwrap_or_else(|e| fail(e.into()));
+            // Whatever an interrupted run left in the .part files needn't be fetched again
+            let n_wanted = piece_indices.len();
+            let piece_indices: BTreeSet<usize> = piece_indices
+                .into_iter()
+                .filter(|&piece_index| {
+                    !writer
+                        .read_piece(piece_index)
+                        .is_ok_and(|piece| info.verify_piece(piece_index, &piece))
                 })
                 .collect();
+            if piece_indices.len() < n_wanted {
+                println!(
+                    "Resuming: {} of {} pieces already downloaded",
+                    n_wanted - piece_indices.len(),
+                    n_wanted
+                );
+            }
 
-            // Combine the downloads into a single payload
-            let downloaded_payloads: Vec<Vec<u8>> = all_downloads
-                .iter()
-                .map(|downloads| {
-                    downloads
-                        .iter()
-                        .enumerate()
-                        .fold(vec![], |mut acc, (_index, download)| {
-                            match download {
-                                PeerMessage::Piece {
-                                    index: _,
-                                    begin: _,
-                                    block,
-                                } => {
-                                    acc.extend_from_slice(block);
-                                }
-                                _ => {
-                                    panic!("Expected Piece message, got {:?}", download);
-                                }
-                            }
-                            acc
-                        })
-                })
-                .collect();
+            // Keep the peer list fresh for as long as the download runs, unless
+            // there's no tracker in the picture
+            let progress = Progress::starting(info.total_length());
+            let (session, new_peers, progress) = match peer {
+                Some(_) => {
+                    let (_, new_peers) = tokio::sync::mpsc::unbounded_channel();
+                    (None, new_peers, Arc::new(Mutex::new(progress)))
+                }
+                None => {
+                    let (session, new_peers) = TrackerSession::spawn(
+                        trackers,
+                        info.info_hash(),
+                        progress,
+                        &plan.tracker_response,
+                    );
+                    let progress = session.progress();
+                    (Some(session), new_peers, progress)
+                }
+            };
 
-            // Verify the payload
-            downloaded_payloads
-                .iter()
-                .enumerate()
-                .all(
-                    |(piece_index, payload)| match info.verify_piece(piece_index, payload) {
-                        true => true,
-                        false => {
-                            println!("Piece {} failed verification.", piece_index);
-                            panic!("Downloaded piece {} failed verification.", piece_index);
-                        }
-                    },
-                );
+            let mut peers = PeerSet::new();
+            peers.extend(plan.tracker_response.peers, plan.source);
+            let coordinator = Coordinator::new(info.clone())
+                .with_max_peers(max_peers)
+                .with_max_attempts(max_attempts)
+                .with_rate_limits(rate_limits)
+                .with_trace(trace)
+                .with_strategy(strategy);
+            let download = download_pieces(
+                &info,
+                peers,
+                new_peers,
+                url_list,
+                progress,
+                &piece_indices,
+                coordinator,
+                writer,
+            );
+            let saved = tokio::select! {
+                result = download => result,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Interrupted")),
+            };
+
+            let event = match &saved {
+                Ok(()) => Event::Completed,
+                Err(_) => Event::Stopped,
+            };
+            if let Some(session) = session {
+                if let Err(e) = session.finish(event).await {
+                    println!("Announce {:?}: Error: {}", event, e);
+                }
+            }
+            if let Err(e) = saved {
+                fail(e);
+            }
+            println!("Downloaded file saved to {}.", output.display());
+        }
+        // Usage: your_bittorrent.sh check "<torrent_file>" "<data_file>"
+        SubCommand::Check {
+            torrent_file,
+            data_file,
+        } => {
+            let code = check(&mut std::io::stdout(), &torrent_file, &data_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        // Usage: your_bittorrent.sh seed "<torrent_file>" "<file_path>"
+        SubCommand::Seed {
+            torrent_file,
+            file_path,
+            max_uploads,
+        } => {
+            let metainfo = load_metainfo(&torrent_file)
+                .await
+                .unwrap_or_else(|e| fail(e));
+            let info_hash = metainfo.info.info_hash();
+            let torrent = SeededTorrent::new(metainfo.info.clone(), &file_path)
+                .unwrap_or_else(|e| fail(e.into()));
+            let n_pieces = metainfo.info.iter_pieces().count();
+            println!(
+                "Serving {} of {} pieces",
+                torrent.have().count_ones(),
+                n_pieces
+            );
+            let listener = match listen_port {
+                ListenPort::Bound(listener) => Listener::from_std(listener),
+                ListenPort::Fixed(port) => {
+                    Listener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await
+                }
+            }
+            .unwrap_or_else(|e| fail(e.into()))
+            .with_torrent(torrent)
+            .with_max_uploads(max_uploads)
+            .with_rate_limits(rate_limits);
+            println!("Listening on port {}", tracker_args.port);
+
+            // Announced as a seed, so the tracker sends leechers our way
+            let mut trackers = tracker_list(&metainfo, &tracker_args);
+            let session = match trackers
+                .announce_event(info_hash, Progress::default(), Event::Started)
+                .await
+            {
+                Ok((tracker_response, _)) => {
+                    let (session, _) = TrackerSession::spawn(
+                        trackers,
+                        info_hash,
+                        Progress::default(),
+                        &tracker_response,
+                    );
+                    Some(session)
+                }
+                Err(e) => {
+                    println!("Announce: Error: {}", e);
+                    None
+                }
+            };
 
-            // Combine all the payload & save to output
-            let mut output_file = std::fs::File::create(&output).unwrap();
-            downloaded_payloads.iter().for_each(|payload| {
-                output_file.write_all(payload).unwrap();
+            let served = tokio::select! {
+                served = listener.run() => served,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            };
+            if let Some(session) = session {
+                if let Err(e) = session.stop().await {
+                    println!("Announce Stopped: Error: {}", e);
+                }
+            }
+            served.unwrap_or_else(|e| fail(e));
+        }
+        // Usage: your_bittorrent.sh inspect "<torrent_file>"
+        SubCommand::Inspect { torrent_file } => {
+            let bytes = std::fs::read(&torrent_file).unwrap_or_else(|e| {
+                fail(anyhow::anyhow!(
+                    "Could not read {}: {}",
+                    torrent_file.display(),
+                    e
+                ))
+            });
+            let (_, value) = decode_bencoded_value(&bytes).unwrap_or_else(|e| fail(e.into()));
+            print_inspected(&mut std::io::stdout(), value).unwrap_or_else(|e| fail(e.into()));
+        }
+        // Usage: your_bittorrent.sh create --tracker <url> [--piece-length <n>] [-o <out>] "<input_file>"
+        SubCommand::Create {
+            input_file,
+            piece_length,
+            tracker,
+            output,
+        } => {
+            let info = Info::from_file(&input_file, piece_length).unwrap_or_else(|e| fail(e));
+            let output = output.unwrap_or_else(|| {
+                let mut name = input_file.clone().into_os_string();
+                name.push(".torrent");
+                PathBuf::from(name)
             });
-            println!("Downloaded file saved to {}.", output.to_str().unwrap());
+            let metainfo = MetainfoFile::new(tracker, info);
+            metainfo
+                .write_to_file(&output)
+                .unwrap_or_else(|e| fail(e.into()));
+            println!("Info Hash: {}", hex::encode(metainfo.info.info_hash()));
+            println!("Torrent saved to {}.", output.display());
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "testsupport.rs"]
+#[allow(dead_code)]
+mod testsupport;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::{
+        info_for, serve_blocks, serve_once, spawn_handshaking_peer, write_message,
+    };
+    use bittorrent_starter_rust::network::{
+        AnnounceRequest, BoxFuture, ScrapeResult, Tracker, TrackerError,
+    };
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_dry_run_does_not_connect_to_peers() {
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        peer_listener.set_nonblocking(true).unwrap();
+        let peer_port = peer_listener.local_addr().unwrap().port();
+
+        // A tracker that hands out the listener above as its only peer
+        let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker_url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = tracker.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+            body.extend([127, 0, 0, 1]);
+            body.extend(peer_port.to_be_bytes());
+            body.push(b'e');
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        let info = Info::single_file("sample.txt".to_string(), 40, 16, vec![0x80; 60]);
+        let metainfo = MetainfoFile::new(tracker_url.clone(), info);
+        let tracker_args = TrackerArgs {
+            no_cache: true,
+            proxy: None,
+            tracker_ca: None,
+            insecure_tracker_tls: false,
+            tracker_timeout: 15,
+            tracker_attempts: 1,
+            tracker_retry_delay: 250,
+            announce_attempts: 1,
+            announce_retry_delay: 5,
+            port: DEFAULT_PORT,
+            allow_privileged_port: false,
+            no_compact: false,
+        };
+        let mut trackers = tracker_list(&metainfo, &tracker_args);
+        let plan = plan_download(
+            &mut trackers,
+            &metainfo.info,
+            &BTreeSet::from([0, 2]),
+            Event::None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(plan.tracker, tracker_url);
+        assert_eq!(
+            plan.tracker_response.peers,
+            vec![SocketAddr::from(([127, 0, 0, 1], peer_port))]
+        );
+        assert_eq!(plan.pieces, vec![(0, 16), (2, 8)]);
+        let accepted = peer_listener.accept();
+        assert_eq!(
+            accepted.unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock,
+            "dry run must not connect to peers"
+        );
+    }
+
+    // Hands out a fixed peer list without touching the network
+    struct OfflineTracker(Vec<SocketAddr>);
+
+    impl Tracker for OfflineTracker {
+        fn url(&self) -> &str {
+            "offline://tracker"
+        }
+
+        fn announce<'a>(
+            &'a self,
+            request: &'a AnnounceRequest,
+        ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+            assert_eq!(request.event, Event::Started);
+            let peers = self.0.clone();
+            Box::pin(async move {
+                Ok(TrackerResponse {
+                    interval: 60,
+                    peers,
+                    ..Default::default()
+                })
+            })
+        }
+
+        fn scrape<'a>(
+            &'a self,
+            _info_hashes: &'a [[u8; 20]],
+        ) -> BoxFuture<'a, anyhow::Result<HashMap<[u8; 20], ScrapeResult>>> {
+            Box::pin(async { Ok(HashMap::new()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_download_with_mock_tracker() {
+        let peer: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+        let mut trackers =
+            TrackerList::from_trackers(vec![vec![Box::new(OfflineTracker(vec![peer]))]]);
+        let info = Info::single_file("sample.txt".to_string(), 40, 16, vec![0x80; 60]);
+        let plan = plan_download(
+            &mut trackers,
+            &info,
+            &BTreeSet::from([1]),
+            Event::Started,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(plan.tracker, "offline://tracker");
+        assert_eq!(plan.tracker_response.peers, vec![peer]);
+        assert_eq!(plan.pieces, vec![(1, 16)]);
+    }
+
+    // Answers every announce with no peers, noting the event each carried
+    struct EmptyTracker(Arc<Mutex<Vec<Event>>>);
+
+    impl Tracker for EmptyTracker {
+        fn url(&self) -> &str {
+            "empty://tracker"
+        }
+
+        fn announce<'a>(
+            &'a self,
+            request: &'a AnnounceRequest,
+        ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+            self.0.lock().unwrap().push(request.event);
+            Box::pin(async {
+                Ok(TrackerResponse {
+                    interval: 60,
+                    ..Default::default()
+                })
+            })
+        }
+
+        fn scrape<'a>(
+            &'a self,
+            _info_hashes: &'a [[u8; 20]],
+        ) -> BoxFuture<'a, anyhow::Result<HashMap<[u8; 20], ScrapeResult>>> {
+            Box::pin(async { Ok(HashMap::new()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_download_with_empty_swarm() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut trackers =
+            TrackerList::from_trackers(vec![vec![Box::new(EmptyTracker(events.clone()))]])
+                .with_empty_retry(RetryPolicy {
+                    attempts: 3,
+                    base_delay: Duration::ZERO,
+                });
+        let info = Info::single_file("sample.txt".to_string(), 40, 16, vec![0x80; 60]);
+        let err = plan_download(
+            &mut trackers,
+            &info,
+            &BTreeSet::from([1]),
+            Event::Started,
+            None,
+        )
+        .await
+        .err()
+        .expect("no peers is an error");
+        assert!(
+            matches!(err.downcast_ref(), Some(TrackerError::EmptySwarm(3))),
+            "{}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "tracker has no peers for this torrent after 3 announces"
+        );
+        // Started once, then plain re-announces
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![Event::Started, Event::None, Event::None]
+        );
+    }
+
+    // Fails the test if anything is announced to it
+    struct UnusedTracker;
+
+    impl Tracker for UnusedTracker {
+        fn url(&self) -> &str {
+            "unused://tracker"
+        }
+
+        fn announce<'a>(
+            &'a self,
+            _request: &'a AnnounceRequest,
+        ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+            panic!("announced despite --peer")
+        }
+
+        fn scrape<'a>(
+            &'a self,
+            _info_hashes: &'a [[u8; 20]],
+        ) -> BoxFuture<'a, anyhow::Result<HashMap<[u8; 20], ScrapeResult>>> {
+            panic!("scraped despite --peer")
+        }
+    }
+
+    // Has every piece of `content`: unchokes & answers every Request
+    fn spawn_seeding_peer(content: Vec<u8>, piece_length: usize) -> SocketAddr {
+        let num_pieces = content.len().div_ceil(piece_length);
+        let mut bitfield = Bitfield::new(num_pieces);
+        (0..num_pieces).for_each(|index| bitfield.set_piece(index));
+        spawn_handshaking_peer(move |stream| {
+            write_message(stream, 5, bitfield.as_bytes());
+            write_message(stream, 1, &[]);
+            serve_blocks(
+                stream,
+                &content,
+                piece_length,
+                usize::MAX,
+                Duration::ZERO,
+                |_| {},
+            );
+        })
+    }
+
+    #[tokio::test]
+    async fn test_download_from_given_peer_skips_tracker() {
+        let content: Vec<u8> = (0..40u8).collect();
+        let info = info_for("sample.txt", &content, 16);
+        let peer = spawn_seeding_peer(content.clone(), 16);
+
+        let mut trackers = TrackerList::from_trackers(vec![vec![Box::new(UnusedTracker)]]);
+        let piece_indices = BTreeSet::from([0, 1, 2]);
+        let plan = plan_download(
+            &mut trackers,
+            &info,
+            &piece_indices,
+            Event::Started,
+            Some(peer),
+        )
+        .await
+        .unwrap();
+        assert_eq!(plan.tracker_response.peers, vec![peer]);
+        assert_eq!(plan.source, PeerSource::Manual);
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.txt");
+        let writer = info.piece_writer(&output, None).unwrap();
+        let mut peers = PeerSet::new();
+        peers.extend(plan.tracker_response.peers, plan.source);
+        let (_, new_peers) = tokio::sync::mpsc::unbounded_channel();
+        download_pieces(
+            &info,
+            peers,
+            new_peers,
+            Vec::new(),
+            Arc::new(Mutex::new(Progress::starting(info.total_length()))),
+            &piece_indices,
+            Coordinator::new(info.clone()),
+            writer,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+
+    #[test]
+    fn test_save_pieces_streams_to_disk() {
+        use std::cell::Cell;
+
+        let content: Vec<u8> = (0..40u8).collect();
+        let info = info_for("sample.txt", &content, 16);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.txt");
+        let mut writer = info.piece_writer(&output, None).unwrap();
+        let progress = Mutex::new(Progress::starting(info.total_length()));
+
+        // Bytes fetched but not yet on disk
+        let buffered = Cell::new(0);
+        let peak = Cell::new(0);
+        save_pieces(
+            &info,
+            &BTreeSet::from([0, 1, 2]),
+            &progress,
+            |piece_index| {
+                let start = piece_index * 16;
+                let piece = content[start..(start + 16).min(40)].to_vec();
+                buffered.set(buffered.get() + piece.len());
+                peak.set(peak.get().max(buffered.get()));
+                Ok(piece)
+            },
+            |piece_index, piece| {
+                buffered.set(buffered.get() - piece.len());
+                writer.write_piece(piece_index, piece)
+            },
+        )
+        .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+        assert!(peak.get() <= 16, "buffered {} bytes", peak.get());
+        assert_eq!(progress.lock().unwrap().left, 0);
+    }
+
+    #[test]
+    fn test_interrupted_download_leaves_part_file() {
+        let content: Vec<u8> = (0..40u8).collect();
+        let info = info_for("sample.txt", &content, 16);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.txt");
+        let mut writer = info.piece_writer(&output, None).unwrap();
+        let progress = Mutex::new(Progress::starting(info.total_length()));
+
+        let saved = save_pieces(
+            &info,
+            &BTreeSet::from([0, 1, 2]),
+            &progress,
+            |piece_index| match piece_index {
+                0 => Ok(content[..16].to_vec()),
+                _ => Err(anyhow::anyhow!("Peer went away")),
+            },
+            |piece_index, piece| writer.write_piece(piece_index, piece),
+        );
+        assert!(saved.is_err());
+        drop(writer);
+
+        assert!(!output.exists());
+        let part = std::fs::read(dir.path().join("sample.txt.part")).unwrap();
+        assert_eq!(&part[..16], &content[..16]);
+    }
+
+    #[tokio::test]
+    async fn test_peers_output_is_only_peers() {
+        let body = b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x7f\x00\x00\x02\x1a\xe2e";
+        let tracker_url = serve_once("200 OK", body.to_vec()) + "/announce?passkey=abc";
+
+        let (tracker_response, _) = TrackerList::new(&tracker_url, None)
+            .announce([1; 20], 100)
+            .await
+            .unwrap();
+        let mut out = Vec::new();
+        print_peers(&mut out, &tracker_response).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Peers:\n127.0.0.1:6881\n127.0.0.2:6882\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_reports_corrupt_piece() {
+        let content: Vec<u8> = (0..40u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let torrent_file = dir.path().join("sample.torrent");
+        MetainfoFile::new(
+            "http://t/announce".to_string(),
+            info_for("sample.txt", &content, 16),
+        )
+        .write_to_file(&torrent_file)
+        .unwrap();
+        let data_file = dir.path().join("sample.txt");
+        std::fs::write(&data_file, &content).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(check(&mut out, &torrent_file, &data_file).await.unwrap(), 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Piece 0: OK\nPiece 1: OK\nPiece 2: OK\nAll 3 pieces OK.\n"
+        );
+
+        let mut corrupt = content.clone();
+        corrupt[20] ^= 0xff;
+        std::fs::write(&data_file, &corrupt).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(check(&mut out, &torrent_file, &data_file).await.unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Piece 0: OK\nPiece 1: FAIL\nPiece 2: OK\n1 of 3 pieces failed.\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_show_consumed() {
+        let mut out = Vec::new();
+        print_decoded(&mut out, "5:hello", true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"hello\"\nConsumed: 7 bytes\n"
+        );
+
+        // Trailing bytes of a second value aren't counted
+        let mut out = Vec::new();
+        print_decoded(&mut out, "l5:helloi52eei1e", true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[\"hello\",52]\nConsumed: 13 bytes\n"
+        );
+
+        let mut out = Vec::new();
+        print_decoded(&mut out, "l5:helloi52ee", false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[\"hello\",52]\n");
+    }
+
+    #[test]
+    fn test_inspect_summarizes_pieces() {
+        let torrent = b"d8:announce9:http://t/7:comment2:hi4:infod6:lengthi5e4:name1:a6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae5:nodesll1:hi1eeee";
+        let (_, value) = decode_bencoded_value(&torrent[..]).unwrap();
+        let mut out = Vec::new();
+        print_inspected(&mut out, value).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\n  announce: http://t/\n  comment: hi\n  info: {\n    length: 5\n    name: a\n    pieces: <2 piece hashes>\n  }\n  nodes: [\n    [\n      h\n      1\n    ]\n  ]\n}\n"
+        );
+    }
+
+    // Answers the handshake with the given bitfield, then hangs up
+    fn spawn_bitfield_peer(bitfield: u8) -> SocketAddr {
+        spawn_handshaking_peer(move |stream| write_message(stream, 5, &[bitfield]))
+    }
+
+    #[test]
+    fn test_availability_histogram() {
+        let info = Info::single_file("sample.txt".to_string(), 40, 16, vec![0x80; 60]);
+        let peers = [
+            spawn_bitfield_peer(0b1100_0000),
+            spawn_bitfield_peer(0b0110_0000),
+        ];
+        let counts = piece_availability(&info, &peers);
+        let mut out = Vec::new();
+        print_availability(&mut out, &counts).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "piece 0: 1 peers\npiece 1: 2 peers\npiece 2: 1 peers\n"
+        );
+    }
+
+    #[test]
+    fn test_verbosity_counts() {
+        let opts = Opts::try_parse_from(["your_bittorrent", "-vv", "peers", "a.torrent"]).unwrap();
+        assert_eq!(opts.verbose, 2);
+        let opts = Opts::try_parse_from(["your_bittorrent", "peers", "a.torrent"]).unwrap();
+        assert_eq!(opts.verbose, 0);
+    }
+
+    #[test]
+    fn test_handshake_accepts_ipv6_peer() {
+        let opts = Opts::try_parse_from([
+            "your_bittorrent",
+            "handshake",
+            "sample.torrent",
+            "[::1]:6881",
+        ])
+        .unwrap();
+        match opts.subcmd {
+            SubCommand::Handshake { peer_ip, .. } => {
+                assert_eq!(peer_ip, "[::1]:6881".parse::<SocketAddr>().unwrap())
+            }
+            other => panic!("Expected handshake, got {:?}", other),
         }
     }
+
+    #[test]
+    fn test_info_format_defaults_to_hex() {
+        let opts = Opts::try_parse_from(["your_bittorrent", "info", "sample.torrent"]).unwrap();
+        assert!(matches!(
+            opts.subcmd,
+            SubCommand::Info {
+                format: HashFormat::Hex,
+                ..
+            }
+        ));
+
+        let opts = Opts::try_parse_from([
+            "your_bittorrent",
+            "info",
+            "--format",
+            "base32",
+            "sample.torrent",
+        ])
+        .unwrap();
+        assert!(matches!(
+            opts.subcmd,
+            SubCommand::Info {
+                format: HashFormat::Base32,
+                ..
+            }
+        ));
+    }
 }
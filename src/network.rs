@@ -1,14 +1,59 @@
-use crate::decoder::{BencodedString, BencodedValue};
+use crate::bitfield::Bitfield;
+use crate::decoder::{decode_bencoded_value, Bencodeable, BencodedString, BencodedValue};
+use crate::extension::{
+    self, MetadataBuffer, MetadataError, MetadataMessage, PeerExtensions, PexMessage,
+    EXTENDED_MESSAGE_ID, HANDSHAKE_ID, OUR_EXTENSIONS, UT_METADATA, UT_METADATA_ID, UT_PEX,
+    UT_PEX_ID,
+};
+use crate::file::{Info, MetainfoFile};
+use crate::peer_id::PeerId;
+use crate::ratelimit::{RateLimits, Throttled};
 use anyhow::{anyhow, Error};
+use log::{debug, info, trace, warn};
+use rand::seq::SliceRandom;
 use serde::Serialize;
 use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{self, Display, Formatter},
+    future::Future,
     io::{Read, Write},
-    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
 };
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 
 const CHUNK_SIZE: i64 = 16 * 1024;
-const PEER_ID: &str = "-TR2940-2b3b6b4b5b6b";
+// Smallest block we'll shrink to for peers that turn down 16 KiB requests
+const MIN_BLOCK_SIZE: u32 = 4 * 1024;
+pub const DEFAULT_PORT: u16 = 6881;
+// Largest peer message we'll allocate for: plenty for a Piece or a big Bitfield
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
+// Log target for --trace, so it can be let through without the rest of the chatter
+pub const TRACE_TARGET: &str = "peer_trace";
+// How long a requested block may be outrun by other messages before it's asked for again
+const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+// How long a peer may keep us choked mid-piece before it's given up on
+const DEFAULT_CHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a peer gets to accept a connection
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// A connection we've sent nothing on for this long gets a keep-alive; each picks its
+// own within the range so they don't all go out at once
+const KEEP_ALIVE_SECS: std::ops::RangeInclusive<u64> = 90..=110;
+// An idle peer is given up on after this long without a word: two minutes past
+// PeerTimeouts' read timeout
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 + 120);
+const USER_AGENT: &str = concat!("your_bittorrent/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
 
 // Serialize the payload to a query string
 #[derive(Serialize)]
@@ -28,6 +73,160 @@ pub struct TrackerPayload {
     // compact: setting this to 1 indicates that we would like to receive a compact response
     #[serde(serialize_with = "serde_bool_to_int")]
     pub compact: bool,
+    // event: started, completed or stopped; left out for regular announces
+    #[serde(skip_serializing_if = "Event::is_none")]
+    pub event: Event,
+    // key: random, so the tracker can tell it's still us if our IP changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    // numwant: how many peers we'd like; the tracker picks (usually 50) if left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
+    // ip: our address, if it differs from the one the request comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    // trackerid: echoes the `tracker id` from this tracker's previous response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+}
+
+// What we tell every tracker about ourselves, beyond the torrent's progress.
+// Kept for the whole session so the peer ID & key don't change between announces.
+#[derive(Debug, Clone)]
+pub struct AnnounceParams {
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub key: String,
+    pub numwant: Option<u32>,
+    pub ip: Option<String>,
+    // Off for trackers that mishandle compact=1
+    pub compact: bool,
+}
+
+impl Default for AnnounceParams {
+    fn default() -> Self {
+        AnnounceParams {
+            peer_id: PeerId::local(),
+            port: DEFAULT_PORT,
+            key: format!("{:08x}", rand::random::<u32>()),
+            numwant: None,
+            ip: None,
+            compact: true,
+        }
+    }
+}
+
+impl AnnounceParams {
+    pub fn request(
+        &self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        event: Event,
+    ) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash,
+            peer_id: self.peer_id,
+            port: self.port,
+            progress,
+            event,
+            numwant: self.numwant,
+            key: Some(self.key.clone()),
+            ip: self.ip.clone(),
+            tracker_id: None,
+            compact: self.compact,
+        }
+    }
+}
+
+// The port peers can reach us on, as advertised to trackers
+pub enum ListenPort {
+    // Bound by the listener once it starts
+    Fixed(u16),
+    // Port 0: the OS picked one, & the socket is held so nobody else takes it
+    Bound(TcpListener),
+}
+
+impl ListenPort {
+    // Ports under 1024 need root on most systems, so they're refused unless asked for
+    pub fn new(port: u16, allow_privileged: bool) -> Result<Self, Error> {
+        match port {
+            0 => Ok(ListenPort::Bound(TcpListener::bind((
+                Ipv4Addr::UNSPECIFIED,
+                0,
+            ))?)),
+            port if port < 1024 && !allow_privileged => Err(anyhow!(
+                "Port {} is privileged, pass --allow-privileged-port to use it anyway",
+                port
+            )),
+            port => Ok(ListenPort::Fixed(port)),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            ListenPort::Fixed(port) => *port,
+            ListenPort::Bound(listener) => {
+                listener.local_addr().map(|addr| addr.port()).unwrap_or(0)
+            }
+        }
+    }
+}
+
+// Everything a single announce sends, whichever kind of tracker it goes to
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub progress: Progress,
+    pub event: Event,
+    pub numwant: Option<u32>,
+    pub key: Option<String>,
+    pub ip: Option<String>,
+    // The `tracker id` this tracker gave us last time, if any
+    pub tracker_id: Option<String>,
+    pub compact: bool,
+}
+
+impl AnnounceRequest {
+    // A regular announce with this session's defaults
+    pub fn new(info_hash: [u8; 20], progress: Progress) -> Self {
+        AnnounceParams::default().request(info_hash, progress, Event::None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    #[default]
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    pub fn is_none(&self) -> bool {
+        *self == Event::None
+    }
+}
+
+// The byte counters reported on every announce
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+impl Progress {
+    // Nothing transferred yet
+    pub fn starting(length: i64) -> Self {
+        Progress {
+            left: length as u64,
+            ..Default::default()
+        }
+    }
 }
 
 // Input: d69f91e6b2ae4c542468d1073a71d4ea13879a7f;
@@ -49,65 +248,229 @@ where
     s.serialize_u8(if *x { 1 } else { 0 })
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    // The tracker answered, but refused us, e.g. for an unregistered torrent
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("malformed tracker response: {0}")]
+    Malformed(String),
+    // A well-formed response without a `peers` key
+    #[error("malformed tracker response: no peers")]
+    NoPeers,
+    // Every announce answered, but with nobody in the swarm
+    #[error("tracker has no peers for this torrent after {0} announces")]
+    EmptySwarm(u32),
+    #[error("tracker request failed on attempt {attempt} of {attempts}: {source}")]
+    Http {
+        attempt: u32,
+        attempts: u32,
+        #[source]
+        source: reqwest::Error,
+    },
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct TrackerResponse {
     // interval: An integer, indicating how often
     // this client should make a request to the tracker
     pub interval: u64,
+    // min interval: Clients must not re-announce more often than this
+    pub min_interval: Option<u64>,
     // peers: A string, which contains list of peers that your client can connect to.
     // A string, which contains list of peers that your client can connect to.
     // Each peer is represented using 6 bytes.
     // The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number
     // pub peers: Vec<String>,
-    pub peers: Vec<SocketAddrV4>,
+    pub peers: Vec<SocketAddr>,
+    // complete: number of seeders
+    pub complete: Option<u64>,
+    // incomplete: number of leechers
+    pub incomplete: Option<u64>,
+    // tracker id: to be echoed back on later announces
+    pub tracker_id: Option<String>,
+    // warning message: the announce worked, but the tracker has something to say
+    pub warning: Option<String>,
 }
 
 impl TryFrom<&BencodedValue> for TrackerResponse {
-    type Error = Error;
+    type Error = TrackerError;
 
     fn try_from(value: &BencodedValue) -> Result<Self, Self::Error> {
+        let malformed = |e: Error| TrackerError::Malformed(e.to_string());
         let mut interval: u64 = 0;
         // let mut peers: Vec<String> = Vec::new();
-        let mut peers: Vec<SocketAddrV4> = Vec::new();
+        let mut peers: Vec<SocketAddr> = Vec::new();
 
         // Error if not a BencodedValue::Dict
-        match value {
-            BencodedValue::Dict(dict) => {
-                // Error if no interval
-                match dict.get(&BencodedString(b"interval".to_vec())) {
-                    Some(BencodedValue::Integer(i)) => {
-                        if *i < 0 {
-                            return Err(anyhow!("Interval is negative"));
-                        }
-                        interval = *i as u64;
-                    }
-                    _ => {
-                        // print out warning
-                        println!("No interval");
-                    }
+        let dict = match value {
+            BencodedValue::Dict(dict) => dict,
+            _ => return Err(TrackerError::Malformed("not a dict".to_string())),
+        };
+        let get = |key: &str| dict.get(&BencodedString(key.as_bytes().to_vec()));
+        let get_string = |key: &str| match get(key) {
+            Some(BencodedValue::String(s)) => Some(String::from(s)),
+            _ => None,
+        };
+        let get_count = |key: &str| match get(key) {
+            Some(BencodedValue::Integer(i)) => Some((*i).max(0) as u64),
+            _ => None,
+        };
+
+        // A failure reason replaces every other key
+        if let Some(reason) = get_string("failure reason") {
+            return Err(TrackerError::Failure(reason));
+        }
+
+        // A missing interval leaves re-announce timing to reannounce_delay's floor
+        match get("interval") {
+            Some(BencodedValue::Integer(i)) => {
+                if *i < 0 {
+                    return Err(TrackerError::Malformed("interval is negative".to_string()));
                 }
-                // Error if no peers
-                match dict.get(&BencodedString(b"peers".to_vec())) {
-                    Some(BencodedValue::String(s)) => {
-                        let peer_bytes: Vec<u8> = s.into();
-                        let peer_chunks: Vec<&[u8]> = peer_bytes.chunks(6).collect();
-
-                        peer_chunks.iter().for_each(|chunk| {
-                            let ip = &chunk[0..4];
-                            let port = &chunk[4..6];
-                            let port_str = format!("{}", u16::from_be_bytes([port[0], port[1]]));
-                            // std::net
-                            let new_ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
-                            let new_peer = SocketAddrV4::new(new_ip, port_str.parse().unwrap());
-                            peers.push(new_peer);
-                        });
-                    }
-                    _ => return Err(anyhow!("No peers")),
+                interval = *i as u64;
+            }
+            _ => debug!("No interval"),
+        }
+        // Error if no peers
+        match get("peers") {
+            Some(BencodedValue::String(s)) => {
+                peers.extend(parse_compact_peers(&s.0, 4).map_err(malformed)?);
+            }
+            // Original, non-compact model: a list of {ip, port, peer id} dicts
+            Some(BencodedValue::List(list)) => {
+                for peer in list {
+                    peers.push(dict_model_peer(peer).map_err(malformed)?);
                 }
             }
-            _ => return Err(anyhow!("Not a dict")),
+            _ => return Err(TrackerError::NoPeers),
+        }
+        // IPv6 peers come separately, 18 bytes each (BEP 7)
+        if let Some(BencodedValue::String(s)) = get("peers6") {
+            peers.extend(parse_compact_peers(&s.0, 16).map_err(malformed)?);
+        }
+
+        Ok(TrackerResponse {
+            interval,
+            min_interval: get_count("min interval"),
+            peers,
+            complete: get_count("complete"),
+            incomplete: get_count("incomplete"),
+            tracker_id: get_string("tracker id"),
+            warning: get_string("warning message"),
+        })
+    }
+}
+
+// A compact peer, as trackers, ut_pex & the DHT send them: its IP address (4 or 16
+// bytes) followed by a big-endian port
+pub fn compact_peer(peer: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match peer.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend(peer.port().to_be_bytes());
+    bytes
+}
+
+pub fn parse_compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at(bytes.len().checked_sub(2)?);
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+// Compact peers back to back, all of them IPv4 or all IPv6
+pub fn compact_peers<'a>(peers: impl IntoIterator<Item = &'a SocketAddr>) -> Vec<u8> {
+    peers.into_iter().flat_map(compact_peer).collect()
+}
+
+pub fn parse_compact_peers(bytes: &[u8], ip_len: usize) -> Result<Vec<SocketAddr>, Error> {
+    let entry_len = ip_len + 2;
+    if !bytes.len().is_multiple_of(entry_len) {
+        return Err(anyhow!(
+            "Compact peers is {} bytes, not a multiple of {}",
+            bytes.len(),
+            entry_len
+        ));
+    }
+    bytes
+        .chunks(entry_len)
+        .map(|chunk| {
+            parse_compact_peer(chunk).ok_or_else(|| anyhow!("Bad compact peer {:?}", chunk))
+        })
+        .collect()
+}
+
+// `ip` may be an IPv4/IPv6 address or a hostname
+fn dict_model_peer(value: &BencodedValue) -> Result<SocketAddr, Error> {
+    let dict = match value {
+        BencodedValue::Dict(dict) => dict,
+        _ => return Err(anyhow!("Peer is not a dict")),
+    };
+    let ip = match dict.get(&BencodedString(b"ip".to_vec())) {
+        Some(BencodedValue::String(ip)) => String::from(ip),
+        _ => return Err(anyhow!("Peer has no ip")),
+    };
+    let port = match dict.get(&BencodedString(b"port".to_vec())) {
+        Some(BencodedValue::Integer(port)) => {
+            u16::try_from(*port).map_err(|_| anyhow!("Peer port {} is out of range", port))?
         }
+        _ => return Err(anyhow!("Peer has no port")),
+    };
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    (ip.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Peer {} has no address", ip))
+}
 
-        Ok(TrackerResponse { interval, peers })
+// Back to the compact form a tracker would send
+impl From<&TrackerResponse> for BencodedValue {
+    fn from(value: &TrackerResponse) -> Self {
+        let (peers, peers6): (Vec<_>, Vec<_>) = value.peers.iter().partition(|peer| peer.is_ipv4());
+        let (peers, peers6) = (compact_peers(peers), compact_peers(peers6));
+        let mut dict = BTreeMap::from([
+            (
+                BencodedString(b"interval".to_vec()),
+                BencodedValue::Integer(value.interval as i64),
+            ),
+            (
+                BencodedString(b"peers".to_vec()),
+                BencodedValue::String(peers.into()),
+            ),
+        ]);
+        if !peers6.is_empty() {
+            dict.insert(
+                BencodedString(b"peers6".to_vec()),
+                BencodedValue::String(peers6.into()),
+            );
+        }
+        let counts = [
+            ("min interval", value.min_interval),
+            ("complete", value.complete),
+            ("incomplete", value.incomplete),
+        ];
+        for (key, count) in counts {
+            if let Some(count) = count {
+                dict.insert(
+                    BencodedString(key.as_bytes().to_vec()),
+                    BencodedValue::Integer(count as i64),
+                );
+            }
+        }
+        if let Some(tracker_id) = &value.tracker_id {
+            dict.insert(
+                BencodedString(b"tracker id".to_vec()),
+                BencodedValue::String(tracker_id.clone().into()),
+            );
+        }
+        BencodedValue::Dict(dict)
     }
 }
 
@@ -117,11 +480,16 @@ impl Default for TrackerPayload {
         TrackerPayload {
             // info_hash: vec![],
             peer_id: "".to_string(),
-            port: 6881,
+            port: DEFAULT_PORT.into(),
             uploaded: 0,
             downloaded: 0,
             left: 0,
             compact: true,
+            event: Event::None,
+            key: None,
+            numwant: None,
+            ip: None,
+            trackerid: None,
         }
     }
 }
@@ -132,8 +500,8 @@ pub struct PeerHandshake {
     length: u64,
     // protocol string (19 bytes) -- default: 'BitTorrent protocol'
     protocol: String,
-    // 8 reserved bytes (all 0) (8 bytes)
-    reserved: Vec<u8>,
+    // 8 reserved bytes flagging protocol extensions (8 bytes)
+    pub reserved: [u8; 8],
     // info hash (20 bytes)
     info_hash: Vec<u8>,
     // peer id (20 bytes)
@@ -145,9 +513,9 @@ impl Default for PeerHandshake {
         PeerHandshake {
             length: 19,
             protocol: "BitTorrent protocol".to_string(),
-            reserved: vec![0; 8],
+            reserved: [0; 8],
             info_hash: vec![],
-            peer_id: PEER_ID.as_bytes().to_vec(),
+            peer_id: PeerId::local().as_bytes().to_vec(),
         }
     }
 }
@@ -161,6 +529,39 @@ impl PeerHandshake {
             ..Default::default()
         }
     }
+
+    // Advertise our DHT node, see supports_dht
+    pub fn with_dht(mut self) -> Self {
+        self.set_reserved_bit(7, 0x01);
+        self
+    }
+
+    pub fn set_reserved_bit(&mut self, byte: usize, mask: u8) {
+        self.reserved[byte] |= mask;
+    }
+
+    fn reserved_bit(&self, byte: usize, mask: u8) -> bool {
+        self.reserved[byte] & mask != 0
+    }
+
+    // BEP 10 extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved_bit(5, 0x10)
+    }
+
+    // BEP 5 DHT, i.e. the peer will send its DHT port
+    pub fn supports_dht(&self) -> bool {
+        self.reserved_bit(7, 0x01)
+    }
+
+    // BEP 6 Fast Extension
+    pub fn supports_fast(&self) -> bool {
+        self.reserved_bit(7, 0x04)
+    }
+
+    pub fn info_hash(&self) -> &[u8] {
+        &self.info_hash
+    }
 }
 
 impl From<PeerHandshake> for Vec<u8> {
@@ -175,607 +576,5628 @@ impl From<PeerHandshake> for Vec<u8> {
     }
 }
 
-impl From<Vec<u8>> for PeerHandshake {
-    fn from(value: Vec<u8>) -> Self {
-        let mut handshake = PeerHandshake::default();
-        handshake.length = value[0] as u64;
-        handshake.protocol = String::from_utf8(value[1..20].to_vec()).unwrap();
-        handshake.reserved = value[20..28].to_vec();
-        handshake.info_hash = value[28..48].to_vec();
-        handshake.peer_id = value[48..68].to_vec();
-        handshake
+// The peer's answer to our handshake, from a read of `buf`. It must be for our torrent.
+fn handshake_reply(
+    read: std::io::Result<()>,
+    buf: &[u8],
+    info_hash: &[u8; 20],
+    peer_addr: SocketAddr,
+) -> Result<PeerHandshake, Error> {
+    let peer_handshake = peer_handshake(read, buf, peer_addr)?;
+    if peer_handshake.info_hash != info_hash {
+        return Err(HandshakeError::InfoHashMismatch {
+            expected: *info_hash,
+            actual: peer_handshake.info_hash.as_slice().try_into()?,
+        }
+        .into());
+    }
+    Ok(peer_handshake)
+}
+
+// Whatever handshake the peer sent, from a read of `buf`
+fn peer_handshake(
+    read: std::io::Result<()>,
+    buf: &[u8],
+    peer_addr: SocketAddr,
+) -> Result<PeerHandshake, Error> {
+    match read {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(HandshakeError::Truncated.into())
+        }
+        Err(e) => Err(anyhow!("No handshake from {}: {}", peer_addr, e)),
+        Ok(()) => Ok(PeerHandshake::try_from(buf)?),
+    }
+}
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+// Why a peer's handshake was turned down; any of these means dropping the peer
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("handshake cut short")]
+    Truncated,
+    #[error("not a BitTorrent handshake: {0:?}")]
+    BadProtocol(String),
+    #[error(
+        "peer is serving info hash {}, not {}",
+        hex::encode(actual),
+        hex::encode(expected)
+    )]
+    InfoHashMismatch {
+        expected: [u8; 20],
+        actual: [u8; 20],
+    },
+    #[error("peer asked for info hash {}, which we don't serve", hex::encode(.0))]
+    NotServed([u8; 20]),
+}
+
+// Checks it's a BitTorrent handshake before picking it apart
+impl TryFrom<&[u8]> for PeerHandshake {
+    type Error = HandshakeError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < 68 {
+            return Err(HandshakeError::Truncated);
+        }
+        if value[0] as usize != PROTOCOL.len() || &value[1..20] != PROTOCOL {
+            return Err(HandshakeError::BadProtocol(
+                String::from_utf8_lossy(&value[1..20]).into_owned(),
+            ));
+        }
+        Ok(PeerHandshake {
+            length: value[0] as u64,
+            protocol: String::from_utf8_lossy(PROTOCOL).into_owned(),
+            reserved: value[20..28].try_into().unwrap(),
+            info_hash: value[28..48].to_vec(),
+            peer_id: value[48..68].to_vec(),
+        })
     }
 }
 
 pub async fn ping_tracker(
+    client: &TrackerClient,
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<TrackerResponse, Error> {
+    match announce_once(client, tracker_url, request).await {
+        // Some old trackers leave the peers out altogether when asked for compact ones
+        Err(e) if request.compact && matches!(e.downcast_ref(), Some(TrackerError::NoPeers)) => {
+            info!("No peers in compact response, retrying with compact=0");
+            let request = AnnounceRequest {
+                compact: false,
+                ..request.clone()
+            };
+            announce_once(client, tracker_url, &request).await
+        }
+        result => result,
+    }
+}
+
+async fn announce_once(
+    client: &TrackerClient,
     tracker_url: &str,
-    info_hash: [u8; 20],
-    length: i64,
+    request: &AnnounceRequest,
 ) -> Result<TrackerResponse, Error> {
     let payload = TrackerPayload {
         // info_hash: metainfo.info.info_hash().as_bytes().to_vec(),
-        peer_id: PEER_ID.to_string(),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: length as u64,
-        compact: true,
+        peer_id: request.peer_id.to_string(),
+        port: request.port.into(),
+        uploaded: request.progress.uploaded,
+        downloaded: request.progress.downloaded,
+        left: request.progress.left,
+        compact: request.compact,
+        event: request.event,
+        key: request.key.clone(),
+        numwant: request.numwant,
+        ip: request.ip.clone(),
+        trackerid: request.tracker_id.clone(),
     };
 
-    // Just add a % in front of each byte (2 chars) by iter String
-    let url = format!(
-        "{}?{}&info_hash={}",
-        tracker_url,
+    // info_hash is raw bytes, so it's percent-encoded by hand rather than by serde
+    let query = format!(
+        "{}&info_hash={}",
         serde_urlencoded::to_string(&payload)?,
-        url_encode(&info_hash).expect("Failed to encode info hash")
+        url_encode(&request.info_hash)?
     );
-    // Preview the url
-    println!("URL: {}", url);
-    let resp_bytes = reqwest::get(&url).await?.bytes().await?;
-    let resp_u8: &[u8] = &resp_bytes;
-    println!("Body Bytes: {:?}", resp_bytes);
-
-    let de_bencoded: BencodedValue = BencodedValue::from(resp_u8);
-    println!("Bencoded Response: {}", de_bencoded);
-    let tracker_response = TrackerResponse::try_from(&de_bencoded)?;
+    let url = append_query(tracker_url, &query)?;
+    // Only spell out the passkey when asked for debug output
+    info!("Announcing to {}", redact_url(&url));
+    debug!("URL: {}", url);
+    let resp_bytes = gunzip(client.get(&url).await?)?;
+    debug!("Body Bytes: {:?}", resp_bytes);
 
+    let (_, de_bencoded) = decode_bencoded_value(&resp_bytes)?;
+    debug!("Bencoded Response: {}", de_bencoded);
+    let tracker_response = TrackerResponse::try_from(&de_bencoded)?;
     Ok(tracker_response)
 }
 
-pub fn url_encode(t: &[u8; 20]) -> anyhow::Result<String> {
-    let mut s = String::new();
-    for b in t {
-        s.push('%');
-        s.push_str(&format!("{:02x}", b));
+// reqwest only inflates bodies labelled with a Content-Encoding; some trackers gzip
+// without saying so. Bencode never starts with the gzip magic bytes.
+fn gunzip(body: bytes::Bytes) -> Result<bytes::Bytes, Error> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Ok(body);
     }
-    Ok(s)
+    let mut inflated = Vec::new();
+    flate2::read::GzDecoder::new(body.as_ref())
+        .read_to_end(&mut inflated)
+        .map_err(|e| anyhow!("Could not gunzip tracker response: {}", e))?;
+    Ok(inflated.into())
 }
 
-#[derive(Debug, PartialEq)]
-pub enum PeerMessage {
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have,
-    Bitfield(Vec<u8>),
-    Request {
-        index: u32,
-        begin: u32,
-        length: u32,
-    },
-    Piece {
-        index: u32,
-        begin: u32,
-        // unknown length
-        block: Vec<u8>,
-    },
-    Cancel {
-        index: u32,
-        begin: u32,
-        length: u32,
-    },
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
 }
 
-impl From<Vec<u8>> for PeerMessage {
-    fn from(value: Vec<u8>) -> Self {
-        match value[4] {
-            0 => PeerMessage::Choke,
-            1 => PeerMessage::Unchoke,
-            2 => PeerMessage::Interested,
-            3 => PeerMessage::NotInterested,
-            4 => PeerMessage::Have,
-            5 => PeerMessage::Bitfield(value[5..].to_vec()),
-            6 => PeerMessage::Request {
-                index: u32::from_be_bytes(value[5..9].try_into().unwrap()), // [5, 6, 7, 8]
-                begin: u32::from_be_bytes(value[9..13].try_into().unwrap()), // [9, 10, 11, 12]
-                length: u32::from_be_bytes(value[13..].try_into().unwrap()), // [13, 14, 15, 16]
-            },
-            7 => {
-                let mut block = vec![0; value.len() - 13];
-                // fill in block with the rest of the bytes & pad with 0s
-                block[..value.len() - 13].copy_from_slice(&value[13..]);
-                PeerMessage::Piece {
-                    index: u32::from_be_bytes(value[5..9].try_into().unwrap()), // [5, 6, 7, 8]
-                    begin: u32::from_be_bytes(value[9..13].try_into().unwrap()), // [9, 10, 11, 12]
-                    block: block.to_vec(),
-                }
+// On-disk cache of the last tracker response per info hash, so repeated
+// invocations respect the tracker's announce interval
+pub struct TrackerCache {
+    dir: PathBuf,
+    // Seconds since the Unix epoch, injectable for tests
+    clock: Box<dyn Fn() -> u64 + Send + Sync>,
+}
+
+impl TrackerCache {
+    pub fn new<T: Into<PathBuf>>(dir: T) -> Self {
+        Self::with_clock(dir, unix_now)
+    }
+
+    pub fn with_clock<T: Into<PathBuf>>(
+        dir: T,
+        clock: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        TrackerCache {
+            dir: dir.into(),
+            clock: Box::new(clock),
+        }
+    }
+
+    pub fn default_dir() -> PathBuf {
+        std::env::temp_dir().join("your_bittorrent-tracker-cache")
+    }
+
+    fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.bencode", hex::encode(info_hash)))
+    }
+
+    // The cached response, the announce URL that gave it & the unix time it was
+    // stored at. Anything missing or out of range is a miss.
+    pub fn load(&self, info_hash: &[u8; 20]) -> Option<(TrackerResponse, String, u64)> {
+        let bytes = std::fs::read(self.path(info_hash)).ok()?;
+        let (_, value) = decode_bencoded_value(&bytes).ok()?;
+        let BencodedValue::Dict(dict) = &value else {
+            return None;
+        };
+        let timestamp = match dict.get(&BencodedString(b"timestamp".to_vec())) {
+            Some(BencodedValue::Integer(i)) => u64::try_from(*i).ok()?,
+            _ => return None,
+        };
+        let url = match dict.get(&BencodedString(b"announce".to_vec())) {
+            Some(BencodedValue::String(url)) => String::from_utf8(url.0.clone()).ok()?,
+            _ => return None,
+        };
+        let tracker_response = TrackerResponse::try_from(&value).ok()?;
+        Some((tracker_response, url, timestamp))
+    }
+
+    pub fn store(
+        &self,
+        info_hash: &[u8; 20],
+        url: &str,
+        tracker_response: &TrackerResponse,
+    ) -> std::io::Result<()> {
+        let mut value = BencodedValue::from(tracker_response);
+        if let BencodedValue::Dict(dict) = &mut value {
+            dict.insert(
+                BencodedString(b"timestamp".to_vec()),
+                BencodedValue::Integer((self.clock)() as i64),
+            );
+            dict.insert(
+                BencodedString(b"announce".to_vec()),
+                BencodedValue::String(url.as_bytes().into()),
+            );
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(info_hash), value.bencode())
+    }
+
+    // False until the cached response's interval, & its min interval, have elapsed
+    pub fn should_reannounce(&self, info_hash: &[u8; 20]) -> bool {
+        match self.load(info_hash) {
+            Some((tracker_response, _, timestamp)) => {
+                let wait = tracker_response
+                    .interval
+                    .max(tracker_response.min_interval.unwrap_or(0));
+                timestamp
+                    .checked_add(wait)
+                    .is_none_or(|due| (self.clock)() >= due)
             }
-            8 => PeerMessage::Cancel {
-                index: u32::from_be_bytes(value[5..9].try_into().unwrap()), // [5, 6, 7, 8]
-                begin: u32::from_be_bytes(value[9..13].try_into().unwrap()), // [9, 10, 11, 12]
-                length: u32::from_be_bytes([value[13], value[14], value[15], value[16]]),
-            },
-            _ => panic!("Invalid message type"),
+            None => true,
         }
     }
+
+    // The cached response & the URL that gave it, if it is still within its interval
+    pub fn fresh(&self, info_hash: &[u8; 20]) -> Option<(TrackerResponse, String)> {
+        if self.should_reannounce(info_hash) {
+            return None;
+        }
+        self.load(info_hash)
+            .map(|(tracker_response, url, _)| (tracker_response, url))
+    }
 }
 
-impl From<&PeerMessage> for Vec<u8> {
-    fn from(value: &PeerMessage) -> Self {
-        let mut message: Vec<u8> = Vec::new();
-        match value {
-            PeerMessage::Choke => {
-                let length = 1 as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(0)
-            }
-            PeerMessage::Unchoke => {
-                let length = 1 as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(1)
-            }
-            PeerMessage::Interested => {
-                let length = 1 as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(2)
-            }
-            PeerMessage::NotInterested => {
-                let length = 1 as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(3)
-            }
-            PeerMessage::Have => {
-                let length = 5 as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(4)
-            }
-            PeerMessage::Bitfield(payload) => {
-                let length = payload.len() as u32 + 1;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(5);
-                message.extend(payload);
-            }
-            PeerMessage::Request {
-                index,
-                begin,
-                length,
-            } => {
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(6);
-                message.extend(index.to_be_bytes().to_vec());
-                message.extend(begin.to_be_bytes().to_vec());
-                message.extend(length.to_be_bytes().to_vec());
-            }
-            PeerMessage::Piece {
-                index,
-                begin,
-                block,
-            } => {
-                let length = 9 + block.len() as u32;
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(7);
-                message.extend(index.to_be_bytes().to_vec());
-                message.extend(begin.to_be_bytes().to_vec());
-                message.extend(block.to_vec());
-            }
-            PeerMessage::Cancel {
-                index,
-                begin,
-                length,
-            } => {
-                message.extend(length.to_be_bytes().to_vec());
-                message.push(8);
-                message.extend(index.to_be_bytes().to_vec());
-                message.extend(begin.to_be_bytes().to_vec());
-                message.extend(length.to_be_bytes().to_vec());
-            }
+// How to reach trackers over HTTP(S), e.g. through a proxy or to private ones with
+// self-signed certificates. Only tracker requests use it, never peer connections.
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    // e.g. http://proxy:3128. Without one, HTTP_PROXY/HTTPS_PROXY are still honored
+    pub proxy: Option<String>,
+    // PEM certificate to trust on top of the system roots
+    pub ca_cert: Option<PathBuf>,
+    // Skip certificate verification entirely. Only for trackers you trust anyway
+    pub accept_invalid_certs: bool,
+    // For connecting & for the whole request, so a silent tracker can't hang us
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            proxy: None,
+            ca_cert: None,
+            accept_invalid_certs: false,
+            timeout: DEFAULT_TRACKER_TIMEOUT,
+            retry: RetryPolicy::default(),
         }
-        message
     }
 }
 
-impl Display for PeerMessage {
+impl TrackerConfig {
+    pub fn client(&self) -> Result<TrackerClient, Error> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .gzip(true)
+            .deflate(true)
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout);
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| anyhow!("Invalid proxy {}: {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow!("Could not read CA certificate {}: {}", path.display(), e))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| anyhow!("Invalid CA certificate {}: {}", path.display(), e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        if self.accept_invalid_certs {
+            warn!("Tracker certificates will not be verified");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(TrackerClient {
+            http: builder.build()?,
+            retry: self.retry,
+        })
+    }
+}
+
+// How often to try a tracker request that failed for a reason that may pass:
+// connection errors, timeouts & 5xx. A 4xx is the tracker's final answer.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Including the first try
+    pub attempts: u32,
+    // Doubled after every failed attempt, plus up to half of it again as jitter
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay * 2u32.pow(attempt.saturating_sub(1).min(16));
+        let jitter = self.base_delay.mul_f64(rand::random::<f64>() / 2.0);
+        backoff + jitter
+    }
+}
+
+// One HTTP client for all tracker requests, so connections are reused across re-announces
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl TrackerClient {
+    pub async fn get(&self, url: &str) -> Result<bytes::Bytes, TrackerError> {
+        let attempts = self.retry.attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                self.http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await
+            }
+            .await;
+            match result {
+                Ok(bytes) => return Ok(bytes),
+                Err(e) if attempt < attempts && is_transient(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    warn!(
+                        "Request to {} failed on attempt {} of {}, retrying in {:?}: {}",
+                        redact_url(url),
+                        attempt,
+                        attempts,
+                        delay,
+                        e.without_url()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                // The URL may hold a passkey, & this error ends up in front of the user
+                Err(source) => {
+                    return Err(TrackerError::Http {
+                        attempt,
+                        attempts,
+                        source: source.without_url(),
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn is_transient(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.is_server_error(),
+        None => e.is_connect() || e.is_timeout(),
+    }
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Something we can announce to & scrape, whatever protocol it speaks. The futures
+// are boxed by hand so trackers of different kinds fit in one TrackerList.
+pub trait Tracker: Send + Sync {
+    fn url(&self) -> &str;
+
+    fn announce<'a>(
+        &'a self,
+        request: &'a AnnounceRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, Error>>;
+
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<HashMap<[u8; 20], ScrapeResult>, Error>>;
+}
+
+impl dyn Tracker {
+    // Picks the backend from the URL's scheme
+    pub fn from_url(url: &str, client: &TrackerClient) -> Result<Box<dyn Tracker>, Error> {
+        let parsed = reqwest::Url::parse(url)?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(Box::new(HttpTracker::new(url, client.clone()))),
+            "udp" => Ok(Box::new(UdpTracker::new(url))),
+            scheme => Err(anyhow!("Unsupported tracker scheme {}", scheme)),
+        }
+    }
+}
+
+pub struct HttpTracker {
+    url: String,
+    client: TrackerClient,
+}
+
+impl HttpTracker {
+    pub fn new(url: &str, client: TrackerClient) -> Self {
+        HttpTracker {
+            url: url.to_string(),
+            client,
+        }
+    }
+}
+
+impl Tracker for HttpTracker {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn announce<'a>(
+        &'a self,
+        request: &'a AnnounceRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, Error>> {
+        Box::pin(ping_tracker(&self.client, &self.url, request))
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<HashMap<[u8; 20], ScrapeResult>, Error>> {
+        Box::pin(scrape_tracker(&self.client, &self.url, info_hashes))
+    }
+}
+
+// BEP 15 trackers. Recognised so they fail with a clear message, rather than
+// as an HTTP error, until the UDP protocol is implemented.
+pub struct UdpTracker {
+    url: String,
+}
+
+impl UdpTracker {
+    pub fn new(url: &str) -> Self {
+        UdpTracker {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Tracker for UdpTracker {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn announce<'a>(
+        &'a self,
+        _request: &'a AnnounceRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, Error>> {
+        Box::pin(async { Err(anyhow!("UDP trackers are not supported yet")) })
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        _info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<HashMap<[u8; 20], ScrapeResult>, Error>> {
+        Box::pin(async { Err(anyhow!("UDP trackers are not supported yet")) })
+    }
+}
+
+// Trackers from `announce` and `announce-list`, grouped in tiers (BEP 12)
+pub struct TrackerList {
+    tiers: Vec<Vec<Box<dyn Tracker>>>,
+    cache: Option<TrackerCache>,
+    params: AnnounceParams,
+    // The `tracker id` each tracker last gave us, to send back on the next announce
+    tracker_ids: HashMap<String, String>,
+    // For announce_for_peers
+    empty_retry: RetryPolicy,
+}
+
+impl TrackerList {
+    // Per BEP 12, `announce` is only used when there is no `announce-list`. Trackerless
+    // torrents have neither & get an empty list; pass "" for their `announce`.
+    pub fn new(announce: &str, announce_list: Option<&Vec<Vec<String>>>) -> Self {
+        let mut tiers: Vec<Vec<String>> = match announce_list {
+            Some(list) => list
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        if tiers.is_empty() && !announce.is_empty() {
+            tiers.push(vec![announce.to_string()]);
+        }
+        // Shuffle within each tier once, so load spreads over equivalent trackers
+        tiers
+            .iter_mut()
+            .for_each(|tier| tier.shuffle(&mut rand::rng()));
+        let client = TrackerConfig::default()
+            .client()
+            .expect("Failed to build the default HTTP client");
+        Self::from_trackers(Self::connect(&tiers, &client))
+    }
+
+    // Trackers already built, e.g. mocks in tests; kept in the given order
+    pub fn from_trackers(tiers: Vec<Vec<Box<dyn Tracker>>>) -> Self {
+        TrackerList {
+            tiers,
+            cache: None,
+            params: AnnounceParams::default(),
+            tracker_ids: HashMap::new(),
+            empty_retry: RetryPolicy::none(),
+        }
+    }
+
+    // Trackers we have no backend for are left out with a warning
+    fn connect(tiers: &[Vec<String>], client: &TrackerClient) -> Vec<Vec<Box<dyn Tracker>>> {
+        tiers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(|url| match <dyn Tracker>::from_url(url, client) {
+                        Ok(tracker) => Some(tracker),
+                        Err(e) => {
+                            warn!("Skipping tracker {}: {}", redact_url(url), e);
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+
+    // e.g. one trusting a private tracker's certificate, see TrackerConfig
+    pub fn with_client(mut self, client: TrackerClient) -> Self {
+        let urls: Vec<Vec<String>> = self
+            .tiers()
+            .into_iter()
+            .map(|tier| tier.into_iter().map(String::from).collect())
+            .collect();
+        self.tiers = Self::connect(&urls, &client);
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.params.peer_id = peer_id;
+        self
+    }
+
+    pub fn with_numwant(mut self, numwant: Option<u32>) -> Self {
+        self.params.numwant = numwant;
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.params.port = port;
+        self
+    }
+
+    pub fn with_compact(mut self, compact: bool) -> Self {
+        self.params.compact = compact;
+        self
+    }
+
+    pub fn with_config(self, config: &TrackerConfig) -> Result<Self, Error> {
+        Ok(self.with_client(config.client()?))
+    }
+
+    pub fn with_cache(mut self, cache: TrackerCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // How often, & how far apart, announce_for_peers asks an empty swarm again
+    pub fn with_empty_retry(mut self, empty_retry: RetryPolicy) -> Self {
+        self.empty_retry = empty_retry;
+        self
+    }
+
+    pub fn from_metainfo(metainfo: &MetainfoFile) -> Self {
+        Self::new(
+            metainfo.announce.as_deref().unwrap_or_default(),
+            metainfo.announce_list.as_ref(),
+        )
+    }
+
+    // Tracker URLs, in the order they'll be tried
+    pub fn tiers(&self) -> Vec<Vec<&str>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|tracker| tracker.url()).collect())
+            .collect()
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        length: i64,
+    ) -> Result<(TrackerResponse, String), Error> {
+        self.announce_event(info_hash, Progress::starting(length), Event::None)
+            .await
+    }
+
+    // Try each tracker in tier order, returning the first response and the URL that gave it.
+    // The tracker that answered moves to the front of its tier for next time.
+    pub async fn announce_event(
+        &mut self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        event: Event,
+    ) -> Result<(TrackerResponse, String), Error> {
+        // Don't hammer the trackers before the announce interval is up,
+        // but events always have to reach them
+        if event.is_none() {
+            if let Some(cached) = self
+                .cache
+                .as_ref()
+                .and_then(|cache| cache.fresh(&info_hash))
+            {
+                info!("Using cached tracker response");
+                return Ok(cached);
+            }
+        }
+
+        let mut failures: Vec<String> = Vec::new();
+        let request = self.params.request(info_hash, progress, event);
+        for tier in self.tiers.iter_mut() {
+            for position in 0..tier.len() {
+                let url = tier[position].url().to_string();
+                let request = AnnounceRequest {
+                    tracker_id: self.tracker_ids.get(&url).cloned(),
+                    ..request.clone()
+                };
+                match tier[position].announce(&request).await {
+                    Ok(tracker_response) => {
+                        if let Some(tracker_id) = &tracker_response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                        }
+                        // Nobody in the swarm isn't worth remembering; the next announce may
+                        // do better
+                        let cache = self
+                            .cache
+                            .as_ref()
+                            .filter(|_| !tracker_response.peers.is_empty());
+                        if let Some(cache) = cache {
+                            if let Err(e) = cache.store(&info_hash, &url, &tracker_response) {
+                                warn!("Could not cache tracker response: {}", e);
+                            }
+                        }
+                        let tracker = tier.remove(position);
+                        tier.insert(0, tracker);
+                        return Ok((tracker_response, url));
+                    }
+                    Err(e) => failures.push(format!("{}: {}", redact_url(&url), e)),
+                }
+            }
+        }
+        if failures.is_empty() {
+            return Err(anyhow!("No supported trackers to announce to"));
+        }
+        Err(anyhow!("All trackers failed:\n  {}", failures.join("\n  ")))
+    }
+
+    // As announce_event, but a response with no peers is asked again after a while,
+    // as with_empty_retry says. Only the first announce carries `event`.
+    pub async fn announce_for_peers(
+        &mut self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        event: Event,
+    ) -> Result<(TrackerResponse, String), Error> {
+        let attempts = self.empty_retry.attempts.max(1);
+        let mut event = event;
+        for attempt in 1..=attempts {
+            let (tracker_response, url) = self.announce_event(info_hash, progress, event).await?;
+            if !tracker_response.peers.is_empty() {
+                return Ok((tracker_response, url));
+            }
+            warn!(
+                "No peers from {} on announce {} of {}",
+                redact_url(&url),
+                attempt,
+                attempts
+            );
+            event = Event::None;
+            if attempt < attempts {
+                tokio::time::sleep(self.empty_retry.delay(attempt)).await;
+            }
+        }
+        Err(TrackerError::EmptySwarm(attempts).into())
+    }
+
+    // Scrape the first tracker that supports it, in tier order
+    pub async fn scrape(&self, info_hash: [u8; 20]) -> Result<(ScrapeResult, String), Error> {
+        let mut failures: Vec<String> = Vec::new();
+        for tracker in self.tiers.iter().flatten() {
+            let url = tracker.url();
+            match tracker.scrape(&[info_hash]).await {
+                Ok(mut results) => match results.remove(&info_hash) {
+                    Some(result) => return Ok((result, url.to_string())),
+                    None => failures.push(format!("{}: torrent not in scrape", redact_url(url))),
+                },
+                Err(e) => failures.push(format!("{}: {}", redact_url(url), e)),
+            }
+        }
+        Err(anyhow!("All scrapes failed:\n  {}", failures.join("\n  ")))
+    }
+}
+
+// Swarm counts for one torrent, from a tracker scrape
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScrapeResult {
+    // complete: seeders
+    pub complete: u64,
+    // downloaded: how many times the torrent was ever completed
+    pub downloaded: u64,
+    // incomplete: leechers
+    pub incomplete: u64,
+}
+
+// By convention the scrape URL is the announce URL with the `announce` at the start
+// of its last path segment swapped for `scrape`; trackers without one don't scrape
+pub fn scrape_url(announce: &str) -> Result<String, Error> {
+    let path_end = announce.find(['?', '#']).unwrap_or(announce.len());
+    let (path, rest) = announce.split_at(path_end);
+    let segment_start = path
+        .rfind('/')
+        .map(|i| i + 1)
+        .ok_or_else(|| anyhow!("{} has no path", announce))?;
+    let segment = &path[segment_start..];
+    match segment.strip_prefix("announce") {
+        Some(suffix) => Ok(format!(
+            "{}scrape{}{}",
+            &path[..segment_start],
+            suffix,
+            rest
+        )),
+        None => Err(anyhow!("{} does not support scrape", announce)),
+    }
+}
+
+pub async fn scrape_tracker(
+    client: &TrackerClient,
+    tracker_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeResult>, Error> {
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| Ok(format!("info_hash={}", url_encode(info_hash)?)))
+        .collect::<Result<Vec<_>, Error>>()?
+        .join("&");
+    let url = append_query(&scrape_url(tracker_url)?, &query)?;
+    debug!("Scrape URL: {}", url);
+
+    let resp_bytes = gunzip(client.get(&url).await?)?;
+    let (_, de_bencoded) = decode_bencoded_value(&resp_bytes)?;
+    debug!("Scrape Response: {}", de_bencoded);
+
+    let dict = match &de_bencoded {
+        BencodedValue::Dict(dict) => dict,
+        _ => return Err(TrackerError::Malformed("not a dict".to_string()).into()),
+    };
+    if let Some(BencodedValue::String(reason)) =
+        dict.get(&BencodedString(b"failure reason".to_vec()))
+    {
+        return Err(TrackerError::Failure(String::from(reason)).into());
+    }
+    let files = match dict.get(&BencodedString(b"files".to_vec())) {
+        Some(BencodedValue::Dict(files)) => files,
+        _ => return Err(TrackerError::Malformed("scrape has no files".to_string()).into()),
+    };
+    let mut results = HashMap::new();
+    for (info_hash, stats) in files {
+        let Ok(info_hash) = <[u8; 20]>::try_from(info_hash.0.as_slice()) else {
+            continue;
+        };
+        let BencodedValue::Dict(stats) = stats else {
+            continue;
+        };
+        let count = |key: &str| match stats.get(&BencodedString(key.as_bytes().to_vec())) {
+            Some(BencodedValue::Integer(i)) => (*i).max(0) as u64,
+            _ => 0,
+        };
+        results.insert(
+            info_hash,
+            ScrapeResult {
+                complete: count("complete"),
+                downloaded: count("downloaded"),
+                incomplete: count("incomplete"),
+            },
+        );
+    }
+    Ok(results)
+}
+
+// Re-announces in the background for as long as a download runs, passing on
+// peers we haven't heard of yet
+pub struct TrackerSession {
+    progress: Arc<Mutex<Progress>>,
+    finish: oneshot::Sender<Event>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl TrackerSession {
+    // `first` is the response to the announce that started the download
+    pub fn spawn(
+        mut trackers: TrackerList,
+        info_hash: [u8; 20],
+        progress: Progress,
+        first: &TrackerResponse,
+    ) -> (Self, mpsc::UnboundedReceiver<SocketAddr>) {
+        let progress = Arc::new(Mutex::new(progress));
+        let (finish, mut finished) = oneshot::channel::<Event>();
+        let (new_peers, peer_rx) = mpsc::unbounded_channel();
+        let mut known: HashSet<SocketAddr> = first.peers.iter().copied().collect();
+        let mut delay = Self::reannounce_delay(first);
+
+        let counters = progress.clone();
+        let handle = tokio::spawn(async move {
+            let event = loop {
+                tokio::select! {
+                    event = &mut finished => break event.unwrap_or(Event::Stopped),
+                    _ = tokio::time::sleep(delay) => {
+                        let progress = *counters.lock().unwrap();
+                        match trackers.announce_event(info_hash, progress, Event::None).await {
+                            Ok((tracker_response, _)) => {
+                                delay = Self::reannounce_delay(&tracker_response);
+                                for peer in tracker_response.peers {
+                                    if known.insert(peer) {
+                                        debug!("New peer from tracker: {}", peer);
+                                        // The download may already be done with its peers
+                                        let _ = new_peers.send(peer);
+                                    }
+                                }
+                            }
+                            Err(e) => warn!("Re-announce failed: {}", e),
+                        }
+                    }
+                }
+            };
+            let progress = *counters.lock().unwrap();
+            trackers.announce_event(info_hash, progress, event).await?;
+            Ok(())
+        });
+
+        (
+            TrackerSession {
+                progress,
+                finish,
+                handle,
+            },
+            peer_rx,
+        )
+    }
+
+    // Never sooner than `min interval`, and never in a busy loop
+    pub fn reannounce_delay(tracker_response: &TrackerResponse) -> Duration {
+        let secs = tracker_response
+            .interval
+            .max(tracker_response.min_interval.unwrap_or(0))
+            .max(1);
+        Duration::from_secs(secs)
+    }
+
+    // Shared counters, updated by the download & sent on the next announce
+    pub fn progress(&self) -> Arc<Mutex<Progress>> {
+        self.progress.clone()
+    }
+
+    // Stop re-announcing & send a final `completed` or `stopped` event
+    pub async fn finish(self, event: Event) -> Result<(), Error> {
+        // The task only ends early if it panicked, which join reports below
+        let _ = self.finish.send(event);
+        self.handle.await?
+    }
+
+    pub async fn stop(self) -> Result<(), Error> {
+        self.finish(Event::Stopped).await
+    }
+}
+
+// Add already-encoded `query` after any query the URL has, e.g. a private tracker's
+// passkey, keeping the fragment at the end
+pub fn append_query(url: &str, query: &str) -> Result<String, Error> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid URL {}: {}", url, e))?;
+    let existing = url.query().unwrap_or_default().trim_end_matches('&');
+    let query = if existing.is_empty() {
+        query.to_string()
+    } else {
+        format!("{}&{}", existing, query)
+    };
+    url.set_query(Some(&query));
+    Ok(url.into())
+}
+
+// Query parameters private trackers use to identify the user
+const SECRET_PARAMS: [&str; 5] = ["passkey", "authkey", "pk", "key", "token"];
+
+// The URL with anything that looks like a passkey blanked out, for logs & errors.
+// Catches both ?passkey=... and the /<passkey>/announce style.
+pub fn redact_url(url: &str) -> String {
+    let mut url = match reqwest::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return "<invalid URL>".to_string(),
+    };
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if SECRET_PARAMS.contains(&name.as_ref()) {
+                    "REDACTED".to_string()
+                } else {
+                    value.into_owned()
+                };
+                (name.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    let segments: Option<Vec<String>> = url.path_segments().map(|segments| {
+        segments
+            .map(|segment| {
+                if segment.len() >= 16 && segment.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    "REDACTED".to_string()
+                } else {
+                    segment.to_string()
+                }
+            })
+            .collect()
+    });
+    if let (Some(segments), Ok(mut path)) = (segments, url.path_segments_mut()) {
+        path.clear().extend(segments);
+    }
+    url.into()
+}
+
+pub fn url_encode(t: &[u8; 20]) -> anyhow::Result<String> {
+    let mut s = String::new();
+    for b in t {
+        s.push('%');
+        s.push_str(&format!("{:02x}", b));
+    }
+    Ok(s)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PeerMessage {
+    // Just a zero length prefix, sent every couple of minutes to keep the connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    // The index of a piece the peer just finished
+    Have(u32),
+    Bitfield(Bitfield),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        // Usually CHUNK_SIZE bytes, but the last block of a piece can be shorter
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // Fast Extension (BEP 6), only sent by peers that set the fast reserved bit
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    // The UDP port of the peer's DHT node (BEP 5)
+    Port(u16),
+    // BEP 10: `ext_id` 0 is the extended handshake, others are as the receiver numbered them
+    Extended {
+        ext_id: u8,
+        payload: Vec<u8>,
+    },
+    // Anything we don't speak
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl TryFrom<Vec<u8>> for PeerMessage {
+    type Error = MessageError;
+
+    // Ids we don't know come out as Unknown; only known ones cut short are errors
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        if value.len() <= 4 {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        let (id, payload) = (value[4], &value[5..]);
+        let malformed = MessageError::Malformed {
+            id,
+            length: payload.len(),
+        };
+        // Every known message but Bitfield & Piece has a fixed size
+        let expected = match id {
+            0..=3 | 0x0e | 0x0f => Some(0),
+            4 | 0x0d | 0x11 => Some(4),
+            9 => Some(2),
+            6 | 8 | 0x10 => Some(12),
+            7 if payload.len() < 8 => return Err(malformed),
+            EXTENDED_MESSAGE_ID if payload.is_empty() => return Err(malformed),
+            _ => None,
+        };
+        if expected.is_some_and(|expected| payload.len() != expected) {
+            return Err(malformed);
+        }
+        let field = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().unwrap());
+        Ok(match id {
+            0 => PeerMessage::Choke,
+            1 => PeerMessage::Unchoke,
+            2 => PeerMessage::Interested,
+            3 => PeerMessage::NotInterested,
+            4 => PeerMessage::Have(field(0)),
+            5 => PeerMessage::Bitfield(Bitfield::from(payload)),
+            6 => PeerMessage::Request {
+                index: field(0),
+                begin: field(4),
+                length: field(8),
+            },
+            // The block is whatever's past index & begin
+            7 => PeerMessage::Piece {
+                index: field(0),
+                begin: field(4),
+                block: payload[8..].to_vec(),
+            },
+            8 => PeerMessage::Cancel {
+                index: field(0),
+                begin: field(4),
+                length: field(8),
+            },
+            9 => PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]])),
+            0x0d => PeerMessage::SuggestPiece { index: field(0) },
+            0x0e => PeerMessage::HaveAll,
+            0x0f => PeerMessage::HaveNone,
+            0x10 => PeerMessage::RejectRequest {
+                index: field(0),
+                begin: field(4),
+                length: field(8),
+            },
+            0x11 => PeerMessage::AllowedFast { index: field(0) },
+            EXTENDED_MESSAGE_ID => PeerMessage::Extended {
+                ext_id: payload[0],
+                payload: payload[1..].to_vec(),
+            },
+            _ => PeerMessage::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        })
+    }
+}
+
+impl From<&PeerMessage> for Vec<u8> {
+    fn from(value: &PeerMessage) -> Self {
+        let mut message: Vec<u8> = Vec::new();
+        match value {
+            PeerMessage::KeepAlive => message.extend(0_u32.to_be_bytes()),
+            PeerMessage::Choke => {
+                let length = 1_u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(0)
+            }
+            PeerMessage::Unchoke => {
+                let length = 1_u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(1)
+            }
+            PeerMessage::Interested => {
+                let length = 1_u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(2)
+            }
+            PeerMessage::NotInterested => {
+                let length = 1_u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(3)
+            }
+            PeerMessage::Have(index) => {
+                let length = 5_u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(4);
+                message.extend(index.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                let length = bitfield.len_bytes() as u32 + 1;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(5);
+                message.extend(bitfield.as_bytes());
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                message.extend(13_u32.to_be_bytes());
+                message.push(6);
+                message.extend(index.to_be_bytes().to_vec());
+                message.extend(begin.to_be_bytes().to_vec());
+                message.extend(length.to_be_bytes().to_vec());
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let length = 9 + block.len() as u32;
+                message.extend(length.to_be_bytes().to_vec());
+                message.push(7);
+                message.extend(index.to_be_bytes().to_vec());
+                message.extend(begin.to_be_bytes().to_vec());
+                message.extend(block.to_vec());
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                message.extend(13_u32.to_be_bytes());
+                message.push(8);
+                message.extend(index.to_be_bytes().to_vec());
+                message.extend(begin.to_be_bytes().to_vec());
+                message.extend(length.to_be_bytes().to_vec());
+            }
+            PeerMessage::Port(port) => {
+                message.extend(3_u32.to_be_bytes());
+                message.push(9);
+                message.extend(port.to_be_bytes());
+            }
+            PeerMessage::SuggestPiece { index } => {
+                message.extend(5_u32.to_be_bytes());
+                message.push(0x0d);
+                message.extend(index.to_be_bytes());
+            }
+            PeerMessage::HaveAll => {
+                message.extend(1_u32.to_be_bytes());
+                message.push(0x0e);
+            }
+            PeerMessage::HaveNone => {
+                message.extend(1_u32.to_be_bytes());
+                message.push(0x0f);
+            }
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                message.extend(13_u32.to_be_bytes());
+                message.push(0x10);
+                message.extend(index.to_be_bytes());
+                message.extend(begin.to_be_bytes());
+                message.extend(length.to_be_bytes());
+            }
+            PeerMessage::AllowedFast { index } => {
+                message.extend(5_u32.to_be_bytes());
+                message.push(0x11);
+                message.extend(index.to_be_bytes());
+            }
+            PeerMessage::Extended { ext_id, payload } => {
+                message.extend((payload.len() as u32 + 2).to_be_bytes());
+                message.push(EXTENDED_MESSAGE_ID);
+                message.push(*ext_id);
+                message.extend(payload);
+            }
+            PeerMessage::Unknown { id, payload } => {
+                message.extend((payload.len() as u32 + 1).to_be_bytes());
+                message.push(*id);
+                message.extend(payload);
+            }
+        }
+        message
+    }
+}
+
+impl Display for PeerMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            PeerMessage::KeepAlive => write!(f, "KeepAlive"),
             PeerMessage::Choke => write!(f, "Choke"),
             PeerMessage::Unchoke => write!(f, "Unchoke"),
             PeerMessage::Interested => write!(f, "Interested"),
             PeerMessage::NotInterested => write!(f, "NotInterested"),
-            PeerMessage::Have => write!(f, "Have"),
+            PeerMessage::Have(index) => write!(f, "Have {{ index: {} }}", index),
             PeerMessage::Bitfield(_) => write!(f, "Bitfield"),
             PeerMessage::Request {
                 index,
                 begin,
                 length,
-            } => write!(
-                f,
-                "Request {{ index: {}, begin: {}, length: {} }}",
-                index, begin, length
-            ),
-            PeerMessage::Piece {
-                index,
-                begin: _,
-                block,
-            } => write!(
-                f,
-                "Piece {{ index: {}, block: {:?}... }}",
-                index,
-                // trim the block to the first 10 bytes
-                &block[..10]
-            ),
-            PeerMessage::Cancel {
+            } => write!(
+                f,
+                "Request {{ index: {}, begin: {}, length: {} }}",
+                index, begin, length
+            ),
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => write!(
+                f,
+                "Piece {{ index: {}, begin: {}, length: {}, block: {:?}... }}",
+                index,
+                begin,
+                block.len(),
+                // trim the block to the first 10 bytes
+                &block[..block.len().min(10)]
+            ),
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "Cancel {{ index: {}, begin: {}, length: {} }}",
+                index, begin, length
+            ),
+            PeerMessage::SuggestPiece { index } => write!(f, "SuggestPiece {{ index: {} }}", index),
+            PeerMessage::HaveAll => write!(f, "HaveAll"),
+            PeerMessage::HaveNone => write!(f, "HaveNone"),
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "RejectRequest {{ index: {}, begin: {}, length: {} }}",
+                index, begin, length
+            ),
+            PeerMessage::AllowedFast { index } => write!(f, "AllowedFast {{ index: {} }}", index),
+            PeerMessage::Port(port) => write!(f, "Port {{ port: {} }}", port),
+            PeerMessage::Extended { ext_id, payload } => write!(
+                f,
+                "Extended {{ ext_id: {}, length: {} }}",
+                ext_id,
+                payload.len()
+            ),
+            PeerMessage::Unknown { id, payload } => {
+                write!(f, "Unknown {{ id: {}, length: {} }}", id, payload.len())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MessageError {
+    #[error("message {id} with a {length} byte payload is malformed")]
+    Malformed { id: u8, length: usize },
+    #[error("peer message of {length} bytes is over the {limit} byte limit")]
+    TooLong { length: u32, limit: u32 },
+    #[error("piece message of {length} bytes is over the {limit} bytes a block can take")]
+    BlockTooLong { length: u32, limit: u32 },
+}
+
+// The prefix is the peer's word, so check it before allocating whatever it claims.
+// We never ask for blocks over CHUNK_SIZE, so a bigger Piece is bogus whatever the
+// overall limit.
+fn check_message_length(length: u32, id: u8, max_message_size: u32) -> Result<(), MessageError> {
+    if length > max_message_size {
+        return Err(MessageError::TooLong {
+            length,
+            limit: max_message_size,
+        });
+    }
+    // Id, index & begin, then the block
+    let block_limit = 9 + CHUNK_SIZE as u32;
+    if id == 7 && length > block_limit {
+        return Err(MessageError::BlockTooLong {
+            length,
+            limit: block_limit,
+        });
+    }
+    Ok(())
+}
+
+// Read one length-prefixed message, refusing any over `max_message_size`
+pub fn read_message<R: Read>(reader: &mut R, max_message_size: u32) -> Result<PeerMessage, Error> {
+    // Read the length prefix
+    let mut length_prefix: [u8; 4] = [0; 4];
+    reader.read_exact(&mut length_prefix)?;
+    let length = u32::from_be_bytes(length_prefix);
+    if length == 0 {
+        return Ok(PeerMessage::KeepAlive);
+    }
+    let mut id = [0; 1];
+    reader.read_exact(&mut id)?;
+    check_message_length(length, id[0], max_message_size)?;
+
+    // Read the payload
+    let mut full_msg: Vec<u8> = vec![0; 4 + length as usize];
+    full_msg[..4].copy_from_slice(&length_prefix);
+    full_msg[4] = id[0];
+    reader.read_exact(&mut full_msg[5..])?;
+    Ok(PeerMessage::try_from(full_msg)?)
+}
+
+// How long to give a peer at each stage before moving on. Half the peers a tracker
+// hands out are dead, so none of these may be unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    pub connect: Duration,
+    // For the peer's handshake to come back
+    pub handshake: Duration,
+    // For any one read or write after that
+    pub read: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        PeerTimeouts {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            handshake: Duration::from_secs(10),
+            read: Duration::from_secs(60),
+        }
+    }
+}
+
+// The byte stream under a PeerStream. Sockets can bound how long a read blocks;
+// streams that can't just ignore it.
+pub trait PeerIo: Read + Write {
+    fn set_read_timeout(&self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PeerIo for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+// What a connection knows about its peer & how it talks to it, whatever carries
+// the bytes. PeerStream & AsyncPeerStream each wrap one & only do the I/O, so the
+// protocol's rules live here once.
+pub struct PeerSession {
+    peer_addr: SocketAddr,
+    state: PeerState,
+    peer_id: PeerId,
+    // Block size this peer accepts, halved each time it rejects or drops a request
+    block_size: u32,
+    // How many block requests to keep in flight
+    window: RequestWindow,
+    max_message_size: u32,
+    // Who to name in the trace of every message sent & received, if tracing
+    trace: Option<String>,
+    timeouts: PeerTimeouts,
+    block_timeout: Duration,
+    choke_timeout: Duration,
+    // What the peer has, from its Bitfield & every Have since
+    bitfield: Bitfield,
+    // Checked against the peer's Bitfield when set
+    num_pieces: Option<usize>,
+    // A message read while waiting for a Bitfield that never came; the next read hands it out
+    pending: Option<PeerMessage>,
+    // Our DHT node's port, advertised in the handshake & sent to peers with DHT
+    dht_port: Option<u16>,
+    // The peer's DHT node's port, once it sends a Port
+    peer_dht_port: Option<u16>,
+    // From the peer's extended handshake, if it sent one
+    extensions: Option<PeerExtensions>,
+    // Off for private torrents: we neither offer ut_pex nor listen to it
+    pex: bool,
+    // ut_pex messages from the peer, until take_pex
+    pex_messages: Vec<PexMessage>,
+}
+
+// A connection to one peer. Generic over the byte stream so the protocol can be
+// driven without sockets; in practice it's a TcpStream.
+pub struct PeerStream<S = TcpStream> {
+    stream: Throttled<S>,
+    session: PeerSession,
+}
+
+// Where a connection stands: whether the handshake is done & the Bitfield's
+// chance is past, plus the four flags from the spec. Both sides start out
+// choking & not interested; every message sent & received keeps them current.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    pub handshaken: bool,
+    // A message we know, other than a keep-alive, came in, so a Bitfield now is out
+    // of order
+    pub bitfield_seen: bool,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StateError {
+    #[error("not handshaken yet")]
+    NotHandshaken,
+    #[error("already handshaken")]
+    AlreadyHandshaken,
+    #[error("bitfield is only allowed right after the handshake")]
+    LateBitfield,
+    #[error("not interested in the peer")]
+    NotInterested,
+    #[error("choked by the peer")]
+    Choked,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState {
+            handshaken: false,
+            bitfield_seen: false,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl PeerState {
+    pub fn handshake(&mut self) -> Result<(), StateError> {
+        if self.handshaken {
+            return Err(StateError::AlreadyHandshaken);
+        }
+        self.handshaken = true;
+        Ok(())
+    }
+
+    pub fn check_handshaken(&self) -> Result<(), StateError> {
+        match self.handshaken {
+            true => Ok(()),
+            false => Err(StateError::NotHandshaken),
+        }
+    }
+
+    // Nothing but keep-alives & unknown messages since the handshake
+    pub fn expect_bitfield(&self) -> Result<(), StateError> {
+        self.check_handshaken()?;
+        match self.bitfield_seen {
+            true => Err(StateError::LateBitfield),
+            false => Ok(()),
+        }
+    }
+
+    // Requests only get answered while we're interested & unchoked
+    pub fn can_request(&self) -> Result<(), StateError> {
+        self.check_handshaken()?;
+        if !self.am_interested {
+            return Err(StateError::NotInterested);
+        }
+        if self.peer_choking {
+            return Err(StateError::Choked);
+        }
+        Ok(())
+    }
+
+    pub fn received(&mut self, message: &PeerMessage) -> Result<(), StateError> {
+        self.check_handshaken()?;
+        match message {
+            // Extensions may get in ahead of the Bitfield
+            PeerMessage::KeepAlive | PeerMessage::Extended { .. } | PeerMessage::Unknown { .. } => {
+                return Ok(())
+            }
+            PeerMessage::Bitfield(_) if self.bitfield_seen => return Err(StateError::LateBitfield),
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+        self.bitfield_seen = true;
+        Ok(())
+    }
+
+    pub fn sent(&mut self, message: &PeerMessage) -> Result<(), StateError> {
+        self.check_handshaken()?;
+        match message {
+            PeerMessage::Request { .. } => self.can_request()?,
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl PeerSession {
+    fn new(peer_addr: SocketAddr) -> Self {
+        PeerSession {
+            peer_addr,
+            state: PeerState::default(),
+            peer_id: PeerId::local(),
+            block_size: CHUNK_SIZE as u32,
+            window: RequestWindow::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            trace: None,
+            timeouts: PeerTimeouts::default(),
+            block_timeout: DEFAULT_BLOCK_TIMEOUT,
+            choke_timeout: DEFAULT_CHOKE_TIMEOUT,
+            bitfield: Bitfield::default(),
+            num_pieces: None,
+            pending: None,
+            dht_port: None,
+            peer_dht_port: None,
+            extensions: None,
+            pex: true,
+            pex_messages: Vec::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: u32) {
+        self.max_message_size = max_message_size;
+    }
+
+    pub fn set_peer_id(&mut self, peer_id: PeerId) {
+        self.peer_id = peer_id;
+    }
+
+    // Takes effect from the handshake on
+    pub fn set_dht_port(&mut self, port: u16) {
+        self.dht_port = Some(port);
+    }
+
+    pub fn peer_dht_port(&self) -> Option<u16> {
+        self.peer_dht_port
+    }
+
+    pub fn peer_extensions(&self) -> Option<&PeerExtensions> {
+        self.extensions.as_ref()
+    }
+
+    // Takes effect from the handshake on
+    pub fn set_pex(&mut self, pex: bool) {
+        self.pex = pex;
+    }
+
+    // Whether we can send the peer ut_pex messages
+    pub fn supports_pex(&self) -> bool {
+        self.pex_id().is_some()
+    }
+
+    fn pex_id(&self) -> Option<u8> {
+        match self.pex {
+            true => self.extensions.as_ref()?.id(UT_PEX),
+            false => None,
+        }
+    }
+
+    // The ut_pex messages read since the last call
+    pub fn take_pex(&mut self) -> Vec<PexMessage> {
+        std::mem::take(&mut self.pex_messages)
+    }
+
+    // `message` as the peer's ut_pex extension message
+    fn pex_extended(&self, message: &PexMessage) -> Result<PeerMessage, Error> {
+        let ext_id = self
+            .pex_id()
+            .ok_or_else(|| anyhow!("{} doesn't support ut_pex", self.peer_addr))?;
+        Ok(PeerMessage::Extended {
+            ext_id,
+            payload: message.into(),
+        })
+    }
+
+    pub fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.num_pieces = Some(num_pieces);
+    }
+
+    pub fn peer_has_piece(&self, index: u32) -> bool {
+        self.bitfield.has_piece(index as usize)
+    }
+
+    // Its Bitfield & every Have since
+    pub fn peer_bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    // Read deadlines for a stream that can't set them on its socket; PeerStream
+    // takes its own from connect()
+    pub fn set_timeouts(&mut self, timeouts: PeerTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // A block this late is asked for again; a peer silent for the whole read timeout
+    // is given up on
+    pub fn set_block_timeout(&mut self, block_timeout: Duration) {
+        self.block_timeout = block_timeout;
+    }
+
+    // Halfway through, Interested is sent again in case the peer forgot us
+    pub fn set_choke_timeout(&mut self, choke_timeout: Duration) {
+        self.choke_timeout = choke_timeout;
+    }
+
+    // Whether the peer is choking us: until its first Unchoke, & again whenever
+    // it chokes us mid-download
+    pub fn is_choked(&self) -> bool {
+        self.state.peer_choking
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    // Log every message to & from the peer under TRACE_TARGET, tagged with `label`
+    pub fn set_trace(&mut self, label: impl Into<String>) {
+        self.trace = Some(label.into());
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn set_block_size(&mut self, block_size: u32) {
+        self.block_size = block_size.clamp(MIN_BLOCK_SIZE, CHUNK_SIZE as u32);
+    }
+
+    // How many block requests are kept in flight, see RequestWindow
+    pub fn request_window(&self) -> usize {
+        self.window.size
+    }
+
+    fn shrink_block_size(&mut self) -> bool {
+        shrink_block_size(&mut self.block_size)
+    }
+
+    fn handshake_bytes(&self, info_hash: &[u8; 20]) -> Vec<u8> {
+        our_handshake(info_hash, &self.peer_id, self.dht_port).into()
+    }
+
+    // Once handshakes are swapped: what to send straight away, our DHT port &
+    // extensions to peers that speak them
+    fn greet(&mut self, peer_handshake: &PeerHandshake) -> Result<Vec<PeerMessage>, Error> {
+        self.state.handshake()?;
+        debug!("Peer Handshake: {:?}", peer_handshake);
+        let mut greeting = Vec::new();
+        if let Some(port) = self.dht_port.filter(|_| peer_handshake.supports_dht()) {
+            greeting.push(PeerMessage::Port(port));
+        }
+        if peer_handshake.supports_extensions() {
+            greeting.push(our_extended_handshake(self.pex));
+        }
+        Ok(greeting)
+    }
+
+    // A message held back by an earlier read, due out before anything new
+    fn take_pending(&mut self) -> Result<Option<PeerMessage>, Error> {
+        self.state.check_handshaken()?;
+        Ok(self.pending.take())
+    }
+
+    // Everything a message from the peer tells us, before it's handed out
+    fn received(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        if let Some(label) = &self.trace {
+            info!(target: TRACE_TARGET, "{} <- {}", label, message);
+        }
+        self.state.received(message)?;
+        match message {
+            PeerMessage::Have(index) => self.bitfield.set_piece(*index as usize),
+            PeerMessage::Port(port) => self.peer_dht_port = Some(*port),
+            PeerMessage::Extended { ext_id, payload } if *ext_id == HANDSHAKE_ID => {
+                self.extensions = peer_extensions(self.peer_addr, payload);
+            }
+            PeerMessage::Extended { ext_id, payload } if *ext_id == UT_PEX_ID && self.pex => {
+                self.pex_messages
+                    .extend(pex_message(self.peer_addr, payload));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Errors if `message` isn't ours to send yet
+    fn sending(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        self.state.sent(message)?;
+        if let Some(label) = &self.trace {
+            info!(target: TRACE_TARGET, "{} -> {}", label, message);
+        }
+        Ok(())
+    }
+
+    // The first message after the handshake, when a Bitfield was due. The Bitfield
+    // is optional: a peer with nothing, or that leads with Have or Unchoke, may
+    // skip it. Then it has what its Haves say & the message keeps.
+    fn bitfield_from(&mut self, message: PeerMessage) -> Result<PeerMessage, Error> {
+        match message {
+            PeerMessage::Bitfield(bitfield) => {
+                if let Some(num_pieces) = self.num_pieces {
+                    bitfield.validate(num_pieces)?;
+                }
+                self.bitfield = bitfield.clone();
+                Ok(PeerMessage::Bitfield(bitfield))
+            }
+            message => {
+                debug!("No bitfield from {}, got {}", self.peer_addr, message);
+                self.pending = Some(message);
+                Ok(PeerMessage::Bitfield(self.bitfield.clone()))
+            }
+        }
+    }
+
+    // No use waiting for an Unchoke we never asked for
+    fn expect_unchoke(&self) -> Result<(), StateError> {
+        match self.state.am_interested {
+            true => Ok(()),
+            false => Err(StateError::NotInterested),
+        }
+    }
+
+    // Asking for a piece the peer doesn't have only gets us stalled or dropped
+    fn piece_requests(&self, piece_id: u32, piece_length: i64) -> Result<PieceRequests, Error> {
+        if !self.peer_has_piece(piece_id) {
+            return Err(DownloadError::MissingPiece(piece_id).into());
+        }
+        Ok(PieceRequests::new(piece_id, piece_length, self.block_size))
+    }
+
+    // Requests to send so the window is full
+    fn next_requests(&self, requests: &mut PieceRequests) -> Vec<PeerMessage> {
+        requests.next_requests(self.window.size)
+    }
+
+    // Wait for the piece responses, but only until the next block is late
+    fn read_wait(&self, requests: &PieceRequests, last_heard: Instant) -> Duration {
+        read_wait(requests, self.block_timeout, last_heard, self.timeouts.read)
+    }
+
+    // A message mid-piece, keeping the window in step with how the peer copes
+    fn block_step(
+        &mut self,
+        requests: &mut PieceRequests,
+        message: PeerMessage,
+    ) -> Result<BlockStep, Error> {
+        let step = requests.handle(message, &mut self.block_size)?;
+        match step {
+            BlockStep::Wait => {}
+            BlockStep::Done(_) => self.window.delivered(),
+            BlockStep::Choked => self.window.back_off(),
+        }
+        Ok(step)
+    }
+
+    // Requests to send again, having backed off if there are any
+    fn late_requests(&mut self, requests: &mut PieceRequests) -> Vec<PeerMessage> {
+        let late = requests.late_requests(self.block_timeout);
+        if !late.is_empty() {
+            self.window.back_off();
+        }
+        late
+    }
+}
+
+impl PeerStream {
+    // An unreachable peer is an error after `timeouts.connect`, never a hang
+    pub fn connect(peer_addr: SocketAddr, timeouts: PeerTimeouts) -> Result<Self, Error> {
+        let stream = TcpStream::connect_timeout(&peer_addr, timeouts.connect)
+            .map_err(|e| anyhow!("Could not connect to {}: {}", peer_addr, e))?;
+        stream.set_write_timeout(Some(timeouts.read))?;
+        let mut peer_stream = Self::from_stream(stream, peer_addr);
+        peer_stream.session.timeouts = timeouts;
+        Ok(peer_stream)
+    }
+
+    // Just enough of the protocol to learn which pieces a peer has
+    pub fn fetch_bitfield(peer_addr: SocketAddr, info_hash: &[u8; 20]) -> Result<Bitfield, Error> {
+        let mut peer_stream = Self::connect(peer_addr, PeerTimeouts::default())?;
+        peer_stream.handshake(info_hash)?;
+        peer_stream.read_bitfield()?;
+        Ok(peer_stream.session.bitfield)
+    }
+}
+
+impl<S: PeerIo> PeerStream<S> {
+    // Talk to the peer at `peer_addr` over any byte stream, e.g. a scripted one in tests
+    pub fn from_stream(stream: S, peer_addr: SocketAddr) -> Self {
+        PeerStream {
+            stream: Throttled::new(stream, RateLimits::default()),
+            session: PeerSession::new(peer_addr),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    pub fn write_pex(&mut self, message: &PexMessage) -> Result<(), Error> {
+        let message = self.session.pex_extended(message)?;
+        self.write(&message)
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.stream.set_limits(limits);
+    }
+
+    pub fn handshake(&mut self, info_hash: &[u8; 20]) -> Result<PeerHandshake, Error> {
+        let handshake_bytes = self.session.handshake_bytes(info_hash);
+        self.stream.write_all(&handshake_bytes)?;
+
+        // Read the handshake response, on its own deadline
+        let timeouts = self.session.timeouts;
+        self.stream.get_ref().set_read_timeout(timeouts.handshake)?;
+        let mut buf = [0; 68];
+        let read = self.stream.read_exact(&mut buf);
+        let peer_handshake = handshake_reply(read, &buf, info_hash, self.session.peer_addr)?;
+        self.stream.get_ref().set_read_timeout(timeouts.read)?;
+        for message in self.session.greet(&peer_handshake)? {
+            self.write(&message)?;
+        }
+        Ok(peer_handshake)
+    }
+
+    pub fn read(&mut self) -> Result<PeerMessage, Error> {
+        if let Some(message) = self.session.take_pending()? {
+            return Ok(message);
+        }
+
+        let message = read_message(&mut self.stream, self.session.max_message_size)?;
+        self.session.received(&message)?;
+        Ok(message)
+    }
+
+    // The next message that isn't a keep-alive
+    fn read_skipping_keep_alives(&mut self) -> Result<PeerMessage, Error> {
+        loop {
+            let message = self.read()?;
+            if !skipped(&message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    pub fn write(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        self.session.sending(message)?;
+        let message_bytes: Vec<u8> = message.into();
+        self.stream.write_all(&message_bytes)?;
+        Ok(())
+    }
+
+    // Specific steps
+    pub fn read_bitfield(&mut self) -> Result<PeerMessage, Error> {
+        self.session.state.expect_bitfield()?;
+        let message = self.read_skipping_keep_alives()?;
+        self.session.bitfield_from(message)
+    }
+
+    pub fn write_interested(&mut self) -> Result<(), Error> {
+        self.write(&PeerMessage::Interested)
+    }
+
+    pub fn read_unchoke(&mut self) -> Result<PeerMessage, Error> {
+        self.session.expect_unchoke()?;
+        while !is_unchoke(self.read_skipping_keep_alives()?)? {}
+        Ok(PeerMessage::Unchoke)
+    }
+
+    // Handshake, Bitfield, Interested & Unchoke: everything before the first Request
+    pub fn prep_download(&mut self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        let handshake = self
+            .handshake(info_hash)
+            .inspect_err(|e| warn!("Handshake: Error: {}", e))?;
+        info!("Peer ID: {}", hex::encode(&handshake.peer_id));
+        let bitfield = self
+            .read_bitfield()
+            .inspect_err(|e| warn!("Bitfield: Error: {}", e))?;
+        debug!("Bitfield: {:?}", bitfield);
+        self.write_interested()
+            .inspect_err(|e| warn!("Interested: Error: {}", e))?;
+        self.read_unchoke()
+            .inspect_err(|e| warn!("Unchoke: Error: {}", e))?;
+        debug!("Unchoke: Received");
+        Ok(())
+    }
+
+    // Sit out a choke, dropping whatever else comes in meanwhile
+    // Our requests died with the choke; the caller asks again once this returns
+    fn wait_for_unchoke(&mut self) -> Result<(), Error> {
+        let mut choke = ChokeWait::new(self.session.choke_timeout);
+        let unchoked = loop {
+            if choke.nudge() {
+                self.write(&PeerMessage::Interested)?;
+            }
+            let wait = match choke.wait() {
+                Ok(wait) => wait,
+                Err(e) => break Err(e.into()),
+            };
+            self.stream.get_ref().set_read_timeout(wait)?;
+            match self.read() {
+                Ok(PeerMessage::Unchoke) => break Ok(()),
+                Ok(msg) => debug!("Ignoring {} while choked", msg),
+                Err(e) if is_timeout(&e) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        let timeouts = self.session.timeouts;
+        self.stream.get_ref().set_read_timeout(timeouts.read)?;
+        unchoked
+    }
+
+    // The info dict, over ut_metadata (BEP 9), for a magnet link's info hash. Takes a
+    // fresh connection, handshaking on it.
+    pub fn fetch_metadata(&mut self, info_hash: &[u8; 20]) -> Result<Info, Error> {
+        if !self.handshake(info_hash)?.supports_extensions() {
+            return Err(MetadataError::NotSupported.into());
+        }
+        // The extended handshake follows the handshake, maybe after a Bitfield & Haves
+        let extensions = loop {
+            self.read()?;
+            if let Some(extensions) = &self.session.extensions {
+                break extensions.clone();
+            }
+        };
+        let ext_id = extensions
+            .id(UT_METADATA)
+            .ok_or(MetadataError::NotSupported)?;
+        let mut metadata = MetadataBuffer::new(extensions.metadata_size)?;
+        debug!(
+            "Fetching {} metadata pieces from {}",
+            metadata.num_pieces(),
+            self.session.peer_addr
+        );
+        for piece in 0..metadata.num_pieces() {
+            let request = MetadataMessage::Request { piece };
+            self.write(&PeerMessage::Extended {
+                ext_id,
+                payload: (&request).into(),
+            })?;
+        }
+
+        while !metadata.is_complete() {
+            let payload = match self.read()? {
+                PeerMessage::Extended { ext_id, payload } if ext_id == UT_METADATA_ID => payload,
+                message => {
+                    trace!("Skipping {}", message);
+                    continue;
+                }
+            };
+            match MetadataMessage::try_from(&payload[..])? {
+                MetadataMessage::Data { piece, data, .. } => metadata.add(piece, &data)?,
+                MetadataMessage::Reject { piece } => {
+                    return Err(MetadataError::Rejected(piece).into())
+                }
+                // We've nothing to give yet
+                MetadataMessage::Request { piece } => {
+                    let reject = MetadataMessage::Reject { piece };
+                    self.write(&PeerMessage::Extended {
+                        ext_id,
+                        payload: (&reject).into(),
+                    })?;
+                }
+            }
+        }
+        Ok(Info::from_bytes(&metadata.finish(info_hash)?)?)
+    }
+
+    // The whole piece, exactly piece_length bytes
+    pub fn download_piece(&mut self, piece_id: u32, piece_length: &i64) -> Result<Vec<u8>, Error> {
+        // Pipelined & re-requested blocks arrive in any order, so each goes by its offset
+        let mut piece = vec![0; *piece_length as usize];
+        let mut received = 0;
+        self.download_blocks(piece_id, *piece_length, |block| {
+            received += place_block(&mut piece, &block)?;
+            Ok(())
+        })?;
+        check_piece_length(piece_id, received, *piece_length)?;
+        Ok(piece)
+    }
+
+    // Request every block of the piece, handing each to `on_block` as it arrives
+    fn download_blocks(
+        &mut self,
+        piece_id: u32,
+        piece_length: i64,
+        mut on_block: impl FnMut(Block) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        // Choked since the last piece: sit it out before asking
+        match self.session.state.can_request() {
+            Err(StateError::Choked) => self.wait_for_unchoke()?,
+            state => state?,
+        }
+        let mut requests = self.session.piece_requests(piece_id, piece_length)?;
+        let mut last_heard = Instant::now();
+        loop {
+            for req in self.session.next_requests(&mut requests) {
+                debug!("{}", req);
+                self.write(&req)?;
+            }
+            if requests.is_done() {
+                return Ok(());
+            }
+
+            let timeouts = self.session.timeouts;
+            let wait = self.session.read_wait(&requests, last_heard);
+            self.stream.get_ref().set_read_timeout(wait)?;
+            let read = self.read();
+            self.stream.get_ref().set_read_timeout(timeouts.read)?;
+            let step = match read {
+                Ok(message) => {
+                    last_heard = Instant::now();
+                    self.session.block_step(&mut requests, message)?
+                }
+                // Only a block is late, so ask for it again below
+                Err(e) if is_timeout(&e) && last_heard.elapsed() < timeouts.read => BlockStep::Wait,
+                // Timed out or hung up on, maybe over the block size;
+                // the next connection to this peer starts smaller
+                Err(e) => {
+                    self.session.shrink_block_size();
+                    return Err(e);
+                }
+            };
+            match step {
+                BlockStep::Wait => {}
+                BlockStep::Done(block) => on_block(block)?,
+                BlockStep::Choked => self.wait_for_unchoke()?,
+            }
+            for req in self.session.late_requests(&mut requests) {
+                self.write(&req)?;
+            }
+        }
+    }
+}
+
+impl<S: PeerIo + Send + 'static> PeerStream<S> {
+    // Like download_piece, but yields each block as it arrives. The download runs off
+    // the async runtime; BlockStream::finish hands the connection back.
+    pub fn async_download_piece(mut self, piece_id: u32, piece_length: i64) -> BlockStream<S> {
+        let (tx, rx) = mpsc::channel(16);
+        let task = tokio::task::spawn_blocking(move || {
+            let downloaded = self.download_blocks(piece_id, piece_length, |block| {
+                tx.blocking_send(Ok(block))
+                    .map_err(|_| anyhow!("Block stream was dropped"))
+            });
+            if let Err(e) = downloaded {
+                let _ = tx.blocking_send(Err(e));
+            }
+            self
+        });
+        BlockStream {
+            blocks: ReceiverStream::new(rx),
+            task,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("peer doesn't have piece {0}")]
+    MissingPiece(u32),
+    #[error("peer kept us choked for over {0:?}")]
+    ChokedTooLong(Duration),
+}
+
+// Flagging extensions, & DHT if we run a node
+fn our_handshake(info_hash: &[u8; 20], peer_id: &PeerId, dht_port: Option<u16>) -> PeerHandshake {
+    let mut handshake = PeerHandshake::new(info_hash.to_vec(), peer_id.as_bytes().to_vec());
+    handshake.set_reserved_bit(5, 0x10);
+    match dht_port {
+        Some(_) => handshake.with_dht(),
+        None => handshake,
+    }
+}
+
+// Leaving out ut_pex when it's off
+fn our_extended_handshake(pex: bool) -> PeerMessage {
+    let extensions: Vec<(&str, u8)> = OUR_EXTENSIONS
+        .iter()
+        .copied()
+        .filter(|&(name, _)| pex || name != UT_PEX)
+        .collect();
+    PeerMessage::Extended {
+        ext_id: HANDSHAKE_ID,
+        payload: extension::handshake_payload(&extensions),
+    }
+}
+
+// A garbled extended handshake only costs us the peer's extensions
+fn peer_extensions(peer_addr: SocketAddr, payload: &[u8]) -> Option<PeerExtensions> {
+    PeerExtensions::try_from(payload)
+        .inspect_err(|e| debug!("Ignoring extended handshake from {}: {}", peer_addr, e))
+        .ok()
+}
+
+// Nor is a garbled ut_pex message worth dropping the peer over
+fn pex_message(peer_addr: SocketAddr, payload: &[u8]) -> Option<PexMessage> {
+    PexMessage::try_from(payload)
+        .inspect_err(|e| debug!("Ignoring ut_pex from {}: {}", peer_addr, e))
+        .ok()
+}
+
+// A read that gave up waiting, rather than one that failed
+fn is_timeout(e: &Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    })
+}
+
+// How long the next read may block: until the first block in flight is late, or
+// the peer has been silent for `silent_limit`, whichever comes first
+fn read_wait(
+    requests: &PieceRequests,
+    block_timeout: Duration,
+    last_heard: Instant,
+    silent_limit: Duration,
+) -> Duration {
+    let now = Instant::now();
+    let mut wait = (last_heard + silent_limit).saturating_duration_since(now);
+    if let Some(deadline) = requests.next_deadline(block_timeout) {
+        wait = wait.min(deadline.saturating_duration_since(now));
+    }
+    // A zero timeout means none at all to a socket
+    wait.max(Duration::from_millis(1))
+}
+
+// Halve a block size; false once it's at MIN_BLOCK_SIZE already
+fn shrink_block_size(block_size: &mut u32) -> bool {
+    if *block_size <= MIN_BLOCK_SIZE {
+        return false;
+    }
+    *block_size = (*block_size / 2).clamp(MIN_BLOCK_SIZE, CHUNK_SIZE as u32);
+    debug!("Block size down to {}", block_size);
+    true
+}
+
+// Keep-alives & messages only worth noting in passing, which a read waiting on
+// something in particular passes over
+fn skipped(message: &PeerMessage) -> bool {
+    match message {
+        PeerMessage::KeepAlive => debug!("Keep-alive"),
+        PeerMessage::Extended { .. } | PeerMessage::Unknown { .. } => {
+            trace!("Skipping {}", message)
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Whether the wait for an Unchoke is over. Haves are already in the bitfield &
+// Ports recorded, so they're passed over; anything else is out of order.
+fn is_unchoke(message: PeerMessage) -> Result<bool, Error> {
+    match message {
+        PeerMessage::Unchoke => Ok(true),
+        PeerMessage::Have(_) | PeerMessage::Port(_) => Ok(false),
+        _ => Err(anyhow!("Expected unchoke message")),
+    }
+}
+
+// Sitting out a choke: Interested again halfway through, in case the peer forgot
+// us, & giving up at the choke timeout
+struct ChokeWait {
+    started: Instant,
+    choke_timeout: Duration,
+    nudged: bool,
+}
+
+impl ChokeWait {
+    fn new(choke_timeout: Duration) -> Self {
+        ChokeWait {
+            started: Instant::now(),
+            choke_timeout,
+            nudged: false,
+        }
+    }
+
+    // Whether it's time to send Interested again
+    fn nudge(&mut self) -> bool {
+        let waited = self.started.elapsed();
+        if self.nudged || waited < self.choke_timeout / 2 {
+            return false;
+        }
+        debug!("Still choked after {:?}, sending Interested again", waited);
+        self.nudged = true;
+        true
+    }
+
+    // How long the next read may wait for the Unchoke
+    fn wait(&self) -> Result<Duration, DownloadError> {
+        let waited = self.started.elapsed();
+        if waited >= self.choke_timeout {
+            return Err(DownloadError::ChokedTooLong(self.choke_timeout));
+        }
+        let until = match self.nudged {
+            true => self.choke_timeout,
+            false => self.choke_timeout / 2,
+        };
+        // A zero timeout means none at all to a socket
+        Ok(until.saturating_sub(waited).max(Duration::from_millis(1)))
+    }
+}
+
+// What to do after a message from the peer, mid-piece
+enum BlockStep {
+    // Keep waiting for the blocks in flight, asking for more if the window allows
+    Wait,
+    Done(Block),
+    // Sit out the choke, then ask again for what was in flight
+    Choked,
+}
+
+// How many requests we keep outstanding with a peer. Too many overflow its queue
+// & get us choked, so it starts small, grows by one for each window's worth of
+// blocks that arrive cleanly & halves when the peer chokes us or a block is late.
+#[derive(Debug, Clone, Copy)]
+struct RequestWindow {
+    size: usize,
+    // Blocks in a row since the last change
+    delivered: usize,
+}
+
+const INITIAL_REQUEST_WINDOW: usize = 5;
+const MAX_REQUEST_WINDOW: usize = 64;
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        RequestWindow {
+            size: INITIAL_REQUEST_WINDOW,
+            delivered: 0,
+        }
+    }
+}
+
+impl RequestWindow {
+    fn delivered(&mut self) {
+        self.delivered += 1;
+        if self.delivered >= self.size && self.size < MAX_REQUEST_WINDOW {
+            self.size += 1;
+            self.delivered = 0;
+        }
+    }
+
+    fn back_off(&mut self) {
+        self.size = (self.size / 2).max(1);
+        self.delivered = 0;
+        debug!("Request window down to {}", self.size);
+    }
+}
+
+// The requests for one piece: which blocks are left & what each message means for
+// the ones in flight. Shared by the blocking & async streams, which just do the I/O.
+struct PieceRequests {
+    piece_id: u32,
+    // Blocks still to request, covering the piece
+    pending: VecDeque<(u32, u32)>,
+    // Blocks asked for & not yet answered, oldest first, with when they were asked for
+    in_flight: VecDeque<(u32, u32, Instant)>,
+    rejected: HashSet<u32>,
+}
+
+impl PieceRequests {
+    fn new(piece_id: u32, piece_length: i64, block_size: u32) -> Self {
+        let pending: VecDeque<(u32, u32)> = blocks_with(piece_length, block_size).into();
+        debug!("piece_length: {}, n_reqs: {}", piece_length, pending.len());
+        PieceRequests {
+            piece_id,
+            pending,
+            in_flight: VecDeque::new(),
+            rejected: HashSet::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pending.is_empty() && self.in_flight.is_empty()
+    }
+
+    // Requests to send so that `window` are in flight
+    fn next_requests(&mut self, window: usize) -> Vec<PeerMessage> {
+        let mut requests = Vec::new();
+        while self.in_flight.len() < window {
+            let Some((begin, length)) = self.pending.pop_front() else {
+                break;
+            };
+            self.in_flight.push_back((begin, length, Instant::now()));
+            requests.push(PeerMessage::Request {
+                index: self.piece_id,
+                begin,
+                length,
+            });
+        }
+        requests
+    }
+
+    // Requests outstanding for `timeout` or more, to be sent again
+    fn late_requests(&mut self, timeout: Duration) -> Vec<PeerMessage> {
+        let piece_id = self.piece_id;
+        self.in_flight
+            .iter_mut()
+            .filter(|(_, _, requested_at)| requested_at.elapsed() >= timeout)
+            .map(|(begin, length, requested_at)| {
+                debug!(
+                    "Block {} of piece {} is late, re-requesting",
+                    begin, piece_id
+                );
+                *requested_at = Instant::now();
+                PeerMessage::Request {
+                    index: piece_id,
+                    begin: *begin,
+                    length: *length,
+                }
+            })
+            .collect()
+    }
+
+    // When the first of the blocks in flight will be late
+    fn next_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.in_flight
+            .iter()
+            .map(|(_, _, requested_at)| *requested_at + timeout)
+            .min()
+    }
+
+    // The in-flight block at `begin`, taken out of flight
+    fn answered(&mut self, index: u32, begin: u32) -> Option<(u32, u32)> {
+        if index != self.piece_id {
+            return None;
+        }
+        let at = self
+            .in_flight
+            .iter()
+            .position(|&(got, _, _)| got == begin)?;
+        self.in_flight
+            .remove(at)
+            .map(|(begin, length, _)| (begin, length))
+    }
+
+    // `block_size` shrinks if the peer turns down blocks that big
+    fn handle(&mut self, message: PeerMessage, block_size: &mut u32) -> Result<BlockStep, Error> {
+        let piece_id = self.piece_id;
+        match message {
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // Answers to requests we already gave up on
+                let Some((begin, length)) = self.answered(index, begin) else {
+                    debug!("Dropping unrequested block {} of piece {}", begin, index);
+                    return Ok(BlockStep::Wait);
+                };
+                if block.len() != length as usize {
+                    return Err(anyhow!(
+                        "Peer sent {} bytes for block {} of piece {}, asked for {}",
+                        block.len(),
+                        begin,
+                        piece_id,
+                        length
+                    ));
+                }
+                return Ok(BlockStep::Done(Block {
+                    index: piece_id,
+                    begin,
+                    data: block,
+                }));
+            }
+            PeerMessage::RejectRequest { index, begin, .. } => {
+                let Some((begin, length)) = self.answered(index, begin) else {
+                    debug!("Ignoring reject of block {} of piece {}", begin, index);
+                    return Ok(BlockStep::Wait);
+                };
+                return self.rejected(begin, length, block_size);
+            }
+            // A choking peer drops our requests, so ask again once unchoked
+            PeerMessage::Choke => {
+                debug!(
+                    "Choked with {} blocks of piece {} in flight",
+                    self.in_flight.len(),
+                    piece_id
+                );
+                for (begin, length, _) in self.in_flight.drain(..).rev() {
+                    self.pending.push_front((begin, length));
+                }
+                return Ok(BlockStep::Choked);
+            }
+            msg @ (PeerMessage::KeepAlive
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::Have(_)
+            | PeerMessage::Request { .. }
+            | PeerMessage::Cancel { .. }
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::SuggestPiece { .. }
+            | PeerMessage::AllowedFast { .. }
+            | PeerMessage::Port(_)
+            | PeerMessage::Extended { .. }
+            | PeerMessage::Unknown { .. }) => debug!("Ignoring {}", msg),
+            msg @ PeerMessage::Bitfield(_) => {
+                return Err(anyhow!(
+                    "Unexpected {} while downloading piece {}",
+                    msg,
+                    piece_id
+                ))
+            }
+        }
+        Ok(BlockStep::Wait)
+    }
+
+    fn rejected(
+        &mut self,
+        begin: u32,
+        length: u32,
+        block_size: &mut u32,
+    ) -> Result<BlockStep, Error> {
+        let piece_id = self.piece_id;
+        // Some peers reject blocks over their limit: halve the block size
+        // & split what's left to match
+        if length > MIN_BLOCK_SIZE && shrink_block_size(block_size) {
+            let block_size = *block_size;
+            self.pending.push_front((begin, length));
+            self.pending = self
+                .pending
+                .drain(..)
+                .flat_map(|(begin, length)| {
+                    blocks_with(length.into(), block_size)
+                        .into_iter()
+                        .map(move |(offset, length)| (begin + offset, length))
+                })
+                .collect();
+            return Ok(BlockStep::Wait);
+        }
+        // At the smallest block size, ask once more before giving up
+        if !self.rejected.insert(begin) {
+            return Err(anyhow!(
+                "Peer rejected block {} of piece {} twice",
+                begin,
+                piece_id
+            ));
+        }
+        debug!(
+            "Block {} of piece {} rejected, re-requesting",
+            begin, piece_id
+        );
+        self.pending.push_back((begin, length));
+        Ok(BlockStep::Wait)
+    }
+}
+
+// One block of a piece, as carried by PeerMessage::Piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub data: Vec<u8>,
+}
+
+// Copy a block to its offset in the piece, returning how many bytes it filled
+fn place_block(piece: &mut [u8], block: &Block) -> Result<usize, Error> {
+    let begin = block.begin as usize;
+    let Some(slot) = piece.get_mut(begin..begin + block.data.len()) else {
+        return Err(anyhow!(
+            "Block {} of piece {} runs past its end at {} bytes",
+            block.begin,
+            block.index,
+            piece.len()
+        ));
+    };
+    slot.copy_from_slice(&block.data);
+    Ok(block.data.len())
+}
+
+fn check_piece_length(piece_id: u32, received: usize, piece_length: i64) -> Result<(), Error> {
+    if received as i64 != piece_length {
+        return Err(anyhow!(
+            "Piece {} came to {} bytes, expected {}",
+            piece_id,
+            received,
+            piece_length
+        ));
+    }
+    Ok(())
+}
+
+// Blocks of a piece in the order they arrive, see PeerStream::async_download_piece.
+// A failed download ends the stream with its error.
+pub struct BlockStream<S> {
+    blocks: ReceiverStream<Result<Block, Error>>,
+    task: JoinHandle<PeerStream<S>>,
+}
+
+impl<S> Stream for BlockStream<S> {
+    type Item = Result<Block, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.blocks).poll_next(cx)
+    }
+}
+
+impl<S> BlockStream<S> {
+    // The connection back, once the download is over. Stops it if it's still going.
+    pub async fn finish(self) -> Result<PeerStream<S>, Error> {
+        drop(self.blocks);
+        Ok(self.task.await?)
+    }
+}
+
+// PeerStream on tokio, so talking to a peer doesn't tie up a runtime thread & many
+// peers can be worked at once. Same PeerSession underneath; rate limits are applied
+// a message at a time.
+pub struct AsyncPeerStream<S = tokio::net::TcpStream> {
+    stream: S,
+    limits: RateLimits,
+    session: PeerSession,
+    // What's come in of the next message, so a read given up on loses nothing
+    read_buf: Vec<u8>,
+    // When we last wrote & heard anything, for keep_alive()
+    last_sent: tokio::time::Instant,
+    last_received: tokio::time::Instant,
+    keep_alive_interval: Duration,
+    idle_timeout: Duration,
+}
+
+impl AsyncPeerStream {
+    pub async fn connect(peer_addr: SocketAddr, connect_timeout: Duration) -> Result<Self, Error> {
+        let stream =
+            tokio::time::timeout(connect_timeout, tokio::net::TcpStream::connect(peer_addr))
+                .await
+                .map_err(|_| anyhow!("Timed out connecting to {}", peer_addr))??;
+        Ok(Self::from_stream(stream, peer_addr))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncPeerStream<S> {
+    pub fn from_stream(stream: S, peer_addr: SocketAddr) -> Self {
+        AsyncPeerStream {
+            stream,
+            limits: RateLimits::default(),
+            session: PeerSession::new(peer_addr),
+            read_buf: Vec::new(),
+            last_sent: tokio::time::Instant::now(),
+            last_received: tokio::time::Instant::now(),
+            keep_alive_interval: Duration::from_secs(rand::random_range(KEEP_ALIVE_SECS)),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub async fn write_pex(&mut self, message: &PexMessage) -> Result<(), Error> {
+        let message = self.session.pex_extended(message)?;
+        self.write(&message).await
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_keep_alive_interval(&mut self, keep_alive_interval: Duration) {
+        self.keep_alive_interval = keep_alive_interval;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    // For a connection with nothing else to do: a keep-alive if we've been quiet for
+    // the keep-alive interval, or an error if the peer's been silent for the idle
+    // timeout. Returns how long until it's worth calling again.
+    pub async fn keep_alive(&mut self) -> Result<Duration, Error> {
+        let silent = self.last_received.elapsed();
+        if silent >= self.idle_timeout {
+            return Err(anyhow!(
+                "Nothing from {} for {:?}",
+                self.session.peer_addr,
+                silent
+            ));
+        }
+        if self.last_sent.elapsed() >= self.keep_alive_interval {
+            self.write(&PeerMessage::KeepAlive).await?;
+        }
+        let until_keep_alive = self
+            .keep_alive_interval
+            .saturating_sub(self.last_sent.elapsed());
+        Ok(until_keep_alive.min(self.idle_timeout - silent))
+    }
+
+    pub async fn handshake(&mut self, info_hash: &[u8; 20]) -> Result<PeerHandshake, Error> {
+        let handshake_bytes = self.session.handshake_bytes(info_hash);
+        self.stream.write_all(&handshake_bytes).await?;
+
+        let mut buf = [0; 68];
+        let read = self.read_handshake(&mut buf).await;
+        let peer_handshake = handshake_reply(read, &buf, info_hash, self.session.peer_addr)?;
+        self.greet(&peer_handshake).await?;
+        Ok(peer_handshake)
+    }
+
+    // The other end of handshake(), for a peer that connected to us: its handshake
+    // comes first, & is only answered if we `serve` the torrent it asks for
+    pub async fn accept_handshake(
+        &mut self,
+        serves: impl Fn(&[u8; 20]) -> bool,
+    ) -> Result<PeerHandshake, Error> {
+        let mut buf = [0; 68];
+        let read = self.read_handshake(&mut buf).await;
+        let peer_handshake = peer_handshake(read, &buf, self.session.peer_addr)?;
+        let info_hash: [u8; 20] = peer_handshake.info_hash().try_into()?;
+        if !serves(&info_hash) {
+            return Err(HandshakeError::NotServed(info_hash).into());
+        }
+
+        let handshake_bytes = self.session.handshake_bytes(&info_hash);
+        self.stream.write_all(&handshake_bytes).await?;
+        self.greet(&peer_handshake).await?;
+        Ok(peer_handshake)
+    }
+
+    // The peer's handshake, on its own deadline
+    async fn read_handshake(&mut self, buf: &mut [u8; 68]) -> std::io::Result<()> {
+        let deadline = self.session.timeouts.handshake;
+        match tokio::time::timeout(deadline, self.stream.read_exact(buf)).await {
+            Ok(read) => read.map(|_| ()),
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn greet(&mut self, peer_handshake: &PeerHandshake) -> Result<(), Error> {
+        for message in self.session.greet(peer_handshake)? {
+            self.write(&message).await?;
+        }
+        Ok(())
+    }
+
+    // Cancel safe, so it can be raced against a timer or another channel
+    pub async fn read(&mut self) -> Result<PeerMessage, Error> {
+        if let Some(message) = self.session.take_pending()? {
+            return Ok(message);
+        }
+
+        let full_msg = loop {
+            if let Some(full_msg) = self.buffered_message()? {
+                break full_msg;
+            }
+            if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        };
+        self.last_received = tokio::time::Instant::now();
+        let wait = self
+            .limits
+            .download
+            .as_ref()
+            .map(|limiter| limiter.acquire(full_msg.len()));
+
+        let message = PeerMessage::try_from(full_msg)?;
+        self.session.received(&message)?;
+        if let Some(wait) = wait {
+            // Held here meanwhile, in case the read is given up on
+            self.session.pending = Some(message);
+            tokio::time::sleep(wait).await;
+            return Ok(self
+                .session
+                .pending
+                .take()
+                .expect("message held while throttled"));
+        }
+        Ok(message)
+    }
+
+    // The first whole message in read_buf, taken out of it
+    fn buffered_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(length_prefix) = self.read_buf.get(..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(length_prefix.try_into()?);
+        if length > 0 {
+            let Some(&id) = self.read_buf.get(4) else {
+                return Ok(None);
+            };
+            check_message_length(length, id, self.session.max_message_size)?;
+        }
+        let end = 4 + length as usize;
+        if self.read_buf.len() < end {
+            self.read_buf.reserve(end - self.read_buf.len());
+            return Ok(None);
+        }
+        Ok(Some(self.read_buf.drain(..end).collect()))
+    }
+
+    async fn read_skipping_keep_alives(&mut self) -> Result<PeerMessage, Error> {
+        loop {
+            let message = self.read().await?;
+            if !skipped(&message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    pub async fn write(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        self.session.sending(message)?;
+        let message_bytes: Vec<u8> = message.into();
+        if let Some(limiter) = &self.limits.upload {
+            tokio::time::sleep(limiter.acquire(message_bytes.len())).await;
+        }
+        self.stream.write_all(&message_bytes).await?;
+        self.last_sent = tokio::time::Instant::now();
+        Ok(())
+    }
+
+    // No socket timeouts here, so each read up to the first Unchoke gets a deadline
+    pub async fn read_bitfield(&mut self) -> Result<PeerMessage, Error> {
+        self.session.state.expect_bitfield()?;
+        let message =
+            tokio::time::timeout(self.session.timeouts.read, self.read_skipping_keep_alives())
+                .await
+                .map_err(|_| {
+                    anyhow!(
+                        "Nothing from {} after the handshake",
+                        self.session.peer_addr
+                    )
+                })??;
+        self.session.bitfield_from(message)
+    }
+
+    pub async fn write_interested(&mut self) -> Result<(), Error> {
+        self.write(&PeerMessage::Interested).await
+    }
+
+    pub async fn read_unchoke(&mut self) -> Result<PeerMessage, Error> {
+        self.session.expect_unchoke()?;
+        let read = tokio::time::timeout(self.session.timeouts.read, async {
+            while !is_unchoke(self.read_skipping_keep_alives().await?)? {}
+            Ok(PeerMessage::Unchoke)
+        });
+        read.await
+            .map_err(|_| anyhow!("No unchoke from {}", self.session.peer_addr))?
+    }
+
+    // Handshake, Bitfield, Interested & Unchoke, as PeerStream::prep_download
+    pub async fn prep_download(&mut self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        let handshake = self
+            .handshake(info_hash)
+            .await
+            .inspect_err(|e| warn!("Handshake: Error: {}", e))?;
+        info!("Peer ID: {}", hex::encode(&handshake.peer_id));
+        let bitfield = self
+            .read_bitfield()
+            .await
+            .inspect_err(|e| warn!("Bitfield: Error: {}", e))?;
+        debug!("Bitfield: {:?}", bitfield);
+        self.write_interested()
+            .await
+            .inspect_err(|e| warn!("Interested: Error: {}", e))?;
+        self.read_unchoke()
+            .await
+            .inspect_err(|e| warn!("Unchoke: Error: {}", e))?;
+        debug!("Unchoke: Received");
+        Ok(())
+    }
+
+    // As PeerStream::wait_for_unchoke
+    async fn wait_for_unchoke(&mut self) -> Result<(), Error> {
+        let mut choke = ChokeWait::new(self.session.choke_timeout);
+        loop {
+            if choke.nudge() {
+                self.write(&PeerMessage::Interested).await?;
+            }
+            match tokio::time::timeout(choke.wait()?, self.read()).await {
+                Ok(Ok(PeerMessage::Unchoke)) => return Ok(()),
+                Ok(Ok(msg)) => debug!("Ignoring {} while choked", msg),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {}
+            }
+        }
+    }
+
+    // The whole piece, exactly piece_length bytes
+    pub async fn download_piece(
+        &mut self,
+        piece_id: u32,
+        piece_length: &i64,
+    ) -> Result<Vec<u8>, Error> {
+        match self.session.state.can_request() {
+            Err(StateError::Choked) => self.wait_for_unchoke().await?,
+            state => state?,
+        }
+        let mut requests = self.session.piece_requests(piece_id, *piece_length)?;
+
+        let mut piece = vec![0; *piece_length as usize];
+        let mut received = 0;
+        let silent_limit = self.session.timeouts.read;
+        let mut last_heard = Instant::now();
+        loop {
+            for req in self.session.next_requests(&mut requests) {
+                debug!("{}", req);
+                self.write(&req).await?;
+            }
+            if requests.is_done() {
+                break;
+            }
+
+            let wait = self.session.read_wait(&requests, last_heard);
+            let step = match tokio::time::timeout(wait, self.read()).await {
+                Ok(Ok(message)) => {
+                    last_heard = Instant::now();
+                    self.session.block_step(&mut requests, message)?
+                }
+                Err(_) if last_heard.elapsed() < silent_limit => BlockStep::Wait,
+                Err(_) => {
+                    self.session.shrink_block_size();
+                    return Err(anyhow!(
+                        "Nothing from {} for {:?}",
+                        self.session.peer_addr,
+                        silent_limit
+                    ));
+                }
+                Ok(Err(e)) => {
+                    self.session.shrink_block_size();
+                    return Err(e);
+                }
+            };
+            match step {
+                BlockStep::Wait => {}
+                BlockStep::Done(block) => received += place_block(&mut piece, &block)?,
+                BlockStep::Choked => self.wait_for_unchoke().await?,
+            }
+            for req in self.session.late_requests(&mut requests) {
+                self.write(&req).await?;
+            }
+        }
+
+        check_piece_length(piece_id, received, *piece_length)?;
+        Ok(piece)
+    }
+}
+
+// Both streams are their session too: the peer's state & every setting
+impl<S> Deref for PeerStream<S> {
+    type Target = PeerSession;
+
+    fn deref(&self) -> &PeerSession {
+        &self.session
+    }
+}
+
+impl<S> DerefMut for PeerStream<S> {
+    fn deref_mut(&mut self) -> &mut PeerSession {
+        &mut self.session
+    }
+}
+
+impl<S> Deref for AsyncPeerStream<S> {
+    type Target = PeerSession;
+
+    fn deref(&self) -> &PeerSession {
+        &self.session
+    }
+}
+
+impl<S> DerefMut for AsyncPeerStream<S> {
+    fn deref_mut(&mut self) -> &mut PeerSession {
+        &mut self.session
+    }
+}
+
+// (begin, length) of each block in a piece: CHUNK_SIZE blocks, the last one
+// holding whatever is left when piece_length isn't a multiple of CHUNK_SIZE
+pub fn blocks_for(piece_length: i64) -> Vec<(u32, u32)> {
+    blocks_with(piece_length, CHUNK_SIZE as u32)
+}
+
+pub fn blocks_with(piece_length: i64, block_size: u32) -> Vec<(u32, u32)> {
+    let block_size = i64::from(block_size);
+    (0..piece_length)
+        .step_by(block_size as usize)
+        .map(|begin| (begin as u32, block_size.min(piece_length - begin) as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsupport::{
+        read_block_request, scripted_peer, serve_once, serve_once_with_headers, serve_responses,
+        serve_sequence, spawn_handshaking_peer, spawn_scripted_peer, write_block, write_message,
+        MockResponse, MockTracker, ScriptedStream, MOCK_PEER,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_urlencode() {
+        let info_hash = [
+            0xd6, 0x9f, 0x91, 0xe6, 0xb2, 0xae, 0x4c, 0x54, 0x24, 0x68, 0xd1, 0x07, 0x3a, 0x71,
+            0xd4, 0xea, 0x13, 0x87, 0x9a, 0x7f,
+        ];
+        let encoded = urlencode(&info_hash).unwrap();
+        assert_eq!(
+            encoded,
+            "%d6%9f%91%e6%b2%ae%4c%54%24%68%d1%07%3a%71%d4%ea%13%87%9a%7f"
+        );
+    }
+
+    #[test]
+    fn test_tracker_payload_default() {
+        let payload = TrackerPayload::default();
+        assert_eq!(payload.port, 6881);
+        assert_eq!(payload.uploaded, 0);
+        assert_eq!(payload.downloaded, 0);
+        assert_eq!(payload.left, 0);
+        assert!(payload.compact);
+    }
+
+    #[test]
+    fn test_tracker_payload_serialize() {
+        let payload = TrackerPayload {
+            // info_hash: vec![],
+            peer_id: "peer_id".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            compact: true,
+            event: Event::None,
+            ..Default::default()
+        };
+        let serialized = serde_urlencoded::to_string(&payload).unwrap();
+        assert_eq!(
+            serialized,
+            "peer_id=peer_id&port=6881&uploaded=0&downloaded=0&left=0&compact=1"
+        );
+    }
+
+    #[test]
+    fn test_tracker_payload_serialize_optional() {
+        let payload = TrackerPayload {
+            peer_id: "peer_id".to_string(),
+            key: Some("1a2b3c4d".to_string()),
+            numwant: Some(10),
+            ip: Some("10.0.0.1".to_string()),
+            trackerid: Some("abc".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_urlencoded::to_string(&payload).unwrap(),
+            "peer_id=peer_id&port=6881&uploaded=0&downloaded=0&left=0&compact=1\
+             &key=1a2b3c4d&numwant=10&ip=10.0.0.1&trackerid=abc"
+        );
+
+        // Each one is left out on its own when unset
+        let payload = TrackerPayload {
+            numwant: Some(10),
+            ..Default::default()
+        };
+        let serialized = serde_urlencoded::to_string(&payload).unwrap();
+        assert!(
+            serialized.ends_with("&compact=1&numwant=10"),
+            "{}",
+            serialized
+        );
+    }
+
+    #[test]
+    fn test_tracker_payload_serialize_event() {
+        let events = [
+            (Event::Started, "started"),
+            (Event::Completed, "completed"),
+            (Event::Stopped, "stopped"),
+        ];
+        for (event, expected) in events {
+            let payload = TrackerPayload {
+                peer_id: "peer_id".to_string(),
+                event,
+                ..Default::default()
+            };
+            let serialized = serde_urlencoded::to_string(&payload).unwrap();
+            assert_eq!(
+                serialized,
+                format!(
+                    "peer_id=peer_id&port=6881&uploaded=0&downloaded=0&left=0&compact=1&event={}",
+                    expected
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_blocks_for() {
+        assert_eq!(blocks_for(20000), vec![(0, 16384), (16384, 3616)]);
+        assert_eq!(blocks_for(32768), vec![(0, 16384), (16384, 16384)]);
+        assert_eq!(blocks_for(100), vec![(0, 100)]);
+        assert_eq!(blocks_for(0), vec![]);
+    }
+
+    #[test]
+    fn test_tracker_response_try_from() {
+        let bencoded = BencodedValue::from(
+            b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\x90\x7f\x00\x00\x01\x1b\x90e"
+                .as_slice(),
+        );
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert_eq!(tracker_response.interval, 1800);
+        // Test without ordering
+        assert!(tracker_response
+            .peers
+            .contains(&"127.0.0.1:6800".parse().unwrap()));
+        assert!(tracker_response
+            .peers
+            .contains(&"127.0.0.1:7056".parse().unwrap()));
+    }
+
+    fn compact_peers_response() -> Vec<u8> {
+        b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\x90e".to_vec()
+    }
+
+    #[test]
+    fn test_tracker_response_dict_model() {
+        let bencoded = BencodedValue::from(
+            b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:-TR2940-0000000000014:porti6881eed2:ip9:localhost4:porti6882eeee"
+                .as_slice(),
+        );
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert_eq!(tracker_response.interval, 900);
+        assert_eq!(
+            tracker_response.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "127.0.0.1:6882".parse().unwrap()
+            ]
+        );
+
+        let bencoded = BencodedValue::from(
+            b"d8:intervali900e5:peersld2:ip9:127.0.0.14:porti70000eeee".as_slice(),
+        );
+        assert!(TrackerResponse::try_from(&bencoded).is_err());
+    }
+
+    #[test]
+    fn test_tracker_response_peers6() {
+        let mut response = b"d8:intervali1800e5:peers6:".to_vec();
+        response.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        response.extend(b"6:peers618:");
+        response.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        response.extend([0x1a, 0xe2]);
+        response.push(b'e');
+        let bencoded = BencodedValue::from(response.as_slice());
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert_eq!(
+            tracker_response.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+
+        // And back again, as the tracker cache stores it
+        let round_trip =
+            TrackerResponse::try_from(&BencodedValue::from(&tracker_response)).unwrap();
+        assert_eq!(round_trip.peers, tracker_response.peers);
+    }
+
+    #[test]
+    fn test_tracker_response_failure_reason() {
+        let bencoded =
+            BencodedValue::from(b"d14:failure reason22:torrent not registerede".as_slice());
+        match TrackerResponse::try_from(&bencoded) {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "torrent not registered"),
+            other => panic!("Expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tracker_response_swarm_counts() {
+        let bencoded = BencodedValue::from(
+            b"d8:completei12e10:incompletei3e8:intervali1800e12:min intervali60e5:peers0:10:tracker id3:abc15:warning message8:be nice!e"
+                .as_slice(),
+        );
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert_eq!(tracker_response.complete, Some(12));
+        assert_eq!(tracker_response.incomplete, Some(3));
+        assert_eq!(tracker_response.min_interval, Some(60));
+        assert_eq!(tracker_response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(tracker_response.warning.as_deref(), Some("be nice!"));
+
+        // Counts survive the tracker cache
+        let cached = TrackerResponse::try_from(&BencodedValue::from(&tracker_response)).unwrap();
+        assert_eq!(cached.complete, Some(12));
+        assert_eq!(cached.incomplete, Some(3));
+        assert_eq!(cached.tracker_id.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let cases = [
+            ("http://t.example/announce", "http://t.example/scrape"),
+            ("http://t.example/x/announce", "http://t.example/x/scrape"),
+            (
+                "http://t.example/announce.php",
+                "http://t.example/scrape.php",
+            ),
+            (
+                "http://t.example/announce?passkey=abc",
+                "http://t.example/scrape?passkey=abc",
+            ),
+            (
+                "http://t.example/announce?passkey=abc#frag",
+                "http://t.example/scrape?passkey=abc#frag",
+            ),
+        ];
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url(announce).unwrap(), scrape);
+        }
+        assert!(scrape_url("http://t.example/a").is_err());
+        assert!(scrape_url("http://t.example/announce/x").is_err());
+        assert!(scrape_url("http://t.example/x?next=/announce").is_err());
+    }
+
+    #[test]
+    fn test_append_query() {
+        let cases = [
+            (
+                "http://t.example/announce",
+                "http://t.example/announce?a=1&b=%ff",
+            ),
+            (
+                "http://t.example/announce?passkey=abc",
+                "http://t.example/announce?passkey=abc&a=1&b=%ff",
+            ),
+            (
+                "http://t.example/announce.php?passkey=abc&",
+                "http://t.example/announce.php?passkey=abc&a=1&b=%ff",
+            ),
+            (
+                "http://t.example/announce?",
+                "http://t.example/announce?a=1&b=%ff",
+            ),
+            (
+                "http://t.example/announce?passkey=abc#frag",
+                "http://t.example/announce?passkey=abc&a=1&b=%ff#frag",
+            ),
+        ];
+        for (announce, expected) in cases {
+            assert_eq!(append_query(announce, "a=1&b=%ff").unwrap(), expected);
+        }
+        assert!(append_query("not a url", "a=1").is_err());
+    }
+
+    #[test]
+    fn test_redact_url() {
+        let cases = [
+            (
+                "http://t.example/announce.php?passkey=abc123&uid=5",
+                "http://t.example/announce.php?passkey=REDACTED&uid=5",
+            ),
+            (
+                "https://t.example/0123456789abcdef0123/announce",
+                "https://t.example/REDACTED/announce",
+            ),
+            ("http://t.example/announce", "http://t.example/announce"),
+        ];
+        for (url, redacted) in cases {
+            assert_eq!(redact_url(url), redacted);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_redacts_passkey_below_debug() {
+        crate::testsupport::init_test_logger();
+        let url = serve_once("200 OK", compact_peers_response()) + "/announce?passkey=s3cr3tpass";
+        let client = TrackerConfig::default().client().unwrap();
+        ping_tracker(
+            &client,
+            &url,
+            &AnnounceRequest::new([8; 20], Progress::starting(100)),
+        )
+        .await
+        .unwrap();
+
+        let records = crate::testsupport::logged_records();
+        assert!(records
+            .iter()
+            .any(|(level, message)| *level == log::Level::Info
+                && message.contains("passkey=REDACTED")));
+        assert!(!records
+            .iter()
+            .any(|(level, message)| *level <= log::Level::Info && message.contains("s3cr3tpass")));
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_keeps_passkey() {
+        let (base, requests) = serve_sequence(vec![compact_peers_response()]);
+        let client = TrackerConfig::default().client().unwrap();
+        ping_tracker(
+            &client,
+            &(base + "/announce?passkey=abc"),
+            &AnnounceRequest::new([0xab; 20], Progress::starting(100)),
+        )
+        .await
+        .unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(
+            request.starts_with("GET /announce?passkey=abc&peer_id="),
+            "{}",
+            request
+        );
+        assert!(request.contains(&format!("&info_hash={} ", url_encode(&[0xab; 20]).unwrap())));
+        assert_eq!(request.matches('?').count(), 1, "{}", request);
+    }
+
+    fn scrape_response(stats: &[([u8; 20], &str)]) -> Vec<u8> {
+        let mut body = b"d5:filesd".to_vec();
+        for (info_hash, counts) in stats {
+            body.extend(b"20:");
+            body.extend(info_hash);
+            body.extend(counts.as_bytes());
+        }
+        body.extend(b"ee");
+        body
+    }
+
+    #[tokio::test]
+    async fn test_scrape_tracker_multiple_hashes() {
+        let body = scrape_response(&[
+            (
+                [0x81; 20],
+                "d8:completei5e10:downloadedi50e10:incompletei10ee",
+            ),
+            (
+                [0x82; 20],
+                "d8:completei1e10:downloadedi2e10:incompletei3ee",
+            ),
+        ]);
+        let (base, requests) = serve_sequence(vec![body]);
+        let client = TrackerConfig::default().client().unwrap();
+        let results = scrape_tracker(
+            &client,
+            &(base + "/announce?passkey=abc"),
+            &[[0x81; 20], [0x82; 20]],
+        )
+        .await
+        .unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(
+            request.starts_with(&format!(
+                "GET /scrape?passkey=abc&info_hash={}&info_hash={} ",
+                url_encode(&[0x81; 20]).unwrap(),
+                url_encode(&[0x82; 20]).unwrap()
+            )),
+            "{}",
+            request
+        );
+        assert_eq!(
+            results[&[0x81; 20]],
+            ScrapeResult {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10
+            }
+        );
+        assert_eq!(results[&[0x82; 20]].incomplete, 3);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_scrape_skips_unsupported() {
+        let body = scrape_response(&[([7; 20], "d8:completei2e10:downloadedi9e10:incompletei4ee")]);
+        let (base, _requests) = serve_sequence(vec![body]);
+        let list = TrackerList::new(
+            "http://unused.example/announce",
+            Some(&vec![
+                vec!["http://127.0.0.1:1/tracker".to_string()],
+                vec![base.clone() + "/announce"],
+            ]),
+        );
+        let (result, url) = list.scrape([7; 20]).await.unwrap();
+        assert_eq!(url, base + "/announce");
+        assert_eq!(result.complete, 2);
+        assert_eq!(result.downloaded, 9);
+    }
+
+    #[test]
+    fn test_tracker_response_empty_peers() {
+        let bencoded = BencodedValue::from(b"d8:intervali1800e5:peers0:e".as_slice());
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert!(tracker_response.peers.is_empty());
+    }
+
+    #[test]
+    fn test_tracker_response_truncated_compact_peers() {
+        let bencoded = BencodedValue::from(
+            b"d8:intervali1800e5:peers8:\x7f\x00\x00\x01\x1a\x90\x7f\x00e".as_slice(),
+        );
+        let err = TrackerResponse::try_from(&bencoded).unwrap_err();
+        assert!(err.to_string().contains("not a multiple of 6"), "{}", err);
+    }
+
+    #[test]
+    fn test_compact_peers() {
+        let peers: Vec<SocketAddr> = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        ];
+        let bytes = compact_peers(&peers);
+        assert_eq!(bytes, [127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80]);
+        assert_eq!(parse_compact_peers(&bytes, 4).unwrap(), peers);
+        assert!(parse_compact_peers(&bytes, 16).is_err());
+
+        let peer: SocketAddr = "[::1]:6881".parse().unwrap();
+        assert_eq!(compact_peer(&peer).len(), 18);
+        assert_eq!(parse_compact_peer(&compact_peer(&peer)), Some(peer));
+        assert_eq!(parse_compact_peer(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_tracker_list_tiers() {
+        let list = TrackerList::new("http://primary/announce", None);
+        assert_eq!(list.tiers(), vec![vec!["http://primary/announce"]]);
+
+        // announce-list takes precedence, empty tiers are dropped
+        let announce_list = vec![
+            vec!["http://a/announce".to_string()],
+            vec![],
+            vec![
+                "http://b/announce".to_string(),
+                "http://c/announce".to_string(),
+            ],
+        ];
+        let list = TrackerList::new("http://primary/announce", Some(&announce_list));
+        assert_eq!(list.tiers().len(), 2);
+        assert_eq!(list.tiers()[0], vec!["http://a/announce"]);
+        let mut second_tier = list.tiers()[1].clone();
+        second_tier.sort();
+        assert_eq!(second_tier, announce_list[2]);
+
+        // Schemes we can't speak are dropped up front
+        let announce_list = vec![vec![
+            "wss://tracker/announce".to_string(),
+            "udp://tracker:80".to_string(),
+        ]];
+        let list = TrackerList::new("http://primary/announce", Some(&announce_list));
+        assert_eq!(list.tiers(), vec![vec!["udp://tracker:80"]]);
+    }
+
+    // Answers from memory & records what it was asked
+    struct FakeTracker {
+        url: String,
+        response: Option<TrackerResponse>,
+        requests: Mutex<Vec<AnnounceRequest>>,
+    }
+
+    impl FakeTracker {
+        fn boxed(url: &str, response: Option<TrackerResponse>) -> (Box<dyn Tracker>, Arc<Self>) {
+            let tracker = Arc::new(FakeTracker {
+                url: url.to_string(),
+                response,
+                requests: Mutex::new(vec![]),
+            });
+            (Box::new(tracker.clone()), tracker)
+        }
+    }
+
+    impl Tracker for Arc<FakeTracker> {
+        fn url(&self) -> &str {
+            &self.url
+        }
+
+        fn announce<'a>(
+            &'a self,
+            request: &'a AnnounceRequest,
+        ) -> BoxFuture<'a, Result<TrackerResponse, Error>> {
+            self.requests.lock().unwrap().push(request.clone());
+            let response = self.response.clone().ok_or_else(|| anyhow!("down"));
+            Box::pin(async move { response })
+        }
+
+        fn scrape<'a>(
+            &'a self,
+            _info_hashes: &'a [[u8; 20]],
+        ) -> BoxFuture<'a, Result<HashMap<[u8; 20], ScrapeResult>, Error>> {
+            Box::pin(async { Err(anyhow!("no scrape")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_dispatches_to_trait() {
+        let response = TrackerResponse {
+            interval: 60,
+            peers: vec!["10.0.0.1:6881".parse().unwrap()],
+            tracker_id: Some("abc".to_string()),
+            ..Default::default()
+        };
+        let (dead, dead_calls) = FakeTracker::boxed("fake://dead", None);
+        let (alive, alive_calls) = FakeTracker::boxed("fake://alive", Some(response.clone()));
+        let mut list =
+            TrackerList::from_trackers(vec![vec![dead], vec![alive]]).with_numwant(Some(10));
+
+        let (tracker_response, url) = list
+            .announce_event([3; 20], Progress::starting(100), Event::Started)
+            .await
+            .unwrap();
+        assert_eq!(url, "fake://alive");
+        assert_eq!(tracker_response.peers, response.peers);
+        list.announce([3; 20], 100).await.unwrap();
+
+        assert_eq!(dead_calls.requests.lock().unwrap().len(), 2);
+        let requests = alive_calls.requests.lock().unwrap();
+        assert_eq!(requests[0].info_hash, [3; 20]);
+        assert_eq!(requests[0].event, Event::Started);
+        assert_eq!(requests[0].numwant, Some(10));
+        assert_eq!(requests[0].port, 6881);
+        assert_eq!(requests[0].tracker_id, None);
+        assert_eq!(requests[1].tracker_id.as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_tracker_from_url() {
+        let client = TrackerConfig::default().client().unwrap();
+        let http = <dyn Tracker>::from_url("https://tracker/announce", &client).unwrap();
+        assert_eq!(http.url(), "https://tracker/announce");
+        let udp = <dyn Tracker>::from_url("udp://tracker:1337/announce", &client).unwrap();
+        let err = udp
+            .announce(&AnnounceRequest::new([0; 20], Progress::starting(1)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("UDP"), "{}", err);
+        assert!(<dyn Tracker>::from_url("wss://tracker/announce", &client).is_err());
+        assert!(<dyn Tracker>::from_url("not a url", &client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_failover() {
+        let dead = MockTracker::spawn().with_responses(vec![MockResponse::status(
+            "404 Not Found",
+            b"gone".to_vec(),
+        )]);
+        let refused = MockTracker::spawn().with_responses(vec![MockResponse::failure("no")]);
+        let alive = MockTracker::spawn();
+        let announce_list = vec![vec![dead.url()], vec![refused.url()], vec![alive.url()]];
+        let mut list = TrackerList::new(&dead.url(), Some(&announce_list));
+
+        let (tracker_response, url) = list.announce([0; 20], 100).await.unwrap();
+        assert_eq!(url, alive.url());
+        assert_eq!(
+            tracker_response.peers,
+            vec!["127.0.0.1:6800".parse().unwrap()]
+        );
+        // Tiers are tried in order, each tracker once
+        assert_eq!(dead.requests().len(), 1);
+        assert_eq!(refused.requests().len(), 1);
+        assert_eq!(alive.requests().len(), 1);
+
+        // Only reordering within a tier sticks, so the first tier is still tried first
+        list.announce([0; 20], 100).await.unwrap();
+        assert_eq!(dead.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_promotes_within_tier() {
+        let dead = MockTracker::spawn().with_responses(vec![MockResponse::status(
+            "404 Not Found",
+            b"gone".to_vec(),
+        )]);
+        let alive = MockTracker::spawn();
+        let announce_list = vec![vec![dead.url(), alive.url()]];
+        let mut list = TrackerList::new(&dead.url(), Some(&announce_list));
+
+        for _ in 0..3 {
+            let (_, url) = list.announce([0; 20], 100).await.unwrap();
+            assert_eq!(url, alive.url());
+        }
+        // However the tier was shuffled, the dead tracker was skipped once alive answered
+        assert!(dead.requests().len() <= 1);
+        assert_eq!(alive.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_all_fail() {
+        let not_found = MockTracker::spawn().with_responses(vec![MockResponse::status(
+            "404 Not Found",
+            b"gone".to_vec(),
+        )]);
+        let garbage =
+            MockTracker::spawn().with_responses(vec![MockResponse::body(b"<html>".to_vec())]);
+        let announce_list = vec![vec![not_found.url(), garbage.url()]];
+        let mut list = TrackerList::new(&not_found.url(), Some(&announce_list));
+
+        let err = list.announce([0; 20], 100).await.unwrap_err().to_string();
+        assert!(err.contains(&format!("{}: ", not_found.url())), "{}", err);
+        assert!(err.contains("404"), "{}", err);
+        assert!(err.contains(&format!("{}: ", garbage.url())), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_announce_advertises_port() {
+        let tracker = MockTracker::spawn();
+        let mut list = TrackerList::new(&tracker.url(), None).with_port(51413);
+        list.announce([0; 20], 100).await.unwrap();
+        assert_eq!(tracker.requests()[0].param("port"), Some("51413"));
+
+        let payload = TrackerPayload {
+            port: 51413,
+            ..Default::default()
+        };
+        let serialized = serde_urlencoded::to_string(&payload).unwrap();
+        assert!(serialized.contains("&port=51413&"), "{}", serialized);
+    }
+
+    #[tokio::test]
+    async fn test_announce_compact_flag() {
+        let tracker = MockTracker::spawn();
+        let mut list = TrackerList::new(&tracker.url(), None);
+        list.announce([0; 20], 100).await.unwrap();
+        let mut list = TrackerList::new(&tracker.url(), None).with_compact(false);
+        list.announce([0; 20], 100).await.unwrap();
+
+        let requests = tracker.requests();
+        assert_eq!(requests[0].param("compact"), Some("1"));
+        assert_eq!(requests[1].param("compact"), Some("0"));
+    }
+
+    #[test]
+    fn test_listen_port() {
+        assert_eq!(ListenPort::new(6881, false).unwrap().port(), 6881);
+        let err = ListenPort::new(80, false).err().unwrap();
+        assert!(
+            err.to_string().contains("--allow-privileged-port"),
+            "{}",
+            err
+        );
+        assert_eq!(ListenPort::new(80, true).unwrap().port(), 80);
+
+        // 0 advertises whatever the OS bound
+        let ephemeral = ListenPort::new(0, false).unwrap();
+        assert!(matches!(ephemeral, ListenPort::Bound(_)));
+        assert_ne!(ephemeral.port(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_passes_on_failure_reason() {
+        let tracker = MockTracker::spawn()
+            .with_responses(vec![MockResponse::failure("torrent not registered")]);
+        let mut list = TrackerList::new(&tracker.url(), None);
+        let err = list.announce([0; 20], 100).await.unwrap_err().to_string();
+        assert!(
+            err.contains("tracker refused the announce: torrent not registered"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_skips_slow_tracker() {
+        let slow = MockTracker::spawn().with_responses(vec![
+            MockResponse::peers(60, &[]).delayed(Duration::from_secs(3))
+        ]);
+        let fast = MockTracker::spawn();
+        let announce_list = vec![vec![slow.url()], vec![fast.url()]];
+        let config = TrackerConfig {
+            timeout: Duration::from_millis(300),
+            retry: RetryPolicy::none(),
+            ..Default::default()
+        };
+        let mut list = TrackerList::new(&slow.url(), Some(&announce_list))
+            .with_config(&config)
+            .unwrap();
+
+        let (_, url) = list.announce([0; 20], 100).await.unwrap();
+        assert_eq!(url, fast.url());
+        assert_eq!(slow.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_query() {
+        let tracker = MockTracker::spawn();
+        let mut list = TrackerList::new(&tracker.url(), None).with_numwant(Some(25));
+        let progress = Progress {
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+        };
+        list.announce_event([0xab; 20], progress, Event::Started)
+            .await
+            .unwrap();
+
+        let requests = tracker.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.path, "/announce");
+        let info_hash = url_encode(&[0xab; 20]).unwrap();
+        let peer_id = PeerId::local().to_string();
+        for (key, value) in [
+            ("info_hash", info_hash.as_str()),
+            ("peer_id", peer_id.as_str()),
+            ("port", "6881"),
+            ("uploaded", "1"),
+            ("downloaded", "2"),
+            ("left", "3"),
+            ("compact", "1"),
+            ("event", "started"),
+            ("numwant", "25"),
+        ] {
+            assert_eq!(request.param(key), Some(value), "{}", key);
+        }
+        assert_eq!(request.param("key").unwrap().len(), 8);
+        assert_eq!(request.param("trackerid"), None);
+    }
+
+    #[test]
+    fn test_tracker_cache_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Arc::new(AtomicU64::new(1_000));
+        let clock = now.clone();
+        let cache = TrackerCache::with_clock(dir.path(), move || clock.load(Ordering::SeqCst));
+        let info_hash = [7; 20];
+        assert!(cache.should_reannounce(&info_hash));
+
+        let bencoded = BencodedValue::from(compact_peers_response().as_slice());
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        cache
+            .store(&info_hash, "http://t/announce", &tracker_response)
+            .unwrap();
+        assert!(!cache.should_reannounce(&info_hash));
+        let (cached, url, timestamp) = cache.load(&info_hash).unwrap();
+        assert_eq!(url, "http://t/announce");
+        assert_eq!(timestamp, 1_000);
+        assert_eq!(cached.interval, 1800);
+        assert_eq!(cached.peers, tracker_response.peers);
+
+        now.store(1_000 + 1799, Ordering::SeqCst);
+        assert!(!cache.should_reannounce(&info_hash));
+        now.store(1_000 + 1800, Ordering::SeqCst);
+        assert!(cache.should_reannounce(&info_hash));
+        assert!(cache.fresh(&info_hash).is_none());
+
+        // A min interval over the interval wins, keys in whatever order
+        let bencoded =
+            BencodedValue::from(b"d12:min intervali60e8:intervali30e5:peers0:e".as_slice());
+        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
+        assert_eq!(
+            (tracker_response.interval, tracker_response.min_interval),
+            (30, Some(60))
+        );
+        cache
+            .store(&info_hash, "http://t/announce", &tracker_response)
+            .unwrap();
+        now.store(2_800 + 59, Ordering::SeqCst);
+        assert!(!cache.should_reannounce(&info_hash));
+        now.store(2_800 + 60, Ordering::SeqCst);
+        assert!(cache.should_reannounce(&info_hash));
+
+        // A corrupt timestamp is a miss, not a wrapped-around time
+        for timestamp in [b"i-1e".as_slice(), b"3:abc"] {
+            let mut entry =
+                b"d8:announce17:http://t/announce8:intervali1800e5:peers0:9:timestamp".to_vec();
+            entry.extend(timestamp);
+            entry.push(b'e');
+            std::fs::write(cache.path(&info_hash), entry).unwrap();
+            assert!(cache.load(&info_hash).is_none());
+            assert!(cache.should_reannounce(&info_hash));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_session_reannounces_new_peers() {
+        let mut first = b"d8:intervali1e5:peers6:".to_vec();
+        first.extend([127, 0, 0, 1, 0x1a, 0x90]);
+        first.extend(b"10:tracker id3:abce");
+        let second = MockResponse::peers(
+            1,
+            &[
+                "127.0.0.1:6800".parse().unwrap(),
+                "127.0.0.2:6801".parse().unwrap(),
+            ],
+        );
+        let tracker = MockTracker::spawn().with_responses(vec![
+            MockResponse::body(first),
+            second,
+            MockResponse::peers(1, &[]),
+        ]);
+        let mut trackers = TrackerList::new(&tracker.url(), None);
+
+        let (tracker_response, _) = trackers
+            .announce_event([5; 20], Progress::starting(100), Event::Started)
+            .await
+            .unwrap();
+        let (session, mut new_peers) = TrackerSession::spawn(
+            trackers,
+            [5; 20],
+            Progress::starting(100),
+            &tracker_response,
+        );
+        session.progress().lock().unwrap().downloaded = 40;
+
+        // Only the peer we didn't know about comes through
+        let peer = tokio::time::timeout(Duration::from_secs(5), new_peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer, "127.0.0.2:6801".parse().unwrap());
+        session.stop().await.unwrap();
+        assert!(new_peers.recv().await.is_none());
+
+        // The interval was 1s, so exactly one regular announce fits in
+        let requests = tracker.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].param("event"), Some("started"));
+        assert_eq!(requests[1].param("event"), None);
+        assert_eq!(requests[1].param("downloaded"), Some("40"));
+        assert_eq!(requests[2].param("event"), Some("stopped"));
+
+        // Same key all session, & the tracker id comes back once we have one
+        assert_eq!(requests[0].param("key"), requests[2].param("key"));
+        assert_eq!(requests[0].param("trackerid"), None);
+        assert_eq!(requests[1].param("trackerid"), Some("abc"));
+    }
+
+    #[test]
+    fn test_reannounce_delay_respects_min_interval() {
+        let mut tracker_response = TrackerResponse {
+            interval: 30,
+            min_interval: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            TrackerSession::reannounce_delay(&tracker_response),
+            Duration::from_secs(60)
+        );
+        tracker_response.min_interval = None;
+        assert_eq!(
+            TrackerSession::reannounce_delay(&tracker_response),
+            Duration::from_secs(30)
+        );
+    }
+
+    // Answer each connection over TLS with the same tracker response, passing on the request
+    fn serve_https(connections: usize) -> (String, std::sync::mpsc::Receiver<String>) {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../testdata/tracker-cert.pem"),
+            include_bytes!("../testdata/tracker-key.pem"),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                // Clients that reject the certificate fail the handshake
+                let Ok(mut stream) = acceptor.accept(stream.unwrap()) else {
+                    continue;
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                tx.send(String::from_utf8_lossy(&request).to_lowercase())
+                    .unwrap();
+                crate::testsupport::write_response(
+                    &mut stream,
+                    "200 OK",
+                    &compact_peers_response(),
+                );
+            }
+        });
+        (format!("https://{}/announce", addr), rx)
+    }
+
+    #[tokio::test]
+    async fn test_https_tracker_with_custom_ca() {
+        let (url, requests) = serve_https(3);
+        let announce = |client: TrackerClient| {
+            let url = url.clone();
+            async move {
+                TrackerList::new(&url, None)
+                    .with_client(client)
+                    .announce([6; 20], 100)
+                    .await
+            }
+        };
+
+        // Self-signed, so the system roots alone don't trust it. Each retry would
+        // take up one of the server's connections, so don't.
+        let untrusted = TrackerConfig {
+            retry: RetryPolicy::none(),
+            ..Default::default()
+        };
+        assert!(announce(untrusted.client().unwrap()).await.is_err());
+
+        let trusted = TrackerConfig {
+            ca_cert: Some(PathBuf::from(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/tracker-cert.pem"
+            ))),
+            ..Default::default()
+        };
+        let (tracker_response, _) = announce(trusted.client().unwrap()).await.unwrap();
+        assert_eq!(tracker_response.peers.len(), 1);
+
+        let insecure = TrackerConfig {
+            accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert!(announce(insecure.client().unwrap()).await.is_ok());
+
+        let request = requests.recv().unwrap();
+        assert!(
+            request.contains("user-agent: your_bittorrent/"),
+            "{}",
+            request
+        );
+    }
+
+    fn quick_retries(attempts: u32) -> TrackerConfig {
+        TrackerConfig {
+            timeout: Duration::from_millis(300),
+            retry: RetryPolicy {
+                attempts,
+                base_delay: Duration::from_millis(10),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_client_retries_server_errors() {
+        let (base, requests) = serve_responses(vec![
+            ("503 Service Unavailable", vec![]),
+            ("503 Service Unavailable", vec![]),
+            ("200 OK", compact_peers_response()),
+        ]);
+        let client = quick_retries(3).client().unwrap();
+        let body = client.get(&(base + "/announce")).await.unwrap();
+        assert_eq!(body.as_ref(), compact_peers_response().as_slice());
+        assert_eq!(requests.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        let retry = RetryPolicy {
+            attempts: 4,
+            base_delay: Duration::from_millis(100),
+        };
+        for (attempt, backoff) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = retry.delay(attempt);
+            let backoff = Duration::from_millis(backoff);
+            assert!(delay >= backoff, "{:?}", delay);
+            assert!(delay <= backoff + Duration::from_millis(50), "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_backs_off_then_succeeds() {
+        let tracker = MockTracker::spawn().with_responses(vec![
+            MockResponse::status("503 Service Unavailable", vec![]),
+            MockResponse::status("502 Bad Gateway", vec![]),
+            MockResponse::peers(60, &["127.0.0.1:6800".parse().unwrap()]),
+        ]);
+        let config = TrackerConfig {
+            retry: RetryPolicy {
+                attempts: 3,
+                base_delay: Duration::from_millis(100),
+            },
+            ..Default::default()
+        };
+        let request = AnnounceRequest::new([1; 20], Progress::starting(100));
+        let started = std::time::Instant::now();
+        let tracker_response = ping_tracker(&config.client().unwrap(), &tracker.url(), &request)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            tracker_response.peers,
+            vec!["127.0.0.1:6800".parse().unwrap()]
+        );
+        assert_eq!(tracker.requests().len(), 3);
+        // 100ms, then 200ms, before the third attempt
+        assert!(
+            started.elapsed() >= Duration::from_millis(300),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracker_client_gives_up_on_client_errors() {
+        let (base, _requests) = serve_responses(vec![("404 Not Found", vec![])]);
+        let client = quick_retries(3).client().unwrap();
+        match client.get(&(base + "/announce")).await {
+            Err(TrackerError::Http {
+                attempt, source, ..
+            }) => {
+                assert_eq!(attempt, 1);
+                assert_eq!(source.status(), Some(reqwest::StatusCode::NOT_FOUND));
+            }
+            other => panic!("Expected an HTTP error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_client_times_out() {
+        // Accepts & reads the request, then never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                crate::testsupport::read_request(&mut stream);
+                held.push(stream);
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let err = quick_retries(2)
+            .client()
+            .unwrap()
+            .get(&url)
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        match &err {
+            TrackerError::Http {
+                attempt, source, ..
+            } => {
+                assert_eq!(*attempt, 2);
+                assert!(source.is_timeout());
+            }
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert!(err.to_string().contains("attempt 2 of 2"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_config_proxy() {
+        let (proxy, requests) = serve_sequence(vec![compact_peers_response()]);
+        let config = TrackerConfig {
+            proxy: Some(proxy),
+            ..Default::default()
+        };
+        // Only the proxy knows how to reach this one
+        let (tracker_response, _) = TrackerList::new("http://tracker.invalid/announce", None)
+            .with_config(&config)
+            .unwrap()
+            .announce([7; 20], 100)
+            .await
+            .unwrap();
+        assert_eq!(tracker_response.peers.len(), 1);
+
+        let request = requests.recv().unwrap();
+        assert!(
+            request.starts_with("GET http://tracker.invalid/announce?"),
+            "{}",
+            request
+        );
+
+        let bad = TrackerConfig {
+            proxy: Some("not a proxy".to_string()),
+            ..Default::default()
+        };
+        assert!(bad.client().is_err());
+    }
+
+    #[test]
+    fn test_tracker_config_bad_ca_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("not-a-cert.pem");
+        std::fs::write(&path, "hello").unwrap();
+        let options = TrackerConfig {
+            ca_cert: Some(path),
+            ..Default::default()
+        };
+        assert!(options.client().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_logs_url_at_debug() {
+        crate::testsupport::init_test_logger();
+        let url = serve_once("200 OK", compact_peers_response()) + "/announce-logged";
+        let client = TrackerConfig::default().client().unwrap();
+        ping_tracker(
+            &client,
+            &url,
+            &AnnounceRequest::new([4; 20], Progress::starting(100)),
+        )
+        .await
+        .unwrap();
+
+        let records = crate::testsupport::logged_records();
+        let url_record = records
+            .iter()
+            .find(|(_, message)| message.starts_with("URL: ") && message.contains(&url))
+            .expect("tracker URL was not logged");
+        assert_eq!(url_record.0, log::Level::Debug);
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_falls_back_to_non_compact() {
+        let (base, requests) = serve_sequence(vec![
+            b"d8:intervali900ee".to_vec(),
+            b"d8:intervali900e5:peersld2:ip9:127.0.0.14:porti6881eeee".to_vec(),
+        ]);
+        let client = TrackerConfig::default().client().unwrap();
+        let request = AnnounceRequest::new([2; 20], Progress::starting(100));
+        let tracker_response = ping_tracker(&client, &(base + "/announce"), &request)
+            .await
+            .unwrap();
+        assert_eq!(
+            tracker_response.peers,
+            vec!["127.0.0.1:6881".parse().unwrap()]
+        );
+        assert!(requests.recv().unwrap().contains("&compact=1"));
+        assert!(requests.recv().unwrap().contains("&compact=0"));
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_retries_compact_only_once() {
+        let (base, requests) = serve_sequence(vec![
+            b"d8:intervali900ee".to_vec(),
+            b"d8:intervali900ee".to_vec(),
+        ]);
+        let client = TrackerConfig::default().client().unwrap();
+        let request = AnnounceRequest::new([2; 20], Progress::starting(100));
+        let err = ping_tracker(&client, &(base + "/announce"), &request)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(TrackerError::NoPeers)));
+        assert_eq!(requests.try_iter().count(), 2);
+
+        // Anything else wrong with the response isn't worth a second try
+        let (base, requests) = serve_sequence(vec![b"<html>".to_vec()]);
+        assert!(ping_tracker(&client, &(base + "/announce"), &request)
+            .await
+            .is_err());
+        assert_eq!(requests.try_iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_gzipped_response() {
+        let client = TrackerConfig::default().client().unwrap();
+        let request = AnnounceRequest::new([2; 20], Progress::starting(100));
+        let labelled = serve_once_with_headers(
+            &[("Content-Encoding", "gzip")],
+            gzip(&compact_peers_response()),
+        );
+        let unlabelled = serve_once_with_headers(
+            &[("Content-Type", "application/octet-stream")],
+            gzip(&compact_peers_response()),
+        );
+        for url in [labelled, unlabelled] {
+            let tracker_response = ping_tracker(&client, &(url + "/announce"), &request)
+                .await
+                .unwrap();
+            assert_eq!(
+                tracker_response.peers,
+                vec!["127.0.0.1:6800".parse().unwrap()]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_list_uses_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TrackerCache::with_clock(dir.path(), || 5_000);
+        // The mock tracker only answers once, so a second network call would fail
+        let url = serve_once("200 OK", compact_peers_response()) + "/announce";
+        let mut list = TrackerList::new(&url, None).with_cache(cache);
+
+        let (first, _) = list.announce([9; 20], 100).await.unwrap();
+        let (second, cached_url) = list.announce([9; 20], 100).await.unwrap();
+        assert_eq!(first.peers, second.peers);
+        assert_eq!(cached_url, url);
+
+        // A hit names the tracker that answered, not just the first one listed
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TrackerCache::with_clock(dir.path(), || 5_000);
+        let dead = MockTracker::spawn().with_responses(vec![MockResponse::failure("no")]);
+        let alive = serve_once("200 OK", compact_peers_response()) + "/announce";
+        let announce_list = vec![vec![dead.url()], vec![alive.clone()]];
+        let mut list = TrackerList::new(&dead.url(), Some(&announce_list)).with_cache(cache);
+        assert_eq!(list.announce([9; 20], 100).await.unwrap().1, alive);
+        assert_eq!(list.announce([9; 20], 100).await.unwrap().1, alive);
+        assert_eq!(dead.requests().len(), 1);
+        assert!(TrackerList::new(&url, None)
+            .announce([9; 20], 100)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_peer_handshake_default() {
+        let handshake = PeerHandshake::default();
+        assert_eq!(handshake.length, 19);
+        assert_eq!(handshake.protocol, "BitTorrent protocol");
+        assert_eq!(handshake.reserved, [0; 8]);
+        assert_eq!(handshake.info_hash, Vec::<u8>::new());
+        assert_eq!(handshake.peer_id, PeerId::local().as_bytes());
+    }
+
+    #[test]
+    fn test_peer_handshake_reserved_bits() {
+        let with_reserved = |reserved: [u8; 8]| PeerHandshake {
+            reserved,
+            ..Default::default()
+        };
+        let none = PeerHandshake::default();
+        assert!(!none.supports_extensions() && !none.supports_dht() && !none.supports_fast());
+
+        let extensions = with_reserved([0, 0, 0, 0, 0, 0x10, 0, 0]);
+        assert!(extensions.supports_extensions());
+        assert!(!extensions.supports_dht() && !extensions.supports_fast());
+
+        let dht = with_reserved([0, 0, 0, 0, 0, 0, 0, 0x01]);
+        assert!(dht.supports_dht());
+        assert!(!dht.supports_extensions() && !dht.supports_fast());
+
+        let fast = with_reserved([0, 0, 0, 0, 0, 0, 0, 0x04]);
+        assert!(fast.supports_fast());
+        assert!(!fast.supports_extensions() && !fast.supports_dht());
+
+        // e.g. libtorrent sets all three
+        let all = with_reserved([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(all.supports_extensions() && all.supports_dht() && all.supports_fast());
+
+        // Our own bits survive the trip over the wire
+        let mut ours = PeerHandshake::new(vec![1; 20], vec![2; 20]).with_dht();
+        ours.set_reserved_bit(5, 0x10);
+        let bytes: Vec<u8> = ours.into();
+        assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        let theirs = PeerHandshake::try_from(&bytes[..]).unwrap();
+        assert!(theirs.supports_dht() && theirs.supports_extensions() && !theirs.supports_fast());
+    }
+
+    #[test]
+    fn test_extended_handshake_exchange() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20])
+            .with_dht()
+            .into();
+        canned[25] |= 0x10;
+        write_message(&mut canned, 5, &[0x80]);
+        // As qBittorrent 4.3 sends it, yourip & all
+        let mut extended = vec![0];
+        extended.extend(b"d12:complete_agoi-1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei24312e4:reqqi500e11:upload_onlyi0e1:v17:qBittorrent/4.3.96:yourip4:\x7f\x00\x00\x01e");
+        write_message(&mut canned, 20, &extended);
+        write_message(&mut canned, 1, &[]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let extensions = peer_stream.peer_extensions().unwrap();
+        assert_eq!(extensions.id("ut_metadata"), Some(2));
+        assert_eq!(extensions.id("ut_pex"), Some(1));
+        assert_eq!(extensions.client.as_deref(), Some("qBittorrent/4.3.9"));
+
+        // Ours goes out straight after the handshake, flagged in its reserved bits
+        let written = &peer_stream.get_ref().written;
+        assert_eq!(written[25], 0x10);
+        let ours = PeerMessage::try_from(written[68..written.len() - 5].to_vec()).unwrap();
+        let PeerMessage::Extended { ext_id, payload } = ours else {
+            panic!("expected an extended handshake, got {}", ours);
+        };
+        assert_eq!(ext_id, HANDSHAKE_ID);
+        assert_eq!(
+            PeerExtensions::try_from(&payload[..])
+                .unwrap()
+                .client
+                .as_deref(),
+            Some(extension::CLIENT_VERSION)
+        );
+
+        let message = PeerMessage::Extended {
+            ext_id: 3,
+            payload: b"d8:msg_typei0ee".to_vec(),
+        };
+        let bytes = Vec::<u8>::from(&message);
+        assert_eq!(bytes[..6], [0, 0, 0, 17, 20, 3]);
+        assert_eq!(PeerMessage::try_from(bytes).unwrap(), message);
+        assert!(PeerMessage::try_from(vec![0, 0, 0, 1, 20]).is_err());
+
+        // Peers without the bit get no extended handshake
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.get_ref().written.len(), 68 + 5);
+        assert!(peer_stream.peer_extensions().is_none());
+    }
+
+    // A peer with the metadata for `info`, which it gives out as ut_metadata id 3,
+    // answering each request with `replies`
+    fn metadata_peer(
+        info_hash: &[u8; 20],
+        info: &[u8],
+        replies: &[MetadataMessage],
+    ) -> PeerStream<ScriptedStream> {
+        let mut canned: Vec<u8> = PeerHandshake::new(info_hash.to_vec(), vec![2; 20]).into();
+        canned[25] |= 0x10;
+        let mut extended = vec![HANDSHAKE_ID];
+        extended
+            .extend(format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", info.len()).bytes());
+        write_message(&mut canned, 20, &extended);
+        write_message(&mut canned, 5, &[0x80]);
+        for reply in replies {
+            let mut payload = vec![UT_METADATA_ID];
+            payload.extend(Vec::<u8>::from(reply));
+            write_message(&mut canned, 20, &payload);
+        }
+        scripted_peer(canned)
+    }
+
+    #[test]
+    fn test_fetch_metadata() {
+        // Enough pieces to need two metadata pieces
+        let info = Info::single_file("meta.txt".to_string(), 16 * 1000, 16, vec![9; 20 * 1000]);
+        let bytes = BencodedValue::from(&info).bencode();
+        let info_hash = info.info_hash();
+        let data = |piece: usize| MetadataMessage::Data {
+            piece,
+            total_size: bytes.len() as i64,
+            data: bytes[piece * 16384..bytes.len().min((piece + 1) * 16384)].to_vec(),
+        };
+
+        // Out of order, as they may come
+        let mut peer_stream = metadata_peer(&info_hash, &bytes, &[data(1), data(0)]);
+        let fetched = peer_stream.fetch_metadata(&info_hash).unwrap();
+        assert_eq!(fetched.name(), "meta.txt");
+        assert_eq!(fetched.info_hash(), info_hash);
+        assert_eq!(fetched.iter_pieces().count(), 1000);
+
+        // Both requests go to the peer's id for ut_metadata, after our extended handshake
+        let written = &peer_stream.get_ref().written;
+        let mut requests = Vec::new();
+        let mut at = 68;
+        while at < written.len() {
+            let length = u32::from_be_bytes(written[at..at + 4].try_into().unwrap()) as usize;
+            requests.push(PeerMessage::try_from(written[at..at + 4 + length].to_vec()).unwrap());
+            at += 4 + length;
+        }
+        let request = |piece| PeerMessage::Extended {
+            ext_id: 3,
+            payload: Vec::from(&MetadataMessage::Request { piece }),
+        };
+        assert_eq!(requests[1..], [request(0), request(1)]);
+
+        // Turned down
+        let reject = MetadataMessage::Reject { piece: 0 };
+        let mut peer_stream = metadata_peer(&info_hash, &bytes, &[data(1), reject]);
+        let e = peer_stream.fetch_metadata(&info_hash).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MetadataError>(),
+            Some(&MetadataError::Rejected(0))
+        );
+
+        // Metadata for some other torrent
+        let mut peer_stream = metadata_peer(&[7; 20], &bytes, &[data(0), data(1)]);
+        let e = peer_stream.fetch_metadata(&[7; 20]).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MetadataError>(),
+            Some(&MetadataError::HashMismatch)
+        );
+
+        // Peers without extensions can't help
+        let mut canned: Vec<u8> = PeerHandshake::new(info_hash.to_vec(), vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        let e = scripted_peer(canned)
+            .fetch_metadata(&info_hash)
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MetadataError>(),
+            Some(&MetadataError::NotSupported)
+        );
+    }
+
+    #[test]
+    fn test_pex_exchange() {
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let pex = PexMessage {
+            added: vec![peer],
+            added_flags: vec![0],
+            dropped: vec![],
+        };
+        let canned = |pex_message: &PexMessage| {
+            let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+            canned[25] |= 0x10;
+            let mut extended = vec![HANDSHAKE_ID];
+            extended.extend(b"d1:md6:ut_pexi1eee");
+            write_message(&mut canned, 20, &extended);
+            write_message(&mut canned, 5, &[0x80]);
+            let mut payload = vec![UT_PEX_ID];
+            payload.extend(Vec::<u8>::from(pex_message));
+            write_message(&mut canned, 20, &payload);
+            write_message(&mut canned, 1, &[]);
+            canned
+        };
+
+        let mut peer_stream = scripted_peer(canned(&pex));
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(peer_stream.supports_pex());
+        assert_eq!(peer_stream.take_pex(), vec![pex.clone()]);
+        assert!(peer_stream.take_pex().is_empty());
+        // Ours go to the peer's id for ut_pex
+        let sent = peer_stream.get_ref().written.len();
+        peer_stream.write_pex(&pex).unwrap();
+        assert_eq!(peer_stream.get_ref().written[sent + 4..sent + 6], [20, 1]);
+
+        // Private torrents neither offer it nor listen to it
+        let mut peer_stream = scripted_peer(canned(&pex));
+        peer_stream.set_pex(false);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(!peer_stream.supports_pex());
+        assert!(peer_stream.take_pex().is_empty());
+        assert!(peer_stream.write_pex(&pex).is_err());
+        let written = &peer_stream.get_ref().written;
+        let ours = PeerMessage::try_from(written[68..written.len() - 5].to_vec()).unwrap();
+        let PeerMessage::Extended { payload, .. } = ours else {
+            panic!("expected an extended handshake, got {}", ours);
+        };
+        let ours = PeerExtensions::try_from(&payload[..]).unwrap();
+        assert_eq!(ours.id(UT_PEX), None);
+        assert_eq!(ours.id(UT_METADATA), Some(UT_METADATA_ID));
+    }
+
+    #[test]
+    fn test_port_message() {
+        let port = PeerMessage::Port(6881);
+        let bytes = Vec::<u8>::from(&port);
+        assert_eq!(bytes, vec![0, 0, 0, 3, 9, 0x1a, 0xe1]);
+        assert_eq!(PeerMessage::try_from(bytes).unwrap(), port);
+        assert_eq!(port.to_string(), "Port { port: 6881 }");
+        assert_eq!(
+            PeerMessage::try_from(vec![0, 0, 0, 2, 9, 0x1a]),
+            Err(MessageError::Malformed { id: 9, length: 1 })
+        );
+    }
+
+    #[test]
+    fn test_dht_ports_are_exchanged() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20])
+            .with_dht()
+            .into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 9, &[0x1a, 0xe2]);
+        write_message(&mut canned, 1, &[]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.set_dht_port(6881);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.peer_dht_port(), Some(6882));
+        // Our handshake flags DHT, & the Port follows it before Interested
+        let written = &peer_stream.get_ref().written;
+        assert_eq!(written[27], 0x01);
+        assert_eq!(written[68..75], [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+        assert_eq!(written[75..], [0, 0, 0, 1, 2]);
+
+        // Without DHT on our side, a peer's Port is still noted but ours isn't sent
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20])
+            .with_dht()
+            .into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_message(&mut canned, 9, &[0x1a, 0xe2]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.read().unwrap(), PeerMessage::Port(6882));
+        assert_eq!(peer_stream.peer_dht_port(), Some(6882));
+        assert_eq!(peer_stream.get_ref().written[27], 0);
+        assert_eq!(peer_stream.get_ref().written.len(), 68 + 5);
+    }
+
+    #[test]
+    fn test_peer_handshake_from() {
+        let handshake_bytes = vec![
+            19, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
+            111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209,
+            7, 58, 113, 212, 234, 19, 135, 154, 127, 45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51,
+            98, 54, 98, 52, 98, 53, 98, 54, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let handshake = PeerHandshake::try_from(handshake_bytes.as_slice()).unwrap();
+        assert_eq!(handshake.length, 19);
+        assert_eq!(handshake.protocol, "BitTorrent protocol");
+        assert_eq!(handshake.reserved, [0; 8]);
+        assert_eq!(
+            handshake.info_hash,
+            vec![
+                214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19, 135,
+                154, 127
+            ]
+        );
+        assert_eq!(
+            handshake.peer_id,
+            vec![45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54, 0]
+        );
+    }
+
+    #[test]
+    fn test_peer_handshake_rejects_malformed() {
+        let valid: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        assert!(PeerHandshake::try_from(valid.as_slice()).is_ok());
+
+        assert_eq!(
+            PeerHandshake::try_from(&valid[..67]).unwrap_err(),
+            HandshakeError::Truncated
+        );
+
+        let mut wrong_length = valid.clone();
+        wrong_length[0] = 18;
+        assert!(matches!(
+            PeerHandshake::try_from(wrong_length.as_slice()),
+            Err(HandshakeError::BadProtocol(_))
+        ));
+
+        assert_eq!(
+            PeerHandshake::try_from(&valid[..40]).unwrap_err(),
+            HandshakeError::Truncated
+        );
+
+        // Extension bits come through as sent
+        let mut extended = valid.clone();
+        extended[20..28].copy_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        let handshake = PeerHandshake::try_from(extended.as_slice()).unwrap();
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(handshake.supports_extensions() && handshake.supports_fast());
+
+        // Something other than a peer on the port
+        let mut http = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n".to_vec();
+        http.resize(68, b' ');
+        assert!(matches!(
+            PeerHandshake::try_from(http.as_slice()),
+            Err(HandshakeError::BadProtocol(_))
+        ));
+        let mut not_utf8 = valid.clone();
+        not_utf8[1..20].copy_from_slice(&[0xff; 19]);
+        assert!(matches!(
+            PeerHandshake::try_from(not_utf8.as_slice()),
+            Err(HandshakeError::BadProtocol(_))
+        ));
+
+        let mut wrong_protocol = valid.clone();
+        wrong_protocol[1..20].copy_from_slice(b"BitTorrent protocoX");
+        assert_eq!(
+            PeerHandshake::try_from(wrong_protocol.as_slice()).unwrap_err(),
+            HandshakeError::BadProtocol("BitTorrent protocoX".to_string())
+        );
+    }
+
+    #[test]
+    fn test_peer_handshake_into() {
+        let handshake = PeerHandshake {
+            length: 19,
+            protocol: "BitTorrent protocol".to_string(),
+            reserved: [0; 8],
+            info_hash: vec![
+                214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19, 135,
+                154, 127,
+            ],
+            peer_id: vec![
+                45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54,
+            ],
+        };
+        let handshake_bytes: Vec<u8> = handshake.into();
+        assert_eq!(
+            handshake_bytes,
+            vec![
+                19, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111,
+                99, 111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 214, 159, 145, 230, 178, 174, 76, 84, 36,
+                104, 209, 7, 58, 113, 212, 234, 19, 135, 154, 127, 45, 84, 82, 50, 57, 52, 48, 45,
+                50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peer_message_from() {
+        // Choke
+        let message_bytes = vec![0, 0, 0, 1, 0];
+        let message = PeerMessage::try_from(message_bytes).unwrap();
+        assert_eq!(message, PeerMessage::Choke);
+
+        // Bitfield
+        let message_bytes = vec![0, 0, 0, 6, 5, 1, 2, 3, 4, 5];
+        let message = PeerMessage::try_from(message_bytes).unwrap();
+        assert_eq!(
+            message,
+            PeerMessage::Bitfield(Bitfield::from(vec![1, 2, 3, 4, 5]))
+        );
+    }
+
+    #[test]
+    fn test_have_round_trip() {
+        let bytes = vec![0, 0, 0, 5, 4, 0, 0, 0x01, 0x02];
+        assert_eq!(Vec::<u8>::from(&PeerMessage::Have(258)), bytes);
+        assert_eq!(
+            PeerMessage::try_from(bytes).unwrap(),
+            PeerMessage::Have(258)
+        );
+        assert_eq!(PeerMessage::Have(7).to_string(), "Have { index: 7 }");
+        for index in [0, u32::MAX] {
+            let bytes = Vec::<u8>::from(&PeerMessage::Have(index));
+            assert_eq!(
+                PeerMessage::try_from(bytes).unwrap(),
+                PeerMessage::Have(index)
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_and_cancel_round_trip() {
+        for (index, begin, length) in [(0, 0, 16384), (3, 16384, 1), (u32::MAX, u32::MAX, 0)] {
+            let request = PeerMessage::Request {
+                index,
+                begin,
+                length,
+            };
+            let bytes = Vec::<u8>::from(&request);
+            assert_eq!(bytes.len(), 17);
+            assert_eq!(PeerMessage::try_from(bytes).unwrap(), request);
+
+            let cancel = PeerMessage::Cancel {
                 index,
                 begin,
                 length,
-            } => write!(
-                f,
-                "Cancel {{ index: {}, begin: {}, length: {} }}",
-                index, begin, length
+            };
+            let bytes = Vec::<u8>::from(&cancel);
+            assert_eq!(bytes.len(), 17);
+            assert_eq!(PeerMessage::try_from(bytes).unwrap(), cancel);
+        }
+    }
+
+    #[test]
+    fn test_request_and_cancel_wire_format() {
+        // <len=0013><id><index><begin><length>, whatever the block length
+        let request = PeerMessage::Request {
+            index: 1,
+            begin: 0x4000,
+            length: 0x4000,
+        };
+        assert_eq!(
+            Vec::<u8>::from(&request),
+            vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0]
+        );
+        let cancel = PeerMessage::Cancel {
+            index: 2,
+            begin: 0,
+            length: 0x3000,
+        };
+        assert_eq!(
+            Vec::<u8>::from(&cancel),
+            vec![0, 0, 0, 13, 8, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0x30, 0]
+        );
+    }
+
+    #[test]
+    fn test_fast_extension_messages_round_trip() {
+        let messages = [
+            (
+                PeerMessage::SuggestPiece { index: 7 },
+                vec![0, 0, 0, 5, 0x0d, 0, 0, 0, 7],
+            ),
+            (PeerMessage::HaveAll, vec![0, 0, 0, 1, 0x0e]),
+            (PeerMessage::HaveNone, vec![0, 0, 0, 1, 0x0f]),
+            (
+                PeerMessage::RejectRequest {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                vec![0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
             ),
+            (
+                PeerMessage::AllowedFast { index: 2 },
+                vec![0, 0, 0, 5, 0x11, 0, 0, 0, 2],
+            ),
+        ];
+        for (message, bytes) in messages {
+            assert_eq!(Vec::<u8>::from(&message), bytes, "{}", message);
+            assert_eq!(PeerMessage::try_from(bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_unknown_messages() {
+        // Ids nobody uses yet
+        for (id, payload) in [
+            (0x43, vec![0, b'd', b'e']),
+            (0x42, vec![0x1a, 0xe1]),
+            (0xff, vec![]),
+        ] {
+            let message = PeerMessage::Unknown { id, payload };
+            let bytes = Vec::<u8>::from(&message);
+            assert_eq!(PeerMessage::try_from(bytes).unwrap(), message);
+        }
+
+        // Known ids the wrong size can't be taken apart
+        let short_request = vec![0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0x40, 0];
+        assert_eq!(
+            PeerMessage::try_from(short_request),
+            Err(MessageError::Malformed { id: 6, length: 8 })
+        );
+        assert!(PeerMessage::try_from(vec![0, 0, 0, 2, 4, 0]).is_err());
+        assert!(PeerMessage::try_from(vec![0, 0, 0, 2, 1, 0]).is_err());
+        assert!(PeerMessage::try_from(vec![0, 0, 0, 5, 7, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_messages_are_skipped() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 0x43, b"\0d1:md11:ut_metadatai1eee");
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 0x42, &[0x1a, 0xe1]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &[3; 8]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(peer_stream.peer_has_piece(0));
+        assert_eq!(peer_stream.download_piece(0, &8).unwrap(), vec![3; 8]);
+    }
+
+    #[test]
+    fn test_download_piece_rerequests_rejected_block() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let content = piece.clone();
+        let peer_addr = spawn_scripted_peer(move |stream| {
+            // Turn down the first block, then serve every request
+            let (index, begin, length) = read_block_request(stream);
+            let mut reject = index.to_be_bytes().to_vec();
+            reject.extend(begin.to_be_bytes());
+            reject.extend(length.to_be_bytes());
+            write_message(stream, 0x0e, &[]);
+            write_message(stream, 0x10, &reject);
+            let mut served = 0;
+            while served < content.len() {
+                let (index, begin, length) = read_block_request(stream);
+                let block = &content[begin as usize..(begin + length) as usize];
+                write_block(stream, index, begin, block);
+                served += block.len();
+            }
+        });
+
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let payload = peer_stream.download_piece(0, &20000).unwrap();
+        assert_eq!(payload, piece);
+    }
+
+    fn quick_timeouts() -> PeerTimeouts {
+        PeerTimeouts {
+            connect: Duration::from_millis(200),
+            handshake: Duration::from_millis(200),
+            read: Duration::from_millis(200),
+        }
+    }
+
+    #[test]
+    fn test_connect_errors_on_unreachable_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let err = PeerStream::connect(closed, quick_timeouts()).err().unwrap();
+        assert!(err.to_string().contains("Could not connect"), "{}", err);
+    }
+
+    #[test]
+    fn test_handshake_deadline() {
+        // Bound but never accepting: the kernel completes the connect, then nothing
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        let mut peer_stream = PeerStream::connect(peer_addr, quick_timeouts()).unwrap();
+        let started = Instant::now();
+        let err = peer_stream.prep_download(&[1; 20]).unwrap_err();
+        assert!(err.to_string().contains("No handshake"), "{}", err);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_peer_stream_over_memory() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &[9; 8]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let payload = peer_stream.download_piece(0, &8).unwrap();
+        assert_eq!(payload, vec![9; 8]);
+
+        // Our side: handshake, Interested, then a Request for the whole piece
+        let written = &peer_stream.get_ref().written;
+        assert_eq!(&written[28..48], &[1; 20]);
+        assert_eq!(&written[68..73], &[0, 0, 0, 1, 2]);
+        let mut rest = std::io::Cursor::new(&written[73..]);
+        assert_eq!(read_block_request(&mut rest), (0, 0, 8));
+    }
+
+    // The peer's end of a duplex pipe: handshake, Bitfield & Unchoke, then `choke_first`
+    // Requests answered with Choke & Unchoke before every one gets its block of `piece`
+    async fn serve_async_peer(
+        mut peer: tokio::io::DuplexStream,
+        piece: Vec<u8>,
+        mut choke_first: usize,
+    ) -> Vec<(u32, u32, u32)> {
+        let mut handshake = [0; 68];
+        peer.read_exact(&mut handshake).await.unwrap();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        peer.write_all(&canned).await.unwrap();
+        let mut interested = [0; 5];
+        peer.read_exact(&mut interested).await.unwrap();
+        assert_eq!(interested, [0, 0, 0, 1, 2]);
+
+        let mut requests = Vec::new();
+        let mut request = [0; 17];
+        while peer.read_exact(&mut request).await.is_ok() {
+            let (index, begin, length) =
+                read_block_request(&mut std::io::Cursor::new(&request[..]));
+            requests.push((index, begin, length));
+            let mut reply = Vec::new();
+            if choke_first > 0 {
+                choke_first -= 1;
+                write_message(&mut reply, 0, &[]);
+                write_message(&mut reply, 1, &[]);
+            } else {
+                let end = (begin + length) as usize;
+                write_block(&mut reply, index, begin, &piece[begin as usize..end]);
+            }
+            peer.write_all(&reply).await.unwrap();
         }
+        requests
+    }
+
+    #[tokio::test]
+    async fn test_async_peer_stream_downloads_piece() {
+        let piece: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(serve_async_peer(theirs, piece.clone(), 0));
+
+        let mut peer_stream = AsyncPeerStream::from_stream(ours, MOCK_PEER);
+        peer_stream.set_num_pieces(1);
+        peer_stream.prep_download(&[1; 20]).await.unwrap();
+        let payload = peer_stream.download_piece(0, &40000).await.unwrap();
+        assert_eq!(payload, piece);
+        assert!(matches!(
+            peer_stream
+                .download_piece(1, &10)
+                .await
+                .unwrap_err()
+                .downcast(),
+            Ok(DownloadError::MissingPiece(1))
+        ));
+
+        drop(peer_stream);
+        assert_eq!(
+            peer.await.unwrap(),
+            vec![(0, 0, 16384), (0, 16384, 16384), (0, 32768, 7232)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_download_piece_waits_out_a_choke() {
+        let piece = vec![5; 20000];
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(serve_async_peer(theirs, piece.clone(), 1));
+
+        let mut peer_stream = AsyncPeerStream::from_stream(ours, MOCK_PEER);
+        peer_stream.prep_download(&[1; 20]).await.unwrap();
+        let payload = peer_stream.download_piece(0, &20000).await.unwrap();
+        assert_eq!(payload, piece);
+
+        // Both requests were in flight when choked, so both are asked for again once
+        // unchoked, in a smaller window
+        drop(peer_stream);
+        assert_eq!(
+            peer.await.unwrap(),
+            vec![
+                (0, 0, 16384),
+                (0, 16384, 3616),
+                (0, 0, 16384),
+                (0, 16384, 3616)
+            ]
+        );
     }
-}
 
-pub struct PeerStream {
-    stream: TcpStream,
-    state: PeerState,
-}
+    #[tokio::test]
+    async fn test_async_peer_stream_needs_handshake() {
+        let (ours, _theirs) = tokio::io::duplex(64);
+        let mut peer_stream = AsyncPeerStream::from_stream(ours, MOCK_PEER);
+        assert!(peer_stream.read().await.is_err());
+        assert!(peer_stream.write(&PeerMessage::Interested).await.is_err());
+    }
 
-enum PeerState {
-    Init = 0,
-    Handshake,
-    Bitfield,
-    Interested,
-    Unchoke,
-}
+    #[tokio::test]
+    async fn test_async_peer_stream_keep_alive() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let started = tokio::time::Instant::now();
+        // Says nothing but one keep-alive early on, & notes when ours come in
+        let peer = tokio::spawn(async move {
+            let mut handshake = [0; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            let handshake: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+            theirs.write_all(&handshake).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            theirs.write_all(&[0; 4]).await.unwrap();
+            let mut heard = Vec::new();
+            let mut keep_alive = [0; 4];
+            while theirs.read_exact(&mut keep_alive).await.is_ok() {
+                assert_eq!(keep_alive, [0; 4]);
+                heard.push(started.elapsed().as_millis());
+            }
+            heard
+        });
 
-impl PeerStream {
-    pub fn new(peer_addr: SocketAddrV4) -> Self {
-        let stream = TcpStream::connect(peer_addr).unwrap();
-        PeerStream {
-            stream,
-            state: PeerState::Init,
+        let mut peer_stream = AsyncPeerStream::from_stream(ours, MOCK_PEER);
+        peer_stream.set_keep_alive_interval(Duration::from_millis(100));
+        peer_stream.set_idle_timeout(Duration::from_millis(300));
+        peer_stream.handshake(&[1; 20]).await.unwrap();
+        let err = loop {
+            let wait = match peer_stream.keep_alive().await {
+                Ok(wait) => wait,
+                Err(e) => break e,
+            };
+            tokio::select! {
+                message = peer_stream.read() => assert_eq!(message.unwrap(), PeerMessage::KeepAlive),
+                _ = tokio::time::sleep(wait) => {}
+            }
+        };
+        // Given up on once it's said nothing for the idle timeout
+        let given_up = started.elapsed().as_millis();
+        assert!((360..420).contains(&given_up), "{}", given_up);
+        assert!(err.to_string().starts_with("Nothing from"), "{}", err);
+        drop(peer_stream);
+        let heard = peer.await.unwrap();
+        assert_eq!(heard.len(), 3, "{:?}", heard);
+        for (i, &at) in heard.iter().enumerate() {
+            let due = 100 * (i as u128 + 1);
+            assert!((due..due + 50).contains(&at), "{:?}", heard);
         }
     }
 
-    pub fn handshake(&mut self, info_hash: &[u8; 20]) -> Result<PeerHandshake, Error> {
-        let handshake = PeerHandshake::new(info_hash.to_vec(), PEER_ID.as_bytes().to_vec());
-        let handshake_bytes: Vec<u8> = handshake.into();
-        self.stream.write_all(&handshake_bytes)?;
+    #[test]
+    fn test_peer_state_flags() {
+        let mut state = PeerState::default();
+        assert!(state.am_choking && state.peer_choking);
+        assert_eq!(
+            state.received(&PeerMessage::Unchoke),
+            Err(StateError::NotHandshaken)
+        );
+        assert_eq!(
+            state.sent(&PeerMessage::Interested),
+            Err(StateError::NotHandshaken)
+        );
+        state.handshake().unwrap();
+        assert_eq!(state.handshake(), Err(StateError::AlreadyHandshaken));
 
-        // Read the handshake response
-        let mut buf = [0; 68];
-        self.stream.read(&mut buf)?;
-        let peer_handshake = PeerHandshake::from(buf.to_vec());
-        self.state = PeerState::Handshake;
-        // println!("Peer Handshake: {:?}", peer_handshake);
-        Ok(peer_handshake)
+        state.received(&PeerMessage::KeepAlive).unwrap();
+        assert_eq!(state.expect_bitfield(), Ok(()));
+        state.received(&PeerMessage::Interested).unwrap();
+        assert!(state.peer_interested);
+        // Anything but a keep-alive closes the Bitfield's window
+        assert_eq!(state.expect_bitfield(), Err(StateError::LateBitfield));
+        assert_eq!(
+            state.received(&PeerMessage::Bitfield(Bitfield::new(8))),
+            Err(StateError::LateBitfield)
+        );
+        state.received(&PeerMessage::NotInterested).unwrap();
+        assert!(!state.peer_interested);
+
+        let request = PeerMessage::Request {
+            index: 0,
+            begin: 0,
+            length: 16384,
+        };
+        assert_eq!(state.sent(&request), Err(StateError::NotInterested));
+        state.sent(&PeerMessage::Interested).unwrap();
+        assert!(state.am_interested);
+        assert_eq!(state.sent(&request), Err(StateError::Choked));
+        state.received(&PeerMessage::Unchoke).unwrap();
+        assert!(!state.peer_choking);
+        state.sent(&request).unwrap();
+        state.received(&PeerMessage::Choke).unwrap();
+        assert_eq!(state.can_request(), Err(StateError::Choked));
+
+        state.sent(&PeerMessage::Unchoke).unwrap();
+        assert!(!state.am_choking);
+        state.sent(&PeerMessage::Choke).unwrap();
+        state.sent(&PeerMessage::NotInterested).unwrap();
+        assert!(state.am_choking && !state.am_interested);
     }
 
-    pub fn read(&mut self) -> Result<PeerMessage, Error> {
-        // Assert that we are at least in the handshake state
-        match self.state {
-            PeerState::Init => panic!("Cannot read if not yet handshaked"),
-            _ => {}
-        }
+    #[test]
+    fn test_peer_stream_out_of_order() {
+        let mut peer_stream = scripted_peer(Vec::new());
+        let err = peer_stream.read().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StateError::NotHandshaken));
+
+        // Unchoked without asking: waiting for it is a mistake on our side
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_message(&mut canned, 5, &[0x80]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.handshake(&[1; 20]).unwrap();
+        peer_stream.read_bitfield().unwrap();
+        let err = peer_stream.read_unchoke().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StateError::NotInterested));
+        let err = peer_stream.download_piece(0, &100).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StateError::NotInterested));
 
-        // Read the length prefix
-        let mut length_prefix: [u8; 4] = [0; 4];
-        self.stream.read_exact(&mut length_prefix)?;
-        let length = u32::from_be_bytes(length_prefix);
+        // A second Bitfield is a protocol error
+        assert_eq!(peer_stream.read().unwrap(), PeerMessage::Unchoke);
+        assert!(!peer_stream.is_choked());
+        let err = peer_stream.read().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StateError::LateBitfield));
+    }
 
-        // Read the message type
-        let mut message_type: [u8; 1] = [0; 1];
-        self.stream.read_exact(&mut message_type)?;
+    #[test]
+    fn test_peer_stream_rejects_malformed_handshake() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        canned[1..20].copy_from_slice(b"BitTorrent protocoX");
+        let err = scripted_peer(canned).prep_download(&[1; 20]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HandshakeError::BadProtocol(_))
+        ));
 
-        // Read the payload
-        let mut payload: Vec<u8> = vec![0; length as usize - 1];
-        self.stream.read_exact(&mut payload)?;
+        // Cut short: the peer hung up partway through its handshake
+        let canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        let err = scripted_peer(canned[..40].to_vec())
+            .prep_download(&[1; 20])
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&HandshakeError::Truncated));
 
-        let mut full_msg: Vec<u8> = Vec::new();
-        full_msg.extend(length_prefix.to_vec());
-        full_msg.extend(message_type.to_vec());
-        full_msg.extend(payload.to_vec());
-        let msg = PeerMessage::from(full_msg);
-        Ok(msg)
+        // Answering for some other torrent
+        let canned: Vec<u8> = PeerHandshake::new(vec![3; 20], vec![2; 20]).into();
+        let mut peer_stream = scripted_peer(canned);
+        let err = peer_stream.handshake(&[1; 20]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&HandshakeError::InfoHashMismatch {
+                expected: [1; 20],
+                actual: [3; 20]
+            })
+        );
+        assert!(err.to_string().contains(&"03".repeat(20)), "{}", err);
+        // Still not handshaked, so nothing else goes out
+        assert!(peer_stream.write(&PeerMessage::Interested).is_err());
     }
 
-    pub fn write(&mut self, message: &PeerMessage) -> Result<(), Error> {
-        // Assert that we are in the handshake state
-        match self.state {
-            PeerState::Init => return Err(anyhow!("Cannot write if not yet handshaked")),
-            _ => {}
-        }
+    #[test]
+    fn test_blocks_out_of_order() {
+        let content: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        // Last block first, as a peer answering pipelined requests may
+        write_block(&mut canned, 0, 32768, &content[32768..]);
+        write_block(&mut canned, 0, 0, &content[..16384]);
+        write_block(&mut canned, 0, 16384, &content[16384..32768]);
 
-        // Write the message
-        let message_bytes: Vec<u8> = message.into();
-        self.stream.write_all(&message_bytes)?;
-        Ok(())
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.download_piece(0, &40000).unwrap(), content);
+
+        // A block that doesn't fit is an error, not a panic
+        let block = Block {
+            index: 0,
+            begin: 30000,
+            data: vec![0; 16384],
+        };
+        assert!(place_block(&mut vec![0; 40000], &block).is_err());
     }
 
-    // Specific steps
-    pub fn read_bitfield(&mut self) -> Result<PeerMessage, Error> {
-        // Assert that we are in the handshake state
-        match self.state {
-            PeerState::Handshake => {}
-            _ => return Err(anyhow!("Bitfield can only be read from Handshake")),
+    #[test]
+    fn test_peer_closes_mid_message() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &[9; 100]);
+        canned.truncate(canned.len() - 50);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let err = peer_stream.download_piece(0, &100).unwrap_err();
+        let io_err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_piece_blocks_keep_their_length() {
+        for len in [1, 16 * 1024] {
+            let piece = PeerMessage::Piece {
+                index: 3,
+                begin: 16384,
+                block: vec![0xab; len],
+            };
+            let bytes = Vec::<u8>::from(&piece);
+            assert_eq!(bytes.len(), 13 + len);
+            assert_eq!(&bytes[..4], &(9 + len as u32).to_be_bytes());
+            assert_eq!(PeerMessage::try_from(bytes).unwrap(), piece);
         }
+    }
 
-        // Read the bitfield message
-        let message = self.read()?;
-        match message {
-            PeerMessage::Bitfield(_) => {
-                self.state = PeerState::Bitfield;
-                Ok(message)
+    #[test]
+    fn test_download_piece_covers_whole_piece() {
+        for piece_length in [16384, 16385, 32768, 40000, 100] {
+            let piece: Vec<u8> = (0..piece_length).map(|i| (i % 251) as u8).collect();
+            let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+            write_message(&mut canned, 5, &[0x80]);
+            write_message(&mut canned, 1, &[]);
+            for (begin, length) in blocks_for(piece_length as i64) {
+                write_block(
+                    &mut canned,
+                    0,
+                    begin,
+                    &piece[begin as usize..(begin + length) as usize],
+                );
+            }
+
+            let mut peer_stream = scripted_peer(canned);
+            peer_stream.prep_download(&[1; 20]).unwrap();
+            let payload = peer_stream
+                .download_piece(0, &(piece_length as i64))
+                .unwrap();
+            assert_eq!(payload, piece, "piece length {}", piece_length);
+
+            // The last request asks for just the remainder
+            let mut requests = std::io::Cursor::new(&peer_stream.get_ref().written[73..]);
+            let mut requested = 0;
+            while (requests.position() as usize) < requests.get_ref().len() {
+                let (_, begin, length) = read_block_request(&mut requests);
+                assert_eq!(begin, requested);
+                assert!(length <= 16384);
+                requested += length;
             }
-            _ => Err(anyhow!("Expected bitfield message")),
+            assert_eq!(requested, piece_length);
         }
     }
 
-    pub fn write_interested(&mut self) -> Result<(), Error> {
-        // Assert that we are in the Bitfield state
-        match self.state {
-            PeerState::Bitfield => {}
-            _ => return Err(anyhow!("Not in bitfield state")),
-        }
+    #[test]
+    fn test_download_piece_tolerates_interleaved_messages() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_message(&mut canned, 4, &3u32.to_be_bytes());
+        write_block(&mut canned, 0, 0, &piece[..16384]);
+        canned.extend([0, 0, 0, 0]);
+        write_message(&mut canned, 4, &5u32.to_be_bytes());
+        // A block nobody asked for is dropped
+        write_block(&mut canned, 1, 16384, &piece[16384..]);
+        write_block(&mut canned, 0, 16384, &piece[16384..]);
 
-        // Write the interested message
-        let message = PeerMessage::Interested;
-        self.write(&message)?;
-        self.state = PeerState::Interested;
-        Ok(())
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.download_piece(0, &20000).unwrap(), piece);
     }
 
-    pub fn read_unchoke(&mut self) -> Result<PeerMessage, Error> {
-        // Assert that we are in the Interested state
-        match self.state {
-            PeerState::Interested => {}
-            _ => return Err(anyhow!("Not in interested state")),
-        }
+    #[test]
+    fn test_download_piece_waits_out_a_choke() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &piece[..16384]);
+        // Choked halfway: the second request is dropped & has to go out again
+        write_message(&mut canned, 0, &[]);
+        write_message(&mut canned, 4, &1u32.to_be_bytes());
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 16384, &piece[16384..]);
 
-        // Read the unchoke message
-        let message = self.read()?;
-        match message {
-            PeerMessage::Unchoke => {
-                self.state = PeerState::Unchoke;
-                Ok(message)
-            }
-            _ => Err(anyhow!("Expected unchoke message")),
-        }
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert_eq!(peer_stream.download_piece(0, &20000).unwrap(), piece);
+
+        let mut written = std::io::Cursor::new(&peer_stream.get_ref().written[73..]);
+        let requests: Vec<_> = (0..3).map(|_| read_block_request(&mut written)).collect();
+        assert_eq!(
+            requests,
+            vec![(0, 0, 16384), (0, 16384, 3616), (0, 16384, 3616)]
+        );
     }
-    
-    pub fn prep_download(&mut self, info_hash: &[u8;20]) -> Result<(), Error> {
-        // Handshake
-        match self.handshake(info_hash) {
-            Ok(handshake) => {
-                println!("Handshake: {:?}", handshake);
-                let hex_peer_id = handshake
-                    .peer_id
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>();
-                println!("Peer ID: {}", hex_peer_id);
-            }
-            Err(e) => {
-                println!("Handshake: Error: {}", e);
-                return Err(e);
-            }
-        }
-        // Bitfield
-        match self.read_bitfield() {
-            Ok(bitfield) => {
-                println!("Bitfield: {:?}", bitfield);
-            }
-            Err(e) => {
-                println!("Bitfield: Error: {}", e);
-                return Err(e);
-            }
-        }
-        // Interest
-        match self.write_interested() {
-            Ok(_) => {
-                println!("Interested: Sent");
-            }
-            Err(e) => {
-                println!("Interested: Error: {}", e);
-                return Err(e);
-            }
+
+    #[test]
+    fn test_download_piece_rerequests_late_block() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        canned.extend([0, 0, 0, 0]);
+        write_block(&mut canned, 0, 0, &[5; 8]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        peer_stream.set_block_timeout(Duration::ZERO);
+        assert_eq!(peer_stream.download_piece(0, &8).unwrap(), vec![5; 8]);
+
+        // Asked once, then again after the keep-alive came in instead
+        let written = &peer_stream.get_ref().written[73..];
+        assert_eq!(written.len(), 2 * 17);
+        assert_eq!(written[..17], written[17..]);
+    }
+
+    // Answers the first of two requests, sits on the second until it's asked again
+    fn spawn_stalling_peer(piece: Vec<u8>) -> SocketAddr {
+        spawn_scripted_peer(move |stream| {
+            let first = read_block_request(stream);
+            let second = read_block_request(stream);
+            assert_eq!((first, second), ((0, 0, 16384), (0, 16384, 3616)));
+            write_block(stream, 0, 0, &piece[..16384]);
+            assert_eq!(read_block_request(stream), second);
+            write_block(stream, 0, 16384, &piece[16384..]);
+        })
+    }
+
+    #[test]
+    fn test_stalled_block_is_rerequested() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let peer_addr = spawn_stalling_peer(piece.clone());
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        peer_stream.set_block_timeout(Duration::from_millis(200));
+        let started = Instant::now();
+        assert_eq!(peer_stream.download_piece(0, &20000).unwrap(), piece);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_async_handshake_and_unchoke_deadlines() {
+        // Accepts, then says nothing
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let mut peer_stream = AsyncPeerStream::connect(peer_addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        peer_stream.set_timeouts(quick_timeouts());
+        let started = Instant::now();
+        let err = peer_stream.prep_download(&[1; 20]).await.unwrap_err();
+        assert!(err.to_string().contains("No handshake"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Handshakes & sends a bitfield, but never unchokes
+        let peer_addr = spawn_handshaking_peer(|stream| {
+            write_message(stream, 5, &[0x80]);
+            std::thread::sleep(Duration::from_secs(5));
+        });
+        let mut peer_stream = AsyncPeerStream::connect(peer_addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        peer_stream.set_timeouts(quick_timeouts());
+        let started = Instant::now();
+        let err = peer_stream.prep_download(&[1; 20]).await.unwrap_err();
+        assert!(err.to_string().contains("No unchoke"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_async_stalled_block_is_rerequested() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let peer_addr = spawn_stalling_peer(piece.clone());
+        let mut peer_stream = AsyncPeerStream::connect(peer_addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        peer_stream.prep_download(&[1; 20]).await.unwrap();
+        peer_stream.set_block_timeout(Duration::from_millis(200));
+        assert_eq!(peer_stream.download_piece(0, &20000).await.unwrap(), piece);
+    }
+
+    #[tokio::test]
+    async fn test_async_download_piece_streams_blocks() {
+        use tokio_stream::StreamExt;
+
+        let piece: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        for (begin, length) in blocks_for(40000) {
+            let block = &piece[begin as usize..(begin + length) as usize];
+            write_block(&mut canned, 0, begin, block);
         }
-        // Unchoke
-        match self.read_unchoke() {
-            Ok(_) => {
-                println!("Unchoke: Received");
-            }
-            Err(e) => {
-                println!("Unchoke: Error: {}", e);
-                return Err(e);
-            }
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let mut blocks = peer_stream.async_download_piece(0, 40000);
+        let mut received = Vec::new();
+        while let Some(block) = blocks.next().await {
+            received.push(block.unwrap());
         }
-        return Ok(());
+        let begins: Vec<u32> = received.iter().map(|block| block.begin).collect();
+        assert_eq!(begins, vec![0, 16384, 32768]);
+        let payload: Vec<u8> = received.into_iter().flat_map(|block| block.data).collect();
+        assert_eq!(payload, piece);
+
+        // The connection is still good for the next piece
+        let peer_stream = blocks.finish().await.unwrap();
+        let mut written = std::io::Cursor::new(&peer_stream.get_ref().written[73..]);
+        assert_eq!(read_block_request(&mut written), (0, 0, 16384));
     }
 
-    pub fn download_piece(
-        &mut self,
-        piece_id: u32,
-        piece_length: &i64,
-    ) -> Result<Vec<PeerMessage>, Error> {
-        // Assert that we are in the Unchoke state
-        match self.state {
-            PeerState::Unchoke => {}
-            _ => return Err(anyhow!("Not in unchoke state")),
-        }
-
-        // Make a Vec of requests to cover piece_length with chunk
-        // by ceil(piece_length / CHUNK_SIZE)
-        let n_reqs = (piece_length + CHUNK_SIZE - 1) / CHUNK_SIZE;
-        println!("piece_length: {}, n_reqs: {}", piece_length, n_reqs);
-        let reqs = (0..n_reqs)
-            .map(|i| {
-                let is_last = n_reqs - 1 == i;
-                let length = if is_last {
-                    piece_length - (i as i64 * CHUNK_SIZE)
-                } else {
-                    CHUNK_SIZE
-                };
-                PeerMessage::Request {
-                    index: piece_id,
-                    begin: (i * CHUNK_SIZE) as u32,
-                    length: length as u32,
-                }
-            })
-            .collect::<Vec<PeerMessage>>();
+    #[test]
+    fn test_download_piece_needs_peer_to_have_it() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        // Pieces 0 & 2 of 10, then a Have for piece 1
+        write_message(&mut canned, 5, &[0b1010_0000, 0]);
+        write_message(&mut canned, 1, &[]);
+        write_message(&mut canned, 4, &1u32.to_be_bytes());
+        write_block(&mut canned, 1, 0, &[3; 8]);
 
-        // Iter & map over the requests
-        let responses = reqs
-            .iter()
-            // zip with reqs
-            .enumerate()
-            .map(|(idx, req)| {
-                // Send the request
-                println!("Idx: {}; {}", idx, req);
-                self.write(req)?;
-
-                // Wait for the piece response
-                let resp = self.read()?;
-                match resp {
-                    PeerMessage::Piece {
-                        index: _,
-                        begin: _,
-                        block: _,
-                    } => Ok(resp),
-                    _ => Err(anyhow!("Expected piece message")),
-                }
-            })
-            .collect::<Result<Vec<PeerMessage>, Error>>()?;
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.set_num_pieces(10);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(peer_stream.peer_has_piece(0));
+        assert!(!peer_stream.peer_has_piece(1));
+        assert!(peer_stream.peer_has_piece(2));
 
-        Ok(responses)
-    }
-}
+        let err = peer_stream.download_piece(3, &8).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::MissingPiece(3))
+        ));
+        // Nothing was asked for
+        assert_eq!(peer_stream.get_ref().written.len(), 73);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert_eq!(peer_stream.read().unwrap(), PeerMessage::Have(1));
+        assert!(peer_stream.peer_has_piece(1));
+        assert_eq!(peer_stream.download_piece(1, &8).unwrap(), vec![3; 8]);
+    }
 
     #[test]
-    fn test_urlencode() {
-        let info_hash = [
-            0xd6, 0x9f, 0x91, 0xe6, 0xb2, 0xae, 0x4c, 0x54, 0x24, 0x68, 0xd1, 0x07, 0x3a, 0x71,
-            0xd4, 0xea, 0x13, 0x87, 0x9a, 0x7f,
-        ];
-        let encoded = urlencode(&info_hash).unwrap();
+    fn test_bitfield_is_optional() {
+        // As expected: Bitfield, then Unchoke
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0b0100_0000]);
+        write_message(&mut canned, 1, &[]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(!peer_stream.peer_has_piece(0));
+        assert!(peer_stream.peer_has_piece(1));
+
+        // Straight to Unchoke: a peer with nothing yet
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 1, &[]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.handshake(&[1; 20]).unwrap();
         assert_eq!(
-            encoded,
-            "%d6%9f%91%e6%b2%ae%4c%54%24%68%d1%07%3a%71%d4%ea%13%87%9a%7f"
+            peer_stream.read_bitfield().unwrap(),
+            PeerMessage::Bitfield(Bitfield::default())
         );
+        peer_stream.write_interested().unwrap();
+        peer_stream.read_unchoke().unwrap();
+        assert!(!peer_stream.peer_has_piece(0));
+
+        // Haves instead of a Bitfield, before & after Interested
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 4, &0u32.to_be_bytes());
+        write_message(&mut canned, 4, &2u32.to_be_bytes());
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 2, 0, &[5; 8]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(peer_stream.peer_has_piece(0));
+        assert!(!peer_stream.peer_has_piece(1));
+        assert_eq!(peer_stream.download_piece(2, &8).unwrap(), vec![5; 8]);
+        // Interested went out all the same
+        assert_eq!(&peer_stream.get_ref().written[68..73], &[0, 0, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_async_peer_stream_without_bitfield() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let mut handshake = [0; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+            write_message(&mut canned, 4, &1u32.to_be_bytes());
+            write_message(&mut canned, 1, &[]);
+            theirs.write_all(&canned).await.unwrap();
+            let mut interested = [0; 5];
+            theirs.read_exact(&mut interested).await.unwrap();
+            interested
+        });
+
+        let mut peer_stream = AsyncPeerStream::from_stream(ours, MOCK_PEER);
+        peer_stream.prep_download(&[1; 20]).await.unwrap();
+        assert!(peer_stream.peer_has_piece(1));
+        assert!(!peer_stream.peer_has_piece(0));
+        assert_eq!(peer.await.unwrap(), [0, 0, 0, 1, 2]);
     }
 
     #[test]
-    fn test_tracker_payload_default() {
-        let payload = TrackerPayload::default();
-        assert_eq!(payload.port, 6881);
-        assert_eq!(payload.uploaded, 0);
-        assert_eq!(payload.downloaded, 0);
-        assert_eq!(payload.left, 0);
-        assert_eq!(payload.compact, true);
+    fn test_bitfield_checked_against_piece_count() {
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0xff]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.set_num_pieces(10);
+        let err = peer_stream.prep_download(&[1; 20]).unwrap_err();
+        assert!(
+            err.to_string().contains("expected 2 for 10 pieces"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_tracker_payload_serialize() {
-        let payload = TrackerPayload {
-            // info_hash: vec![],
-            peer_id: "peer_id".to_string(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: 0,
-            compact: true,
-        };
-        let serialized = serde_urlencoded::to_string(&payload).unwrap();
+    fn test_download_piece_rejects_short_block() {
+        // A block shorter than asked for isn't quietly accepted
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &[1; 100]);
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let err = peer_stream.download_piece(0, &200).unwrap_err();
+        assert!(err.to_string().contains("asked for 200"), "{}", err);
+    }
+
+    #[test]
+    fn test_trace_logs_messages_in_order() {
+        crate::testsupport::init_test_logger();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 0, &[9; 8]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.set_trace("trace-test");
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        peer_stream.download_piece(0, &8).unwrap();
+
+        let trace: Vec<String> = crate::testsupport::logged_records()
+            .into_iter()
+            .filter_map(|(_, message)| message.strip_prefix("trace-test ").map(str::to_string))
+            .collect();
         assert_eq!(
-            serialized,
-            "peer_id=peer_id&port=6881&uploaded=0&downloaded=0&left=0&compact=1"
+            trace,
+            vec![
+                "<- Bitfield",
+                "-> Interested",
+                "<- Unchoke",
+                "-> Request { index: 0, begin: 0, length: 8 }",
+                "<- Piece { index: 0, begin: 0, length: 8, block: [9, 9, 9, 9, 9, 9, 9, 9]... }",
+            ]
         );
     }
 
     #[test]
-    fn test_tracker_response_try_from() {
-        let bencoded = BencodedValue::from(
-            b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\x90\x7f\x00\x00\x01\x1b\x90e"
-                .as_slice(),
+    fn test_read_message_keep_alive() {
+        let mut stream = std::io::Cursor::new(vec![0, 0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(
+            read_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE).unwrap(),
+            PeerMessage::KeepAlive
+        );
+        assert_eq!(
+            read_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE).unwrap(),
+            PeerMessage::Unchoke
+        );
+        assert!(read_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE).is_err());
+
+        assert_eq!(Vec::<u8>::from(&PeerMessage::KeepAlive), vec![0, 0, 0, 0]);
+        assert_eq!(
+            PeerMessage::try_from(vec![0, 0, 0, 0]).unwrap(),
+            PeerMessage::KeepAlive
         );
-        let tracker_response = TrackerResponse::try_from(&bencoded).unwrap();
-        assert_eq!(tracker_response.interval, 1800);
-        // Test without ordering
-        assert!(tracker_response
-            .peers
-            .contains(&SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6800)));
-        assert!(tracker_response
-            .peers
-            .contains(&SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 7056)));
     }
 
     #[test]
-    fn test_peer_handshake_default() {
-        let handshake = PeerHandshake::default();
-        assert_eq!(handshake.length, 19);
-        assert_eq!(handshake.protocol, "BitTorrent protocol");
-        assert_eq!(handshake.reserved, vec![0; 8]);
-        assert_eq!(handshake.info_hash, Vec::<u8>::new());
-        assert_eq!(handshake.peer_id, PEER_ID.as_bytes());
+    fn test_download_piece_skips_keep_alives() {
+        let peer_addr = spawn_scripted_peer(|stream| {
+            stream.write_all(&[0, 0, 0, 0]).unwrap();
+            let (index, begin, _) = read_block_request(stream);
+            write_block(stream, index, begin, &[7; 8]);
+        });
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let payload = peer_stream.download_piece(0, &8).unwrap();
+        assert_eq!(payload, vec![7; 8]);
     }
 
     #[test]
-    fn test_peer_handshake_from() {
-        let handshake_bytes = vec![
-            19, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99,
-            111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209,
-            7, 58, 113, 212, 234, 19, 135, 154, 127, 45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51,
-            98, 54, 98, 52, 98, 53, 98, 54, 0, 0, 0, 0, 0, 0, 0, 0,
-        ];
-        let handshake = PeerHandshake::from(handshake_bytes);
-        assert_eq!(handshake.length, 19);
-        assert_eq!(handshake.protocol, "BitTorrent protocol");
-        assert_eq!(handshake.reserved, vec![0; 8]);
+    fn test_read_rejects_oversized_message() {
+        let peer_addr = spawn_scripted_peer(|stream| {
+            // The id is read along with the length, so the next message lines up
+            stream.write_all(&[0xff, 0xff, 0xff, 0xff, 0x05]).unwrap();
+            stream.write_all(&[0x00, 0x00, 0x40, 0x0a, 0x05]).unwrap();
+        });
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let err = peer_stream.read().unwrap_err();
+        assert!(err.to_string().contains("4294967295 bytes"), "{}", err);
+
+        // A lower limit applies to the next message
+        peer_stream.set_max_message_size(16 * 1024);
+        let err = peer_stream.read().unwrap_err();
+        assert!(err.to_string().contains("16394 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_read_message_checks_length_before_allocating() {
+        // Nothing past the id: had the length been believed, the read would fail on
+        // EOF rather than the limit
+        let mut huge = std::io::Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 7]);
+        let err = read_message(&mut huge, DEFAULT_MAX_MESSAGE_SIZE).unwrap_err();
         assert_eq!(
-            handshake.info_hash,
-            vec![
-                214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19, 135,
-                154, 127
-            ]
+            err.downcast_ref(),
+            Some(&MessageError::TooLong {
+                length: u32::MAX,
+                limit: DEFAULT_MAX_MESSAGE_SIZE
+            })
         );
+
+        // Under the overall limit, but more than any block we'd ask for
+        let mut piece = std::io::Cursor::new([&20000u32.to_be_bytes()[..], &[7]].concat());
+        let err = read_message(&mut piece, DEFAULT_MAX_MESSAGE_SIZE).unwrap_err();
         assert_eq!(
-            handshake.peer_id,
-            vec![45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54, 0]
+            err.downcast_ref(),
+            Some(&MessageError::BlockTooLong {
+                length: 20000,
+                limit: 16393
+            })
         );
+        // Bitfields that size are fine
+        let bitfield = [&20000u32.to_be_bytes()[..], &[5], &[0; 19999]].concat();
+        let mut bitfield = std::io::Cursor::new(bitfield);
+        assert!(read_message(&mut bitfield, DEFAULT_MAX_MESSAGE_SIZE).is_ok());
     }
 
     #[test]
-    fn test_peer_handshake_into() {
-        let handshake = PeerHandshake {
-            length: 19,
-            protocol: "BitTorrent protocol".to_string(),
-            reserved: vec![0; 8],
-            info_hash: vec![
-                214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19, 135,
-                154, 127,
-            ],
-            peer_id: vec![
-                45, 84, 82, 50, 57, 52, 48, 45, 50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54,
-            ],
-        };
-        let handshake_bytes: Vec<u8> = handshake.into();
+    fn test_choke_mid_piece_pauses_requests() {
+        use sha1::{Digest, Sha1};
+
+        let piece: Vec<u8> = (0..3 * 4096u32).map(|i| (i % 251) as u8).collect();
+        let mut canned: Vec<u8> = PeerHandshake::new(vec![1; 20], vec![2; 20]).into();
+        write_message(&mut canned, 5, &[0x80]);
+        write_message(&mut canned, 1, &[]);
+        // One block, then choked: the other two requests are dropped
+        write_block(&mut canned, 0, 0, &piece[..4096]);
+        write_message(&mut canned, 0, &[]);
+        write_message(&mut canned, 4, &3u32.to_be_bytes());
+        canned.extend([0, 0, 0, 0]);
+        write_message(&mut canned, 1, &[]);
+        write_block(&mut canned, 0, 4096, &piece[4096..8192]);
+        write_block(&mut canned, 0, 8192, &piece[8192..]);
+
+        let mut peer_stream = scripted_peer(canned);
+        peer_stream.set_block_size(MIN_BLOCK_SIZE);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        assert!(!peer_stream.is_choked());
+        let payload = peer_stream
+            .download_piece(0, &(piece.len() as i64))
+            .unwrap();
+        assert_eq!(Sha1::digest(&payload), Sha1::digest(&piece));
+        assert!(!peer_stream.is_choked());
+        assert!(peer_stream.peer_has_piece(3));
+
+        // All three asked for, then the two missing ones again after the Unchoke
+        let mut written = std::io::Cursor::new(&peer_stream.get_ref().written[73..]);
+        let requests: Vec<_> = (0..5).map(|_| read_block_request(&mut written)).collect();
         assert_eq!(
-            handshake_bytes,
+            requests,
             vec![
-                19, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111,
-                99, 111, 108, 0, 0, 0, 0, 0, 0, 0, 0, 214, 159, 145, 230, 178, 174, 76, 84, 36,
-                104, 209, 7, 58, 113, 212, 234, 19, 135, 154, 127, 45, 84, 82, 50, 57, 52, 48, 45,
-                50, 98, 51, 98, 54, 98, 52, 98, 53, 98, 54
+                (0, 0, 4096),
+                (0, 4096, 4096),
+                (0, 8192, 4096),
+                (0, 4096, 4096),
+                (0, 8192, 4096)
             ]
         );
     }
 
     #[test]
-    fn test_peer_message_from() {
-        // Choke
-        let message_bytes = vec![0, 0, 0, 1, 0];
-        let message = PeerMessage::from(message_bytes);
-        assert_eq!(message, PeerMessage::Choke);
+    fn test_choked_too_long() {
+        let (interested_tx, interested_rx) = std::sync::mpsc::channel();
+        let peer_addr = spawn_scripted_peer(move |stream| {
+            read_block_request(stream);
+            write_message(stream, 0, &[]);
+            // Silent from here on, but for noting a second Interested
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).unwrap();
+            interested_tx.send(interested).unwrap();
+            let _ = stream.read(&mut [0; 1]);
+        });
 
-        // Bitfield
-        let message_bytes = vec![0, 0, 0, 6, 5, 1, 2, 3, 4, 5];
-        let message = PeerMessage::from(message_bytes);
-        assert_eq!(message, PeerMessage::Bitfield(vec![1, 2, 3, 4, 5]));
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.set_choke_timeout(Duration::from_millis(200));
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let started = Instant::now();
+        let err = peer_stream.download_piece(0, &100).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(DownloadError::ChokedTooLong(timeout)) if *timeout == Duration::from_millis(200)
+            ),
+            "{}",
+            err
+        );
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(peer_stream.is_choked());
+        assert_eq!(interested_rx.recv().unwrap(), [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_request_window_backs_off_when_choked() {
+        let piece: Vec<u8> = (0..20480u32).map(|i| (i % 251) as u8).collect();
+        let content = piece.clone();
+        let peer_addr = spawn_scripted_peer(move |stream| {
+            // Five requests at once is more than this peer queues: it chokes, dropping them
+            for _ in 0..INITIAL_REQUEST_WINDOW {
+                read_block_request(stream);
+            }
+            write_message(stream, 0, &[]);
+            write_message(stream, 1, &[]);
+            let mut served = 0;
+            while served < content.len() {
+                let (index, begin, length) = read_block_request(stream);
+                let block = &content[begin as usize..(begin + length) as usize];
+                write_block(stream, index, begin, block);
+                served += block.len();
+            }
+        });
+
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.set_block_size(MIN_BLOCK_SIZE);
+        assert_eq!(peer_stream.request_window(), INITIAL_REQUEST_WINDOW);
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let payload = peer_stream.download_piece(0, &20480).unwrap();
+        assert_eq!(payload, piece);
+        // Halved to 2, then one more for each window's worth of blocks: 2 at 2, 3 at 3
+        assert_eq!(peer_stream.request_window(), 4);
+
+        let mut window = RequestWindow::default();
+        (0..10_000).for_each(|_| window.delivered());
+        assert_eq!(window.size, MAX_REQUEST_WINDOW);
+        (0..10).for_each(|_| window.back_off());
+        assert_eq!(window.size, 1);
+    }
+
+    #[test]
+    fn test_download_piece_shrinks_rejected_blocks() {
+        let piece: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let content = piece.clone();
+        let (served_tx, served_rx) = std::sync::mpsc::channel();
+        let peer_addr = spawn_scripted_peer(move |stream| {
+            // Anything over 8 KiB is turned down
+            let mut served = 0;
+            while served < content.len() {
+                let (index, begin, length) = read_block_request(stream);
+                if length > 8192 {
+                    let mut reject = index.to_be_bytes().to_vec();
+                    reject.extend(begin.to_be_bytes());
+                    reject.extend(length.to_be_bytes());
+                    write_message(stream, 0x10, &reject);
+                    continue;
+                }
+                let block = &content[begin as usize..(begin + length) as usize];
+                write_block(stream, index, begin, block);
+                served_tx.send(block.len()).unwrap();
+                served += block.len();
+            }
+        });
+
+        let mut peer_stream = PeerStream::connect(peer_addr, PeerTimeouts::default()).unwrap();
+        peer_stream.prep_download(&[1; 20]).unwrap();
+        let payload = peer_stream.download_piece(0, &20000).unwrap();
+        assert_eq!(peer_stream.block_size(), 8192);
+        // The short last block was already in flight when the first was turned down
+        assert_eq!(served_rx.iter().collect::<Vec<_>>(), vec![3616, 8192, 8192]);
+        assert_eq!(payload, piece);
     }
 }
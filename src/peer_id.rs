@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Error};
+use rand::distr::{Alphanumeric, Distribution};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Azureus-style client tag: `-`, two letters for the client, four version digits, `-`
+pub const DEFAULT_PREFIX: &str = "-RS0010-";
+
+// The id this process announces & handshakes with, see PeerId::local
+static LOCAL: OnceLock<PeerId> = OnceLock::new();
+
+// 20 bytes: the client prefix, then random bytes. The random part is alphanumeric
+// so the id goes into tracker query strings & logs as-is.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    pub fn generate() -> Self {
+        Self::with_prefix(DEFAULT_PREFIX)
+    }
+
+    // Prefixes longer than 20 bytes are cut short
+    pub fn with_prefix(prefix: &str) -> Self {
+        let mut id = [0; 20];
+        let prefix_len = prefix.len().min(id.len());
+        id[..prefix_len].copy_from_slice(&prefix.as_bytes()[..prefix_len]);
+        let mut rng = rand::rng();
+        for byte in id[prefix_len..].iter_mut() {
+            *byte = Alphanumeric.sample(&mut rng);
+        }
+        PeerId(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    // Generated on first use, so the tracker & every peer see the same id
+    pub fn local() -> Self {
+        *LOCAL.get_or_init(Self::generate)
+    }
+
+    // Pin the local id, e.g. from --peer-id. Too late once it's been handed out.
+    pub fn set_local(id: PeerId) -> Result<(), Error> {
+        LOCAL
+            .set(id)
+            .map_err(|_| anyhow!("Peer ID is already in use"))
+    }
+}
+
+// Azureus-style client codes, the two letters after the leading `-`
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libTorrent"),
+    (b"lt", "libtorrent"),
+    (b"qB", "qBittorrent"),
+    (b"SD", "Thunder"),
+    (b"TR", "Transmission"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"UW", "µTorrent Web"),
+    (b"WW", "WebTorrent"),
+    (b"XL", "Xunlei"),
+];
+
+// Shadow-style client codes, the first byte
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Which client a peer id says it is, with its version, e.g. "Transmission 2.9.4".
+// None for ids that follow no convention we know, like most random ones.
+pub fn client_name(peer_id: &[u8]) -> Option<String> {
+    let id = peer_id.get(..8)?;
+    // -TR2940-: four version digits, 0-9 then A-Z for 10 & up
+    if id[0] == b'-' && id[7] == b'-' {
+        let (_, name) = AZUREUS_CLIENTS
+            .iter()
+            .find(|(code, _)| id[1..3] == code[..])?;
+        let mut version = id[3..7]
+            .iter()
+            .map(|&c| (c as char).to_digit(36).map(|digit| digit.to_string()))
+            .collect::<Option<Vec<_>>>()?;
+        while version.len() > 2 && version.last().is_some_and(|part| part == "0") {
+            version.pop();
+        }
+        return Some(format!("{} {}", name, version.join(".")));
+    }
+    // M4-3-6--: Mainline, dash-separated numbers
+    if id[0] == b'M' {
+        let version: Vec<&str> = std::str::from_utf8(&id[1..])
+            .ok()?
+            .split('-')
+            .filter(|part| !part.is_empty())
+            .collect();
+        let numeric = |part: &&str| part.bytes().all(|c| c.is_ascii_digit());
+        if version.len() != 3 || !version.iter().all(numeric) {
+            return None;
+        }
+        return Some(format!("Mainline {}", version.join(".")));
+    }
+    // S58B-----: a letter, up to five version characters, then dashes
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(code, _)| id[0] == *code)?;
+    if peer_id.get(6..9)? != b"---" {
+        return None;
+    }
+    let version = id[1..6]
+        .iter()
+        .take_while(|&&c| c != b'-')
+        .map(|&c| shadow_digit(c).map(|digit| digit.to_string()))
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+// 0-9, A-Z, a-z & `.` for 0 to 62
+fn shadow_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id: [u8; 20] = s
+            .as_bytes()
+            .try_into()
+            .map_err(|_| anyhow!("Peer ID must be 20 bytes, got {}", s.len()))?;
+        Ok(PeerId(id))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let first = PeerId::generate();
+        let second = PeerId::generate();
+        assert_eq!(first.as_bytes().len(), 20);
+        assert!(first.as_bytes().starts_with(DEFAULT_PREFIX.as_bytes()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_client_name() {
+        assert_eq!(
+            client_name(b"-TR2940-k8hj0wgej6ch").as_deref(),
+            Some("Transmission 2.9.4")
+        );
+        assert_eq!(
+            client_name(b"-qB4250-abcdefghijkl").as_deref(),
+            Some("qBittorrent 4.2.5")
+        );
+        assert_eq!(
+            client_name(b"-DE13F0-abcdefghijkl").as_deref(),
+            Some("Deluge 1.3.15")
+        );
+        assert_eq!(
+            client_name(b"-lt0D60-abcdefghijkl").as_deref(),
+            Some("libtorrent 0.13.6")
+        );
+        assert_eq!(
+            client_name(b"-UT3000-abcdefghijkl").as_deref(),
+            Some("µTorrent 3.0")
+        );
+        assert_eq!(
+            client_name(b"M4-3-6--abcdefghijkl").as_deref(),
+            Some("Mainline 4.3.6")
+        );
+        assert_eq!(
+            client_name(b"S58B-----abcdefghijk").as_deref(),
+            Some("Shadow 5.8.11")
+        );
+        assert_eq!(
+            client_name(b"T03I-----abcdefghijk").as_deref(),
+            Some("BitTornado 0.3.18")
+        );
+
+        // Unknown codes, random ids & ids too short to tell
+        assert_eq!(client_name(b"-XX1000-abcdefghijkl"), None);
+        assert_eq!(client_name(b"-TR29-0-abcdefghijkl"), None);
+        assert_eq!(client_name(&[0x9c; 20]), None);
+        assert_eq!(client_name(b"Mabcdefghijklmnopqrs"), None);
+        assert_eq!(client_name(b"Sabcdefghijklmnopqrs"), None);
+        assert_eq!(client_name(b"-TR"), None);
+    }
+
+    #[test]
+    fn test_from_str() {
+        let id: PeerId = "-RS0010-abcdefghijkl".parse().unwrap();
+        assert_eq!(id.to_string(), "-RS0010-abcdefghijkl");
+        assert!("-RS0010-short".parse::<PeerId>().is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// How long a peer that failed sits out before it's handed out again
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+// A peer failing this many times within the window is shut out until they age out
+const DEFAULT_BLACKLIST_FAILURES: usize = 3;
+const DEFAULT_BLACKLIST_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Where we heard of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Manual,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub source: PeerSource,
+    pub first_seen: Instant,
+    pub last_failure: Option<Instant>,
+    pub fail_count: u32,
+}
+
+// Every peer we know of for a torrent, whichever source it came from, in the
+// order they'll be tried. Failed peers go to the back & cool down for a while.
+pub struct PeerSet {
+    peers: HashMap<SocketAddr, PeerInfo>,
+    order: VecDeque<SocketAddr>,
+    own_addr: Option<SocketAddr>,
+    cooldown: Duration,
+    blacklist: PeerBlacklist,
+}
+
+impl Default for PeerSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerSet {
+    pub fn new() -> Self {
+        PeerSet {
+            peers: HashMap::new(),
+            order: VecDeque::new(),
+            own_addr: None,
+            cooldown: DEFAULT_COOLDOWN,
+            blacklist: PeerBlacklist::default(),
+        }
+    }
+
+    // Trackers happily hand us back to ourselves
+    pub fn with_own_addr(mut self, own_addr: SocketAddr) -> Self {
+        self.own_addr = Some(own_addr);
+        self
+    }
+
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn with_blacklist(mut self, blacklist: PeerBlacklist) -> Self {
+        self.blacklist = blacklist;
+        self
+    }
+
+    // False if the peer was already known or isn't worth trying
+    pub fn insert(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        if is_bogus(&addr) || Some(addr) == self.own_addr {
+            debug!("Ignoring peer {} from {:?}", addr, source);
+            return false;
+        }
+        self.peers.insert(
+            addr,
+            PeerInfo {
+                source,
+                first_seen: Instant::now(),
+                last_failure: None,
+                fail_count: 0,
+            },
+        );
+        self.order.push_back(addr);
+        true
+    }
+
+    // How many of the peers were new
+    pub fn extend(
+        &mut self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        addrs
+            .into_iter()
+            .filter(|&addr| self.insert(addr, source))
+            .count()
+    }
+
+    // The first peer in line that isn't cooling down. It stays in line, so the
+    // same peer keeps being handed out until it fails.
+    pub fn next_candidate(&self) -> Option<SocketAddr> {
+        self.next_candidate_at(Instant::now())
+    }
+
+    pub fn next_candidate_at(&self, now: Instant) -> Option<SocketAddr> {
+        self.candidates_at(now).next()
+    }
+
+    // Every peer not cooling down or blacklisted, in the order they'd be tried
+    pub fn candidates(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.candidates_at(Instant::now())
+    }
+
+    pub fn candidates_at(&self, now: Instant) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.order.iter().copied().filter(move |addr| {
+            self.peers[addr]
+                .last_failure
+                .is_none_or(|failed| now.duration_since(failed) >= self.cooldown)
+                && !self.blacklist.is_blacklisted_at(addr, now)
+        })
+    }
+
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        self.mark_failed_at(addr, Instant::now());
+    }
+
+    pub fn mark_failed_at(&mut self, addr: SocketAddr, now: Instant) {
+        let Some(info) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        info.last_failure = Some(now);
+        info.fail_count += 1;
+        self.blacklist.record_failure_at(addr, now);
+        self.order.retain(|&other| other != addr);
+        self.order.push_back(addr);
+    }
+
+    // E.g. a PEX peer another peer has since lost. False if it wasn't known.
+    pub fn remove(&mut self, addr: &SocketAddr) -> bool {
+        self.order.retain(|other| other != addr);
+        self.peers.remove(addr).is_some()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+// Peers that keep failing, e.g. serving corrupt blocks or hanging up: one that
+// fails `max_failures` times within `window` is skipped until the oldest of those
+// failures is `window` old.
+pub struct PeerBlacklist {
+    max_failures: usize,
+    window: Duration,
+    failures: HashMap<SocketAddr, VecDeque<Instant>>,
+}
+
+impl Default for PeerBlacklist {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLACKLIST_FAILURES, DEFAULT_BLACKLIST_WINDOW)
+    }
+}
+
+impl PeerBlacklist {
+    pub fn new(max_failures: usize, window: Duration) -> Self {
+        PeerBlacklist {
+            max_failures,
+            window,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        self.record_failure_at(addr, Instant::now());
+    }
+
+    pub fn record_failure_at(&mut self, addr: SocketAddr, now: Instant) {
+        let failures = self.failures.entry(addr).or_default();
+        failures.push_back(now);
+        // Only the last few within the window ever count
+        while failures.len() > self.max_failures
+            || failures
+                .front()
+                .is_some_and(|&failed| now.duration_since(failed) >= self.window)
+        {
+            failures.pop_front();
+        }
+        if failures.len() >= self.max_failures {
+            debug!("Blacklisting {} after {} failures", addr, failures.len());
+        }
+    }
+
+    pub fn is_blacklisted(&self, addr: &SocketAddr) -> bool {
+        self.is_blacklisted_at(addr, Instant::now())
+    }
+
+    pub fn is_blacklisted_at(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.failures.get(addr).is_some_and(|failures| {
+            failures
+                .iter()
+                .filter(|&&failed| now.saturating_duration_since(failed) < self.window)
+                .count()
+                >= self.max_failures
+        })
+    }
+}
+
+// Addresses no real peer can be reached at
+fn is_bogus(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 {
+        return true;
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_unspecified() || ip.is_multicast(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_dedupes_across_sources() {
+        let mut peers = PeerSet::new();
+        assert!(peers.insert(addr("10.0.0.1:6881"), PeerSource::Tracker));
+        assert!(!peers.insert(addr("10.0.0.1:6881"), PeerSource::Dht));
+        let added = peers.extend(
+            [addr("10.0.0.1:6881"), addr("10.0.0.2:6881")],
+            PeerSource::Pex,
+        );
+        assert_eq!(added, 1);
+        assert_eq!(peers.len(), 2);
+        // The first source to mention a peer is the one remembered
+        assert_eq!(
+            peers.get(&addr("10.0.0.1:6881")).unwrap().source,
+            PeerSource::Tracker
+        );
+
+        assert!(peers.remove(&addr("10.0.0.1:6881")));
+        assert!(!peers.remove(&addr("10.0.0.1:6881")));
+        assert_eq!(
+            peers.candidates().collect::<Vec<_>>(),
+            [addr("10.0.0.2:6881")]
+        );
+    }
+
+    #[test]
+    fn test_filters_bogus_peers() {
+        let mut peers = PeerSet::new().with_own_addr(addr("192.168.1.5:6881"));
+        for bogus in [
+            "10.0.0.1:0",
+            "0.0.0.0:6881",
+            "224.0.0.1:6881",
+            "255.255.255.255:6881",
+            "[::]:6881",
+            "[ff02::1]:6881",
+            "192.168.1.5:6881",
+        ] {
+            assert!(!peers.insert(addr(bogus), PeerSource::Tracker), "{}", bogus);
+        }
+        assert!(peers.is_empty());
+        assert!(peers.insert(addr("192.168.1.5:6882"), PeerSource::Tracker));
+    }
+
+    #[test]
+    fn test_failed_peers_cool_down_at_the_back() {
+        let mut peers = PeerSet::new().with_cooldown(Duration::from_secs(10));
+        peers.extend(
+            [
+                addr("10.0.0.1:6881"),
+                addr("10.0.0.2:6881"),
+                addr("10.0.0.3:6881"),
+            ],
+            PeerSource::Tracker,
+        );
+        let now = Instant::now();
+        assert_eq!(peers.next_candidate_at(now), Some(addr("10.0.0.1:6881")));
+
+        peers.mark_failed_at(addr("10.0.0.1:6881"), now);
+        assert_eq!(peers.next_candidate_at(now), Some(addr("10.0.0.2:6881")));
+        peers.mark_failed_at(addr("10.0.0.2:6881"), now);
+        peers.mark_failed_at(addr("10.0.0.3:6881"), now);
+        assert_eq!(peers.next_candidate_at(now), None);
+        assert_eq!(peers.candidates_at(now).count(), 0);
+
+        // Once cooled down they come back in the order they failed
+        let later = now + Duration::from_secs(10);
+        assert_eq!(peers.next_candidate_at(later), Some(addr("10.0.0.1:6881")));
+        assert_eq!(peers.get(&addr("10.0.0.1:6881")).unwrap().fail_count, 1);
+    }
+
+    #[test]
+    fn test_blacklist() {
+        let bad = addr("10.0.0.1:6881");
+        let mut blacklist = PeerBlacklist::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        blacklist.record_failure_at(bad, now);
+        blacklist.record_failure_at(bad, now + Duration::from_secs(1));
+        assert!(!blacklist.is_blacklisted_at(&bad, now + Duration::from_secs(2)));
+        blacklist.record_failure_at(bad, now + Duration::from_secs(2));
+        assert!(blacklist.is_blacklisted_at(&bad, now + Duration::from_secs(2)));
+        assert!(!blacklist.is_blacklisted_at(&addr("10.0.0.2:6881"), now));
+        // Reconsidered once the first failure is a window old
+        assert!(!blacklist.is_blacklisted_at(&bad, now + Duration::from_secs(60)));
+
+        // PeerSet skips it, long after its cooldown
+        let mut peers = PeerSet::new()
+            .with_cooldown(Duration::from_secs(1))
+            .with_blacklist(PeerBlacklist::new(3, Duration::from_secs(60)));
+        peers.extend([bad, addr("10.0.0.2:6881")], PeerSource::Tracker);
+        for seconds in 0..3 {
+            peers.mark_failed_at(bad, now + Duration::from_secs(seconds));
+        }
+        let later = now + Duration::from_secs(30);
+        assert_eq!(
+            peers.candidates_at(later).collect::<Vec<_>>(),
+            vec![addr("10.0.0.2:6881")]
+        );
+        let much_later = now + Duration::from_secs(62);
+        assert_eq!(peers.candidates_at(much_later).count(), 2);
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Error};
+use rand::seq::SliceRandom;
+use std::str::FromStr;
+
+use crate::bitfield::Bitfield;
+
+// Picks which piece to ask a peer for next. `peer_bitfield` has only the pieces up
+// for grabs set: ones the peer has that are wanted, not done & not out with another
+// peer.
+pub trait PieceSelector: Send {
+    fn next_piece(&self, peer_bitfield: &Bitfield) -> Option<usize>;
+
+    // What the connected peers have, for selectors that care: each peer's Bitfield
+    // once it's ready, its Haves after that, & its Bitfield again when it goes
+    fn peer_added(&mut self, _bitfield: &Bitfield) {}
+    fn peer_has(&mut self, _piece_index: usize) {}
+    fn peer_removed(&mut self, _bitfield: &Bitfield) {}
+}
+
+// Which PieceSelector a download uses, e.g. from --strategy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    Sequential,
+    RarestFirst,
+}
+
+impl Strategy {
+    pub fn selector(self, num_pieces: usize) -> Box<dyn PieceSelector> {
+        match self {
+            Strategy::Sequential => Box::new(Sequential),
+            Strategy::RarestFirst => Box::new(RarestFirst::new(num_pieces)),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Strategy::Sequential),
+            "rarest-first" => Ok(Strategy::RarestFirst),
+            _ => Err(anyhow!(
+                "Unknown strategy {}, expected sequential or rarest-first",
+                s
+            )),
+        }
+    }
+}
+
+// Lowest index first, so files fill in from the front
+pub struct Sequential;
+
+impl PieceSelector for Sequential {
+    fn next_piece(&self, peer_bitfield: &Bitfield) -> Option<usize> {
+        peer_bitfield.pieces(peer_bitfield.len_bytes() * 8).next()
+    }
+}
+
+// The piece the fewest connected peers have, so it's safe before they leave. Ties
+// go by an order shuffled for each selector.
+pub struct RarestFirst {
+    availability: Vec<usize>,
+    tie_break: Vec<usize>,
+}
+
+impl RarestFirst {
+    pub fn new(num_pieces: usize) -> Self {
+        Self::with_availability(vec![0; num_pieces])
+    }
+
+    // Counts to start from, e.g. bitfield::availability's
+    pub fn with_availability(availability: Vec<usize>) -> Self {
+        let mut tie_break: Vec<usize> = (0..availability.len()).collect();
+        tie_break.shuffle(&mut rand::rng());
+        RarestFirst {
+            availability,
+            tie_break,
+        }
+    }
+
+    pub fn availability(&self) -> &[usize] {
+        &self.availability
+    }
+}
+
+impl PieceSelector for RarestFirst {
+    fn next_piece(&self, peer_bitfield: &Bitfield) -> Option<usize> {
+        peer_bitfield
+            .pieces(self.availability.len())
+            .min_by_key(|&index| (self.availability[index], self.tie_break[index]))
+    }
+
+    fn peer_added(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.pieces(self.availability.len()) {
+            self.availability[index] += 1;
+        }
+    }
+
+    fn peer_has(&mut self, piece_index: usize) {
+        if let Some(count) = self.availability.get_mut(piece_index) {
+            *count += 1;
+        }
+    }
+
+    fn peer_removed(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.pieces(self.availability.len()) {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn bitfield(pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(8);
+        pieces.iter().for_each(|&index| bitfield.set_piece(index));
+        bitfield
+    }
+
+    #[test]
+    fn test_sequential() {
+        assert_eq!(Sequential.next_piece(&bitfield(&[5, 2, 7])), Some(2));
+        assert_eq!(Sequential.next_piece(&bitfield(&[])), None);
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let selector = RarestFirst::with_availability(vec![3, 1, 2, 5, 2]);
+        assert_eq!(selector.next_piece(&bitfield(&[0, 1, 2, 3, 4])), Some(1));
+        // Only from what the peer has
+        assert_eq!(selector.next_piece(&bitfield(&[0, 3])), Some(0));
+        assert_eq!(selector.next_piece(&bitfield(&[])), None);
+
+        // A peer's pieces count while it's connected, & its Haves with them
+        let mut selector = RarestFirst::new(5);
+        selector.peer_added(&bitfield(&[0, 1, 2]));
+        selector.peer_added(&bitfield(&[0, 1]));
+        selector.peer_has(2);
+        selector.peer_has(99);
+        assert_eq!(selector.availability(), &[2, 2, 2, 0, 0]);
+        selector.peer_removed(&bitfield(&[0, 1]));
+        assert_eq!(selector.availability(), &[1, 1, 2, 0, 0]);
+        assert_eq!(selector.next_piece(&bitfield(&[1, 2])), Some(1));
+    }
+
+    #[test]
+    fn test_rarest_first_breaks_ties_randomly() {
+        // Pieces 1 & 3 tie for rarest
+        let peer_bitfield = bitfield(&[0, 1, 2, 3]);
+        let picked: BTreeSet<usize> = (0..100)
+            .map(|_| {
+                let selector = RarestFirst::with_availability(vec![2, 1, 2, 1]);
+                let piece = selector.next_piece(&peer_bitfield).unwrap();
+                // The same every time for one selector
+                assert_eq!(selector.next_piece(&peer_bitfield), Some(piece));
+                piece
+            })
+            .collect();
+        assert_eq!(picked, BTreeSet::from([1, 3]));
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(
+            "sequential".parse::<Strategy>().unwrap(),
+            Strategy::Sequential
+        );
+        assert_eq!(
+            "rarest-first".parse::<Strategy>().unwrap(),
+            Strategy::RarestFirst
+        );
+        assert!("random".parse::<Strategy>().is_err());
+    }
+}
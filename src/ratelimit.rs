@@ -0,0 +1,148 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Token bucket: one token per byte, refilled at `rate` per second and capped at a
+// second's worth. It starts empty so even the first bytes are paced.
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            rate: bytes_per_sec.max(1) as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_kib(kib_per_sec: u64) -> Self {
+        Self::new(kib_per_sec * 1024)
+    }
+
+    // Take `n` tokens, going into debt if need be, & return how long to wait it off
+    pub fn acquire(&self, n: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.rate) - n as f64;
+        bucket.last = now;
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    pub fn throttle(&self, n: usize) {
+        let wait = self.acquire(n);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+// Limiters are shared, so a rate caps every connection together
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    pub download: Option<Arc<RateLimiter>>,
+    pub upload: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    // In KiB/s, as given on the command line
+    pub fn from_kib(download: Option<u64>, upload: Option<u64>) -> Self {
+        RateLimits {
+            download: download.map(|rate| Arc::new(RateLimiter::from_kib(rate))),
+            upload: upload.map(|rate| Arc::new(RateLimiter::from_kib(rate))),
+        }
+    }
+}
+
+// Paces reads against the download limit & writes against the upload limit
+pub struct Throttled<S> {
+    inner: S,
+    limits: RateLimits,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limits: RateLimits) -> Self {
+        Throttled { inner, limits }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(limiter) = &self.limits.download {
+            limiter.throttle(n);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(limiter) = &self.limits.upload {
+            limiter.throttle(n);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_throttled_read_takes_n_over_r() {
+        // 20 KiB at 100 KiB/s should take at least 0.2s
+        let limits = RateLimits::from_kib(Some(100), None);
+        let mut reader = Throttled::new(Cursor::new(vec![0; 20 * 1024]), limits);
+        let started = Instant::now();
+        let mut buf = [0; 1024];
+        let mut total = 0;
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        let elapsed = started.elapsed();
+        assert_eq!(total, 20 * 1024);
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_unlimited_is_not_throttled() {
+        let mut writer = Throttled::new(Vec::new(), RateLimits::default());
+        let started = Instant::now();
+        writer.write_all(&[0; 1024 * 1024]).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
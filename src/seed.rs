@@ -0,0 +1,356 @@
+use anyhow::{Error, Result};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::bitfield::Bitfield;
+use crate::file::{Info, PieceReader};
+use crate::network::{AsyncPeerStream, PeerMessage};
+use crate::ratelimit::RateLimits;
+
+// Bigger requests than this are turned down; everyone asks for 16 KiB anyway
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+// How many peers are unchoked at once unless told otherwise
+pub const DEFAULT_MAX_UPLOADS: usize = 4;
+
+// A torrent we serve, from its files on disk
+pub struct SeededTorrent {
+    info: Info,
+    path: PathBuf,
+    have: Bitfield,
+}
+
+impl SeededTorrent {
+    // Only pieces that check out against their hashes are offered. `path` is as for
+    // Info::verify_file.
+    pub fn new(info: Info, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut have = Bitfield::new(info.iter_pieces().count());
+        for (piece_index, ok) in info.verify_file(&path)?.into_iter().enumerate() {
+            if ok {
+                have.set_piece(piece_index);
+            }
+        }
+        Ok(SeededTorrent { info, path, have })
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+}
+
+// Accepts peers that connect to us & serves them pieces of whichever torrent they
+// ask for. Interested peers are unchoked while there's an upload slot free.
+pub struct Listener {
+    listener: TcpListener,
+    torrents: HashMap<[u8; 20], Arc<SeededTorrent>>,
+    max_uploads: usize,
+    limits: RateLimits,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(addr).await?))
+    }
+
+    // E.g. the socket ListenPort already holds
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::new(TcpListener::from_std(listener)?))
+    }
+
+    fn new(listener: TcpListener) -> Self {
+        Listener {
+            listener,
+            torrents: HashMap::new(),
+            max_uploads: DEFAULT_MAX_UPLOADS,
+            limits: RateLimits::default(),
+        }
+    }
+
+    pub fn with_torrent(mut self, torrent: SeededTorrent) -> Self {
+        self.torrents
+            .insert(torrent.info.info_hash(), Arc::new(torrent));
+        self
+    }
+
+    // At least one
+    pub fn with_max_uploads(mut self, max_uploads: usize) -> Self {
+        self.max_uploads = max_uploads.max(1);
+        self
+    }
+
+    // Shared by every peer, so the upload rate caps all of them together
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serve every peer that connects, a task each, until dropped
+    pub async fn run(self) -> Result<()> {
+        let torrents = Arc::new(self.torrents);
+        let uploads = Arc::new(Semaphore::new(self.max_uploads));
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            debug!("Incoming connection from {}", addr);
+            let torrents = torrents.clone();
+            let uploads = uploads.clone();
+            let limits = self.limits.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, addr, &torrents, uploads, limits).await {
+                    warn!("Peer {} failed: {:#}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+// One peer, from its handshake until it hangs up
+async fn serve(
+    stream: TcpStream,
+    addr: SocketAddr,
+    torrents: &HashMap<[u8; 20], Arc<SeededTorrent>>,
+    uploads: Arc<Semaphore>,
+    limits: RateLimits,
+) -> Result<()> {
+    let mut peer_stream = AsyncPeerStream::from_stream(stream, addr);
+    peer_stream.set_rate_limits(limits);
+    // We keep no list of peers to share
+    peer_stream.set_pex(false);
+    let handshake = peer_stream
+        .accept_handshake(|info_hash| torrents.contains_key(info_hash))
+        .await?;
+    let torrent = &torrents[handshake.info_hash()];
+    info!("Seeding {} to {}", torrent.info.name(), addr);
+    peer_stream
+        .write(&PeerMessage::Bitfield(torrent.have.clone()))
+        .await?;
+    let mut reader = torrent.info.piece_reader(&torrent.path)?;
+
+    // Held while the peer is unchoked
+    let mut upload: Option<OwnedSemaphorePermit> = None;
+    loop {
+        // Kept alive while the peer's quiet, & dropped once it's been quiet too long
+        let wait = peer_stream.keep_alive().await?;
+        let read = tokio::select! {
+            read = peer_stream.read() => read,
+            _ = tokio::time::sleep(wait) => continue,
+        };
+        let message = match read {
+            Ok(message) => message,
+            Err(e) if is_hang_up(&e) => {
+                debug!("{} hung up", addr);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        match message {
+            PeerMessage::Interested if upload.is_none() => {
+                match uploads.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        upload = Some(permit);
+                        peer_stream.write(&PeerMessage::Unchoke).await?;
+                    }
+                    Err(_) => debug!("No upload slot for {}", addr),
+                }
+            }
+            PeerMessage::NotInterested if upload.is_some() => {
+                upload = None;
+                peer_stream.write(&PeerMessage::Choke).await?;
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if upload.is_none() {
+                    debug!("Ignoring request from choked peer {}", addr);
+                    continue;
+                }
+                // Without the Fast Extension there's no rejecting, only not answering
+                if length > MAX_REQUEST_LENGTH || !torrent.have.has_piece(index as usize) {
+                    debug!(
+                        "Ignoring request from {} for {} bytes of piece {}",
+                        addr, length, index
+                    );
+                    continue;
+                }
+                let block;
+                (reader, block) = read_block(reader, index, begin, length).await?;
+                peer_stream
+                    .write(&PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Off the runtime's threads, as the disk read blocks. The reader comes back for the
+// next block.
+async fn read_block(
+    mut reader: PieceReader,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> Result<(PieceReader, Vec<u8>)> {
+    tokio::task::spawn_blocking(move || {
+        let block = reader.read_block(index as usize, begin as u64, length as usize)?;
+        Ok((reader, block))
+    })
+    .await?
+}
+
+fn is_hang_up(e: &Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::Coordinator;
+    use crate::network::{HandshakeError, DEFAULT_CONNECT_TIMEOUT};
+    use crate::peer_set::{PeerSet, PeerSource};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::time::{Duration, Instant};
+
+    // Four and a bit 16 KiB pieces
+    fn fixture() -> (tempfile::TempDir, PathBuf, Info, Vec<u8>) {
+        let content: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.bin");
+        std::fs::write(&path, &content).unwrap();
+        let info = Info::from_file(&path, 16 * 1024).unwrap();
+        (dir, path, info, content)
+    }
+
+    async fn spawn_seeder(torrent: SeededTorrent) -> SocketAddr {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_torrent(torrent);
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_seed_then_download() {
+        let (_dir, path, info, content) = fixture();
+        let seeder = spawn_seeder(SeededTorrent::new(info.clone(), &path).unwrap()).await;
+
+        let mut peers = PeerSet::new();
+        peers.insert(seeder, PeerSource::Manual);
+        let (_tx, mut new_peers) = tokio::sync::mpsc::unbounded_channel();
+        let wanted: BTreeSet<usize> = info.iter_pieces().map(|(index, _, _)| index).collect();
+        let mut pieces = BTreeMap::new();
+        let left = Coordinator::new(info.clone())
+            .download(&mut peers, &mut new_peers, &wanted, |index, piece| {
+                pieces.insert(index, piece);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(left.is_empty());
+        assert_eq!(pieces.into_values().flatten().collect::<Vec<_>>(), content);
+    }
+
+    #[tokio::test]
+    async fn test_seeder_throttles_uploads() {
+        let (_dir, path, info, content) = fixture();
+        // 32 KiB at 64 KiB/s should take at least half a second
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_torrent(SeededTorrent::new(info.clone(), &path).unwrap())
+            .with_rate_limits(RateLimits::from_kib(None, Some(64)));
+        let seeder = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+
+        let mut peer_stream = AsyncPeerStream::connect(seeder, DEFAULT_CONNECT_TIMEOUT)
+            .await
+            .unwrap();
+        peer_stream.prep_download(&info.info_hash()).await.unwrap();
+        let started = Instant::now();
+        for index in 0..2 {
+            let piece = peer_stream
+                .download_piece(index, &info.piece_len(index as usize))
+                .await
+                .unwrap();
+            let start = index as usize * 16 * 1024;
+            assert_eq!(piece, content[start..start + 16 * 1024]);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_seeder_turns_down_bad_requests() {
+        let (_dir, path, info, content) = fixture();
+        // The third piece is corrupt on disk, so it's not offered
+        let mut corrupt = content.clone();
+        corrupt[2 * 16 * 1024] ^= 0xff;
+        std::fs::write(&path, &corrupt).unwrap();
+        let torrent = SeededTorrent::new(info.clone(), &path).unwrap();
+        assert_eq!(torrent.have().count_ones(), 4);
+        let seeder = spawn_seeder(torrent).await;
+
+        // Some other torrent
+        let mut peer_stream = AsyncPeerStream::connect(seeder, DEFAULT_CONNECT_TIMEOUT)
+            .await
+            .unwrap();
+        let e = peer_stream.handshake(&[7; 20]).await.unwrap_err();
+        assert!(e.downcast_ref::<HandshakeError>().is_some(), "{:#}", e);
+
+        let mut peer_stream = AsyncPeerStream::connect(seeder, DEFAULT_CONNECT_TIMEOUT)
+            .await
+            .unwrap();
+        peer_stream.prep_download(&info.info_hash()).await.unwrap();
+        assert!(!peer_stream.peer_has_piece(2));
+        let request = |index, begin, length| PeerMessage::Request {
+            index,
+            begin,
+            length,
+        };
+        // Too big, then a piece it lacks: neither is answered, only the last request is
+        peer_stream.write(&request(0, 0, 256 * 1024)).await.unwrap();
+        peer_stream.write(&request(2, 0, 16 * 1024)).await.unwrap();
+        peer_stream.write(&request(1, 100, 50)).await.unwrap();
+        let message = loop {
+            match peer_stream.read().await.unwrap() {
+                PeerMessage::Extended { .. } => continue,
+                message => break message,
+            }
+        };
+        assert_eq!(
+            message,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 100,
+                block: content[16 * 1024 + 100..16 * 1024 + 150].to_vec()
+            }
+        );
+    }
+}
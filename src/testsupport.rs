@@ -0,0 +1,484 @@
+// Helpers shared by the unit tests, the binary's included
+use bittorrent_starter_rust::file::Info;
+use bittorrent_starter_rust::network::{PeerIo, PeerStream};
+use sha1::{Digest, Sha1};
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+// Read an HTTP request up to the end of its headers & return the request line
+pub fn read_request<R: Read>(stream: &mut R) -> String {
+    read_request_head(stream)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The request line & headers
+pub fn read_request_head<R: Read>(stream: &mut R) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
+
+pub fn write_response<W: Write>(stream: &mut W, status: &str, body: &[u8]) {
+    write_response_with_headers(stream, status, &[], body);
+}
+
+pub fn write_response_with_headers<W: Write>(
+    stream: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) {
+    let mut header = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    for (name, value) in headers {
+        header.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    header.push_str("\r\n");
+    stream.write_all(header.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+}
+
+// Serve a single HTTP response on an ephemeral port & return its base URL
+pub fn serve_once(status: &str, body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let status = status.to_string();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_request(&mut stream);
+        write_response(&mut stream, &status, &body);
+    });
+    format!("http://{}", addr)
+}
+
+// Like serve_once, with extra response headers, e.g. Content-Encoding
+pub fn serve_once_with_headers(
+    headers: &'static [(&'static str, &'static str)],
+    body: Vec<u8>,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_request(&mut stream);
+        write_response_with_headers(&mut stream, "200 OK", headers, &body);
+    });
+    format!("http://{}", addr)
+}
+
+// Serve one HTTP 200 per body, in order, on an ephemeral port. Returns the base URL
+// & a channel of the request lines received
+pub fn serve_sequence(bodies: Vec<Vec<u8>>) -> (String, std::sync::mpsc::Receiver<String>) {
+    serve_responses(bodies.into_iter().map(|body| ("200 OK", body)).collect())
+}
+
+// Like serve_sequence, with a status line per response
+pub fn serve_responses(
+    responses: Vec<(&'static str, Vec<u8>)>,
+) -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            tx.send(read_request(&mut stream)).unwrap();
+            write_response(&mut stream, status, &body);
+        }
+    });
+    (format!("http://{}", addr), rx)
+}
+
+// Serve `content` to `n` requests like a web seed, answering `Range: bytes=a-b` with
+// a 206. Returns the base URL & a channel of the ranges asked for.
+pub fn serve_ranges(content: Vec<u8>, n: usize) -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for _ in 0..n {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = read_request_head(&mut stream);
+            let range = head
+                .lines()
+                .map(str::to_ascii_lowercase)
+                .find_map(|line| line.strip_prefix("range: bytes=").map(str::to_string))
+                .unwrap_or_default();
+            match range.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to): (usize, usize) = (from.parse().unwrap(), to.parse().unwrap());
+                    write_response(&mut stream, "206 Partial Content", &content[from..=to]);
+                }
+                None => write_response(&mut stream, "200 OK", &content),
+            }
+            tx.send(range).unwrap();
+        }
+    });
+    (format!("http://{}", addr), rx)
+}
+
+// A canned tracker answer
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: &'static str,
+    body: Vec<u8>,
+    delay: Duration,
+}
+
+impl MockResponse {
+    pub fn body(body: Vec<u8>) -> Self {
+        Self::status("200 OK", body)
+    }
+
+    pub fn status(status: &'static str, body: Vec<u8>) -> Self {
+        MockResponse {
+            status,
+            body,
+            delay: Duration::ZERO,
+        }
+    }
+
+    // A compact IPv4 peer list
+    pub fn peers(interval: u64, peers: &[SocketAddr]) -> Self {
+        let compact: Vec<u8> = peers
+            .iter()
+            .flat_map(|peer| match peer {
+                SocketAddr::V4(peer) => [
+                    peer.ip().octets().to_vec(),
+                    peer.port().to_be_bytes().to_vec(),
+                ]
+                .concat(),
+                SocketAddr::V6(_) => panic!("compact peers are IPv4 only"),
+            })
+            .collect();
+        let mut body = format!("d8:intervali{}e5:peers{}:", interval, compact.len()).into_bytes();
+        body.extend(compact);
+        body.push(b'e');
+        Self::body(body)
+    }
+
+    pub fn failure(reason: &str) -> Self {
+        Self::body(format!("d14:failure reason{}:{}e", reason.len(), reason).into_bytes())
+    }
+
+    // Hold the response back, e.g. to run into a client timeout
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+// One request a MockTracker got: its path & query parameters, still percent-encoded
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub path: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl MockRequest {
+    fn parse(request_line: &str) -> Self {
+        let target = request_line.split(' ').nth(1).unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        MockRequest {
+            path: path.to_string(),
+            params: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// An HTTP tracker on an ephemeral port. Its responses are handed out in turn, starting
+// over after the last, & every request is recorded for assertions.
+pub struct MockTracker {
+    addr: SocketAddr,
+    responses: Arc<Mutex<Vec<MockResponse>>>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockTracker {
+    // Answers every announce with one peer, 127.0.0.1:6800, until told otherwise
+    pub fn spawn() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responses = Arc::new(Mutex::new(vec![MockResponse::peers(
+            1800,
+            &[SocketAddr::from(([127, 0, 0, 1], 6800))],
+        )]));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (canned, seen) = (responses.clone(), requests.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let request = MockRequest::parse(&read_request(&mut stream));
+                let response = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(request);
+                    let canned = canned.lock().unwrap();
+                    canned[(seen.len() - 1) % canned.len()].clone()
+                };
+                // Delayed responses mustn't hold up the next request
+                std::thread::spawn(move || {
+                    std::thread::sleep(response.delay);
+                    let header = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        response.status,
+                        response.body.len()
+                    );
+                    // The client may have given up by now
+                    let _ = stream.write_all(header.as_bytes());
+                    let _ = stream.write_all(&response.body);
+                });
+            }
+        });
+        MockTracker {
+            addr,
+            responses,
+            requests,
+        }
+    }
+
+    pub fn with_responses(self, responses: Vec<MockResponse>) -> Self {
+        assert!(!responses.is_empty());
+        *self.responses.lock().unwrap() = responses;
+        self
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// A peer that handshakes, sends a Bitfield with the first 8 pieces & unchokes, then
+// hands its single connection to `script`
+pub fn spawn_scripted_peer<F>(script: F) -> SocketAddr
+where
+    F: FnOnce(&mut TcpStream) + Send + 'static,
+{
+    spawn_handshaking_peer(|stream| {
+        stream.write_all(&[0, 0, 0, 2, 5, 0xff]).unwrap();
+        let mut interested = [0; 5];
+        stream.read_exact(&mut interested).unwrap();
+        stream.write_all(&[0, 0, 0, 1, 1]).unwrap();
+        script(stream);
+    })
+}
+
+// A peer that only answers the handshake before handing its single connection to
+// `script`
+pub fn spawn_handshaking_peer<F>(script: F) -> SocketAddr
+where
+    F: FnOnce(&mut TcpStream) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        echo_handshake(&mut stream).unwrap();
+        script(&mut stream);
+    });
+    addr
+}
+
+// Read the client's handshake & send it back as a peer without extensions would
+pub fn echo_handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<()> {
+    let mut handshake = [0; 68];
+    stream.read_exact(&mut handshake)?;
+    handshake[20..28].fill(0);
+    stream.write_all(&handshake)
+}
+
+// Replays canned bytes to whoever reads it & keeps whatever is written, to drive
+// a PeerStream without a socket
+pub struct ScriptedStream {
+    input: Cursor<Vec<u8>>,
+    pub written: Vec<u8>,
+}
+
+impl ScriptedStream {
+    pub fn new(input: Vec<u8>) -> Self {
+        ScriptedStream {
+            input: Cursor::new(input),
+            written: Vec::new(),
+        }
+    }
+}
+
+impl Read for ScriptedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl PeerIo for ScriptedStream {}
+
+// Where scripted peers claim to be
+pub const MOCK_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6881);
+
+// A PeerStream replaying `canned` as everything the peer sends
+pub fn scripted_peer(canned: Vec<u8>) -> PeerStream<ScriptedStream> {
+    PeerStream::from_stream(ScriptedStream::new(canned), MOCK_PEER)
+}
+
+impl Write for ScriptedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Read a block Request as (index, begin, length). Requests are always 17 bytes on the
+// wire, so this doesn't go by the length prefix
+pub fn read_block_request<R: Read>(stream: &mut R) -> (u32, u32, u32) {
+    let mut request = [0; 17];
+    stream.read_exact(&mut request).unwrap();
+    assert_eq!(request[4], 6, "expected a Request, got {:?}", request);
+    let field = |at: usize| u32::from_be_bytes(request[at..at + 4].try_into().unwrap());
+    (field(5), field(9), field(13))
+}
+
+// A Request read by read_message as (index, begin, length), or None for anything else
+pub fn block_request(message: &[u8]) -> Option<(u32, u32, u32)> {
+    if message.len() != 13 || message[0] != 6 {
+        return None;
+    }
+    let field = |at: usize| u32::from_be_bytes(message[at..at + 4].try_into().unwrap());
+    Some((field(1), field(5), field(9)))
+}
+
+// Answer Requests out of `content`, taking `block_delay` over each block, until
+// `serves` are answered or the downloader hangs up. Everything else goes to `heard`
+pub fn serve_blocks<S: Read + Write>(
+    stream: &mut S,
+    content: &[u8],
+    piece_length: usize,
+    serves: usize,
+    block_delay: Duration,
+    mut heard: impl FnMut(Vec<u8>),
+) {
+    let mut served = 0;
+    while served < serves {
+        let Ok(message) = read_message(stream) else {
+            return;
+        };
+        let Some((index, begin, length)) = block_request(&message) else {
+            heard(message);
+            continue;
+        };
+        served += 1;
+        let start = index as usize * piece_length + begin as usize;
+        std::thread::sleep(block_delay);
+        write_block(
+            stream,
+            index,
+            begin,
+            &content[start..start + length as usize],
+        );
+    }
+}
+
+// Read a framed message: its id & payload, or nothing for a keep-alive
+pub fn read_message<R: Read>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut message = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+// Write a framed message: length prefix, id, payload
+pub fn write_message<W: Write>(stream: &mut W, id: u8, payload: &[u8]) {
+    stream
+        .write_all(&(payload.len() as u32 + 1).to_be_bytes())
+        .unwrap();
+    stream.write_all(&[id]).unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+// Write a Piece message carrying `block`
+pub fn write_block<W: Write>(stream: &mut W, index: u32, begin: u32, block: &[u8]) {
+    let mut payload = index.to_be_bytes().to_vec();
+    payload.extend(begin.to_be_bytes());
+    payload.extend(block);
+    write_message(stream, 7, &payload);
+}
+
+// A single-file Info for `content`, hashed in `piece_length` pieces
+pub fn info_for(name: &str, content: &[u8], piece_length: usize) -> Info {
+    let pieces = content
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    Info::single_file(
+        name.to_string(),
+        content.len() as i64,
+        piece_length as i64,
+        pieces,
+    )
+}
+
+// Captures every log record so tests can check what went to the log rather than stdout
+struct TestLogger;
+
+static RECORDS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+impl log::Log for TestLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        RECORDS
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init_test_logger() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&TestLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+// Records logged so far by every test in this process
+pub fn logged_records() -> Vec<(log::Level, String)> {
+    RECORDS.lock().unwrap().clone()
+}
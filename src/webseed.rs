@@ -0,0 +1,172 @@
+use anyhow::{bail, Context, Result};
+use log::debug;
+use reqwest::blocking::Client;
+use reqwest::header::RANGE;
+use reqwest::{StatusCode, Url};
+use std::time::Duration;
+
+use crate::file::Info;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// A web seed (BEP 19): a plain HTTP server holding the torrent's files, read a piece
+// at a time with Range requests. Blocking, like the peer downloads.
+pub struct WebSeed {
+    url: Url,
+    http: Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid web seed URL: {}", url))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Web seed {} is not http(s)", url);
+        }
+        let http = Client::builder().timeout(TIMEOUT).build()?;
+        Ok(WebSeed { url, http })
+    }
+
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    // Where one of the torrent's files lives. A URL ending in / is a directory holding
+    // the torrent's name; for multi-file torrents it always is.
+    fn file_url(&self, info: &Info, path: &[String]) -> Url {
+        let mut url = self.url.clone();
+        if info.files().is_none() && !url.path().ends_with('/') {
+            return url;
+        }
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push(info.name());
+            if info.files().is_some() {
+                segments.extend(path);
+            }
+        }
+        url
+    }
+
+    // Fetch a piece, from every file it overlaps, & check it against its hash
+    pub fn fetch_piece(&self, info: &Info, piece_index: usize) -> Result<Vec<u8>> {
+        let start = piece_index as u64 * info.piece_length() as u64;
+        let end = start + info.piece_len(piece_index) as u64;
+        let mut piece = Vec::with_capacity((end - start) as usize);
+        let mut offset = 0;
+        for file in info.file_entries() {
+            let range = offset..offset + file.length as u64;
+            offset = range.end;
+            let (from, to) = (start.max(range.start), end.min(range.end));
+            if from >= to {
+                continue;
+            }
+            // Padding files aren't on the server
+            if file.is_padding() {
+                piece.resize(piece.len() + (to - from) as usize, 0);
+                continue;
+            }
+            let url = self.file_url(info, &file.path);
+            piece.extend(self.fetch_range(url, from - range.start, to - range.start)?);
+        }
+        if !info.verify_piece(piece_index, &piece) {
+            bail!(
+                "Piece {} from web seed {} failed verification",
+                piece_index,
+                self.url
+            );
+        }
+        Ok(piece)
+    }
+
+    // Bytes `from..to` of the file at `url`
+    fn fetch_range(&self, url: Url, from: u64, to: u64) -> Result<Vec<u8>> {
+        debug!("Fetching bytes {}..{} of {}", from, to, url);
+        let response = self
+            .http
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", from, to - 1))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("HTTP error: could not fetch {}", url))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .with_context(|| format!("HTTP error: could not read body from {}", url))?;
+        // Servers that ignore Range send the whole file
+        let block = match status {
+            StatusCode::PARTIAL_CONTENT => &body[..],
+            _ => body.get(from as usize..to as usize).unwrap_or_default(),
+        };
+        if block.len() as u64 != to - from {
+            bail!(
+                "Web seed {} sent {} bytes, expected {}",
+                url,
+                block.len(),
+                to - from
+            );
+        }
+        Ok(block.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FileEntry;
+    use crate::testsupport::{info_for, serve_ranges};
+
+    #[test]
+    fn test_fetch_piece() {
+        let content: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let info = info_for("sample.bin", &content, 16384);
+        let (url, ranges) = serve_ranges(content.clone(), 2);
+        let seed = WebSeed::new(&format!("{}/files/", url)).unwrap();
+
+        let piece = seed.fetch_piece(&info, 1).unwrap();
+        assert_eq!(piece, content[16384..32768]);
+        assert_eq!(ranges.recv().unwrap(), "16384-32767");
+
+        // The last piece is short
+        let piece = seed.fetch_piece(&info, 2).unwrap();
+        assert_eq!(piece, content[32768..]);
+        assert_eq!(ranges.recv().unwrap(), "32768-39999");
+    }
+
+    #[test]
+    fn test_fetch_piece_rejects_bad_data() {
+        let content = vec![7; 1000];
+        let info = info_for("sample.bin", &content, 1000);
+        let (url, _ranges) = serve_ranges(vec![8; 1000], 1);
+        let seed = WebSeed::new(&url).unwrap();
+        let err = seed.fetch_piece(&info, 0).unwrap_err();
+        assert!(err.to_string().contains("failed verification"), "{}", err);
+    }
+
+    #[test]
+    fn test_file_url() {
+        let seed = WebSeed::new("http://seed.example/pub/").unwrap();
+        let info = info_for("sample.bin", &[0; 10], 10);
+        assert_eq!(
+            seed.file_url(&info, &[]).as_str(),
+            "http://seed.example/pub/sample.bin"
+        );
+        let exact = WebSeed::new("http://seed.example/pub/sample.bin").unwrap();
+        assert_eq!(
+            exact.file_url(&info, &[]).as_str(),
+            "http://seed.example/pub/sample.bin"
+        );
+
+        let file = FileEntry {
+            length: 10,
+            path: vec!["a dir".to_string(), "b.txt".to_string()],
+            md5sum: None,
+            attr: None,
+        };
+        let info = Info::multi_file("sample.bin".to_string(), vec![file], 10, Vec::new());
+        // Multi-file seeds are directories, with or without the /
+        let dir = WebSeed::new("http://seed.example/pub").unwrap();
+        assert_eq!(
+            dir.file_url(&info, &info.file_entries()[0].path).as_str(),
+            "http://seed.example/pub/sample.bin/a%20dir/b.txt"
+        );
+    }
+}